@@ -0,0 +1,178 @@
+//! Throughput of the per-packet work every routed packet goes through:
+//! parsing, filtering, duplicate checks and KISS framing.
+//!
+//! Run with `cargo bench`, or `cargo bench -- parse` for one group.
+//! Criterion keeps the previous run's results and reports the change.
+
+use aprstx::aprs::{parse_packet, AprsPacket};
+use aprstx::config::FilterConfig;
+use aprstx::dedup::{packet_key, DupeCache};
+use aprstx::filter::PacketFilter;
+use aprstx::serial::kiss::KissCodec;
+use bytes::BytesMut;
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use std::time::{Duration, Instant};
+
+/// A mix like a busy APRS-IS feed: positions in each encoding, messages,
+/// objects, telemetry, status and weather
+const PACKETS: &[&str] = &[
+    "N0CALL-9>APRS,WIDE1-1,WIDE2-1:!4903.50N/07201.75W>088/036/A=001234 Mobile",
+    "N1CALL>APRS,TCPIP*,qAC,T2TEXAS:@092345z4903.50N/07201.75W_090/000g005t077r000p000P000h50b09900",
+    "N2CALL-7>T2SP0W,WIDE1-1,qAR,N0CALL-10:`(_fn\"Oj/]\"4)}",
+    "N3CALL>APRS,qAS,N0CALL:=/5L!!<*e7>7P[Compressed beacon",
+    "N4CALL>APRS,WIDE2-2,qAR,N0CALL-10::N0CALL   :Meet at the hamfest{42",
+    "N5CALL>APRS,TCPIP*:;LEADER   *092345z4903.50N/07201.75W>088/036",
+    "N6CALL>APRS,qAR,N0CALL-10:T#005,199,000,255,073,123,01101001",
+    "N7CALL>APRS,WIDE1-1:>Listening on 146.520MHz",
+    "N8CALL-15>APDW16,WIDE1-1,WIDE2-1,qAR,N0CALL-10:!4903.50N\\07201.75W# Digipeater",
+    "N9CALL>APRS,TCPIP*,qAC,FOURTH:}N0CALL>APRS,TCPIP,N9CALL*:>Third party",
+];
+
+fn packets() -> Vec<AprsPacket> {
+    PACKETS.iter().map(|p| parse_packet(p).unwrap()).collect()
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Elements(PACKETS.len() as u64));
+    group.bench_function("parse_packet", |b| {
+        b.iter(|| {
+            for packet in PACKETS {
+                black_box(parse_packet(black_box(packet)).unwrap());
+            }
+        })
+    });
+    group.bench_function("to_string", |b| {
+        let packets = packets();
+        b.iter(|| {
+            for packet in &packets {
+                black_box(packet.to_string());
+            }
+        })
+    });
+    group.finish();
+}
+
+fn bench_filter(c: &mut Criterion) {
+    let configs: Vec<FilterConfig> = toml::from_str::<toml::Table>(
+        r#"
+        [[filters]]
+        name = "rfonly"
+        action = "drop"
+        pattern = "RFONLY"
+
+        [[filters]]
+        name = "nogate"
+        action = "drop"
+        pattern = "NOGATE"
+
+        [[filters]]
+        name = "tcpip"
+        action = "drop"
+        pattern = "TCPIP"
+        direction = "is_to_rf"
+
+        [[filters]]
+        name = "no-weather"
+        action = "drop"
+        types = ["weather"]
+
+        [[filters]]
+        name = "locals"
+        action = "count"
+        callsigns = ["N?CALL*", "W1AW*"]
+        "#,
+    )
+    .unwrap()["filters"]
+        .clone()
+        .try_into()
+        .unwrap();
+    let filter = PacketFilter::new(configs).unwrap();
+    let packets = packets();
+
+    let mut group = c.benchmark_group("filter");
+    group.throughput(Throughput::Elements(packets.len() as u64));
+    group.bench_function("should_pass", |b| {
+        b.iter(|| {
+            for packet in &packets {
+                black_box(filter.should_pass(black_box(packet)));
+            }
+        })
+    });
+    group.finish();
+}
+
+fn bench_dedup(c: &mut Criterion) {
+    // As full as the router lets it get
+    let now = Instant::now();
+    let mut cache = DupeCache::new(1000);
+    let statuses: Vec<AprsPacket> = (0..1000)
+        .map(|i| parse_packet(&format!("N{}CALL>APRS:>Status {}", i % 10, i)).unwrap())
+        .collect();
+    for packet in &statuses {
+        cache.insert(&packet_key(packet), now);
+    }
+    let window = Duration::from_secs(30);
+    let fresh = packets();
+
+    let mut group = c.benchmark_group("dedup");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("key", |b| {
+        b.iter(|| black_box(packet_key(black_box(&fresh[0]))))
+    });
+    group.bench_function("hit", |b| {
+        b.iter(|| {
+            let key = packet_key(black_box(&statuses[500]));
+            black_box(cache.is_duplicate(&key, now, window))
+        })
+    });
+    group.bench_function("miss", |b| {
+        b.iter(|| {
+            let key = packet_key(black_box(&fresh[0]));
+            black_box(cache.is_duplicate(&key, now, window))
+        })
+    });
+    group.bench_function("insert", |b| {
+        let mut i = 0u64;
+        b.iter(|| {
+            i += 1;
+            cache.insert(&i, now);
+        })
+    });
+    group.finish();
+}
+
+fn bench_kiss(c: &mut Criterion) {
+    // A stream of frames the size of typical packets, encoded once
+    let codec = KissCodec::new();
+    let mut stream = Vec::new();
+    for packet in PACKETS {
+        stream.extend(codec.encode(packet.as_bytes(), 0));
+    }
+
+    let mut group = c.benchmark_group("kiss");
+    group.throughput(Throughput::Bytes(stream.len() as u64));
+    group.bench_function("decode", |b| {
+        b.iter_batched(
+            || BytesMut::from(&stream[..]),
+            |mut buf| {
+                let mut codec = KissCodec::new();
+                while let Some(frame) = codec.decode(&mut buf).unwrap() {
+                    black_box(frame);
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("encode", |b| {
+        b.iter(|| {
+            for packet in PACKETS {
+                black_box(codec.encode(black_box(packet.as_bytes()), 0));
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse, bench_filter, bench_dedup, bench_kiss);
+criterion_main!(benches);
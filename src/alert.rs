@@ -0,0 +1,319 @@
+//! Alerts for packets worth a human's attention.
+//!
+//! Every alert is logged. Depending on its settings an alert can also run a
+//! command, POST to a webhook, and send an APRS message to the operator.
+//! Commands and webhooks run in the background so a slow one never holds
+//! up the router.
+
+use crate::aprs::{AprsPacket, CallSign};
+use crate::router::{PacketSource, RoutedPacket};
+use anyhow::{anyhow, Result};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tokio::time::timeout;
+
+/// Longest a command or webhook may take before it's abandoned
+const ALERT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest APRS message text
+const MAX_MESSAGE_TEXT: usize = 67;
+
+/// Where to send alerts, beyond the log
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct AlertConfig {
+    #[serde(default)]
+    pub command: Option<String>, // Run with sh -c, packet fields in APRS_* variables
+    #[serde(default)]
+    pub webhook: Option<String>, // http:// URL to POST the alert to as JSON
+    #[serde(default)]
+    pub notify: Option<String>, // Callsign to send an APRS message to
+}
+
+pub struct Alerter {
+    config: AlertConfig,
+    from: CallSign,
+    webhook: Option<Webhook>,
+}
+
+impl Alerter {
+    /// Alerts messaged to the operator come from `mycall`
+    pub fn new(config: AlertConfig, mycall: &str) -> Result<Self> {
+        let from =
+            CallSign::parse(mycall).ok_or_else(|| anyhow!("Invalid callsign: {}", mycall))?;
+        if let Some(notify) = &config.notify {
+            CallSign::parse(notify).ok_or_else(|| anyhow!("Invalid callsign: {}", notify))?;
+        }
+        let webhook = config.webhook.as_deref().map(Webhook::parse).transpose()?;
+        Ok(Alerter {
+            config,
+            from,
+            webhook,
+        })
+    }
+
+    /// Raise an alert about `packet`. A message to the operator is pushed
+    /// to `inject` for the router to send.
+    pub fn fire(
+        &self,
+        event: &str,
+        summary: &str,
+        packet: &AprsPacket,
+        inject: &mut Vec<RoutedPacket>,
+    ) {
+        if let Some(notify) = &self.config.notify {
+            inject.push(self.message(notify, summary));
+        }
+
+        if self.config.command.is_none() && self.webhook.is_none() {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            warn!("No runtime to run {} alert on", event);
+            return;
+        };
+
+        if let Some(command) = &self.config.command {
+            let mut command = shell(command);
+            command.envs(packet_env(packet)).env("APRS_EVENT", event);
+            runtime.spawn(async move {
+                if let Err(e) = run_command(command, None, ALERT_TIMEOUT).await {
+                    warn!("Alert command failed: {}", e);
+                }
+            });
+        }
+
+        if let Some(webhook) = self.webhook.clone() {
+            let body = json!({
+                "event": event,
+                "summary": summary,
+                "packet": packet.to_json(),
+            })
+            .to_string();
+            runtime.spawn(async move {
+                if let Err(e) = webhook.post(&body).await {
+                    warn!("Alert webhook {} failed: {}", webhook.host, e);
+                }
+            });
+        }
+    }
+
+    fn message(&self, to: &str, text: &str) -> RoutedPacket {
+        let text: String = text.chars().take(MAX_MESSAGE_TEXT).collect();
+        let packet = AprsPacket::new(
+            self.from.clone(),
+            CallSign::new("APRS", 0),
+            format!(":{:<9}:{}", to, text),
+        );
+        RoutedPacket {
+            packet,
+            source: PacketSource::Internal,
+        }
+    }
+}
+
+/// A command line run through the shell
+pub fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command).kill_on_drop(true);
+    cmd
+}
+
+/// Run a command to completion, with `input` on its stdin, killing it if
+/// it outlasts `limit`
+pub async fn run_command(
+    mut command: Command,
+    input: Option<String>,
+    limit: Duration,
+) -> Result<()> {
+    let stdin = if input.is_some() {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    };
+    let mut child = command.stdin(stdin).spawn()?;
+    let status = timeout(limit, async {
+        if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+            // A command that doesn't read its input isn't an error
+            stdin.write_all(input.as_bytes()).await.ok();
+        }
+        child.wait().await
+    })
+    .await
+    .map_err(|_| anyhow!("timed out after {}s", limit.as_secs()))??;
+    if !status.success() {
+        return Err(anyhow!("exited with {}", status));
+    }
+    Ok(())
+}
+
+/// Packet fields for a command's environment
+pub fn packet_env(packet: &AprsPacket) -> Vec<(&'static str, String)> {
+    let mut env = vec![
+        ("APRS_SOURCE", packet.source.to_string()),
+        ("APRS_DESTINATION", packet.destination.to_string()),
+        (
+            "APRS_PATH",
+            packet
+                .path
+                .iter()
+                .map(|hop| hop.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+        ),
+        ("APRS_INFO", packet.information.clone()),
+        ("APRS_RAW", packet.to_string()),
+    ];
+    if let Some(pos) = packet.position() {
+        env.push(("APRS_LATITUDE", format!("{:.5}", pos.latitude)));
+        env.push(("APRS_LONGITUDE", format!("{:.5}", pos.longitude)));
+    }
+    if let Some(msg) = packet.message() {
+        env.push(("APRS_ADDRESSEE", msg.addressee.clone()));
+        env.push(("APRS_MESSAGE", msg.text.clone()));
+    }
+    env
+}
+
+/// A plain HTTP endpoint
+#[derive(Debug, Clone, PartialEq)]
+struct Webhook {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Webhook {
+    fn parse(url: &str) -> Result<Self> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| anyhow!("Webhook {} must be an http:// URL", url))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .map_err(|_| anyhow!("Invalid port in webhook {}", url))?,
+            ),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(anyhow!("Webhook {} has no host", url));
+        }
+        Ok(Webhook {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    async fn post(&self, body: &str) -> Result<()> {
+        timeout(ALERT_TIMEOUT, async {
+            let mut stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+            let request = format!(
+                "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                self.path,
+                self.host,
+                body.len(),
+                body
+            );
+            stream.write_all(request.as_bytes()).await?;
+
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).await?;
+            let response = String::from_utf8_lossy(&response);
+            let status = response.split_whitespace().nth(1).unwrap_or("");
+            debug!("Webhook {} answered {}", self.host, status);
+            if !status.starts_with('2') {
+                return Err(anyhow!("HTTP status {}", status));
+            }
+            Ok(())
+        })
+        .await
+        .map_err(|_| anyhow!("timed out after {}s", ALERT_TIMEOUT.as_secs()))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aprs::parse_packet;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_webhook_urls() {
+        assert_eq!(
+            Webhook::parse("http://alerts.local:8080/aprs").unwrap(),
+            Webhook {
+                host: "alerts.local".to_string(),
+                port: 8080,
+                path: "/aprs".to_string(),
+            }
+        );
+        assert_eq!(Webhook::parse("http://alerts.local").unwrap().port, 80);
+        assert!(Webhook::parse("https://alerts.local").is_err());
+        assert!(Webhook::parse("http://:80/").is_err());
+    }
+
+    #[test]
+    fn test_notify_message() {
+        let config = AlertConfig {
+            notify: Some("N0CALL-7".to_string()),
+            ..Default::default()
+        };
+        let alerter = Alerter::new(config, "N0CALL-10").unwrap();
+        let packet = parse_packet("N1CALL>APRS:>Status").unwrap();
+        let mut inject = Vec::new();
+
+        alerter.fire("test", &"x".repeat(100), &packet, &mut inject);
+        assert_eq!(inject.len(), 1);
+        let sent = &inject[0].packet;
+        assert_eq!(sent.source.to_string(), "N0CALL-10");
+        assert_eq!(sent.message().unwrap().addressee, "N0CALL-7");
+        assert_eq!(sent.message().unwrap().text.len(), MAX_MESSAGE_TEXT);
+    }
+
+    #[test]
+    fn test_packet_env() {
+        let packet = parse_packet("N1CALL-9>APRS,WIDE1-1:!4903.50N/07201.75W>").unwrap();
+        let env = packet_env(&packet);
+        let get = |name: &str| {
+            env.iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(get("APRS_SOURCE"), Some("N1CALL-9"));
+        assert_eq!(get("APRS_PATH"), Some("WIDE1-1"));
+        assert_eq!(get("APRS_LATITUDE"), Some("49.05833"));
+        assert_eq!(get("APRS_MESSAGE"), None);
+    }
+
+    #[tokio::test]
+    async fn test_webhook_post() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 4096];
+            let n = stream.read(&mut buf).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&buf[..n]).into_owned()
+        });
+
+        Webhook::parse(&url).unwrap().post("{}").await.unwrap();
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(request.ends_with("\r\n\r\n{}"));
+    }
+}
@@ -0,0 +1,79 @@
+//! Character sets for text on the air.
+//!
+//! APRS text was ASCII, and plenty of radios still show nothing else, but
+//! newer software sends UTF-8 and older European software Latin-1. APRS-IS
+//! carries UTF-8, so this only matters for RF: how the bytes of a heard
+//! packet are read, and how our text is written out.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Charset {
+    /// 7-bit ASCII only; anything else is sent and read as `?`
+    Ascii,
+    /// ISO 8859-1, though text that is valid UTF-8 is still read as such
+    Latin1,
+    /// UTF-8 passed through as it is
+    #[default]
+    Utf8,
+}
+
+impl Charset {
+    /// Text from bytes heard on the air
+    pub fn decode(self, bytes: &[u8]) -> String {
+        match self {
+            Charset::Ascii => bytes
+                .iter()
+                .map(|&b| if b.is_ascii() { b as char } else { '?' })
+                .collect(),
+            // A byte over 0x7F alone is hardly ever valid UTF-8, so text
+            // that is must be UTF-8 sent by newer software
+            Charset::Latin1 => match std::str::from_utf8(bytes) {
+                Ok(text) => text.to_string(),
+                Err(_) => bytes.iter().map(|&b| b as char).collect(),
+            },
+            Charset::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+        }
+    }
+
+    /// Bytes to send for `text`, with what the set lacks as `?`
+    pub fn encode(self, text: &str) -> Vec<u8> {
+        let limit = match self {
+            Charset::Ascii => 0x7F,
+            Charset::Latin1 => 0xFF,
+            Charset::Utf8 => return text.as_bytes().to_vec(),
+        };
+        text.chars()
+            .map(|c| if c as u32 <= limit { c as u8 } else { b'?' })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let latin1 = b">Gr\xFC\xDFe aus K\xF6ln";
+        let utf8 = ">Grüße aus Köln".as_bytes();
+
+        assert_eq!(Charset::Latin1.decode(latin1), ">Grüße aus Köln");
+        assert_eq!(Charset::Latin1.decode(utf8), ">Grüße aus Köln");
+        assert_eq!(Charset::Utf8.decode(utf8), ">Grüße aus Köln");
+        assert_eq!(
+            Charset::Utf8.decode(latin1),
+            ">Gr\u{FFFD}\u{FFFD}e aus K\u{FFFD}ln"
+        );
+        assert_eq!(Charset::Ascii.decode(latin1), ">Gr??e aus K?ln");
+    }
+
+    #[test]
+    fn test_encode() {
+        let text = ">Grüße, 73 ☺";
+        assert_eq!(Charset::Utf8.encode(text), text.as_bytes());
+        assert_eq!(Charset::Latin1.encode(text), b">Gr\xFC\xDFe, 73 ?");
+        assert_eq!(Charset::Ascii.encode(text), b">Gr??e, 73 ?");
+    }
+}
@@ -0,0 +1,119 @@
+//! Maidenhead grid locators.
+//!
+//! A locator names a rectangle: a field (`IO`, 20° by 10°), a square in it
+//! (`IO91`, 2° by 1°), then a subsquare (`IO91SX`, 5' by 2.5'). APRS
+//! carries them in status reports that start with one, as in
+//! `>IO91SX/G On the air`, where the symbol follows the locator.
+
+/// The locator of the rectangle holding a position, to `chars` characters:
+/// 4 for a square, 6 for a subsquare.
+pub fn grid_square(latitude: f64, longitude: f64, chars: usize) -> String {
+    let lon = (longitude + 180.0).clamp(0.0, 359.999_999);
+    let lat = (latitude + 90.0).clamp(0.0, 179.999_999);
+    let mut grid = String::new();
+    grid.push((b'A' + (lon / 20.0) as u8) as char);
+    grid.push((b'A' + (lat / 10.0) as u8) as char);
+    if chars >= 4 {
+        grid.push((b'0' + ((lon % 20.0) / 2.0) as u8) as char);
+        grid.push((b'0' + (lat % 10.0) as u8) as char);
+    }
+    if chars >= 6 {
+        grid.push((b'a' + ((lon % 2.0) * 12.0) as u8) as char);
+        grid.push((b'a' + ((lat % 1.0) * 24.0) as u8) as char);
+    }
+    grid
+}
+
+/// The centre of a 4 or 6 character locator, as [lat, lon]
+pub fn grid_center(grid: &str) -> Option<[f64; 2]> {
+    let bytes = grid.to_ascii_uppercase().into_bytes();
+    if !matches!(bytes.len(), 4 | 6) || !is_grid(&bytes) {
+        return None;
+    }
+    let mut lon = (bytes[0] - b'A') as f64 * 20.0 + (bytes[2] - b'0') as f64 * 2.0 - 180.0;
+    let mut lat = (bytes[1] - b'A') as f64 * 10.0 + (bytes[3] - b'0') as f64 - 90.0;
+    if bytes.len() == 6 {
+        lon += (bytes[4] - b'A') as f64 / 12.0 + 1.0 / 24.0;
+        lat += (bytes[5] - b'A') as f64 / 24.0 + 1.0 / 48.0;
+    } else {
+        lon += 1.0;
+        lat += 0.5;
+    }
+    Some([lat, lon])
+}
+
+fn is_grid(bytes: &[u8]) -> bool {
+    let field = |b: u8| (b'A'..=b'R').contains(&b);
+    let square = |b: u8| b.is_ascii_digit();
+    let subsquare = |b: u8| (b'A'..=b'X').contains(&b);
+    field(bytes[0])
+        && field(bytes[1])
+        && square(bytes[2])
+        && square(bytes[3])
+        && bytes[4..].iter().all(|&b| subsquare(b))
+}
+
+/// The locator a status report starts with, if it does: `>IO91SX/G` or
+/// `>IO91/G`, then the end or a space before the text
+pub fn status_grid(information: &str) -> Option<String> {
+    let text = information.strip_prefix('>')?;
+    for len in [6, 4] {
+        let (Some(grid), Some(rest)) = (text.get(..len), text.get(len..)) else {
+            continue;
+        };
+        let mut symbol = rest.chars();
+        let table = symbol.next();
+        let code = symbol.next();
+        let after = symbol.next();
+        let table_ok = table
+            .is_some_and(|t| t == '/' || t == '\\' || t.is_ascii_uppercase() || t.is_ascii_digit());
+        if table_ok
+            && code.is_some_and(|c| c.is_ascii_graphic())
+            && after.is_none_or(|c| c == ' ')
+            && grid_center(grid).is_some()
+        {
+            return Some(grid.to_ascii_uppercase());
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grid_square() {
+        // Greenwich, and Washington DC
+        assert_eq!(grid_square(51.4779, -0.0015, 6), "IO91xl");
+        assert_eq!(grid_square(38.8977, -77.0365, 6), "FM18lv");
+        assert_eq!(grid_square(38.8977, -77.0365, 4), "FM18");
+        assert_eq!(grid_square(-33.8568, 151.2153, 6), "QF56od");
+        // The far edges stay in the last field
+        assert_eq!(grid_square(90.0, 180.0, 4), "RR99");
+    }
+
+    #[test]
+    fn test_grid_center() {
+        let [lat, lon] = grid_center("FM18lv").unwrap();
+        assert_eq!(grid_square(lat, lon, 6), "FM18lv");
+        assert!((lat - 38.8958).abs() < 0.001 && (lon - -77.0417).abs() < 0.001);
+        assert_eq!(grid_center("IO91"), Some([51.5, -1.0]));
+        assert_eq!(grid_center("IO9"), None);
+        assert_eq!(grid_center("ZZ99"), None);
+        assert_eq!(grid_center("IO91ZZ"), None);
+    }
+
+    #[test]
+    fn test_status_grid() {
+        assert_eq!(status_grid(">IO91SX/G"), Some("IO91SX".to_string()));
+        assert_eq!(
+            status_grid(">io91sx/G On the air"),
+            Some("IO91SX".to_string())
+        );
+        assert_eq!(status_grid(">IO91/G Hi"), Some("IO91".to_string()));
+        assert_eq!(status_grid(">IO91SX/GHi"), None);
+        assert_eq!(status_grid(">Net tonight at 8"), None);
+        assert_eq!(status_grid("!IO91SX/G"), None);
+    }
+}
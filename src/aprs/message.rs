@@ -0,0 +1,124 @@
+/// A decoded `:ADDRESSEE:text{id` message, ack or rej.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub addressee: String,
+    pub text: String,           // Empty for acks and rejs
+    pub msg_id: Option<String>, // For acks and rejs, the id being answered
+    pub is_ack: bool,
+    pub is_rej: bool,
+}
+
+impl Message {
+    /// Parse a message information field. The addressee should be padded
+    /// to 9 characters, but shorter unpadded addressees are accepted too.
+    pub fn parse(info: &str) -> Option<Self> {
+        let body = info.strip_prefix(':')?;
+        let end = body.find(':').filter(|&end| end <= 9)?;
+        let addressee = body[..end].trim();
+        if addressee.is_empty() {
+            return None;
+        }
+        let text = &body[end + 1..];
+
+        for (prefix, is_ack) in [("ack", true), ("rej", false)] {
+            if let Some(id) = text.strip_prefix(prefix).and_then(parse_msg_id) {
+                return Some(Message {
+                    addressee: addressee.to_string(),
+                    text: String::new(),
+                    msg_id: Some(id.to_string()),
+                    is_ack,
+                    is_rej: !is_ack,
+                });
+            }
+        }
+
+        // A brace only starts an id if a valid one follows; otherwise it's
+        // part of the text
+        let (text, msg_id) = match text
+            .rfind('{')
+            .and_then(|pos| Some((pos, parse_msg_id(&text[pos + 1..])?)))
+        {
+            Some((pos, id)) => (&text[..pos], Some(id)),
+            None => (text, None),
+        };
+
+        Some(Message {
+            addressee: addressee.to_string(),
+            text: text.to_string(),
+            msg_id: msg_id.map(str::to_string),
+            is_ack: false,
+            is_rej: false,
+        })
+    }
+
+    pub fn is_ack_or_rej(&self) -> bool {
+        self.is_ack || self.is_rej
+    }
+}
+
+/// Message ids are 1-5 alphanumerics. Reply-ack capable stations send
+/// `MM}AA`; only the `MM` part identifies this message.
+fn parse_msg_id(id: &str) -> Option<&str> {
+    let id = id.trim_end();
+    let id = id.split_once('}').map_or(id, |(id, _)| id);
+    if (1..=5).contains(&id.len()) && id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        Some(id)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_message() {
+        let msg = Message::parse(":N1CALL   :Test message{123").unwrap();
+        assert_eq!(msg.addressee, "N1CALL");
+        assert_eq!(msg.text, "Test message");
+        assert_eq!(msg.msg_id.as_deref(), Some("123"));
+        assert!(!msg.is_ack_or_rej());
+
+        let msg = Message::parse(":N1CALL-12:No id").unwrap();
+        assert_eq!(msg.addressee, "N1CALL-12");
+        assert_eq!(msg.msg_id, None);
+
+        let msg = Message::parse(":N1CALL   :use {braces} freely").unwrap();
+        assert_eq!(msg.text, "use {braces} freely");
+        assert_eq!(msg.msg_id, None);
+
+        // Reply-ack ids
+        let msg = Message::parse(":N1CALL   :Hi{AB}CD").unwrap();
+        assert_eq!(msg.msg_id.as_deref(), Some("AB"));
+    }
+
+    #[test]
+    fn test_parse_short_addressee() {
+        let msg = Message::parse(":N1CALL:Unpadded{7").unwrap();
+        assert_eq!(msg.addressee, "N1CALL");
+        assert_eq!(msg.text, "Unpadded");
+        assert_eq!(msg.msg_id.as_deref(), Some("7"));
+
+        assert!(Message::parse(":N1CALL").is_none());
+        assert!(Message::parse(":TOOLONGCALL:x").is_none());
+        assert!(Message::parse(":         :x").is_none());
+    }
+
+    #[test]
+    fn test_parse_ack_rej() {
+        let msg = Message::parse(":N1CALL   :ack123").unwrap();
+        assert!(msg.is_ack);
+        assert_eq!(msg.msg_id.as_deref(), Some("123"));
+        assert_eq!(msg.text, "");
+
+        let msg = Message::parse(":N1CALL   :rej5").unwrap();
+        assert!(msg.is_rej);
+        assert!(!msg.is_ack);
+
+        // Text that merely starts with "ack" is a message
+        let msg = Message::parse(":N1CALL   :acknowledged, thanks{9").unwrap();
+        assert!(!msg.is_ack_or_rej());
+        assert_eq!(msg.text, "acknowledged, thanks");
+    }
+}
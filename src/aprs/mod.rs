@@ -1,5 +1,17 @@
+pub mod charset;
+pub mod grid;
+pub mod message;
+pub mod object;
 pub mod packet;
 pub mod parser;
+pub mod position;
+pub mod telemetry;
+pub mod timestamp;
 
-pub use packet::{AprsPacket, CallSign};
+pub use charset::Charset;
+pub use message::Message;
+pub use object::ObjectReport;
+pub use packet::{AprsPacket, CallSign, Payload, MAX_INFO_LEN, MAX_PATH_LEN};
 pub use parser::parse_packet;
+pub use position::Position;
+pub use telemetry::{TelemetryDefinition, TelemetryReport};
@@ -0,0 +1,98 @@
+use super::position::Position;
+use super::timestamp::parse_timestamp;
+use chrono::{DateTime, Utc};
+
+/// A decoded `;` object or `)` item report. The report's comment is the
+/// position comment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectReport {
+    pub name: String,
+    pub alive: bool,                      // False once the owner has killed it
+    pub timestamp: Option<DateTime<Utc>>, // Objects only
+    pub position: Position,
+}
+
+impl ObjectReport {
+    /// Parse `;NAME_____*DDHHMMz<position>` (name padded to 9, `*` live or
+    /// `_` killed).
+    pub fn parse_object(info: &str) -> Option<Self> {
+        let rest = info.strip_prefix(';')?;
+        let name = rest.get(..9)?;
+        let alive = match rest.as_bytes().get(9)? {
+            b'*' => true,
+            b'_' => false,
+            _ => return None,
+        };
+        let timestamp = parse_timestamp(rest.get(10..17)?, Utc::now());
+        let position = Position::parse(rest.get(17..)?)?;
+
+        Some(ObjectReport {
+            name: name.trim_end().to_string(),
+            alive,
+            timestamp,
+            position,
+        })
+    }
+
+    /// Parse `)NAME!<position>`, where the name is 3-9 characters and `!`
+    /// marks it live or `_` killed.
+    pub fn parse_item(info: &str) -> Option<Self> {
+        let rest = info.strip_prefix(')')?;
+        let end = rest
+            .char_indices()
+            .take(10)
+            .skip(3)
+            .find(|&(_, c)| c == '!' || c == '_')
+            .map(|(i, _)| i)?;
+        let position = Position::parse(&rest[end + 1..])?;
+
+        Some(ObjectReport {
+            name: rest[..end].to_string(),
+            alive: rest.as_bytes()[end] == b'!',
+            timestamp: None,
+            position,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_object() {
+        let obj =
+            ObjectReport::parse_object(";LEADER   *092345z4903.50N/07201.75W>088/036").unwrap();
+        assert_eq!(obj.name, "LEADER");
+        assert!(obj.alive);
+        assert_eq!(
+            obj.timestamp.unwrap().format("%d%H%M").to_string(),
+            "092345"
+        );
+        assert_eq!(obj.position.symbol, '>');
+        assert_eq!(obj.position.course, Some(88));
+        assert_eq!(obj.position.speed, Some(36.0));
+
+        let obj = ObjectReport::parse_object(";LEADER   _092345z4903.50N/07201.75W>").unwrap();
+        assert!(!obj.alive);
+
+        assert!(ObjectReport::parse_object(";LEADER   #092345z4903.50N/07201.75W>").is_none());
+        assert!(ObjectReport::parse_object(";SHORT").is_none());
+    }
+
+    #[test]
+    fn test_parse_item() {
+        let item = ObjectReport::parse_item(")AID #2!4903.50N/07201.75WA").unwrap();
+        assert_eq!(item.name, "AID #2");
+        assert!(item.alive);
+        assert_eq!(item.timestamp, None);
+        assert_eq!(item.position.symbol, 'A');
+
+        let item = ObjectReport::parse_item(")G/WB4APR_4903.50N/07201.75WA").unwrap();
+        assert_eq!(item.name, "G/WB4APR");
+        assert!(!item.alive);
+
+        // Names must be at least 3 characters
+        assert!(ObjectReport::parse_item(")AB!4903.50N/07201.75WA").is_none());
+    }
+}
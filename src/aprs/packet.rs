@@ -1,5 +1,20 @@
+use super::message::Message;
+use super::object::ObjectReport;
+use super::parser::decode_payload;
+use super::position::Position;
+use super::telemetry::{TelemetryDefinition, TelemetryReport};
+use anyhow::{anyhow, Result};
 use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::fmt;
+use std::sync::OnceLock;
+
+/// AX.25 allows at most 8 digipeater addresses.
+pub const MAX_PATH_LEN: usize = 8;
+
+/// The APRS spec's limit on the information field, in bytes.
+pub const MAX_INFO_LEN: usize = 256;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct AprsPacket {
@@ -8,8 +23,34 @@ pub struct AprsPacket {
     pub path: Vec<CallSign>,
     pub data_type: DataType,
     pub information: String,
+    payload: LazyPayload,
     pub timestamp: DateTime<Utc>,
     pub raw: Option<Vec<u8>>,
+    /// Labels attached by tag filters while routing
+    pub tags: Vec<String>,
+}
+
+/// The payload, decoded the first time something asks for it. Most packets
+/// are only forwarded, so routing never pays for decoding them.
+#[derive(Debug, Clone, Default)]
+struct LazyPayload(OnceLock<Option<Payload>>);
+
+// Decoded or not, it's the information field the packets are compared by
+impl PartialEq for LazyPayload {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+/// Decoded contents of the information field, for the types we understand.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Payload {
+    Position(Position),
+    Object(ObjectReport),
+    Item(ObjectReport),
+    Message(Message),
+    Telemetry(TelemetryReport),
+    TelemetryDefinition(TelemetryDefinition),
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -22,7 +63,8 @@ pub struct CallSign {
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Ssid(pub u8);
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum DataType {
     Position,
     Status,
@@ -34,6 +76,7 @@ pub enum DataType {
     Weather,
     UserDefined,
     ThirdParty,
+    RawGps,
     Invalid,
 }
 
@@ -54,17 +97,12 @@ impl CallSign {
             (input, false)
         };
 
-        let parts: Vec<&str> = input.split('-').collect();
-        if parts.is_empty() || parts[0].is_empty() {
-            return None;
-        }
-
-        let call = parts[0].to_uppercase();
-        let ssid = if parts.len() > 1 {
-            parts[1].parse::<u8>().unwrap_or(0)
-        } else {
-            0
-        };
+        let mut parts = input.split('-');
+        let call = parts.next().filter(|call| !call.is_empty())?;
+        let call = call.to_uppercase();
+        let ssid = parts
+            .next()
+            .map_or(0, |ssid| ssid.parse::<u8>().unwrap_or(0));
 
         if ssid > 15 {
             return None;
@@ -78,6 +116,33 @@ impl CallSign {
     }
 }
 
+impl CallSign {
+    /// Check that this can be sent as an AX.25 address: 1-6 letters and
+    /// digits and an SSID of 0-15.
+    pub fn validate_ax25(&self) -> Result<()> {
+        if self.call.is_empty() || self.call.len() > 6 {
+            return Err(anyhow!(
+                "callsign {} must be 1-6 characters for AX.25",
+                self.call
+            ));
+        }
+        if !self
+            .call
+            .bytes()
+            .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit())
+        {
+            return Err(anyhow!(
+                "callsign {} may only contain A-Z and 0-9 for AX.25",
+                self.call
+            ));
+        }
+        if self.ssid.0 > 15 {
+            return Err(anyhow!("SSID {} of {} is out of range", self.ssid.0, self));
+        }
+        Ok(())
+    }
+}
+
 impl fmt::Display for CallSign {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if self.ssid.0 == 0 {
@@ -104,8 +169,10 @@ impl AprsPacket {
             path: Vec::new(),
             data_type,
             information,
+            payload: LazyPayload::default(),
             timestamp: Utc::now(),
             raw: None,
+            tags: Vec::new(),
         }
     }
 
@@ -126,10 +193,168 @@ impl AprsPacket {
             '_' => DataType::Weather,
             '{' => DataType::UserDefined,
             '}' => DataType::ThirdParty,
+            '$' => DataType::RawGps,
             _ => DataType::Invalid,
         }
     }
 
+    /// The decoded information field, if it's a type we understand.
+    pub fn payload(&self) -> Option<&Payload> {
+        self.payload
+            .0
+            .get_or_init(|| decode_payload(&self.data_type, &self.destination, &self.information))
+            .as_ref()
+    }
+
+    /// Coordinates reported by this packet, whatever its type.
+    pub fn position(&self) -> Option<&Position> {
+        match self.payload() {
+            Some(Payload::Position(pos)) => Some(pos),
+            Some(Payload::Object(obj)) | Some(Payload::Item(obj)) => Some(&obj.position),
+            _ => None,
+        }
+    }
+
+    /// The object or item this packet reports, if any.
+    pub fn object(&self) -> Option<&ObjectReport> {
+        match self.payload() {
+            Some(Payload::Object(obj)) | Some(Payload::Item(obj)) => Some(obj),
+            _ => None,
+        }
+    }
+
+    /// The time the payload says it was produced, as opposed to
+    /// `timestamp`, which is when we received it.
+    pub fn reported_time(&self) -> Option<DateTime<Utc>> {
+        match self.payload() {
+            Some(Payload::Position(pos)) => pos.timestamp,
+            Some(Payload::Object(obj)) | Some(Payload::Item(obj)) => obj.timestamp,
+            _ => None,
+        }
+    }
+
+    pub fn message(&self) -> Option<&Message> {
+        match self.payload() {
+            Some(Payload::Message(msg)) => Some(msg),
+            _ => None,
+        }
+    }
+
+    /// Check every address can be encoded and the path fits in an AX.25
+    /// header.
+    pub fn validate_ax25(&self) -> Result<()> {
+        if self.path.len() > MAX_PATH_LEN {
+            return Err(anyhow!(
+                "path has {} digipeaters, AX.25 allows at most {}",
+                self.path.len(),
+                MAX_PATH_LEN
+            ));
+        }
+        self.destination.validate_ax25()?;
+        self.source.validate_ax25()?;
+        for hop in &self.path {
+            hop.validate_ax25()?;
+        }
+        Ok(())
+    }
+
+    /// Make the information field fit to transmit: control characters,
+    /// which would end the line on APRS-IS and garble some TNCs, are
+    /// stripped, and anything past `MAX_INFO_LEN` is cut off. A message
+    /// is refused rather than cut, as that would lose its number, and so
+    /// is a field with nothing left in it. Returns what was changed.
+    pub fn fit_information(&mut self) -> Result<Vec<String>> {
+        let mut changes = Vec::new();
+        let before = self.information.chars().count();
+        self.information.retain(|c| !c.is_ascii_control());
+        let stripped = before - self.information.chars().count();
+        if stripped > 0 {
+            changes.push(format!("stripped {} control characters", stripped));
+        }
+        if self.information.is_empty() {
+            return Err(anyhow!("information field is empty"));
+        }
+
+        let len = self.information.len();
+        if len > MAX_INFO_LEN {
+            if self.data_type == DataType::Message {
+                return Err(anyhow!(
+                    "message is {} bytes, longer than the {} allowed",
+                    len,
+                    MAX_INFO_LEN
+                ));
+            }
+            let mut end = MAX_INFO_LEN;
+            while !self.information.is_char_boundary(end) {
+                end -= 1;
+            }
+            self.information.truncate(end);
+            changes.push(format!("cut from {} to {} bytes", len, end));
+        }
+
+        if !changes.is_empty() {
+            self.payload = LazyPayload::default();
+        }
+        Ok(changes)
+    }
+
+    /// The packet and whatever we decoded from it, for feeding to other
+    /// systems
+    pub fn to_json(&self) -> serde_json::Value {
+        let position = self.position().map(|pos| {
+            json!({
+                "latitude": pos.latitude,
+                "longitude": pos.longitude,
+                "symbol": format!("{}{}", pos.symbol_table, pos.symbol),
+                "course": pos.course,
+                "speed": pos.speed,
+                "altitude": pos.altitude,
+                "comment": pos.comment,
+            })
+        });
+        let object = self.object().map(|obj| {
+            json!({
+                "name": obj.name.trim_end(),
+                "alive": obj.alive,
+            })
+        });
+        let message = self.message().map(|msg| {
+            json!({
+                "addressee": msg.addressee.trim_end(),
+                "text": msg.text,
+                "id": msg.msg_id,
+                "ack": msg.is_ack,
+                "rej": msg.is_rej,
+            })
+        });
+
+        json!({
+            "source": self.source.to_string(),
+            "destination": self.destination.to_string(),
+            "path": self.path.iter().map(|hop| hop.to_string()).collect::<Vec<_>>(),
+            "type": self.data_type,
+            "received": self.timestamp.to_rfc3339(),
+            "position": position,
+            "object": object,
+            "message": message,
+            "tags": self.tags,
+            "raw": self.to_string(),
+        })
+    }
+
+    /// A Mic-E Emergency status, or a message to EMERGENCY or starting
+    /// with it
+    pub fn is_emergency(&self) -> bool {
+        if self.data_type == DataType::MicE {
+            return self.payload().is_some() && Position::mic_e_emergency(&self.destination.call);
+        }
+        self.message().is_some_and(|msg| {
+            !msg.is_ack_or_rej()
+                && (msg.addressee.eq_ignore_ascii_case("EMERGENCY")
+                    || msg.text.to_ascii_uppercase().starts_with("EMERGENCY"))
+        })
+    }
+
     pub fn has_rfonly(&self) -> bool {
         self.information.contains("RFONLY")
     }
@@ -137,6 +362,33 @@ impl AprsPacket {
     pub fn has_nogate(&self) -> bool {
         self.information.contains("NOGATE")
     }
+
+    /// Whether the packet has already been through APRS-IS: its path, or
+    /// the inner header of third-party traffic, names TCPIP, TCPXX or a
+    /// q construct
+    pub fn via_internet(&self) -> bool {
+        if self.path.iter().any(|hop| internet_hop(&hop.call)) {
+            return true;
+        }
+        if self.data_type != DataType::ThirdParty {
+            return false;
+        }
+        // }SOURCE>DEST,PATH:payload
+        let header = self.information[1..].split(':').next().unwrap_or_default();
+        header
+            .split(',')
+            .skip(1)
+            .any(|hop| internet_hop(hop.trim_end_matches('*')))
+    }
+}
+
+fn internet_hop(call: &str) -> bool {
+    // qAC, qAR and friends; callsign parsing may have uppercased them
+    let q_construct = call.len() == 3
+        && call.is_char_boundary(2)
+        && call[..2].eq_ignore_ascii_case("qA")
+        && call[2..].chars().all(|c| c.is_ascii_alphabetic());
+    q_construct || call.starts_with("TCPIP") || call.starts_with("TCPXX")
 }
 
 impl fmt::Display for AprsPacket {
@@ -185,6 +437,73 @@ mod tests {
         assert!(CallSign::parse("-5").is_none());
     }
 
+    #[test]
+    fn test_callsign_validate_ax25() {
+        assert!(CallSign::new("N0CALL", 15).validate_ax25().is_ok());
+        assert!(CallSign::new("A", 0).validate_ax25().is_ok());
+        assert!(CallSign::new("N0CALLX", 0).validate_ax25().is_err());
+        assert!(CallSign::new("N0-CAL", 0).validate_ax25().is_err());
+        assert!(CallSign::new("", 0).validate_ax25().is_err());
+
+        let mut call = CallSign::new("N0CALL", 0);
+        call.ssid = Ssid(16);
+        assert!(call.validate_ax25().is_err());
+    }
+
+    #[test]
+    fn test_packet_validate_ax25() {
+        let mut packet = AprsPacket::new(
+            CallSign::new("N0CALL", 0),
+            CallSign::new("APRS", 0),
+            ">Test".to_string(),
+        );
+        for i in 0..MAX_PATH_LEN {
+            packet.path.push(CallSign::new("WIDE1", i as u8));
+        }
+        assert!(packet.validate_ax25().is_ok());
+
+        packet.path.push(CallSign::new("WIDE2", 1));
+        assert!(packet.validate_ax25().is_err());
+
+        packet.path.truncate(1);
+        packet.source = CallSign::new("TOOLONGCALL", 0);
+        assert!(packet.validate_ax25().is_err());
+    }
+
+    #[test]
+    fn test_fit_information() {
+        let packet = |info: &str| {
+            AprsPacket::new(
+                CallSign::new("N0CALL", 0),
+                CallSign::new("APRS", 0),
+                info.to_string(),
+            )
+        };
+
+        let mut ok = packet("!4903.50N/07201.75W>Test");
+        assert!(ok.fit_information().unwrap().is_empty());
+
+        let mut controls = packet("!4903.50N/07201.75W>Line\r\nTwo\0");
+        assert_eq!(
+            controls.fit_information().unwrap(),
+            ["stripped 3 control characters"]
+        );
+        assert_eq!(controls.information, "!4903.50N/07201.75W>LineTwo");
+        assert!(controls.position().is_some());
+
+        // Cut at a character boundary, never inside one
+        let mut long = packet(&format!(">{}", "é".repeat(200)));
+        assert_eq!(
+            long.fit_information().unwrap(),
+            ["cut from 401 to 255 bytes"]
+        );
+        assert_eq!(long.information.len(), 255);
+
+        let mut message = packet(&format!(":N1CALL   :{}{{1", "x".repeat(250)));
+        assert!(message.fit_information().is_err());
+        assert!(packet("\r\n").fit_information().is_err());
+    }
+
     #[test]
     fn test_callsign_display() {
         let call = CallSign::new("N0CALL", 0);
@@ -234,6 +553,10 @@ mod tests {
         assert_eq!(AprsPacket::detect_data_type("_weather"), DataType::Weather);
         assert_eq!(AprsPacket::detect_data_type("{user"), DataType::UserDefined);
         assert_eq!(AprsPacket::detect_data_type("}third"), DataType::ThirdParty);
+        assert_eq!(
+            AprsPacket::detect_data_type("$GPRMC,225446,A"),
+            DataType::RawGps
+        );
         assert_eq!(AprsPacket::detect_data_type(""), DataType::Invalid);
         assert_eq!(AprsPacket::detect_data_type("Invalid"), DataType::Invalid);
     }
@@ -251,6 +574,18 @@ mod tests {
         assert_eq!(packet.information, ">Test status");
     }
 
+    #[test]
+    fn test_payload_decoded_on_demand() {
+        let source = CallSign::new("N0CALL", 0);
+        let dest = CallSign::new("APRS", 0);
+        let packet = AprsPacket::new(source, dest, "!4903.50N/07201.75W-".to_string());
+        let fresh = packet.clone();
+        assert!(packet.position().is_some());
+        // Decoding doesn't change what the packet is
+        assert_eq!(packet, fresh);
+        assert_eq!(fresh.position(), packet.position());
+    }
+
     #[test]
     fn test_packet_display() {
         let source = CallSign::new("N0CALL", 5);
@@ -286,4 +621,42 @@ mod tests {
         assert!(!packet.has_rfonly());
         assert!(!packet.has_nogate());
     }
+
+    #[test]
+    fn test_via_internet() {
+        let parse = |s: &str| crate::aprs::parse_packet(s).unwrap();
+        assert!(!parse("N0CALL>APRS,WIDE1-1:>Status").via_internet());
+        assert!(parse("N0CALL>APRS,TCPIP*,qAC,T2TEST:>Status").via_internet());
+        assert!(parse("N0CALL>APRS,qAR,N1CALL:>Status").via_internet());
+        assert!(parse("N1CALL>APRS,WIDE1*:}N2CALL>APRS,TCPIP,N1CALL*:>Hi").via_internet());
+        assert!(parse("N1CALL>APRS:}N2CALL>APRS,TCPXX*:>Hi").via_internet());
+        assert!(!parse("N1CALL>APRS:}N2CALL>APRS,WIDE1-1:>Hi").via_internet());
+    }
+
+    #[test]
+    fn test_is_emergency() {
+        let parse = |s: &str| crate::aprs::parse_packet(s).unwrap();
+        assert!(parse("N0CALL-9>332UVT:`(#fpPO>/").is_emergency());
+        assert!(!parse("N0CALL-9>T32UVT:`(#fpPO>/").is_emergency());
+        assert!(parse("N0CALL>APRS::EMERGENCY:Car off road").is_emergency());
+        assert!(parse("N0CALL>APRS::N1CALL   :Emergency at mile 12{1").is_emergency());
+        assert!(!parse("N0CALL>APRS::N1CALL   :All fine{1").is_emergency());
+        assert!(!parse("N0CALL>APRS:>EMERGENCY test").is_emergency());
+    }
+
+    #[test]
+    fn test_to_json() {
+        let packet =
+            crate::aprs::parse_packet("N0CALL-9>APRS,WIDE1-1:!4903.50N/07201.75W>088/036Hi")
+                .unwrap();
+        let json = packet.to_json();
+
+        assert_eq!(json["source"], "N0CALL-9");
+        assert_eq!(json["path"][0], "WIDE1-1");
+        assert_eq!(json["type"], "position");
+        assert_eq!(json["position"]["symbol"], "/>");
+        assert_eq!(json["position"]["course"], 88);
+        assert!(json["message"].is_null());
+        assert_eq!(json["raw"], packet.to_string());
+    }
 }
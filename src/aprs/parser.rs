@@ -1,4 +1,8 @@
-use super::packet::{AprsPacket, CallSign};
+use super::message::Message;
+use super::object::ObjectReport;
+use super::packet::{AprsPacket, CallSign, DataType, Payload};
+use super::position::Position;
+use super::telemetry::{TelemetryDefinition, TelemetryReport};
 use anyhow::{anyhow, Result};
 use chrono::Utc;
 
@@ -16,28 +20,21 @@ pub fn parse_packet(input: &str) -> Result<AprsPacket> {
     let header = header.trim(); // Trim the header part
     let information = &information[1..];
 
-    let header_parts: Vec<&str> = header.split('>').collect();
-    if header_parts.len() != 2 {
-        return Err(anyhow!("Invalid header format"));
-    }
-
-    let source =
-        CallSign::parse(header_parts[0]).ok_or_else(|| anyhow!("Invalid source callsign"))?;
+    let (source, rest) = header
+        .split_once('>')
+        .filter(|(_, rest)| !rest.contains('>'))
+        .ok_or_else(|| anyhow!("Invalid header format"))?;
 
-    let path_parts: Vec<&str> = header_parts[1].split(',').collect();
-    if path_parts.is_empty() {
-        return Err(anyhow!("No destination in header"));
-    }
+    let source = CallSign::parse(source).ok_or_else(|| anyhow!("Invalid source callsign"))?;
 
+    let mut hops = rest.split(',');
+    let destination = hops
+        .next()
+        .ok_or_else(|| anyhow!("No destination in header"))?;
     let destination =
-        CallSign::parse(path_parts[0]).ok_or_else(|| anyhow!("Invalid destination callsign"))?;
+        CallSign::parse(destination).ok_or_else(|| anyhow!("Invalid destination callsign"))?;
 
-    let mut path = Vec::new();
-    for path_part in path_parts.iter().skip(1) {
-        if let Some(call) = CallSign::parse(path_part) {
-            path.push(call);
-        }
-    }
+    let path = hops.filter_map(CallSign::parse).collect();
 
     let mut packet = AprsPacket::new(source, destination, information.to_string());
     packet.path = path;
@@ -46,9 +43,81 @@ pub fn parse_packet(input: &str) -> Result<AprsPacket> {
     Ok(packet)
 }
 
+/// Decode the information field of a packet whose type has already been
+/// detected. Returns None for types we don't decode or malformed payloads.
+pub fn decode_payload(data_type: &DataType, destination: &CallSign, info: &str) -> Option<Payload> {
+    match data_type {
+        DataType::Position => Position::from_info(info).map(Payload::Position),
+        DataType::MicE => Position::from_mic_e(&destination.call, info).map(Payload::Position),
+        DataType::RawGps => Position::from_nmea(info).map(Payload::Position),
+        DataType::Object => ObjectReport::parse_object(info).map(Payload::Object),
+        DataType::Item => ObjectReport::parse_item(info).map(Payload::Item),
+        DataType::Message => {
+            let msg = Message::parse(info)?;
+            match TelemetryDefinition::parse(&msg.addressee, &msg.text) {
+                Some(def) => Some(Payload::TelemetryDefinition(def)),
+                None => Some(Payload::Message(msg)),
+            }
+        }
+        DataType::Telemetry => TelemetryReport::parse(info).map(Payload::Telemetry),
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
+
+    /// Information fields close enough to each format to get past the
+    /// first checks of its decoder, with multi-byte characters mixed in
+    /// where slicing on byte offsets would panic
+    fn info() -> impl Strategy<Value = String> {
+        let tail = "([ -~]|é|😀){0,20}";
+        let lat = "[0-9 ]{4}\\.[0-9 ]{2}[NSnsé]";
+        let lon = "[0-9 ]{5}\\.[0-9 ]{2}[EWewé]";
+        let position = format!(
+            "{}[/\\\\A-Zé]{}.(([0-9.]{{3}}/[0-9.]{{3}})|(/A=[0-9-]{{0,6}}))?{}",
+            lat, lon, tail
+        );
+        let formats = [
+            format!("[!=]{}", position),
+            format!("[/@][0-9]{{6}}[zh/é]{}", position),
+            format!("[!=][/\\\\A-Za-jé][!-{{]{{8}}.[ !-{{]{{3}}{}", tail),
+            format!("[`'][\\x1c-\\x7fé]{{8}}([!-{{]{{3}}\\}})?{}", tail),
+            format!(";([ -~]|é){{9}}[*_é][0-9]{{6}}[zh/]{}", position),
+            format!("\\)([ -~]|é){{1,10}}[!_]{}", position),
+            ":([A-Z0-9 ]|é){0,10}:([ -~]|é){0,10}(ack|rej|\\{)?([A-Za-z0-9}]|é){0,7}".to_string(),
+            "T#([0-9]{3}|MIC),([0-9.,-]|é){0,30}[01é]{0,10}([ -~]|é){0,5}".to_string(),
+            "\\$GP(RMC|GGA|GLL),([0-9.,NSEW]|é){0,60}(\\*[0-9A-F]{0,2})?".to_string(),
+            "\\}([ -~]|é){0,40}".to_string(),
+            "([ -~]|é){0,40}".to_string(),
+        ];
+        proptest::strategy::Union::new(
+            formats.map(|format| proptest::string::string_regex(&format).unwrap()),
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn fuzz_parse_packet(input in "\\PC{0,100}") {
+            let _ = parse_packet(&input);
+        }
+
+        #[test]
+        fn fuzz_decode_payloads(dest in "[0-9A-LP-Z]{1,6}", path in "([A-Z0-9qé*-]{1,9},){0,3}", info in info()) {
+            let input = format!("N0CALL>{},{}:{}", dest, path, info);
+            if let Ok(packet) = parse_packet(&input) {
+                let _ = packet.position();
+                let _ = packet.message();
+                let _ = packet.object();
+                let _ = packet.via_internet();
+                // What we send on is what we heard
+                let again = parse_packet(&packet.to_string()).unwrap();
+                prop_assert_eq!(again.information, packet.information);
+            }
+        }
+    }
 
     #[test]
     fn test_parse_basic_packet() {
@@ -130,4 +199,39 @@ mod tests {
 
         assert_eq!(packet.information, ":N1CALL   :Test message{123");
     }
+
+    #[test]
+    fn test_decoded_positions() {
+        let packet = parse_packet("N0CALL>APRS:!4903.50N/07201.75W>Test").unwrap();
+        let pos = packet.position().unwrap();
+        assert!((pos.latitude - 49.058333).abs() < 0.00001);
+        assert_eq!(pos.comment, "Test");
+
+        let packet = parse_packet("N0CALL>332UVT:`(#fpPO>/").unwrap();
+        assert!((packet.position().unwrap().latitude - 33.427333).abs() < 0.00001);
+
+        let packet =
+            parse_packet("N0CALL>APRS:;LEADER   *092345z4903.50N/07201.75W>088/036").unwrap();
+        assert_eq!(packet.position().unwrap().course, Some(88));
+        assert_eq!(packet.object().unwrap().name, "LEADER");
+
+        let packet = parse_packet("N0CALL>APRS:)AID #2!4903.50N/07201.75WA").unwrap();
+        assert_eq!(packet.position().unwrap().symbol, 'A');
+
+        let packet = parse_packet("N0CALL>APRS:>Test status").unwrap();
+        assert!(packet.payload().is_none());
+    }
+
+    #[test]
+    fn test_decoded_telemetry() {
+        let packet = parse_packet("N0CALL>APRS:T#001,1,2,3,4,5,00000000").unwrap();
+        assert!(matches!(packet.payload(), Some(Payload::Telemetry(_))));
+
+        let packet = parse_packet("N0CALL>APRS::N0CALL   :UNIT.Pkts,Pkts").unwrap();
+        assert!(matches!(
+            packet.payload(),
+            Some(Payload::TelemetryDefinition(_))
+        ));
+        assert!(packet.message().is_none());
+    }
 }
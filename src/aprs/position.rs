@@ -0,0 +1,531 @@
+use super::timestamp::parse_timestamp;
+use chrono::{DateTime, Utc};
+
+/// A decoded position report. Speeds are in knots and distances in metric
+/// units regardless of how the packet encoded them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Position {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub symbol_table: char,
+    pub symbol: char,
+    pub course: Option<u16>,   // degrees
+    pub speed: Option<f32>,    // knots
+    pub altitude: Option<f32>, // metres
+    pub range: Option<f32>,    // km, radio range from compressed reports
+    pub compressed: bool,
+    pub timestamp: Option<DateTime<Utc>>, // When the station says it was there
+    pub comment: String,
+}
+
+const FEET_TO_METRES: f32 = 0.3048;
+const MILES_TO_KM: f32 = 1.609344;
+
+impl Position {
+    /// Decode the position from a `!`, `=`, `/` or `@` information field.
+    pub fn from_info(info: &str) -> Option<Self> {
+        match info.chars().next()? {
+            '!' | '=' => Self::parse(&info[1..]),
+            // Timestamped reports carry a 7 character time before the position
+            '/' | '@' => {
+                let mut position = Self::parse(info.get(8..)?)?;
+                position.timestamp = parse_timestamp(&info[1..8], Utc::now());
+                Some(position)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether a Mic-E destination carries the Emergency status: message
+    /// bits A, B and C (characters 1-3) all zero.
+    pub fn mic_e_emergency(destination: &str) -> bool {
+        let dest = destination.as_bytes();
+        dest.len() == 6 && dest[..3].iter().all(|c| c.is_ascii_digit() || *c == b'L')
+    }
+
+    /// Decode a Mic-E report. The latitude and hemisphere flags are carried
+    /// in the destination callsign (without SSID), the rest in `info`.
+    pub fn from_mic_e(destination: &str, info: &str) -> Option<Self> {
+        let dest = destination.as_bytes();
+        let bytes = info.as_bytes();
+        if dest.len() != 6 || bytes.len() < 9 || !bytes[..9].is_ascii() {
+            return None;
+        }
+
+        let mut digits = [0u32; 6];
+        for (digit, &c) in digits.iter_mut().zip(dest) {
+            *digit = match c {
+                b'0'..=b'9' => (c - b'0') as u32,
+                b'A'..=b'J' => (c - b'A') as u32,
+                b'P'..=b'Y' => (c - b'P') as u32,
+                // Position ambiguity
+                b'K' | b'L' | b'Z' => 0,
+                _ => return None,
+            };
+        }
+        // Characters 4-6 also carry the N/S, longitude offset and E/W flags
+        let flag = |c: u8| matches!(c, b'P'..=b'Z');
+        let north = flag(dest[3]);
+        let lon_offset = if flag(dest[4]) { 100 } else { 0 };
+        let west = flag(dest[5]);
+
+        let lat_minutes =
+            (digits[2] * 10 + digits[3]) as f64 + (digits[4] * 10 + digits[5]) as f64 / 100.0;
+        if lat_minutes >= 60.0 {
+            return None;
+        }
+        let latitude = (digits[0] * 10 + digits[1]) as f64 + lat_minutes / 60.0;
+
+        let field = |i: usize| (bytes[i] as i32) - 28;
+        let mut degrees = field(1) + lon_offset;
+        if (180..=189).contains(&degrees) {
+            degrees -= 80;
+        } else if (190..=199).contains(&degrees) {
+            degrees -= 190;
+        }
+        let mut minutes = field(2);
+        if minutes >= 60 {
+            minutes -= 60;
+        }
+        let hundredths = field(3);
+        if !(0..=179).contains(&degrees) || !(0..60).contains(&minutes) || hundredths < 0 {
+            return None;
+        }
+        let longitude = degrees as f64 + (minutes as f64 + hundredths as f64 / 100.0) / 60.0;
+
+        let (sp, dc, se) = (field(4), field(5), field(6));
+        let mut speed = sp * 10 + dc / 10;
+        if speed >= 800 {
+            speed -= 800;
+        }
+        let mut course = (dc % 10) * 100 + se;
+        if course >= 400 {
+            course -= 400;
+        }
+
+        let mut comment = &info[9..];
+        let altitude = Self::mic_e_altitude(&mut comment);
+
+        Some(Position {
+            latitude: if north { latitude } else { -latitude },
+            longitude: if west { -longitude } else { longitude },
+            symbol_table: bytes[8] as char,
+            symbol: bytes[7] as char,
+            // A course of zero means unknown
+            course: (1..=360).contains(&course).then_some(course as u16),
+            speed: (speed >= 0).then_some(speed as f32),
+            altitude,
+            range: None,
+            compressed: false,
+            timestamp: None,
+            comment: comment.to_string(),
+        })
+    }
+
+    /// Mic-E altitude is three Base91 digits and a `}`, in metres above
+    /// -10000, at the start of the comment (possibly after a type byte).
+    fn mic_e_altitude(comment: &mut &str) -> Option<f32> {
+        let bytes = comment.as_bytes();
+        let start = if bytes.get(3) == Some(&b'}') {
+            0
+        } else if bytes.get(4) == Some(&b'}') {
+            1
+        } else {
+            return None;
+        };
+        let value = decode_base91(&bytes[start..start + 3])?;
+        *comment = &comment[start + 4..];
+        Some(value as f32 - 10000.0)
+    }
+
+    /// Decode a raw `$GPRMC`, `$GPGGA` or `$GPGLL` sentence sent as the
+    /// information field. Such reports carry no symbol, so the default dot
+    /// is used. A missing checksum is tolerated, since some trackers omit it.
+    pub fn from_nmea(info: &str) -> Option<Self> {
+        let sentence = info.trim_end();
+        let sentence = match sentence.find('*') {
+            Some(star) => sentence.get(..star + 3)?.to_string(),
+            None => {
+                let checksum = sentence.bytes().skip(1).fold(0u8, |acc, b| acc ^ b);
+                format!("{}*{:02X}", sentence, checksum)
+            }
+        };
+
+        let (latitude, longitude, speed, course, altitude) =
+            match nmea::parse_str(&sentence).ok()? {
+                nmea::ParseResult::RMC(rmc) => {
+                    if rmc.status_of_fix == nmea::sentences::rmc::RmcStatusOfFix::Invalid {
+                        return None;
+                    }
+                    (
+                        rmc.lat?,
+                        rmc.lon?,
+                        rmc.speed_over_ground,
+                        rmc.true_course,
+                        None,
+                    )
+                }
+                nmea::ParseResult::GGA(gga) => {
+                    if !gga.fix_type.is_some_and(|f| f.is_valid()) {
+                        return None;
+                    }
+                    (gga.latitude?, gga.longitude?, None, None, gga.altitude)
+                }
+                nmea::ParseResult::GLL(gll) if gll.valid => {
+                    (gll.latitude?, gll.longitude?, None, None, None)
+                }
+                _ => return None,
+            };
+
+        Some(Position {
+            latitude,
+            longitude,
+            symbol_table: '/',
+            symbol: '/',
+            course: course.map(|c| c.round() as u16),
+            speed,
+            altitude,
+            range: None,
+            compressed: false,
+            timestamp: None,
+            comment: String::new(),
+        })
+    }
+
+    /// Decode a position that starts at `body`, in either the plain
+    /// `DDMM.hhN/DDDMM.hhW$` form or the Base91 compressed form.
+    pub fn parse(body: &str) -> Option<Self> {
+        let mut position = match body.chars().next()? {
+            '0'..='9' | ' ' => {
+                let mut position = parse_uncompressed(body)?;
+                position.extract_course_speed();
+                position
+            }
+            _ => parse_compressed(body)?,
+        };
+        position.extract_altitude();
+        Some(position)
+    }
+
+    /// Take a `CSE/SPD` data extension (degrees/knots) off the start of the
+    /// comment. A course of 000 or placeholder dots mean it is unknown.
+    fn extract_course_speed(&mut self) {
+        let Some(ext) = self.comment.get(..7) else {
+            return;
+        };
+        if !ext.is_ascii() || ext.as_bytes()[3] != b'/' {
+            return;
+        }
+        let field = |s: &str| -> Option<Option<u16>> {
+            if s == "..." || s == "   " {
+                Some(None)
+            } else if s.bytes().all(|b| b.is_ascii_digit()) {
+                Some(s.parse().ok())
+            } else {
+                None
+            }
+        };
+        let (Some(course), Some(speed)) = (field(&ext[..3]), field(&ext[4..])) else {
+            return;
+        };
+
+        self.course = course.filter(|c| (1..=360).contains(c));
+        self.speed = speed.map(|s| s as f32);
+        self.comment.drain(..7);
+    }
+
+    /// Take a `/A=aaaaaa` altitude (feet) out of the comment.
+    fn extract_altitude(&mut self) {
+        let Some(start) = self.comment.find("/A=") else {
+            return;
+        };
+        let Some(value) = self.comment.get(start + 3..start + 9) else {
+            return;
+        };
+        let Ok(feet) = value.parse::<i32>() else {
+            return;
+        };
+
+        if self.altitude.is_none() {
+            self.altitude = Some(feet as f32 * FEET_TO_METRES);
+        }
+        self.comment.replace_range(start..start + 9, "");
+    }
+}
+
+fn parse_uncompressed(body: &str) -> Option<Position> {
+    let bytes = body.as_bytes();
+    if bytes.len() < 19 || !bytes[..19].is_ascii() {
+        return None;
+    }
+
+    let latitude = parse_coordinate(&body[0..8], 2, b'N', b'S')?;
+    let symbol_table = bytes[8] as char;
+    let longitude = parse_coordinate(&body[9..18], 3, b'E', b'W')?;
+    let symbol = bytes[18] as char;
+
+    Some(Position {
+        latitude,
+        longitude,
+        symbol_table,
+        symbol,
+        course: None,
+        speed: None,
+        altitude: None,
+        range: None,
+        compressed: false,
+        timestamp: None,
+        comment: body[19..].to_string(),
+    })
+}
+
+/// Parse `DDMM.hhN` / `DDDMM.hhW`. Spaces used for position ambiguity are
+/// read as zeros.
+fn parse_coordinate(field: &str, degree_digits: usize, pos: u8, neg: u8) -> Option<f64> {
+    let bytes = field.as_bytes();
+    let hemisphere = *bytes.last()?;
+    let digits: String = field[..field.len() - 1].replace(' ', "0");
+    if digits.as_bytes().get(degree_digits + 2) != Some(&b'.') {
+        return None;
+    }
+
+    let degrees: f64 = digits[..degree_digits].parse().ok()?;
+    let minutes: f64 = digits[degree_digits..].parse().ok()?;
+    if minutes >= 60.0 {
+        return None;
+    }
+
+    let value = degrees + minutes / 60.0;
+    let limit = if degree_digits == 2 { 90.0 } else { 180.0 };
+    if value > limit {
+        return None;
+    }
+
+    match hemisphere.to_ascii_uppercase() {
+        h if h == pos => Some(value),
+        h if h == neg => Some(-value),
+        _ => None,
+    }
+}
+
+fn parse_compressed(body: &str) -> Option<Position> {
+    let bytes = body.as_bytes();
+    if bytes.len() < 13 || !bytes[..13].is_ascii() {
+        return None;
+    }
+
+    // Primary or alternate table, or an overlay (A-Z, or a-j standing for 0-9)
+    let symbol_table = bytes[0] as char;
+    if !matches!(symbol_table, '/' | '\\' | 'A'..='Z' | 'a'..='j') {
+        return None;
+    }
+    let y = decode_base91(&bytes[1..5])?;
+    let x = decode_base91(&bytes[5..9])?;
+    let symbol = bytes[9] as char;
+
+    let mut position = Position {
+        latitude: 90.0 - y as f64 / 380926.0,
+        longitude: -180.0 + x as f64 / 190463.0,
+        symbol_table,
+        symbol,
+        course: None,
+        speed: None,
+        altitude: None,
+        range: None,
+        compressed: true,
+        timestamp: None,
+        comment: body[13..].to_string(),
+    };
+
+    // A space in the course byte means there is no cs data
+    let (c, s, t) = (bytes[10], bytes[11], bytes[12]);
+    if c != b' ' {
+        let (c, s) = (c.checked_sub(33)?, s.checked_sub(33)?);
+        let compression_type = t.checked_sub(33)?;
+
+        if compression_type & 0x18 == 0x10 {
+            // NMEA source was GGA, so cs is altitude
+            let cs = c as i32 * 91 + s as i32;
+            position.altitude = Some(1.002f32.powi(cs) * FEET_TO_METRES);
+        } else if c == b'{' - 33 {
+            position.range = Some(2.0 * 1.08f32.powi(s as i32) * MILES_TO_KM);
+        } else if c <= 89 {
+            position.course = Some(c as u16 * 4);
+            position.speed = Some(1.08f32.powi(s as i32) - 1.0);
+        }
+    }
+
+    Some(position)
+}
+
+fn decode_base91(bytes: &[u8]) -> Option<u32> {
+    bytes.iter().try_fold(0u32, |acc, &b| {
+        if !(33..=124).contains(&b) {
+            return None;
+        }
+        Some(acc * 91 + (b - 33) as u32)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mic_e_emergency() {
+        assert!(!Position::mic_e_emergency("T7SVWP"));
+        assert!(!Position::mic_e_emergency("S32U6T"));
+        assert!(Position::mic_e_emergency("332U6T"));
+        assert!(Position::mic_e_emergency("3L2U6T"));
+        assert!(!Position::mic_e_emergency("APRS"));
+    }
+
+    #[test]
+    fn test_uncompressed_position() {
+        let pos = Position::from_info("!4903.50N/07201.75W>Test").unwrap();
+        assert!((pos.latitude - 49.058333).abs() < 0.00001);
+        assert!((pos.longitude + 72.029167).abs() < 0.00001);
+        assert_eq!(pos.symbol_table, '/');
+        assert_eq!(pos.symbol, '>');
+        assert_eq!(pos.comment, "Test");
+        assert!(!pos.compressed);
+
+        // Timestamped, southern/eastern hemisphere
+        let pos = Position::from_info("@092345z3351.00S\\15112.00E-").unwrap();
+        assert!((pos.latitude + 33.85).abs() < 0.00001);
+        assert!((pos.longitude - 151.2).abs() < 0.00001);
+        assert_eq!(pos.symbol_table, '\\');
+        assert_eq!(
+            pos.timestamp.unwrap().format("%d%H%M").to_string(),
+            "092345"
+        );
+
+        // Position ambiguity
+        let pos = Position::from_info("!4903.  N/07201.  W>").unwrap();
+        assert!((pos.latitude - 49.05).abs() < 0.00001);
+    }
+
+    #[test]
+    fn test_mic_e_position() {
+        let pos = Position::from_mic_e("332UVT", "`(#fpPO>/\"4{}Hello").unwrap();
+        assert!((pos.latitude - 33.427333).abs() < 0.00001);
+        assert!((pos.longitude + 112.129).abs() < 0.00001);
+        assert_eq!(pos.symbol, '>');
+        assert_eq!(pos.symbol_table, '/');
+        assert_eq!(pos.speed, Some(45.0));
+        assert_eq!(pos.course, Some(251));
+        assert_eq!(pos.altitude, Some(100.0));
+        assert_eq!(pos.comment, "Hello");
+
+        // Southern/eastern hemisphere, no altitude
+        let pos = Position::from_mic_e("3325LL", "`(#fpPO>/").unwrap();
+        assert!(pos.latitude < 0.0);
+        assert!((pos.longitude - 12.129).abs() < 0.00001);
+        assert_eq!(pos.altitude, None);
+
+        assert!(Position::from_mic_e("APRS", "`(#fpPO>/").is_none());
+        assert!(Position::from_mic_e("332UVT", "`(#f").is_none());
+    }
+
+    #[test]
+    fn test_raw_nmea_position() {
+        let pos = Position::from_nmea(
+            "$GPRMC,225446,A,4916.45,N,12311.12,W,000.5,054.7,191194,020.3,E*68",
+        )
+        .unwrap();
+        assert!((pos.latitude - 49.274167).abs() < 0.0001);
+        assert!((pos.longitude + 123.185333).abs() < 0.0001);
+        assert_eq!(pos.course, Some(55));
+        assert_eq!(pos.speed, Some(0.5));
+
+        let pos = Position::from_nmea(
+            "$GPGGA,092750.000,5321.6802,N,00630.3372,W,1,8,1.03,61.7,M,55.2,M,,*76\r",
+        )
+        .unwrap();
+        assert!((pos.latitude - 53.36134).abs() < 0.0001);
+        assert_eq!(pos.altitude, Some(61.7));
+
+        // Without a checksum
+        assert!(Position::from_nmea(
+            "$GPRMC,225446,A,4916.45,N,12311.12,W,000.5,054.7,191194,020.3,E"
+        )
+        .is_some());
+
+        // No fix, bad checksum, unsupported sentence
+        assert!(Position::from_nmea(
+            "$GPRMC,225446,V,4916.45,N,12311.12,W,000.5,054.7,191194,020.3,E"
+        )
+        .is_none());
+        assert!(Position::from_nmea(
+            "$GPRMC,225446,A,4916.45,N,12311.12,W,000.5,054.7,191194,020.3,E*00"
+        )
+        .is_none());
+        assert!(Position::from_nmea("$GPGSA,A,3,04,05,,09,12,,,24,,,,,2.5,1.3,2.1*39").is_none());
+    }
+
+    #[test]
+    fn test_course_speed_altitude_extension() {
+        let pos = Position::from_info("!4903.50N/07201.75W>088/036/A=001234 Mobile").unwrap();
+        assert_eq!(pos.course, Some(88));
+        assert_eq!(pos.speed, Some(36.0));
+        assert!((pos.altitude.unwrap() - 376.1).abs() < 0.1);
+        assert_eq!(pos.comment, " Mobile");
+
+        // Unknown course, negative altitude in the middle of the comment
+        let pos = Position::from_info("!4903.50N/07201.75W>.../005Low /A=-00012 point").unwrap();
+        assert_eq!(pos.course, None);
+        assert_eq!(pos.speed, Some(5.0));
+        assert!(pos.altitude.unwrap() < 0.0);
+        assert_eq!(pos.comment, "Low  point");
+
+        // Not an extension
+        let pos = Position::from_info("!4903.50N/07201.75W>Hello/World").unwrap();
+        assert_eq!(pos.course, None);
+        assert_eq!(pos.comment, "Hello/World");
+        let pos = Position::from_info("!4903.50N/07201.75W>é/é/abc").unwrap();
+        assert_eq!(pos.comment, "é/é/abc");
+
+        // Compressed reports only get /A=
+        let pos = Position::from_info("!/5L!!<*e7>  !/A=000100").unwrap();
+        assert!((pos.altitude.unwrap() - 30.48).abs() < 0.01);
+        assert_eq!(pos.comment, "");
+    }
+
+    #[test]
+    fn test_invalid_positions() {
+        assert!(Position::from_info("!4903.50X/07201.75W>").is_none());
+        assert!(Position::from_info("!9903.50N/07201.75W>").is_none());
+        assert!(Position::from_info("!4903.50N").is_none());
+        assert!(Position::from_info(">status").is_none());
+        assert!(Position::from_info("/5L!!<*e7").is_none());
+        assert!(Position::from_info("!x5L!!<*e7>7P[").is_none());
+    }
+
+    #[test]
+    fn test_compressed_course_speed() {
+        let pos = Position::from_info("!/5L!!<*e7>7P[comment").unwrap();
+        assert!((pos.latitude - 49.5).abs() < 0.00001);
+        assert!((pos.longitude + 72.75).abs() < 0.00001);
+        assert_eq!(pos.symbol, '>');
+        assert_eq!(pos.course, Some(88));
+        assert!((pos.speed.unwrap() - 36.2).abs() < 0.1);
+        assert_eq!(pos.comment, "comment");
+        assert!(pos.compressed);
+    }
+
+    #[test]
+    fn test_compressed_altitude_and_range() {
+        let pos = Position::from_info("=/5L!!<*e7OS]S").unwrap();
+        // 10004 ft
+        assert!((pos.altitude.unwrap() - 3049.0).abs() < 2.0);
+        assert_eq!(pos.course, None);
+
+        let pos = Position::from_info("!/5L!!<*e7>{?!").unwrap();
+        // 20.1 miles
+        assert!((pos.range.unwrap() - 32.4).abs() < 0.1);
+
+        let pos = Position::from_info("!/5L!!<*e7>  !").unwrap();
+        assert_eq!(pos.speed, None);
+        assert_eq!(pos.altitude, None);
+    }
+}
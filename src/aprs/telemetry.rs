@@ -0,0 +1,176 @@
+/// A decoded `T#` telemetry report.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TelemetryReport {
+    pub sequence: Option<u32>,      // None for "T#MIC"
+    pub analog: Vec<f64>,           // Up to 5 raw values
+    pub digital: Option<[bool; 8]>, // B1 first
+    pub comment: String,
+}
+
+/// A PARM/UNIT/EQNS/BITS definition message, which a station sends to
+/// itself (or is sent on its behalf) to describe its telemetry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TelemetryDefinition {
+    pub station: String, // The station whose telemetry this describes
+    pub kind: DefinitionKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DefinitionKind {
+    Parameters(Vec<String>),  // Names for A1-A5 then B1-B8
+    Units(Vec<String>),       // Units for A1-A5, labels for B1-B8
+    Equations(Vec<[f64; 3]>), // a, b, c per analog channel
+    Bits { active: [bool; 8], project: String },
+}
+
+impl TelemetryReport {
+    pub fn parse(info: &str) -> Option<Self> {
+        let body = info.strip_prefix("T#")?;
+        let mut fields = body.splitn(7, ',');
+
+        let sequence = match fields.next()? {
+            "MIC" => None,
+            seq => Some(seq.trim().parse().ok()?),
+        };
+
+        let mut analog = Vec::new();
+        let mut digital = None;
+        let mut comment = String::new();
+        for (i, field) in fields.enumerate() {
+            if i < 5 {
+                analog.push(field.trim().parse().ok()?);
+            } else {
+                // Eight bits, optionally followed by a comment
+                let bits = field.get(..8)?;
+                digital = Some(parse_bits(bits)?);
+                comment = field[8..].to_string();
+            }
+        }
+        if analog.is_empty() {
+            return None;
+        }
+
+        Some(TelemetryReport {
+            sequence,
+            analog,
+            digital,
+            comment,
+        })
+    }
+}
+
+impl TelemetryDefinition {
+    /// Decode a definition from a message addressee and text, or None if
+    /// the text is an ordinary message.
+    pub fn parse(addressee: &str, text: &str) -> Option<Self> {
+        let (keyword, rest) = text.split_once('.')?;
+        let list = || rest.split(',').map(|s| s.trim().to_string()).collect();
+
+        let kind = match keyword {
+            "PARM" => DefinitionKind::Parameters(list()),
+            "UNIT" => DefinitionKind::Units(list()),
+            "EQNS" => {
+                let values: Vec<f64> = rest
+                    .split(',')
+                    .map(|v| v.trim().parse().ok())
+                    .collect::<Option<_>>()?;
+                if values.is_empty() || !values.len().is_multiple_of(3) {
+                    return None;
+                }
+                DefinitionKind::Equations(values.chunks(3).map(|c| [c[0], c[1], c[2]]).collect())
+            }
+            "BITS" => {
+                let (bits, project) = rest.split_once(',').unwrap_or((rest, ""));
+                DefinitionKind::Bits {
+                    active: parse_bits(bits.get(..8)?)?,
+                    project: project.trim().to_string(),
+                }
+            }
+            _ => return None,
+        };
+
+        Some(TelemetryDefinition {
+            station: addressee.to_string(),
+            kind,
+        })
+    }
+}
+
+/// Apply EQNS coefficients to a raw analog value.
+pub fn scale(coefficients: [f64; 3], raw: f64) -> f64 {
+    let [a, b, c] = coefficients;
+    a * raw * raw + b * raw + c
+}
+
+fn parse_bits(bits: &str) -> Option<[bool; 8]> {
+    let mut out = [false; 8];
+    for (bit, c) in out.iter_mut().zip(bits.chars()) {
+        *bit = match c {
+            '1' => true,
+            '0' => false,
+            _ => return None,
+        };
+    }
+    (bits.len() == 8).then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_telemetry_report() {
+        let report = TelemetryReport::parse("T#005,199,000,255,073,123,01101001").unwrap();
+        assert_eq!(report.sequence, Some(5));
+        assert_eq!(report.analog, vec![199.0, 0.0, 255.0, 73.0, 123.0]);
+        assert_eq!(
+            report.digital,
+            Some([false, true, true, false, true, false, false, true])
+        );
+        assert_eq!(report.comment, "");
+
+        // Decimal values, a comment, and the Mic-E sequence
+        let report = TelemetryReport::parse("T#MIC,1.5,2,3,4,5,11111111 battery").unwrap();
+        assert_eq!(report.sequence, None);
+        assert_eq!(report.analog[0], 1.5);
+        assert_eq!(report.comment, " battery");
+
+        // Fewer channels, no digital
+        let report = TelemetryReport::parse("T#012,100,200").unwrap();
+        assert_eq!(report.analog.len(), 2);
+        assert_eq!(report.digital, None);
+
+        assert!(TelemetryReport::parse("T#abc,1,2").is_none());
+        assert!(TelemetryReport::parse("T#001").is_none());
+        assert!(TelemetryReport::parse("T#001,1,2,3,4,5,0110").is_none());
+    }
+
+    #[test]
+    fn test_parse_definitions() {
+        let def = TelemetryDefinition::parse("N0CALL", "PARM.RxPkts,TxPkts,Digi").unwrap();
+        assert_eq!(def.station, "N0CALL");
+        assert_eq!(
+            def.kind,
+            DefinitionKind::Parameters(vec!["RxPkts".into(), "TxPkts".into(), "Digi".into()])
+        );
+
+        let def = TelemetryDefinition::parse("N0CALL", "EQNS.0,1,0,0,0.5,-10").unwrap();
+        let DefinitionKind::Equations(eqns) = def.kind else {
+            panic!("expected equations");
+        };
+        assert_eq!(eqns.len(), 2);
+        assert_eq!(scale(eqns[1], 100.0), 40.0);
+
+        let def = TelemetryDefinition::parse("N0CALL", "BITS.10000000,Weather station").unwrap();
+        assert_eq!(
+            def.kind,
+            DefinitionKind::Bits {
+                active: [true, false, false, false, false, false, false, false],
+                project: "Weather station".into()
+            }
+        );
+
+        assert!(TelemetryDefinition::parse("N0CALL", "EQNS.0,1").is_none());
+        assert!(TelemetryDefinition::parse("N0CALL", "Hello. How are you").is_none());
+    }
+}
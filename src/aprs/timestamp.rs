@@ -0,0 +1,156 @@
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+
+/// How far into the future a timestamp may be, to allow for clock skew,
+/// before it is taken to refer to the previous day, month or year.
+const FUTURE_TOLERANCE: Duration = Duration::hours(1);
+
+/// Parse an APRS timestamp relative to `now`:
+///
+/// - `DDHHMMz` day/hours/minutes, UTC
+/// - `DDHHMM/` day/hours/minutes, local time
+/// - `HHMMSSh` hours/minutes/seconds, UTC
+/// - `MMDDHHMM` month/day/hours/minutes, UTC (positionless weather)
+///
+/// The date parts that are not sent are taken from the most recent matching
+/// time not in the future.
+pub fn parse_timestamp(field: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let bytes = field.as_bytes();
+    if !bytes.is_ascii() {
+        return None;
+    }
+    let num = |range: std::ops::Range<usize>| -> Option<u32> {
+        let s = field.get(range)?;
+        if !s.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        s.parse().ok()
+    };
+
+    match (bytes.len(), bytes.last()) {
+        (7, Some(b'z')) => day_hour_minute(num(0..2)?, num(2..4)?, num(4..6)?, now, Utc),
+        (7, Some(b'/')) => day_hour_minute(num(0..2)?, num(2..4)?, num(4..6)?, now, Local),
+        (7, Some(b'h')) => {
+            let (hour, minute, second) = (num(0..2)?, num(2..4)?, num(4..6)?);
+            let today = now
+                .date_naive()
+                .and_hms_opt(hour, minute, second)?
+                .and_utc();
+            if today > now + FUTURE_TOLERANCE {
+                Some(today - Duration::days(1))
+            } else {
+                Some(today)
+            }
+        }
+        (8, _) => {
+            let (month, day, hour, minute) = (num(0..2)?, num(2..4)?, num(4..6)?, num(6..8)?);
+            [now.year(), now.year() - 1].into_iter().find_map(|year| {
+                let time = NaiveDate::from_ymd_opt(year, month, day)?
+                    .and_hms_opt(hour, minute, 0)?
+                    .and_utc();
+                (time <= now + FUTURE_TOLERANCE).then_some(time)
+            })
+        }
+        _ => None,
+    }
+}
+
+fn day_hour_minute<Tz: TimeZone>(
+    day: u32,
+    hour: u32,
+    minute: u32,
+    now: DateTime<Utc>,
+    tz: Tz,
+) -> Option<DateTime<Utc>> {
+    let local_now = now.with_timezone(&tz).naive_local();
+    let (mut year, mut month) = (local_now.year(), local_now.month());
+
+    // Walk back until the day exists and isn't in the future; a day-31
+    // timestamp may need to skip a short month
+    for _ in 0..3 {
+        if let Some(time) = NaiveDate::from_ymd_opt(year, month, day)
+            .and_then(|d| d.and_hms_opt(hour, minute, 0))
+            .and_then(|t: NaiveDateTime| tz.from_local_datetime(&t).earliest())
+            .map(|t| t.with_timezone(&Utc))
+        {
+            if time <= now + FUTURE_TOLERANCE {
+                return Some(time);
+            }
+        }
+        (year, month) = if month == 1 {
+            (year - 1, 12)
+        } else {
+            (year, month - 1)
+        };
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_day_hour_minute() {
+        let now = at("2024-03-15T12:00:00Z");
+        assert_eq!(
+            parse_timestamp("150930z", now),
+            Some(at("2024-03-15T09:30:00Z"))
+        );
+        // Later in the month than today means last month
+        assert_eq!(
+            parse_timestamp("201200z", now),
+            Some(at("2024-02-20T12:00:00Z"))
+        );
+        // No 31st in February, so January
+        assert_eq!(
+            parse_timestamp("310000z", now),
+            Some(at("2024-01-31T00:00:00Z"))
+        );
+        // A little clock skew is tolerated
+        assert_eq!(
+            parse_timestamp("151230z", now),
+            Some(at("2024-03-15T12:30:00Z"))
+        );
+        assert_eq!(parse_timestamp("321200z", now), None);
+    }
+
+    #[test]
+    fn test_hour_minute_second() {
+        let now = at("2024-03-15T00:10:00Z");
+        assert_eq!(
+            parse_timestamp("000512h", now),
+            Some(at("2024-03-15T00:05:12Z"))
+        );
+        assert_eq!(
+            parse_timestamp("235959h", now),
+            Some(at("2024-03-14T23:59:59Z"))
+        );
+        assert_eq!(parse_timestamp("246000h", now), None);
+    }
+
+    #[test]
+    fn test_month_day_hour_minute() {
+        let now = at("2024-01-02T00:00:00Z");
+        assert_eq!(
+            parse_timestamp("01011530", now),
+            Some(at("2024-01-01T15:30:00Z"))
+        );
+        assert_eq!(
+            parse_timestamp("12312359", now),
+            Some(at("2023-12-31T23:59:00Z"))
+        );
+    }
+
+    #[test]
+    fn test_invalid_timestamps() {
+        let now = Utc::now();
+        assert_eq!(parse_timestamp("", now), None);
+        assert_eq!(parse_timestamp("15093Xz", now), None);
+        assert_eq!(parse_timestamp("150930x", now), None);
+        assert_eq!(parse_timestamp("1509+0z", now), None);
+    }
+}
@@ -1,10 +1,15 @@
 use crate::aprs::{AprsPacket, CallSign};
+use crate::channel::{self, Overflow};
 use crate::config::BeaconConfig;
-use crate::gps::{GpsPosition, GpsTracker};
-use crate::router::{PacketSource, RoutedPacket};
+use crate::geofence::Geofence;
+use crate::gps::{distance_km, GpsPosition, GpsTracker, TripStats};
+use crate::rig::{self, Rig};
+use crate::router::{PacketSource, RoutedPacket, TAG_PORT_PREFIX};
+use crate::shutdown::Shutdown;
+use crate::template;
 use anyhow::Result;
 use chrono::{DateTime, Utc};
-use log::{debug, info};
+use log::{debug, error, info};
 use std::sync::Arc;
 use tokio::sync::mpsc;
 use tokio::time::{interval, Duration};
@@ -12,23 +17,49 @@ use tokio::time::{interval, Duration};
 pub struct BeaconService {
     config: BeaconConfig,
     gps: Arc<GpsTracker>,
+    geofences: Vec<Geofence>,
+    active_geofence: Option<usize>,
     last_position: Option<GpsPosition>,
     last_beacon_time: DateTime<Utc>,
     stationary_count: u32,
+    rig: Option<Arc<Rig>>,
+    beaconed_frequency: Option<u64>, // Hz, as given in the last beacon
 }
 
 impl BeaconService {
     pub fn new(config: BeaconConfig, gps: Arc<GpsTracker>) -> Self {
+        let geofences = config
+            .geofences
+            .iter()
+            .filter_map(|c| match Geofence::from_config(c) {
+                Ok(fence) => Some(fence),
+                Err(e) => {
+                    error!("Ignoring geofence {}: {}", c.name, e);
+                    None
+                }
+            })
+            .collect();
+
         BeaconService {
             config,
             gps,
+            geofences,
+            active_geofence: None,
             last_position: None,
             last_beacon_time: Utc::now(),
             stationary_count: 0,
+            rig: None,
+            beaconed_frequency: None,
         }
     }
 
-    pub async fn run(mut self, tx: mpsc::Sender<RoutedPacket>) -> Result<()> {
+    /// Give the rig's frequency in the comment, if the config asks for it
+    pub fn with_rig(mut self, rig: Arc<Rig>) -> Self {
+        self.rig = Some(rig);
+        self
+    }
+
+    pub async fn run(mut self, tx: mpsc::Sender<RoutedPacket>, shutdown: Shutdown) -> Result<()> {
         info!("Starting beacon service");
 
         let mut check_interval = interval(Duration::from_secs(
@@ -36,14 +67,95 @@ impl BeaconService {
         ));
 
         loop {
-            check_interval.tick().await;
+            tokio::select! {
+                _ = check_interval.tick() => {}
+                _ = shutdown.wait() => return Ok(()),
+            }
 
-            if let Some(current_pos) = self.gps.get_position().await {
-                if self.should_beacon(&current_pos).await {
-                    self.send_beacon(&current_pos, &tx).await?;
+            match self.gps.get_current_position().await {
+                Some(current_pos) => {
+                    self.update_geofence(&current_pos);
+                    if self.suppressed() {
+                        debug!("Inside a TX-suppressed geofence, not beaconing");
+                        continue;
+                    }
+                    if self.should_beacon(&current_pos).await {
+                        self.send_beacon(&current_pos, &tx).await?;
+                    }
                 }
+                None => debug!("No current GPS fix, not beaconing"),
+            }
+        }
+    }
+
+    fn update_geofence(&mut self, pos: &GpsPosition) {
+        let fence = self
+            .geofences
+            .iter()
+            .position(|f| f.contains(pos.latitude, pos.longitude));
+
+        if fence != self.active_geofence {
+            match fence {
+                Some(idx) => info!("Entered geofence {}", self.geofences[idx].name()),
+                None => info!("Left geofence, using default beacon profile"),
             }
+            self.active_geofence = fence;
+        }
+    }
+
+    fn geofence(&self) -> Option<&Geofence> {
+        self.active_geofence.map(|idx| &self.geofences[idx])
+    }
+
+    fn suppressed(&self) -> bool {
+        self.geofence().is_some_and(|f| f.config().suppress)
+    }
+
+    fn interval(&self) -> u32 {
+        self.geofence()
+            .and_then(|f| f.config().interval)
+            .unwrap_or(self.config.interval)
+    }
+
+    /// The path for a beacon at this speed. The fastest speed rule we're
+    /// over wins, then the geofence's path, then the default.
+    fn path(&self, speed: Option<f32>) -> &str {
+        let speed = speed.unwrap_or(0.0);
+        let rule = self
+            .config
+            .speed_paths
+            .iter()
+            .filter(|rule| speed > rule.above)
+            .max_by(|a, b| a.above.total_cmp(&b.above));
+        if let Some(rule) = rule {
+            return &rule.path;
+        }
+        self.geofence()
+            .and_then(|f| f.config().path.as_deref())
+            .unwrap_or(&self.config.path)
+    }
+
+    fn comment(&self) -> &str {
+        self.geofence()
+            .and_then(|f| f.config().comment.as_deref())
+            .unwrap_or(&self.config.comment)
+    }
+
+    fn symbol(&self) -> (char, char) {
+        let fence = self.geofence().map(|f| f.config());
+        (
+            fence
+                .and_then(|f| f.symbol_table)
+                .unwrap_or(self.config.symbol_table),
+            fence.and_then(|f| f.symbol).unwrap_or(self.config.symbol),
+        )
+    }
+
+    fn frequency(&self) -> Option<u64> {
+        if !self.config.frequency {
+            return None;
         }
+        self.rig.as_ref().and_then(|rig| rig.frequency())
     }
 
     async fn should_beacon(&mut self, current_pos: &GpsPosition) -> bool {
@@ -51,11 +163,21 @@ impl BeaconService {
         let time_since_last = now.signed_duration_since(self.last_beacon_time);
 
         // Always beacon if we haven't sent one in max_interval
-        if time_since_last.num_seconds() >= self.config.interval as i64 {
+        if time_since_last.num_seconds() >= self.interval() as i64 {
             debug!("Beaconing due to max interval");
             return true;
         }
 
+        // Tell people straight away when the operator QSYs
+        let frequency = self.frequency();
+        if frequency.is_some()
+            && frequency != self.beaconed_frequency
+            && time_since_last.num_seconds() >= self.config.smart_beacon.min_interval as i64
+        {
+            debug!("Beaconing due to frequency change");
+            return true;
+        }
+
         // Smart beaconing logic
         if self.config.smart_beacon.enabled {
             match &self.last_position {
@@ -134,22 +256,33 @@ impl BeaconService {
         position: &GpsPosition,
         tx: &mpsc::Sender<RoutedPacket>,
     ) -> Result<()> {
-        let packet_info = self.format_position_packet(position);
+        let comment = template::expand(self.comment(), Some(&self.gps)).await;
+        let mut packet_info = self.format_position_packet(position, &comment);
+        if self.config.trip_comment {
+            packet_info.push(' ');
+            packet_info.push_str(&format_trip(&self.gps.trip_stats().await));
+        }
 
         let source = CallSign::parse(&self.config.callsign).unwrap_or(CallSign::new("N0CALL", 0));
 
         let mut packet = AprsPacket::new(source, CallSign::new("APRS", 0), packet_info);
 
         // Add path if configured
-        if !self.config.path.is_empty() {
-            packet.path = self
-                .config
-                .path
+        let path = self.path(position.speed);
+        if !path.is_empty() {
+            packet.path = path
                 .split(',')
                 .filter_map(|p| CallSign::parse(p.trim()))
                 .collect();
         }
 
+        packet.tags.extend(
+            self.config
+                .ports
+                .iter()
+                .map(|port| format!("{}{}", TAG_PORT_PREFIX, port)),
+        );
+
         info!("Sending position beacon: {}", packet);
 
         let routed = RoutedPacket {
@@ -157,15 +290,16 @@ impl BeaconService {
             source: PacketSource::Internal,
         };
 
-        let _ = tx.send(routed).await;
+        channel::send(tx, routed, channel::ROUTER, Overflow::Drop).await;
 
         self.last_position = Some(*position);
         self.last_beacon_time = Utc::now();
+        self.beaconed_frequency = self.frequency();
 
         Ok(())
     }
 
-    fn format_position_packet(&self, pos: &GpsPosition) -> String {
+    fn format_position_packet(&self, pos: &GpsPosition, comment: &str) -> String {
         let lat = format_latitude(pos.latitude);
         let lon = format_longitude(pos.longitude);
 
@@ -175,8 +309,9 @@ impl BeaconService {
             "!".to_string()
         };
 
-        let mut info = format!("{}{}{}{}", timestamp, lat, self.config.symbol_table, lon);
-        info.push(self.config.symbol);
+        let (symbol_table, symbol) = self.symbol();
+        let mut info = format!("{}{}{}{}", timestamp, lat, symbol_table, lon);
+        info.push(symbol);
 
         // Add course/speed if available and moving
         if let (Some(course), Some(speed)) = (pos.course, pos.speed) {
@@ -185,6 +320,15 @@ impl BeaconService {
             }
         }
 
+        // The frequency goes first in the comment, where APRS clients look
+        // for it
+        if let Some(frequency) = self.frequency() {
+            info.push_str(&rig::format_frequency(frequency));
+            if pos.altitude.is_some() {
+                info.push(' ');
+            }
+        }
+
         // Add altitude if available
         if let Some(alt) = pos.altitude {
             let alt_ft = (alt * 3.28084) as i32;
@@ -192,16 +336,16 @@ impl BeaconService {
         }
 
         // Add comment
-        if !self.config.comment.is_empty() {
+        if !comment.is_empty() {
             info.push(' ');
-            info.push_str(&self.config.comment);
+            info.push_str(comment);
         }
 
         info
     }
 }
 
-fn format_latitude(lat: f64) -> String {
+pub(crate) fn format_latitude(lat: f64) -> String {
     let lat_abs = lat.abs();
     let degrees = lat_abs as u8;
     let minutes = (lat_abs - degrees as f64) * 60.0;
@@ -210,7 +354,7 @@ fn format_latitude(lat: f64) -> String {
     format!("{:02}{:05.2}{}", degrees, minutes, ns)
 }
 
-fn format_longitude(lon: f64) -> String {
+pub(crate) fn format_longitude(lon: f64) -> String {
     let lon_abs = lon.abs();
     let degrees = lon_abs as u8;
     let minutes = (lon_abs - degrees as f64) * 60.0;
@@ -219,17 +363,15 @@ fn format_longitude(lon: f64) -> String {
     format!("{:03}{:05.2}{}", degrees, minutes, ew)
 }
 
-fn calculate_distance(pos1: &GpsPosition, pos2: &GpsPosition) -> f64 {
-    // Haversine formula
-    let lat1 = pos1.latitude.to_radians();
-    let lat2 = pos2.latitude.to_radians();
-    let dlat = (pos2.latitude - pos1.latitude).to_radians();
-    let dlon = (pos2.longitude - pos1.longitude).to_radians();
-
-    let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
-    let c = 2.0 * a.sqrt().asin();
+fn format_trip(trip: &TripStats) -> String {
+    format!(
+        "Trip {:.1}km max {:.0}kts",
+        trip.distance_km, trip.max_speed
+    )
+}
 
-    6371.0 * c // Earth radius in km
+fn calculate_distance(pos1: &GpsPosition, pos2: &GpsPosition) -> f64 {
+    distance_km(pos1.latitude, pos1.longitude, pos2.latitude, pos2.longitude)
 }
 
 fn angle_difference(angle1: f32, angle2: f32) -> f32 {
@@ -244,8 +386,8 @@ fn angle_difference(angle1: f32, angle2: f32) -> f32 {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::SmartBeaconConfig;
-    use crate::gps::{GpsPosition, GpsSource, GpsTracker};
+    use crate::config::{GeofenceConfig, SmartBeaconConfig, SpeedPathConfig};
+    use crate::gps::{FixQuality, GpsPosition, GpsSource, GpsTracker};
 
     fn create_test_config() -> BeaconConfig {
         BeaconConfig {
@@ -258,6 +400,11 @@ mod tests {
             comment: "Test beacon".to_string(),
             timestamp: true,
             smart_beacon: SmartBeaconConfig::default(),
+            geofences: vec![],
+            trip_comment: false,
+            speed_paths: vec![],
+            frequency: false,
+            ports: vec![],
         }
     }
 
@@ -274,6 +421,10 @@ mod tests {
             speed,
             course,
             timestamp: Utc::now(),
+            fix: FixQuality::Fix3D,
+            received: std::time::Instant::now(),
+            satellites: None,
+            hdop: None,
         }
     }
 
@@ -397,7 +548,7 @@ mod tests {
         let beacon = BeaconService::new(config, gps);
 
         let pos = create_test_position(40.7128, -74.0060, Some(50.0), Some(90.0));
-        let packet = beacon.format_position_packet(&pos);
+        let packet = beacon.format_position_packet(&pos, beacon.comment());
 
         assert!(packet.starts_with('@'));
         assert!(packet.contains("4042.77N/07400.36W>"));
@@ -406,6 +557,42 @@ mod tests {
         assert!(packet.contains("Test beacon"));
     }
 
+    #[tokio::test]
+    async fn test_rig_frequency() {
+        let mut config = create_test_config();
+        config.frequency = true;
+        let gps = Arc::new(GpsTracker::new(GpsSource::None));
+        let rig = Arc::new(Rig::new());
+        let mut beacon = BeaconService::new(config, gps).with_rig(rig.clone());
+        let pos = create_test_position(40.7128, -74.0060, Some(50.0), Some(90.0));
+
+        // Nothing to say until rigctld has answered
+        assert!(!beacon
+            .format_position_packet(&pos, beacon.comment())
+            .contains("MHz"));
+
+        rig.set_state(Some(rig::RigState {
+            frequency: 146_520_000,
+            mode: "FM".to_string(),
+        }));
+        let packet = beacon.format_position_packet(&pos, beacon.comment());
+        assert!(packet.contains("090/050146.520MHz /A=000328"), "{}", packet);
+
+        beacon.last_position = Some(pos);
+        beacon.last_beacon_time = Utc::now() - chrono::Duration::seconds(60);
+        beacon.beaconed_frequency = Some(146_520_000);
+        assert!(!beacon.should_beacon(&pos).await);
+
+        // A QSY beacons once min_interval has passed
+        rig.set_state(Some(rig::RigState {
+            frequency: 145_500_000,
+            mode: "FM".to_string(),
+        }));
+        assert!(beacon.should_beacon(&pos).await);
+        beacon.last_beacon_time = Utc::now() - chrono::Duration::seconds(10);
+        assert!(!beacon.should_beacon(&pos).await);
+    }
+
     #[test]
     fn test_format_position_packet_stationary() {
         let mut config = create_test_config();
@@ -414,9 +601,93 @@ mod tests {
         let beacon = BeaconService::new(config, gps);
 
         let pos = create_test_position(40.7128, -74.0060, Some(0.5), None);
-        let packet = beacon.format_position_packet(&pos);
+        let packet = beacon.format_position_packet(&pos, beacon.comment());
 
         assert!(packet.starts_with('!'));
         assert!(!packet.contains("000/000"));
     }
+
+    #[test]
+    fn test_geofence_profile_overrides() {
+        let mut config = create_test_config();
+        config.geofences.push(GeofenceConfig {
+            name: "metro".to_string(),
+            center: Some([40.7128, -74.0060]),
+            radius: Some(20.0),
+            path: Some("WIDE1-1".to_string()),
+            interval: Some(120),
+            comment: Some("In town".to_string()),
+            symbol: Some('k'),
+            ..Default::default()
+        });
+        let gps = Arc::new(GpsTracker::new(GpsSource::None));
+        let mut beacon = BeaconService::new(config, gps);
+
+        let pos = create_test_position(40.72, -74.01, None, None);
+        beacon.update_geofence(&pos);
+        assert_eq!(beacon.path(None), "WIDE1-1");
+        assert_eq!(beacon.interval(), 120);
+        let packet = beacon.format_position_packet(&pos, beacon.comment());
+        assert!(packet.contains("/07400.60Wk"));
+        assert!(packet.ends_with(" In town"));
+
+        // Far away the defaults apply again
+        let pos = create_test_position(42.0, -71.0, None, None);
+        beacon.update_geofence(&pos);
+        assert_eq!(beacon.path(None), "WIDE1-1,WIDE2-2");
+        assert_eq!(beacon.interval(), 600);
+        assert!(beacon
+            .format_position_packet(&pos, beacon.comment())
+            .ends_with(" Test beacon"));
+    }
+
+    #[test]
+    fn test_speed_paths() {
+        let mut config = create_test_config();
+        config.speed_paths = vec![
+            SpeedPathConfig {
+                above: 60.0,
+                path: "WIDE1-1".to_string(),
+            },
+            SpeedPathConfig {
+                above: 100.0,
+                path: String::new(),
+            },
+        ];
+        let gps = Arc::new(GpsTracker::new(GpsSource::None));
+        let beacon = BeaconService::new(config, gps);
+
+        assert_eq!(beacon.path(None), "WIDE1-1,WIDE2-2");
+        assert_eq!(beacon.path(Some(60.0)), "WIDE1-1,WIDE2-2");
+        assert_eq!(beacon.path(Some(75.0)), "WIDE1-1");
+        assert_eq!(beacon.path(Some(120.0)), "");
+    }
+
+    #[test]
+    fn test_geofence_suppress() {
+        let mut config = create_test_config();
+        config.geofences.push(GeofenceConfig {
+            name: "home".to_string(),
+            center: Some([40.7128, -74.0060]),
+            radius: Some(1.0),
+            suppress: true,
+            ..Default::default()
+        });
+        let gps = Arc::new(GpsTracker::new(GpsSource::None));
+        let mut beacon = BeaconService::new(config, gps);
+
+        beacon.update_geofence(&create_test_position(40.7128, -74.0060, None, None));
+        assert!(beacon.suppressed());
+
+        beacon.update_geofence(&create_test_position(41.0, -74.0060, None, None));
+        assert!(!beacon.suppressed());
+    }
+
+    #[test]
+    fn test_format_trip() {
+        let mut trip = TripStats::default();
+        trip.update(&create_test_position(40.0, -74.0, Some(0.0), None));
+        trip.update(&create_test_position(40.1, -74.0, Some(55.2), None));
+        assert_eq!(format_trip(&trip), "Trip 11.1km max 55kts");
+    }
 }
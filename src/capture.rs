@@ -0,0 +1,211 @@
+//! Traffic capture for later replay.
+//!
+//! While a capture runs, every packet in or out of a serial port or
+//! APRS-IS is appended to a file in the form `aprstx replay` reads:
+//!
+//! ```text
+//! 2024-06-01T14:03:09.120Z [vhf] rx N1CALL>APRS,WIDE1-1:>Hello
+//! 2024-06-01T14:03:09.871Z [vhf] tx N1CALL>APRS,N0CALL-10*:>Hello
+//! ```
+//!
+//! Timestamps count on from when the capture started with a monotonic
+//! clock, so they never run backwards when the system clock is stepped.
+//! Captures are started and stopped from the control socket.
+
+use crate::aprs::AprsPacket;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Rx,
+    Tx,
+}
+
+impl Direction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Rx => "rx",
+            Direction::Tx => "tx",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "rx" => Some(Direction::Rx),
+            "tx" => Some(Direction::Tx),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CaptureStatus {
+    pub file: PathBuf,
+    pub started: DateTime<Utc>,
+    pub frames: u64,
+}
+
+struct Capture {
+    out: File,
+    status: CaptureStatus,
+    clock: Instant,
+}
+
+lazy_static! {
+    static ref CAPTURE: Mutex<Option<Capture>> = Mutex::new(None);
+}
+
+// Checked before taking the lock, so ports pay nothing while no capture runs
+static CAPTURING: AtomicBool = AtomicBool::new(false);
+
+/// Start capturing to `path`, appending if it exists. Any capture already
+/// running is stopped first.
+pub fn start(path: &Path) -> Result<CaptureStatus> {
+    let mut out = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| anyhow!("Failed to open {}: {}", path.display(), e))?;
+    let started = Utc::now();
+    writeln!(
+        out,
+        "# aprstx {} capture started {}",
+        env!("CARGO_PKG_VERSION"),
+        started.to_rfc3339()
+    )?;
+
+    let status = CaptureStatus {
+        file: path.to_path_buf(),
+        started,
+        frames: 0,
+    };
+    let mut capture = CAPTURE.lock().unwrap();
+    if let Some(previous) = capture.take() {
+        info!("Stopped capture to {}", previous.status.file.display());
+    }
+    *capture = Some(Capture {
+        out,
+        status: status.clone(),
+        clock: Instant::now(),
+    });
+    CAPTURING.store(true, Ordering::Relaxed);
+    info!("Capturing traffic to {}", path.display());
+    Ok(status)
+}
+
+/// Stop capturing, returning what the capture wrote
+pub fn stop() -> Option<CaptureStatus> {
+    let mut capture = CAPTURE.lock().unwrap();
+    CAPTURING.store(false, Ordering::Relaxed);
+    let stopped = capture.take()?;
+    info!(
+        "Stopped capture to {} after {} frames",
+        stopped.status.file.display(),
+        stopped.status.frames
+    );
+    Some(stopped.status)
+}
+
+pub fn status() -> Option<CaptureStatus> {
+    CAPTURE
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|capture| capture.status.clone())
+}
+
+/// Note a packet heard on or sent to an interface, if capturing
+pub fn record(interface: &str, direction: Direction, packet: &AprsPacket) {
+    if !CAPTURING.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut guard = CAPTURE.lock().unwrap();
+    let Some(capture) = guard.as_mut() else {
+        return;
+    };
+
+    let time = capture.status.started
+        + chrono::Duration::from_std(capture.clock.elapsed()).unwrap_or_default();
+    let line = capture_line(time, interface, direction, packet);
+    if let Err(e) = capture.out.write_all(line.as_bytes()) {
+        warn!(
+            "Stopping capture to {}: {}",
+            capture.status.file.display(),
+            e
+        );
+        *guard = None;
+        CAPTURING.store(false, Ordering::Relaxed);
+        return;
+    }
+    capture.status.frames += 1;
+}
+
+fn capture_line(
+    time: DateTime<Utc>,
+    interface: &str,
+    direction: Direction,
+    packet: &AprsPacket,
+) -> String {
+    format!(
+        "{} [{}] {} {}\n",
+        time.format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+        interface,
+        direction.as_str(),
+        packet
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aprs::parse_packet;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_capture_line() {
+        let packet = parse_packet("N1CALL>APRS,WIDE1-1:>Hello").unwrap();
+        let time = Utc.with_ymd_and_hms(2024, 6, 1, 14, 3, 9).unwrap()
+            + chrono::Duration::milliseconds(120);
+        assert_eq!(
+            capture_line(time, "vhf", Direction::Rx, &packet),
+            "2024-06-01T14:03:09.120Z [vhf] rx N1CALL>APRS,WIDE1-1:>Hello\n"
+        );
+    }
+
+    #[test]
+    fn test_capture() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("capture.log");
+        let packet = parse_packet("N1CALL>APRS:>Hello").unwrap();
+
+        // Nothing is written until a capture starts. Other tests' ports
+        // may be captured too, so only this test's interfaces are checked.
+        record("capture-a", Direction::Rx, &packet);
+        start(&path).unwrap();
+        record("capture-a", Direction::Rx, &packet);
+        record("capture-b", Direction::Tx, &packet);
+        assert!(stop().unwrap().frames >= 2);
+        record("capture-a", Direction::Rx, &packet);
+        assert!(status().is_none());
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert!(written.starts_with("# aprstx"));
+        let lines: Vec<&str> = written
+            .lines()
+            .filter(|l| l.contains("[capture-"))
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(" [capture-a] rx N1CALL>APRS:>Hello"));
+        assert!(lines[1].ends_with(" [capture-b] tx N1CALL>APRS:>Hello"));
+    }
+}
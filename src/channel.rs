@@ -0,0 +1,223 @@
+//! Sending into the daemon's bounded channels.
+//!
+//! Every channel between tasks is bounded. A sender picks an [`Overflow`]
+//! policy for what happens when the receiver falls behind, and every packet
+//! that doesn't make it is counted against the channel it was meant for so
+//! the drops show up in the status reply and telemetry.
+
+use crate::router::RoutedPacket;
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+/// Packets into the router from ports, APRS-IS and local services
+pub const ROUTER: &str = "router";
+/// Packets from the router to the digipeater
+pub const DIGIPEATER: &str = "digipeater";
+/// Messages from the router to the message handler
+pub const MESSAGES: &str = "messages";
+/// Packets from the router to the APRS-IS connection
+pub const APRS_IS: &str = "aprs-is";
+
+/// Packets held for the APRS-IS connection before the oldest are lost
+pub const APRS_IS_QUEUE_LEN: usize = 100;
+
+lazy_static! {
+    static ref DROPS: Mutex<HashMap<String, u64>> = Mutex::new(HashMap::new());
+    /// Packets the router has handed to the APRS-IS connection that it
+    /// hasn't written yet
+    pub static ref APRS_IS_QUEUE: QueueGauge = QueueGauge::new(APRS_IS_QUEUE_LEN);
+}
+
+/// What a sender does when the channel is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Wait for room. Used for traffic that must not be lost, like messages
+    /// and acks, and to push back on a receiving port.
+    Block,
+    /// Drop the packet and count it. Used for traffic that is regenerated
+    /// anyway, like beacons and telemetry; once queued for a port, the TX
+    /// queue sheds the oldest of those first.
+    Drop,
+}
+
+/// Send a packet, dropping and counting it if it can't be delivered.
+/// Returns whether the packet was queued.
+pub async fn send(
+    tx: &mpsc::Sender<RoutedPacket>,
+    routed: RoutedPacket,
+    channel: &str,
+    overflow: Overflow,
+) -> bool {
+    let result = match overflow {
+        Overflow::Block => tx.send(routed).await.map_err(|e| TrySendError::Closed(e.0)),
+        Overflow::Drop => tx.try_send(routed),
+    };
+    match result {
+        Ok(()) => true,
+        Err(TrySendError::Full(routed)) => {
+            debug!("Channel {} full, dropping: {}", channel, routed.packet);
+            record_drops(channel, 1);
+            false
+        }
+        Err(TrySendError::Closed(routed)) => {
+            warn!("Channel {} closed, dropping: {}", channel, routed.packet);
+            record_drops(channel, 1);
+            false
+        }
+    }
+}
+
+/// Count packets lost on a channel outside of [`send`], such as a lagging
+/// broadcast receiver.
+pub fn record_drops(channel: &str, count: u64) {
+    *DROPS
+        .lock()
+        .unwrap()
+        .entry(channel.to_string())
+        .or_default() += count;
+}
+
+/// Dropped packets per channel, sorted by channel name
+pub fn drop_counts() -> Vec<(String, u64)> {
+    let mut counts: Vec<_> = DROPS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, count)| (name.clone(), *count))
+        .collect();
+    counts.sort();
+    counts
+}
+
+/// Dropped packets across all channels
+pub fn total_drops() -> u64 {
+    DROPS.lock().unwrap().values().sum()
+}
+
+/// Follows what's waiting in a channel whose contents can't be looked at,
+/// by having the sender and receiver report each packet.
+#[derive(Debug)]
+pub struct QueueGauge {
+    pending: Mutex<VecDeque<DateTime<Utc>>>, // When each waiting packet was received
+    capacity: usize,
+}
+
+impl QueueGauge {
+    pub fn new(capacity: usize) -> Self {
+        QueueGauge {
+            pending: Mutex::new(VecDeque::new()),
+            capacity,
+        }
+    }
+
+    /// A packet went into the channel; past capacity the oldest is lost
+    pub fn push(&self, received: DateTime<Utc>) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.push_back(received);
+        while pending.len() > self.capacity {
+            pending.pop_front();
+        }
+    }
+
+    /// Packets came out of the channel, or were skipped over
+    pub fn pop(&self, count: u64) {
+        let mut pending = self.pending.lock().unwrap();
+        let count = (count as usize).min(pending.len());
+        pending.drain(..count);
+    }
+
+    /// The receiver started over with an empty channel
+    pub fn clear(&self) {
+        self.pending.lock().unwrap().clear();
+    }
+
+    pub fn depth(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    /// How long the longest-waiting packet has been around
+    pub fn oldest_age(&self, now: DateTime<Utc>) -> Option<chrono::Duration> {
+        oldest_age(self.pending.lock().unwrap().iter().copied(), now)
+    }
+}
+
+/// How long the longest-waiting of some queued packets has been around,
+/// given when each was received
+pub fn oldest_age(
+    received: impl IntoIterator<Item = DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> Option<chrono::Duration> {
+    received
+        .into_iter()
+        .min()
+        .map(|oldest| now.signed_duration_since(oldest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aprs::{AprsPacket, CallSign};
+    use crate::router::PacketSource;
+
+    fn routed() -> RoutedPacket {
+        RoutedPacket {
+            packet: AprsPacket::new(
+                CallSign::new("N0CALL", 0),
+                CallSign::new("APRS", 0),
+                ">test".to_string(),
+            ),
+            source: PacketSource::Internal,
+        }
+    }
+
+    fn drops(channel: &str) -> u64 {
+        drop_counts()
+            .into_iter()
+            .find(|(name, _)| name == channel)
+            .map_or(0, |(_, count)| count)
+    }
+
+    #[tokio::test]
+    async fn test_drop_counts_full_channel() {
+        let (tx, mut rx) = mpsc::channel(1);
+        assert!(send(&tx, routed(), "test-full", Overflow::Drop).await);
+        assert!(!send(&tx, routed(), "test-full", Overflow::Drop).await);
+        assert_eq!(drops("test-full"), 1);
+
+        // Blocking waits for room instead of dropping
+        rx.recv().await.unwrap();
+        assert!(send(&tx, routed(), "test-full", Overflow::Block).await);
+        assert_eq!(drops("test-full"), 1);
+    }
+
+    #[test]
+    fn test_queue_gauge() {
+        let gauge = QueueGauge::new(2);
+        let now = Utc::now();
+        gauge.push(now - chrono::Duration::seconds(30));
+        gauge.push(now - chrono::Duration::seconds(20));
+        gauge.push(now - chrono::Duration::seconds(10));
+
+        assert_eq!(gauge.depth(), 2);
+        assert_eq!(gauge.oldest_age(now), Some(chrono::Duration::seconds(20)));
+
+        gauge.pop(1);
+        assert_eq!(gauge.oldest_age(now), Some(chrono::Duration::seconds(10)));
+        gauge.pop(5);
+        assert_eq!(gauge.depth(), 0);
+        assert_eq!(gauge.oldest_age(now), None);
+    }
+
+    #[tokio::test]
+    async fn test_drop_counts_closed_channel() {
+        let (tx, rx) = mpsc::channel(1);
+        drop(rx);
+        assert!(!send(&tx, routed(), "test-closed", Overflow::Block).await);
+        assert_eq!(drops("test-closed"), 1);
+        assert!(total_drops() >= 1);
+    }
+}
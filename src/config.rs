@@ -1,92 +1,521 @@
-use anyhow::Result;
+use crate::alert::AlertConfig;
+use crate::aprs::packet::DataType;
+use crate::aprs::Charset;
+use crate::filter::APRS_IS_PORT;
+use crate::proxy::Proxy;
+use crate::router::{TX_FROM_DIGIPEATER, TX_FROM_LOCAL};
+use crate::units;
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+/// The commented example configuration, with every option and its default
+pub const EXAMPLE_CONFIG: &str = include_str!("../aprstx.conf.example");
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     pub mycall: String,
+    #[serde(default)]
     pub serial_ports: Vec<SerialPortConfig>,
+    #[serde(default)]
+    pub groups: Vec<GroupConfig>,
     pub aprs_is: Option<AprsIsConfig>,
+    #[serde(default)]
     pub digipeater: DigipeaterConfig,
+    #[serde(default)]
     pub telemetry: TelemetryConfig,
+    #[serde(default)]
     pub filters: Vec<FilterConfig>,
     pub gps: Option<GpsConfig>,
     pub beacon: Option<BeaconConfig>,
+    #[serde(default)]
+    pub messaging: MessagingConfig,
+    pub message_spool: Option<MessageSpoolConfig>,
+    pub control: Option<ControlConfig>,
+    pub rate_limit: Option<RateLimitConfig>,
+    #[serde(default = "default_dedup_window", deserialize_with = "units::seconds")]
+    pub dedup_window: u32, // Seconds an identical packet counts as a duplicate
+    #[serde(default)]
+    pub plugins: Vec<PluginConfig>,
+    pub mqtt: Option<MqttConfig>, // Used when built with the mqtt feature
+    pub nats: Option<NatsConfig>, // Used when built with the nats feature
+    pub postgres: Option<PostgresConfig>, // Used when built with the postgres feature
+    pub sqlite: Option<SqliteConfig>, // Used when built with the sqlite feature
+    pub websocket: Option<WebSocketConfig>,
+    pub is_server: Option<IsServerConfig>,
+    pub metrics: Option<MetricsConfig>,
+    pub udp_output: Option<UdpOutputConfig>,
+    pub waypoints: Option<WaypointConfig>,
+    pub track_log: Option<TrackLogConfig>,
+    pub igate_beacon: Option<IgateBeaconConfig>,
+    pub coverage: Option<CoverageConfig>,
+    pub objects: Option<ObjectsConfig>,
+    pub heard: Option<HeardConfig>,
+    pub stale: Option<StaleConfig>,
+    pub rig: Option<RigConfig>,
+    pub status_beacon: Option<StatusBeaconConfig>,
+    pub station_id: Option<StationIdConfig>,
+}
+
+fn default_dedup_window() -> u32 {
+    30
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct SerialPortConfig {
     pub name: String,
     pub device: String,
+    #[serde(default = "default_baud_rate")]
     pub baud_rate: u32,
+    #[serde(default)]
     pub protocol: SerialProtocol,
+    #[serde(default = "default_enable")]
     pub tx_enable: bool,
+    #[serde(default = "default_enable")]
     pub rx_enable: bool,
+    #[serde(default)]
+    pub raw_tap: Option<String>, // File to append non-APRS frames to (KISS only)
+    #[serde(default)]
+    pub inbound_filters: Vec<String>, // Filter names applied to packets heard here
+    #[serde(default)]
+    pub outbound_filters: Vec<String>, // Filter names applied before transmitting here
+    #[serde(default = "default_tx_queue")]
+    pub tx_queue: usize, // Packets held waiting to transmit before shedding
+    #[serde(default)]
+    pub tx_overflow: OverflowPolicy,
+    #[serde(default)]
+    pub tx_per_minute: Option<u32>, // Most packets to transmit in any minute
+    #[serde(default)]
+    pub duty_cycle: Option<f64>, // Percent of each minute we may transmit
+    #[serde(default = "default_air_baud")]
+    pub air_baud: u32, // Radio bit rate, for airtime estimates
+    #[serde(default, deserialize_with = "units::millis")]
+    pub digipeat_jitter: u32, // Most milliseconds of random delay before digipeated and gated packets
+    #[serde(default, deserialize_with = "units::millis")]
+    pub beacon_jitter: u32, // Most milliseconds of random delay before our own packets
+    #[serde(default)]
+    pub dcd: DcdSource,
+    #[serde(default)]
+    pub persist: Option<u8>, // Chance in 256 (less one) of sending in each clear slot; unset always sends
+    #[serde(default = "default_slot_time", deserialize_with = "units::millis")]
+    pub slot_time: u32, // Milliseconds between tries for the channel
+    #[serde(default)]
+    pub tx_from: Option<Vec<String>>, // Origins allowed to transmit here; unset allows all
+    #[serde(default)]
+    pub loopback: bool, // Digipeat packets back out the port they were heard on
+    #[serde(default)]
+    pub digipeat_to: Option<Vec<String>>, // Ports digipeats of packets heard here go out; unset lets tx_from decide
+    #[serde(default = "default_digipeat")]
+    pub digipeat: bool, // Offer packets heard here to the digipeater
+    #[serde(default)]
+    pub max_hops: Option<u8>, // Hops a packet heard here may have used; unset uses the digipeater's
+    #[serde(default)]
+    pub init: Vec<String>, // Commands sent to the TNC when the port opens, before any packets
+    #[serde(default)]
+    pub exit: Vec<String>, // Commands sent to the TNC on shutdown, e.g. to leave KISS mode
+    #[serde(default = "default_init_delay", deserialize_with = "units::millis")]
+    pub init_delay: u32, // Milliseconds to wait after each init or exit command
+    #[serde(default)]
+    pub charset: Charset, // How text is read and written on the air
 }
 
+fn default_baud_rate() -> u32 {
+    9600
+}
+
+fn default_enable() -> bool {
+    true
+}
+
+fn default_digipeat() -> bool {
+    true
+}
+
+/// A name for several interfaces, usable wherever a port's name is in
+/// `tx_from`, `digipeat_to` and beacon `ports`.
 #[derive(Debug, Clone, Deserialize, Serialize)]
-#[serde(rename_all = "lowercase")]
+#[serde(deny_unknown_fields)]
+pub struct GroupConfig {
+    pub name: String,
+    pub ports: Vec<String>, // Serial port names, or "aprs-is"
+    #[serde(default)]
+    pub inbound_filters: Vec<String>, // Run on each member before its own inbound_filters
+    #[serde(default)]
+    pub outbound_filters: Vec<String>, // Run on each member before its own outbound_filters
+}
+
+/// What to drop when a port's transmit queue is full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    #[default]
+    ShedLowest, // Oldest packet of the lowest priority
+    DropOldest, // Oldest packet of any priority
+    DropNewest, // The packet that didn't fit
+}
+
+/// How a port tells the channel is busy before transmitting
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DcdSource {
+    #[default]
+    Off, // Don't check, just send
+    Rx,      // Busy while the TNC is handing us frames
+    Carrier, // The serial port's carrier detect line, wired to the radio's squelch
+}
+
+fn default_slot_time() -> u32 {
+    100
+}
+
+fn default_init_delay() -> u32 {
+    500
+}
+
+fn default_air_baud() -> u32 {
+    1200
+}
+
+fn default_tx_queue() -> usize {
+    50
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum SerialProtocol {
+    #[default]
     Kiss,
     Tnc2,
+    Lora,     // LoRa APRS text packets, one per line
+    LoraKiss, // LoRa APRS text packets in KISS frames
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct AprsIsConfig {
+    #[serde(default = "default_aprs_is_server")]
     pub server: String,
+    #[serde(default = "default_aprs_is_port")]
     pub port: u16,
-    pub callsign: String,
-    pub passcode: String,
+    #[serde(default)]
+    pub addresses: Vec<IpAddr>, // Connect to these rather than looking up the server
+    #[serde(default = "default_dns_timeout", deserialize_with = "units::seconds")]
+    pub dns_timeout: u32, // Seconds to wait for the server's name to resolve
+    pub proxy: Option<Proxy>, // socks5://host:port or http://host:port
+    #[serde(default)]
+    pub callsign: String, // Defaults to mycall
+    #[serde(default = "default_passcode")]
+    pub passcode: String, // -1 logs in receive-only
     pub filter: Option<String>,
+    #[serde(default)]
     pub tx_enable: bool,
+    #[serde(default = "default_enable")]
     pub rx_enable: bool,
+    #[serde(default)]
+    pub inbound_filters: Vec<String>, // Filter names applied to packets from APRS-IS
+    #[serde(default)]
+    pub outbound_filters: Vec<String>, // Filter names applied before sending to APRS-IS
+    #[serde(default, deserialize_with = "units::opt_minutes")]
+    pub local_window: Option<u32>, // Minutes; when set only messages to stations heard on RF this recently go to RF
+    #[serde(default)]
+    pub full_feed: bool, // Tune for the full feed (port 10152): big reads, parallel parsing, quiet logs
+    #[serde(default)]
+    pub rf_budget: Option<RfBudgetConfig>,
+    #[serde(default)]
+    pub spool: Option<IsSpoolConfig>,
+    #[serde(default)]
+    pub follow_gps: Option<FollowGpsConfig>,
 }
 
+/// A range filter added to the APRS-IS login that follows the GPS, for a
+/// mobile igate.
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FollowGpsConfig {
+    #[serde(default)]
+    pub kind: FollowFilter,
+    #[serde(default = "default_follow_range", deserialize_with = "units::km")]
+    pub range: f64, // km
+    #[serde(default = "default_follow_move", deserialize_with = "units::km")]
+    pub min_move: f64, // km moved before an r/ filter is centred again
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+pub enum FollowFilter {
+    /// r/lat/lon/range around our GPS position, sent again as we move
+    #[default]
+    #[serde(rename = "r")]
+    Range,
+    /// m/range, which the server centres on our own position reports
+    #[serde(rename = "m")]
+    MyRange,
+}
+
+fn default_follow_range() -> f64 {
+    50.0
+}
+
+fn default_follow_move() -> f64 {
+    5.0
+}
+
+fn default_dns_timeout() -> u32 {
+    10
+}
+
+/// Where packets for APRS-IS wait while the connection is down.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct IsSpoolConfig {
+    pub file: String,
+    #[serde(default = "default_spool_packets")]
+    pub max_packets: usize,
+    #[serde(default = "default_spool_age", deserialize_with = "units::seconds")]
+    pub max_age: u32, // Seconds; older packets aren't uploaded
+}
+
+fn default_spool_packets() -> usize {
+    1000
+}
+
+fn default_spool_age() -> u32 {
+    300
+}
+
+/// Most packets to gate from APRS-IS to RF, so a loose filter can't fill
+/// the channel. Unset caps don't apply.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RfBudgetConfig {
+    pub messages_per_minute: Option<u32>,
+    pub messages_per_hour: Option<u32>,
+    pub positions_per_minute: Option<u32>, // Everything that isn't a message
+    pub positions_per_hour: Option<u32>,
+}
+
+fn default_aprs_is_server() -> String {
+    "rotate.aprs2.net".to_string()
+}
+
+fn default_aprs_is_port() -> u16 {
+    14580
+}
+
+fn default_passcode() -> String {
+    "-1".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct DigipeaterConfig {
     pub enabled: bool,
-    pub mycall: String,
+    pub mycall: String, // Defaults to mycall
     pub aliases: Vec<String>,
-    pub viscous_delay: u32,
+    #[serde(deserialize_with = "units::seconds")]
+    pub viscous_delay: u32, // seconds
     pub max_hops: u8,
 }
 
+impl Default for DigipeaterConfig {
+    fn default() -> Self {
+        DigipeaterConfig {
+            enabled: false,
+            mycall: String::new(),
+            aliases: vec!["WIDE1-1".to_string()],
+            viscous_delay: 5,
+            max_hops: 3,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct TelemetryConfig {
     pub enabled: bool,
-    pub interval: u32,
+    #[serde(deserialize_with = "units::seconds")]
+    pub interval: u32, // seconds
     pub comment: String,
+    pub channels: Vec<TelemetryChannel>, // Up to 5 analog channels, in order
+    pub deltas: Vec<TelemetryChannel>,   // Counters reported as the change since the last report
+    pub wide: bool,                      // Write values in full instead of 8 bits
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        TelemetryConfig {
+            enabled: false,
+            interval: 1200,
+            comment: "aprstx daemon telemetry".to_string(),
+            channels: default_telemetry_channels(),
+            deltas: Vec::new(),
+            wide: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TelemetryChannel {
+    RxPackets,
+    TxPackets,
+    Digipeated,
+    RfToIs,
+    IsToRf,
+    ClockDrift,   // Seconds between system clock and GPS time
+    TripDistance, // Kilometres travelled since startup
+    MaxSpeed,     // Highest speed seen, knots
+    MovingTime,   // Minutes spent moving
+    Satellites,   // Satellites used in the current fix
+    Hdop,         // Tenths of HDOP, 0 when unknown
+    FixType,      // 0 no fix, 2 for 2D, 3 for 3D
+    NonAprs,      // Connected-mode and other non-APRS frames heard
+    Dropped,      // Packets dropped on full or closed internal channels
+    IsRtt,        // APRS-IS round trip in tens of milliseconds, 0 when unknown
+    IsReconnects, // Times the APRS-IS connection was lost and made again
+    SerialErrors, // Read, write and framing errors across serial ports
+}
+
+fn default_telemetry_channels() -> Vec<TelemetryChannel> {
+    vec![
+        TelemetryChannel::RxPackets,
+        TelemetryChannel::TxPackets,
+        TelemetryChannel::Digipeated,
+        TelemetryChannel::RfToIs,
+        TelemetryChannel::IsToRf,
+    ]
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct FilterConfig {
     pub name: String,
     pub action: FilterAction,
-    pub pattern: String,
+    #[serde(default)]
+    pub pattern: String, // Regex over the packet text, empty matches everything
+    #[serde(default)]
+    pub direction: Option<FilterDirection>, // Unset applies to every packet routed
+    #[serde(default)]
+    pub types: Vec<DataType>, // Match any of these data types
+    #[serde(default)]
+    pub symbols: Vec<String>, // "_" for any table, or "/_" for table and symbol
+    #[serde(default)]
+    pub addressees: Vec<String>, // Message addressees, "BLN*" matches a prefix
+    #[serde(default, deserialize_with = "units::opt_km")]
+    pub range: Option<f64>, // km from our own position to the packet's position
+    #[serde(default)]
+    pub callsigns: Vec<String>, // Source, destination or addressee; '*' and '?' wildcards
+    #[serde(default)]
+    pub tagged: Vec<String>, // Match packets carrying any of these tags
+    #[serde(default)]
+    pub label: Option<String>, // Counter or tag name for count/tag, default the filter name
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum FilterAction {
+    #[default]
     Drop,
     Pass,
+    Log,   // Log the packet and carry on down the chain
+    Count, // Bump a counter shown in the status API and carry on
+    Tag,   // Attach a tag for later chains and the router, and carry on
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterDirection {
+    RfToIs,   // Gating RF packets to APRS-IS
+    IsToRf,   // Gating APRS-IS packets to RF
+    Digipeat, // Packets offered to the digipeater
+    Log,      // Packets written to the traffic log
+    Port,     // Only where a port's inbound/outbound list names it
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct GpsConfig {
-    #[serde(rename = "type")]
+    #[serde(rename = "type", default = "default_gps_type")]
     pub gps_type: String, // "none", "serial", "gpsd", "fixed"
     pub device: Option<String>,
     pub baud_rate: Option<u32>,
     pub host: Option<String>,
     pub port: Option<u16>,
     pub position: Option<String>, // for fixed position: "lat,lon[,alt]"
+    #[serde(
+        default = "default_gps_stale_after",
+        deserialize_with = "units::seconds"
+    )]
+    pub stale_after: u32, // Seconds before a position is considered stale
+    #[serde(default)]
+    pub failover: Vec<GpsSourceConfig>, // Lower-priority sources, tried in order
+    pub survey: Option<SurveyConfig>,
+}
+
+/// Average the live position for a while, then use the result as a fixed
+/// position. The result is saved so the survey only runs once.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SurveyConfig {
+    #[serde(
+        default = "default_survey_duration",
+        deserialize_with = "units::seconds"
+    )]
+    pub duration: u32, // Seconds of fixes to average
+    pub file: String, // Where the surveyed "lat,lon[,alt]" is kept
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct GpsSourceConfig {
+    #[serde(rename = "type")]
+    pub gps_type: String,
+    pub device: Option<String>,
+    pub baud_rate: Option<u32>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub position: Option<String>,
+}
+
+impl GpsConfig {
+    /// All configured sources, primary first.
+    pub fn sources(&self) -> Vec<GpsSourceConfig> {
+        let primary = GpsSourceConfig {
+            gps_type: self.gps_type.clone(),
+            device: self.device.clone(),
+            baud_rate: self.baud_rate,
+            host: self.host.clone(),
+            port: self.port,
+            position: self.position.clone(),
+        };
+
+        std::iter::once(primary)
+            .chain(self.failover.iter().cloned())
+            .collect()
+    }
+}
+
+fn default_gps_type() -> String {
+    "none".to_string()
+}
+
+fn default_gps_stale_after() -> u32 {
+    30
+}
+
+fn default_survey_duration() -> u32 {
+    3600
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct BeaconConfig {
     pub enabled: bool,
-    pub callsign: String,
+    pub callsign: String, // Defaults to mycall
+    #[serde(deserialize_with = "units::seconds")]
     pub interval: u32, // seconds
     pub path: String,
     pub symbol_table: char,
@@ -94,20 +523,625 @@ pub struct BeaconConfig {
     pub comment: String,
     pub timestamp: bool,
     pub smart_beacon: SmartBeaconConfig,
+    pub geofences: Vec<GeofenceConfig>,
+    pub trip_comment: bool, // Append trip distance and top speed to the comment
+    pub speed_paths: Vec<SpeedPathConfig>,
+    pub frequency: bool, // Start the comment with the rig's frequency, e.g. 145.500MHz
+    pub ports: Vec<String>, // Ports or groups to beacon on; empty sends wherever tx_from allows
+}
+
+impl Default for BeaconConfig {
+    fn default() -> Self {
+        BeaconConfig {
+            enabled: true,
+            callsign: String::new(),
+            interval: 600,
+            path: "WIDE1-1,WIDE2-1".to_string(),
+            symbol_table: '/',
+            symbol: '>',
+            comment: String::new(),
+            timestamp: false,
+            smart_beacon: SmartBeaconConfig::default(),
+            geofences: Vec::new(),
+            trip_comment: false,
+            speed_paths: Vec::new(),
+            frequency: false,
+            ports: Vec::new(),
+        }
+    }
+}
+
+/// A shorter path to beacon with above a speed.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpeedPathConfig {
+    #[serde(deserialize_with = "units::knots")]
+    pub above: f32, // knots
+    pub path: String, // Empty for direct only
+}
+
+/// A region with beacon overrides. Either `center` and `radius` (a circle)
+/// or `polygon` must be given; unset overrides keep the beacon defaults.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct GeofenceConfig {
+    pub name: String,
+    pub center: Option<[f64; 2]>, // [lat, lon]
+    #[serde(default, deserialize_with = "units::opt_km")]
+    pub radius: Option<f64>, // km
+    #[serde(default)]
+    pub polygon: Vec<[f64; 2]>, // [[lat, lon], ...]
+    pub path: Option<String>,
+    #[serde(default, deserialize_with = "units::opt_seconds")]
+    pub interval: Option<u32>,
+    pub comment: Option<String>,
+    pub symbol_table: Option<char>,
+    pub symbol: Option<char>,
+    #[serde(default)]
+    pub suppress: bool, // Don't beacon at all inside this region
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct SmartBeaconConfig {
     pub enabled: bool,
-    pub check_interval: u32,      // How often to check position (seconds)
-    pub min_interval: u32,        // Minimum time between beacons
+    #[serde(deserialize_with = "units::seconds")]
+    pub check_interval: u32, // How often to check position (seconds)
+    #[serde(deserialize_with = "units::seconds")]
+    pub min_interval: u32, // Minimum time between beacons
+    #[serde(deserialize_with = "units::seconds")]
     pub stationary_interval: u32, // Interval when not moving
-    pub low_speed: u32,           // Speed threshold (knots)
-    pub low_speed_interval: u32,  // Interval at low speed
-    pub high_speed: u32,          // High speed threshold
+    #[serde(deserialize_with = "units::knots")]
+    pub low_speed: u32, // Speed threshold (knots)
+    #[serde(deserialize_with = "units::seconds")]
+    pub low_speed_interval: u32, // Interval at low speed
+    #[serde(deserialize_with = "units::knots")]
+    pub high_speed: u32, // High speed threshold
+    #[serde(deserialize_with = "units::seconds")]
     pub high_speed_interval: u32, // Interval at high speed
-    pub turn_angle: u32,          // Degrees to trigger beacon
-    pub turn_speed: u32,          // Minimum speed for turn detection
+    pub turn_angle: u32, // Degrees to trigger beacon
+    #[serde(deserialize_with = "units::knots")]
+    pub turn_speed: u32, // Minimum speed for turn detection
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MessageSpoolConfig {
+    pub enabled: bool,
+    #[serde(deserialize_with = "units::hours")]
+    pub heard_window: u32, // Hours since a station was last heard on RF
+    #[serde(deserialize_with = "units::seconds")]
+    pub retry_interval: u32, // Seconds between retransmissions
+    #[serde(deserialize_with = "units::seconds")]
+    pub hold_time: u32, // Seconds to keep retrying before giving up
+}
+
+/// How messages we send are retried until acked.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MessagingConfig {
+    #[serde(deserialize_with = "units::seconds")]
+    pub retry_interval: u32, // Seconds before the first retry, doubling after each
+    #[serde(deserialize_with = "units::seconds")]
+    pub max_retry_interval: u32, // Longest wait between two tries
+    pub max_attempts: u8, // Tries, the first send included
+    #[serde(deserialize_with = "units::seconds")]
+    pub expiry: u32, // Seconds from the first send to give up, whatever tries are left
+    pub groups: Vec<String>, // Group addressees besides ALL, CQ and QST
+    pub group_alert: Option<AlertConfig>, // Where to pass on messages to groups
+}
+
+impl Default for MessagingConfig {
+    fn default() -> Self {
+        MessagingConfig {
+            retry_interval: 30,
+            max_retry_interval: 600,
+            max_attempts: 5,
+            expiry: 1800,
+            groups: Vec::new(),
+            group_alert: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ControlConfig {
+    pub socket: String, // Path of the Unix control socket
+    #[serde(default = "default_control_mode")]
+    pub mode: u32, // Permissions of the socket, as 0o660
+    #[serde(default)]
+    pub capture_dir: Option<String>, // Where "capture start" may write
+}
+
+fn default_control_mode() -> u32 {
+    0o660
+}
+
+/// MQTT broker to publish heard packets to.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    pub server: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default = "default_mqtt_topic_prefix")]
+    pub topic_prefix: String, // Packets go to <prefix>/rx/<port>/<source call>
+    #[serde(default = "default_mqtt_keepalive")]
+    pub keepalive: u16, // Seconds
+    #[serde(default)]
+    pub tx_enable: bool, // Send JSON packets published on <prefix>/tx
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_client_id() -> String {
+    "aprstx".to_string()
+}
+
+fn default_mqtt_topic_prefix() -> String {
+    "aprs".to_string()
+}
+
+fn default_mqtt_keepalive() -> u16 {
+    60
+}
+
+/// NATS server to stream heard and gated packets to.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct NatsConfig {
+    pub server: String,
+    #[serde(default = "default_nats_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(default = "default_nats_subject_prefix")]
+    pub subject_prefix: String, // <prefix>.rx.<port> and <prefix>.is.tx
+    #[serde(default = "default_nats_buffer")]
+    pub buffer: usize, // Packets held while the server is unreachable
+}
+
+fn default_nats_port() -> u16 {
+    4222
+}
+
+fn default_nats_subject_prefix() -> String {
+    "aprs".to_string()
+}
+
+fn default_nats_buffer() -> usize {
+    1000
+}
+
+/// PostgreSQL database to record heard packets and positions in.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PostgresConfig {
+    pub host: String,
+    #[serde(default = "default_postgres_port")]
+    pub port: u16,
+    pub user: String,
+    #[serde(default)]
+    pub password: Option<String>,
+    pub database: String,
+}
+
+fn default_postgres_port() -> u16 {
+    5432
+}
+
+/// SQLite file to record heard packets and positions in.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SqliteConfig {
+    pub file: String, // Created if missing
+}
+
+/// WebSocket endpoint streaming heard packets as JSON.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebSocketConfig {
+    pub listen: String, // Address and port, e.g. "0.0.0.0:8073"
+}
+
+/// Where to serve Prometheus metrics.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub listen: String, // Address and port, e.g. "127.0.0.1:9273"
+}
+
+/// A local APRS-IS server for client programs to share the station.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct IsServerConfig {
+    pub listen: String, // Address and port, e.g. "127.0.0.1:14580"
+    #[serde(default = "default_is_server_clients")]
+    pub max_clients: usize,
+    #[serde(default = "default_enable")]
+    pub rf: bool, // Transmit what clients send on RF, not just to APRS-IS
+}
+
+fn default_is_server_clients() -> usize {
+    10
+}
+
+/// Broadcast or multicast address to send heard packets to as TNC2 lines.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct UdpOutputConfig {
+    pub address: String, // e.g. "192.168.1.255:8001" or "239.1.2.3:8001"
+    #[serde(default)]
+    pub aprs_is: bool, // Send packets heard from APRS-IS too, not just RF
+    #[serde(default = "default_udp_ttl")]
+    pub ttl: u32, // Multicast hops
+}
+
+fn default_udp_ttl() -> u32 {
+    1
+}
+
+/// Recording our GPS track and those of listed stations, for export.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TrackLogConfig {
+    #[serde(
+        default = "default_track_interval",
+        deserialize_with = "units::seconds"
+    )]
+    pub interval: u32, // Seconds between points of our own track
+    #[serde(default = "default_track_move", deserialize_with = "units::km")]
+    pub min_move: f64, // Kilometres to move before our next point is kept
+    #[serde(default = "default_track_points")]
+    pub max_points: usize, // Points kept per track, the oldest dropped first
+    #[serde(default)]
+    pub stations: Vec<String>, // Others to record; a call without an SSID records every SSID
+}
+
+fn default_track_interval() -> u32 {
+    30
+}
+
+fn default_track_move() -> f64 {
+    0.02
+}
+
+fn default_track_points() -> usize {
+    10000
+}
+
+/// Where to send heard positions as NMEA waypoint sentences.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct WaypointConfig {
+    pub device: Option<String>, // Serial port of the radio or GPS, opened like a TNC's
+    #[serde(default = "default_waypoint_baud_rate")]
+    pub baud_rate: u32,
+    pub listen: Option<String>, // TCP address to serve the sentences on
+    #[serde(default)]
+    pub format: WaypointFormat,
+    #[serde(default)]
+    pub aprs_is: bool, // Plot stations heard from APRS-IS too, not just RF
+    #[serde(
+        default = "default_waypoint_interval",
+        deserialize_with = "units::seconds"
+    )]
+    pub interval: u32, // Seconds before sending a station's waypoint again
+}
+
+fn default_waypoint_baud_rate() -> u32 {
+    4800
+}
+
+fn default_waypoint_interval() -> u32 {
+    30
+}
+
+/// Which waypoint sentence to send
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WaypointFormat {
+    #[default]
+    Nmea, // $GPWPL, for GPS units
+    Kenwood, // $PKWDWPL, with course, speed, altitude and symbol
+}
+
+/// How often to announce our igate capabilities.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct IgateBeaconConfig {
+    #[serde(
+        default = "default_igate_beacon_interval",
+        deserialize_with = "units::seconds"
+    )]
+    pub interval: u32, // seconds
+    #[serde(default)]
+    pub rf: bool, // Transmit on RF as well as sending to APRS-IS
+}
+
+fn default_igate_beacon_interval() -> u32 {
+    1800
+}
+
+/// An object showing how far the station hears, from the stations it has
+/// heard direct.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CoverageConfig {
+    #[serde(default)]
+    pub name: Option<String>, // Object name, the base callsign and "RNG" if unset
+    #[serde(
+        default = "default_coverage_interval",
+        deserialize_with = "units::seconds"
+    )]
+    pub interval: u32, // seconds
+    #[serde(
+        default = "default_coverage_window",
+        deserialize_with = "units::minutes"
+    )]
+    pub window: u32, // Minutes a station heard direct counts towards the range
+    #[serde(default = "default_coverage_stations")]
+    pub min_stations: usize, // Stations with positions needed before sending
+    #[serde(default = "default_coverage_symbol_table")]
+    pub symbol_table: char,
+    #[serde(default = "default_coverage_symbol")]
+    pub symbol: char,
+    #[serde(default)]
+    pub rf: bool, // Transmit on RF as well as sending to APRS-IS
+}
+
+fn default_coverage_interval() -> u32 {
+    1800
+}
+
+fn default_coverage_window() -> u32 {
+    60
+}
+
+fn default_coverage_stations() -> usize {
+    3
+}
+
+fn default_coverage_symbol_table() -> char {
+    '/'
+}
+
+fn default_coverage_symbol() -> char {
+    '&'
+}
+
+/// Announcing the software and what this station does in a status packet.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct StatusBeaconConfig {
+    #[serde(
+        default = "default_status_beacon_interval",
+        deserialize_with = "units::seconds"
+    )]
+    pub interval: u32, // seconds
+    #[serde(default)]
+    pub rf: bool, // Transmit on RF as well as sending to APRS-IS
+    #[serde(default)]
+    pub comment: String, // Added after the capabilities
+    #[serde(default)]
+    pub grid: bool, // Start with our Maidenhead locator and the beacon symbol, from GPS
+}
+
+fn default_status_beacon_interval() -> u32 {
+    3600
+}
+
+/// Identifying the station on ports it has transmitted on, for rules that
+/// want unattended stations to identify every so often.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct StationIdConfig {
+    #[serde(
+        default = "default_station_id_interval",
+        deserialize_with = "units::seconds"
+    )]
+    pub interval: u32, // seconds
+    #[serde(default)]
+    pub text: Option<String>, // Status text, "DE <mycall>" if unset
+    #[serde(default)]
+    pub ports: Vec<String>, // Serial ports to identify on, all if empty
+}
+
+fn default_station_id_interval() -> u32 {
+    600
+}
+
+/// A file of objects and items to beacon in turn.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ObjectsConfig {
+    #[serde(default)]
+    pub file: Option<String>, // TOML file with [[objects]] entries, re-read when it changes
+    #[serde(default)]
+    pub callsign: Option<String>, // Defaults to mycall
+    #[serde(default = "default_objects_path")]
+    pub path: String,
+    #[serde(
+        default = "default_objects_interval",
+        deserialize_with = "units::seconds"
+    )]
+    pub interval: u32, // Seconds between sends of each object
+    #[serde(
+        default = "default_objects_spacing",
+        deserialize_with = "units::seconds"
+    )]
+    pub spacing: u32, // Seconds between any two object packets
+    #[serde(default)]
+    pub repeaters: Vec<RepeaterConfig>,
+}
+
+/// A repeater advertised as a frequency object, named for its output
+/// frequency with the tone, offset and range in the comment.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RepeaterConfig {
+    pub frequency: f64, // Output frequency in MHz
+    #[serde(default)]
+    pub suffix: String, // Up to two characters after the frequency in the name
+    pub position: [f64; 2], // [lat, lon]
+    #[serde(default)]
+    pub tone: Option<f32>, // CTCSS tone in Hz; unset for carrier access
+    #[serde(default)]
+    pub dcs: Option<u16>, // DCS code, instead of a tone
+    #[serde(default)]
+    pub offset: Option<i32>, // kHz, e.g. -600; unset for the band's standard offset
+    #[serde(default, deserialize_with = "units::opt_km")]
+    pub range: Option<f64>, // km
+    #[serde(default)]
+    pub comment: String,
+    #[serde(default, deserialize_with = "units::opt_seconds")]
+    pub interval: Option<u32>, // seconds; unset uses the objects interval
+}
+
+fn default_objects_path() -> String {
+    "WIDE2-1".to_string()
+}
+
+fn default_objects_interval() -> u32 {
+    600
+}
+
+fn default_objects_spacing() -> u32 {
+    30
+}
+
+/// Keeping the heard stations list.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeardConfig {
+    #[serde(default)]
+    pub file: Option<String>, // JSON file the list is saved to and restored from
+    #[serde(default = "default_heard_expire", deserialize_with = "units::hours")]
+    pub expire: u32, // Hours before an unheard station is forgotten
+    #[serde(
+        default = "default_heard_save_interval",
+        deserialize_with = "units::seconds"
+    )]
+    pub save_interval: u32, // Seconds between saves
+}
+
+impl Default for HeardConfig {
+    fn default() -> Self {
+        HeardConfig {
+            file: None,
+            expire: default_heard_expire(),
+            save_interval: default_heard_save_interval(),
+        }
+    }
+}
+
+fn default_heard_expire() -> u32 {
+    24
+}
+
+fn default_heard_save_interval() -> u32 {
+    300
+}
+
+/// A packet processor to load, by type name, with its own settings.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PluginConfig {
+    pub plugin: String,
+    #[serde(flatten)]
+    pub options: toml::Table, // Everything else in the entry, for the plugin to read
+}
+
+impl PluginConfig {
+    /// The plugin's settings as its own config struct
+    pub fn options<'de, T: Deserialize<'de>>(&self) -> Result<T> {
+        Ok(toml::Value::Table(self.options.clone()).try_into()?)
+    }
+}
+
+/// Token bucket applied per source callsign to packets from RF and APRS-IS.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    #[serde(default = "default_rate_per_minute")]
+    pub per_minute: f64, // Sustained packets per minute per source
+    #[serde(default = "default_rate_burst")]
+    pub burst: u32, // Packets a source may send back to back
+}
+
+/// Reading the radio's frequency from hamlib's rigctld.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RigConfig {
+    #[serde(default = "default_rig_host")]
+    pub host: String,
+    #[serde(default = "default_rig_port")]
+    pub port: u16,
+    #[serde(default = "default_rig_poll", deserialize_with = "units::seconds")]
+    pub poll_interval: u32, // Seconds between frequency reads
+}
+
+fn default_rig_host() -> String {
+    "localhost".to_string()
+}
+
+fn default_rig_port() -> u16 {
+    4532
+}
+
+fn default_rig_poll() -> u32 {
+    5
+}
+
+/// Refusing to pass on packets whose timestamps show they were delayed.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct StaleConfig {
+    #[serde(default = "default_stale_max_age", deserialize_with = "units::seconds")]
+    pub max_age: u32, // Seconds a reported time may trail the clock
+    #[serde(default = "default_stale_refuse")]
+    pub gate: bool, // Don't gate stale packets either way
+    #[serde(default = "default_stale_refuse")]
+    pub digipeat: bool, // Don't digipeat stale packets
+}
+
+fn default_stale_max_age() -> u32 {
+    3600
+}
+
+fn default_stale_refuse() -> bool {
+    true
+}
+
+fn default_rate_per_minute() -> f64 {
+    6.0
+}
+
+fn default_rate_burst() -> u32 {
+    10
+}
+
+impl Default for MessageSpoolConfig {
+    fn default() -> Self {
+        MessageSpoolConfig {
+            enabled: true,
+            heard_window: 2,
+            retry_interval: 300,
+            hold_time: 3600,
+        }
+    }
 }
 
 impl Default for SmartBeaconConfig {
@@ -128,32 +1162,269 @@ impl Default for SmartBeaconConfig {
 }
 
 impl Config {
+    /// Load a config file along with any files named by its `include`
+    /// list. Tables from included files are merged in and arrays such as
+    /// `filters` are appended to, in file name order; setting the same
+    /// value in two files is an error.
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
         let path = path.as_ref();
-        let contents = std::fs::read_to_string(path).map_err(|e| {
-            if e.kind() == std::io::ErrorKind::NotFound {
-                anyhow::anyhow!(
-                    "Configuration file not found: {}\n\
-                         Hint: Copy aprstx.conf.example to {} and edit it with your settings.\n\
-                         Or use --config to specify a different path.",
-                    path.display(),
-                    path.display()
-                )
-            } else {
-                anyhow::anyhow!("Failed to read config file {}: {}", path.display(), e)
-            }
-        })?;
-        let config: Config = toml::from_str(&contents).map_err(|e| {
-            anyhow::anyhow!(
-                "Failed to parse configuration file {}: {}\n\
-                     Hint: Check the TOML syntax. Common issues:\n\
-                     - Missing quotes around strings\n\
-                     - Incorrect array syntax (use [[section]] for arrays)\n\
-                     - Invalid data types for fields",
+        if !path.exists() {
+            return Err(anyhow!(
+                "Configuration file not found: {}\n\
+                     Hint: Copy aprstx.conf.example to {} and edit it with your settings.\n\
+                     Or use --config to specify a different path.",
                 path.display(),
-                e
+                path.display()
+            ));
+        }
+        let mut table = read_table(path)?;
+
+        // Which files each top-level section came from, for errors
+        let mut origins: HashMap<String, Vec<PathBuf>> = HashMap::new();
+        for key in table.keys() {
+            origins.insert(key.clone(), vec![path.to_path_buf()]);
+        }
+
+        if let Some(include) = table.remove("include") {
+            for file in include_files(path, include)? {
+                let included = read_table(&file)?;
+                if included.contains_key("include") {
+                    return Err(anyhow!(
+                        "{}: included files can't include others",
+                        file.display()
+                    ));
+                }
+                let keys: Vec<String> = included.keys().cloned().collect();
+                merge_table(&mut table, included, "", &file, &origins)?;
+                for key in keys {
+                    origins.entry(key).or_default().push(file.clone());
+                }
+            }
+        }
+
+        let mut config = Config::deserialize(toml::Value::Table(table)).map_err(|e| {
+            let message = e.to_string();
+            // Errors end with the key they're about, "in `section.key`"
+            let files = message
+                .rsplit_once("in `")
+                .and_then(|(_, key)| key.split(['.', '`']).next())
+                .and_then(|section| origins.get(section))
+                .map(|files| file_list(files))
+                .unwrap_or_else(|| path.display().to_string());
+            anyhow!(
+                "Invalid configuration in {}: {}\n{}",
+                files,
+                message.trim(),
+                CONFIG_HINT
             )
         })?;
+        config.fill_callsigns();
+        config.expand_groups()?;
         Ok(config)
     }
+
+    /// The settings in effect, defaults and includes applied, as TOML
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+
+    /// Callsigns left unset default to mycall
+    pub fn fill_callsigns(&mut self) {
+        let mycall = &self.mycall;
+        let fill = |call: &mut String| {
+            if call.is_empty() {
+                *call = mycall.clone();
+            }
+        };
+        fill(&mut self.digipeater.mycall);
+        if let Some(aprs_is) = &mut self.aprs_is {
+            fill(&mut aprs_is.callsign);
+        }
+        if let Some(beacon) = &mut self.beacon {
+            fill(&mut beacon.callsign);
+        }
+    }
+
+    /// Replace group names with the ports in them, and put each group's
+    /// filter lists ahead of its members' own
+    pub fn expand_groups(&mut self) -> Result<()> {
+        if self.groups.is_empty() {
+            return Ok(());
+        }
+        let ports: Vec<&str> = self
+            .serial_ports
+            .iter()
+            .map(|p| p.name.as_str())
+            .chain([APRS_IS_PORT])
+            .collect();
+        for group in &self.groups {
+            if ports.contains(&group.name.as_str())
+                || [TX_FROM_DIGIPEATER, TX_FROM_LOCAL].contains(&group.name.as_str())
+            {
+                return Err(anyhow!(
+                    "Group {} has the name of a port or tx_from origin",
+                    group.name
+                ));
+            }
+            if let Some(member) = group.ports.iter().find(|m| !ports.contains(&m.as_str())) {
+                return Err(anyhow!(
+                    "Group {} lists {}, which isn't a serial port or aprs-is",
+                    group.name,
+                    member
+                ));
+            }
+        }
+
+        let groups = std::mem::take(&mut self.groups);
+        let expand = |names: &mut Vec<String>| *names = expand_names(&groups, names);
+        let add_filters = |port: &str, inbound: &mut Vec<String>, outbound: &mut Vec<String>| {
+            let mut group_inbound = Vec::new();
+            let mut group_outbound = Vec::new();
+            for group in groups.iter().filter(|g| g.ports.iter().any(|p| p == port)) {
+                group_inbound.extend_from_slice(&group.inbound_filters);
+                group_outbound.extend_from_slice(&group.outbound_filters);
+            }
+            // A list already holding them has been expanded before, as when
+            // a dumped config is loaded back
+            group_inbound.retain(|f| !inbound.contains(f));
+            group_outbound.retain(|f| !outbound.contains(f));
+            inbound.splice(0..0, group_inbound);
+            outbound.splice(0..0, group_outbound);
+        };
+
+        for port in &mut self.serial_ports {
+            port.tx_from.iter_mut().for_each(expand);
+            port.digipeat_to.iter_mut().for_each(expand);
+            add_filters(
+                &port.name,
+                &mut port.inbound_filters,
+                &mut port.outbound_filters,
+            );
+        }
+        if let Some(aprs_is) = &mut self.aprs_is {
+            add_filters(
+                APRS_IS_PORT,
+                &mut aprs_is.inbound_filters,
+                &mut aprs_is.outbound_filters,
+            );
+        }
+        if let Some(beacon) = &mut self.beacon {
+            expand(&mut beacon.ports);
+        }
+        if let Some(station_id) = &mut self.station_id {
+            expand(&mut station_id.ports);
+        }
+        self.groups = groups;
+        Ok(())
+    }
+}
+
+/// Names with any group swapped for its members, in order, each once
+fn expand_names(groups: &[GroupConfig], names: &[String]) -> Vec<String> {
+    let mut expanded: Vec<String> = Vec::new();
+    for name in names {
+        let members = match groups.iter().find(|g| g.name == *name) {
+            Some(group) => group.ports.as_slice(),
+            None => std::slice::from_ref(name),
+        };
+        for member in members {
+            if !expanded.contains(member) {
+                expanded.push(member.clone());
+            }
+        }
+    }
+    expanded
+}
+
+const CONFIG_HINT: &str = "Hint: Check the TOML syntax. Common issues:\n\
+     - Missing quotes around strings\n\
+     - Incorrect array syntax (use [[section]] for arrays)\n\
+     - Invalid data types for fields";
+
+fn file_list(files: &[PathBuf]) -> String {
+    files
+        .iter()
+        .map(|f| f.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn read_table(path: &Path) -> Result<toml::Table> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read config file {}: {}", path.display(), e))?;
+    toml::from_str(&contents).map_err(|e| {
+        anyhow!(
+            "Failed to parse configuration file {}: {}\n{}",
+            path.display(),
+            e,
+            CONFIG_HINT
+        )
+    })
+}
+
+/// The files an `include` list names, relative to the including file.
+/// Patterns may match nothing; a plain file name must exist.
+fn include_files(config: &Path, include: toml::Value) -> Result<Vec<PathBuf>> {
+    let patterns: Vec<String> = include
+        .try_into()
+        .map_err(|_| anyhow!("{}: include must be a list of files", config.display()))?;
+    let base = config.parent().unwrap_or(Path::new("."));
+
+    let mut files = Vec::new();
+    for pattern in patterns {
+        let full = base.join(&pattern);
+        let matches = glob::glob(&full.to_string_lossy())
+            .map_err(|e| anyhow!("{}: bad include {}: {}", config.display(), pattern, e))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        if matches.is_empty() && !pattern.contains(['*', '?', '[']) {
+            return Err(anyhow!(
+                "{}: included file {} not found",
+                config.display(),
+                full.display()
+            ));
+        }
+        files.extend(matches);
+    }
+    Ok(files)
+}
+
+fn merge_table(
+    into: &mut toml::Table,
+    from: toml::Table,
+    prefix: &str,
+    file: &Path,
+    origins: &HashMap<String, Vec<PathBuf>>,
+) -> Result<()> {
+    for (key, value) in from {
+        let name = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        match (into.get_mut(&key), value) {
+            (None, value) => {
+                into.insert(key, value);
+            }
+            (Some(toml::Value::Table(existing)), toml::Value::Table(table)) => {
+                merge_table(existing, table, &name, file, origins)?;
+            }
+            (Some(toml::Value::Array(existing)), toml::Value::Array(array)) => {
+                existing.extend(array);
+            }
+            (Some(_), _) => {
+                let section = name.split('.').next().unwrap_or_default();
+                let earlier = origins
+                    .get(section)
+                    .map(|files| file_list(files))
+                    .unwrap_or_default();
+                return Err(anyhow!(
+                    "{}: {} is already set in {}",
+                    file.display(),
+                    name,
+                    earlier
+                ));
+            }
+        }
+    }
+    Ok(())
 }
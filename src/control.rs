@@ -0,0 +1,641 @@
+//! Control socket: a line-oriented command interface on a Unix socket.
+//! Each request is a single line and each reply is a single line of JSON,
+//! so it can be driven with `socat` as easily as from a program.
+
+use crate::capture;
+use crate::channel;
+use crate::config::ControlConfig;
+use crate::filter::PacketFilter;
+use crate::gps::GpsTracker;
+use crate::heard::HEARD;
+use crate::igate::GateBudget;
+use crate::is_spool;
+use crate::message::MessageHandler;
+use crate::serial::queue::PortStats;
+use crate::serial::{parse_hex, send_kiss_command};
+use crate::telemetry::TELEMETRY_STATS;
+use crate::tracklog::{self, TrackPoint, TRACKS};
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use log::{debug, error, info};
+use serde_json::{json, Value};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{UnixListener, UnixStream};
+
+/// Daemon state reachable from the control socket.
+pub struct ControlContext {
+    started: Instant,
+    gps: Option<Arc<GpsTracker>>,
+    filter: Option<Arc<PacketFilter>>,
+    messages: Option<Arc<MessageHandler>>,
+    gate_budget: Option<Arc<std::sync::Mutex<GateBudget>>>,
+    capture_dir: Option<PathBuf>,
+}
+
+impl Default for ControlContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ControlContext {
+    pub fn new() -> Self {
+        ControlContext {
+            started: Instant::now(),
+            gps: None,
+            filter: None,
+            messages: None,
+            gate_budget: None,
+            capture_dir: None,
+        }
+    }
+
+    pub fn with_gps(mut self, gps: Arc<GpsTracker>) -> Self {
+        self.gps = Some(gps);
+        self
+    }
+
+    pub fn with_filter(mut self, filter: Arc<PacketFilter>) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    pub fn with_messages(mut self, messages: Arc<MessageHandler>) -> Self {
+        self.messages = Some(messages);
+        self
+    }
+
+    pub fn with_gate_budget(mut self, budget: Arc<std::sync::Mutex<GateBudget>>) -> Self {
+        self.gate_budget = Some(budget);
+        self
+    }
+
+    pub fn with_capture_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.capture_dir = Some(dir.into());
+        self
+    }
+
+    pub async fn handle_command(&self, line: &str) -> Value {
+        let mut args = line.split_whitespace();
+        match args.next() {
+            Some("status") => self.status().await,
+            Some("queue") => queue_contents(args.next()),
+            Some("heard") => heard_stations(args.next()),
+            Some("trip") => match args.next() {
+                Some("reset") => match &self.gps {
+                    Some(gps) => {
+                        gps.reset_trip().await;
+                        json!({ "ok": true })
+                    }
+                    None => json!({ "error": "GPS not configured" }),
+                },
+                _ => self.gps_status().await,
+            },
+            Some("track") => track_command(args.next(), args.next()),
+            Some("msg") => self.send_message(line["msg".len()..].trim()).await,
+            Some("msgstatus") => self.message_status(args.next()).await,
+            Some("kiss") => kiss_command(args.next(), args.next(), args.collect()),
+            Some("capture") => {
+                capture_command(self.capture_dir.as_deref(), args.next(), args.next())
+            }
+            Some(cmd) => json!({ "error": format!("unknown command: {}", cmd) }),
+            None => json!({ "error": "empty command" }),
+        }
+    }
+
+    async fn status(&self) -> Value {
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "uptime": self.started.elapsed().as_secs(),
+            "packets": {
+                "rx": TELEMETRY_STATS.packets_rx.load(Ordering::Relaxed),
+                "tx": TELEMETRY_STATS.packets_tx.load(Ordering::Relaxed),
+                "digipeated": TELEMETRY_STATS.packets_digipeated.load(Ordering::Relaxed),
+                "rf_to_is": TELEMETRY_STATS.packets_igate_rf_to_is.load(Ordering::Relaxed),
+                "is_to_rf": TELEMETRY_STATS.packets_igate_is_to_rf.load(Ordering::Relaxed),
+                "rate_limited": TELEMETRY_STATS.packets_rate_limited.load(Ordering::Relaxed),
+                "stale": TELEMETRY_STATS.packets_stale.load(Ordering::Relaxed),
+            },
+            "frames": {
+                "non_aprs": TELEMETRY_STATS.frames_non_aprs.load(Ordering::Relaxed),
+                "invalid": TELEMETRY_STATS.frames_invalid.load(Ordering::Relaxed),
+            },
+            "filters": self.filter_counts(),
+            "ports": port_stats(),
+            "aprs_is_link": {
+                "rtt_ms": match TELEMETRY_STATS.is_rtt_ms.load(Ordering::Relaxed) {
+                    0 => Value::Null,
+                    rtt => Value::from(rtt),
+                },
+                "reconnects": TELEMETRY_STATS.is_reconnects.load(Ordering::Relaxed),
+                "bytes_rx": TELEMETRY_STATS.is_bytes_rx.load(Ordering::Relaxed),
+                "bytes_tx": TELEMETRY_STATS.is_bytes_tx.load(Ordering::Relaxed),
+            },
+            "aprs_is_queue": {
+                "queued": channel::APRS_IS_QUEUE.depth(),
+                "oldest": channel::APRS_IS_QUEUE.oldest_age(Utc::now()).map(|a| a.num_seconds()),
+                "spooled": is_spool::spooled(),
+            },
+            "dropped": drop_counts(),
+            "heard": heard_counts(),
+            "rf_budget": self.gate_budget_use(),
+            "gps": self.gps_status().await,
+        })
+    }
+
+    /// How much of the APRS-IS to RF budget has been used, if there is one
+    fn gate_budget_use(&self) -> Value {
+        let Some(budget) = &self.gate_budget else {
+            return Value::Null;
+        };
+        let (messages, positions) = budget.lock().unwrap().used(Instant::now());
+        json!({ "messages": messages, "positions": positions })
+    }
+
+    /// "msg CALL text": send a message, replying with its id
+    async fn send_message(&self, args: &str) -> Value {
+        let Some(messages) = &self.messages else {
+            return json!({ "error": "messaging not available" });
+        };
+        let Some((to, text)) = args.split_once(char::is_whitespace) else {
+            return json!({ "error": "usage: msg CALL text" });
+        };
+        match messages.send(to, text.trim()).await {
+            Ok(id) => json!(messages.status(&id).await),
+            Err(e) => json!({ "error": e.to_string() }),
+        }
+    }
+
+    async fn message_status(&self, id: Option<&str>) -> Value {
+        let (Some(messages), Some(id)) = (&self.messages, id) else {
+            return json!({ "error": "usage: msgstatus ID" });
+        };
+        match messages.status(id).await {
+            Some(sent) => json!(sent),
+            None => json!({ "error": format!("no message {}", id) }),
+        }
+    }
+
+    fn filter_counts(&self) -> Value {
+        let counts = self
+            .filter
+            .as_ref()
+            .map(|f| f.counts())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, count)| (name, Value::from(count)))
+            .collect::<serde_json::Map<_, _>>();
+        Value::Object(counts)
+    }
+
+    async fn gps_status(&self) -> Value {
+        let Some(gps) = &self.gps else {
+            return Value::Null;
+        };
+
+        // Looking up the position also settles which source is active
+        let position = gps.get_position().await.map(|pos| {
+            json!({
+                "latitude": pos.latitude,
+                "longitude": pos.longitude,
+                "altitude": pos.altitude,
+                "speed": pos.speed,
+                "course": pos.course,
+                "fix": pos.fix.as_str(),
+                "satellites": pos.satellites,
+                "hdop": pos.hdop,
+                "age": pos.age().as_secs(),
+            })
+        });
+
+        json!({
+            "source": gps.active_source().await,
+            "position": position,
+            "trip": gps.trip_stats().await,
+        })
+    }
+}
+
+fn drop_counts() -> Value {
+    let drops = channel::drop_counts()
+        .into_iter()
+        .map(|(name, count)| (name, Value::from(count)))
+        .collect::<serde_json::Map<_, _>>();
+    Value::Object(drops)
+}
+
+/// "capture": what's being captured. "capture start FILE": capture traffic
+/// to a file in the capture directory for replay. "capture stop": stop.
+fn capture_command(dir: Option<&Path>, action: Option<&str>, file: Option<&str>) -> Value {
+    match (action, file) {
+        (None, _) => match capture::status() {
+            Some(status) => json!({ "capturing": true, "capture": status }),
+            None => json!({ "capturing": false }),
+        },
+        (Some("start"), Some(file)) => {
+            let path = match capture_path(dir, file) {
+                Ok(path) => path,
+                Err(e) => return json!({ "error": e.to_string() }),
+            };
+            match capture::start(&path) {
+                Ok(status) => json!({ "capturing": true, "capture": status }),
+                Err(e) => json!({ "error": e.to_string() }),
+            }
+        }
+        (Some("stop"), None) => match capture::stop() {
+            Some(status) => json!({ "capturing": false, "capture": status }),
+            None => json!({ "error": "not capturing" }),
+        },
+        _ => json!({ "error": "usage: capture [start FILE | stop]" }),
+    }
+}
+
+/// Where a capture named over the socket goes: only ever inside the
+/// configured directory, so a client can't overwrite files elsewhere
+fn capture_path(dir: Option<&Path>, file: &str) -> Result<PathBuf> {
+    let dir = dir.ok_or_else(|| anyhow!("captures need capture_dir set in [control]"))?;
+    let file = Path::new(file);
+    if !file
+        .components()
+        .all(|part| matches!(part, Component::Normal(_)))
+    {
+        return Err(anyhow!(
+            "capture file must be a plain name inside capture_dir"
+        ));
+    }
+    Ok(dir.join(file))
+}
+
+/// "track": the recorded tracks. "track gpx|kml [CALL]": our track, or
+/// CALL's, as a GPX or KML document. "track clear [CALL]": forget them.
+fn track_command(action: Option<&str>, call: Option<&str>) -> Value {
+    let export: fn(&str, &[TrackPoint]) -> String = match action {
+        None => {
+            let counts = TRACKS
+                .counts()
+                .into_iter()
+                .map(|(call, points)| (call, Value::from(points)))
+                .collect::<serde_json::Map<_, _>>();
+            return Value::Object(counts);
+        }
+        Some("clear") => {
+            TRACKS.clear(call);
+            return json!({ "ok": true });
+        }
+        Some("gpx") => tracklog::gpx,
+        Some("kml") => tracklog::kml,
+        Some(_) => return json!({ "error": "usage: track [gpx|kml|clear [CALL]]" }),
+    };
+    match TRACKS.track(call) {
+        Some((call, points)) => json!({
+            "call": call,
+            "points": points.len(),
+            "data": export(&call, &points),
+        }),
+        None => json!({ "error": format!("no track for {}", call.unwrap_or("us")) }),
+    }
+}
+
+/// What's waiting to transmit on each port, or just the one named
+fn queue_contents(port: Option<&str>) -> Value {
+    let now = Utc::now();
+    let ports = PortStats::all()
+        .into_iter()
+        .filter(|(name, _)| port.is_none_or(|p| p == name))
+        .map(|(name, stats)| {
+            let queued = stats
+                .queued
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|q| {
+                    json!({
+                        "packet": q.packet,
+                        "priority": q.priority.as_str(),
+                        "age": now.signed_duration_since(q.received).num_seconds(),
+                    })
+                })
+                .collect::<Vec<_>>();
+            (name, Value::from(queued))
+        })
+        .collect::<serde_json::Map<_, _>>();
+    if let Some(port) = port {
+        if ports.is_empty() {
+            return json!({ "error": format!("unknown port: {}", port) });
+        }
+    }
+    Value::Object(ports)
+}
+
+/// "kiss PORT": the command frames the TNC has sent, such as battery
+/// reports. "kiss PORT COMMAND HEX": send one.
+fn kiss_command(port: Option<&str>, command: Option<&str>, data: Vec<&str>) -> Value {
+    let Some(port) = port else {
+        return json!({ "error": "usage: kiss PORT [COMMAND HEX]" });
+    };
+    if let Some(command) = command {
+        let number = match command.strip_prefix("0x") {
+            Some(hex) => u8::from_str_radix(hex, 16),
+            None => command.parse(),
+        };
+        let Ok(number) = number else {
+            return json!({ "error": format!("invalid KISS command: {}", command) });
+        };
+        return match parse_hex(&data.concat())
+            .and_then(|data| send_kiss_command(port, number, data))
+        {
+            Ok(()) => json!({ "ok": true }),
+            Err(e) => json!({ "error": e.to_string() }),
+        };
+    }
+
+    let Some((_, stats)) = PortStats::all().into_iter().find(|(name, _)| name == port) else {
+        return json!({ "error": format!("unknown port: {}", port) });
+    };
+    let now = Utc::now();
+    let frames = stats
+        .hardware
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|frame| {
+            json!({
+                "age": now.signed_duration_since(frame.received).num_seconds(),
+                "command": frame.command,
+                "hex": frame.data.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" "),
+                "text": String::from_utf8_lossy(&frame.data),
+            })
+        })
+        .collect::<Vec<_>>();
+    Value::from(frames)
+}
+
+/// Every station heard on each port, or just the one named
+fn heard_stations(port: Option<&str>) -> Value {
+    let stations = HEARD.stations(port);
+    if let Some(port) = port {
+        if stations.is_empty() {
+            return json!({ "error": format!("nothing heard on {}", port) });
+        }
+    }
+    serde_json::to_value(stations).unwrap_or_default()
+}
+
+fn heard_counts() -> Value {
+    let counts = HEARD
+        .counts()
+        .into_iter()
+        .map(|(port, count)| (port, Value::from(count)))
+        .collect::<serde_json::Map<_, _>>();
+    Value::Object(counts)
+}
+
+fn port_stats() -> Value {
+    let ports = PortStats::all()
+        .into_iter()
+        .map(|(name, stats)| {
+            let stats = json!({
+                "sent": stats.sent.load(Ordering::Relaxed),
+                "shed": stats.shed.load(Ordering::Relaxed),
+                "deferred": stats.deferred.load(Ordering::Relaxed),
+                "airtime": stats.airtime_ms.load(Ordering::Relaxed) as f64 / 1000.0,
+                "errors": stats.errors.load(Ordering::Relaxed),
+                "queued": stats.depth(),
+                "oldest": stats.oldest_age(Utc::now()).map(|a| a.num_seconds()),
+            });
+            (name, stats)
+        })
+        .collect::<serde_json::Map<_, _>>();
+    Value::Object(ports)
+}
+
+pub async fn run_control_socket(config: ControlConfig, ctx: Arc<ControlContext>) -> Result<()> {
+    let path = Path::new(&config.socket);
+
+    // A socket left behind by an unclean exit would make bind fail. One
+    // that still answers, or anything that isn't a socket, is left alone.
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(anyhow!("{} exists and isn't a socket", config.socket));
+        }
+        if UnixStream::connect(path).await.is_ok() {
+            return Err(anyhow!("{} is in use", config.socket));
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    // Anyone who can connect can transmit, so not everyone may
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(config.mode))?;
+    info!("Control socket listening on {}", config.socket);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(stream, ctx).await {
+                error!("Control client error: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_client(stream: UnixStream, ctx: Arc<ControlContext>) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        debug!("Control command: {}", line);
+
+        let mut reply = ctx.handle_command(line).await.to_string();
+        reply.push('\n');
+        writer.write_all(reply.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+/// A connection to a running daemon's control socket
+pub struct ControlClient {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+}
+
+impl ControlClient {
+    pub async fn connect(socket: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(socket).await.map_err(|e| {
+            anyhow!(
+                "Can't reach the daemon's control socket {}: {}",
+                socket.display(),
+                e
+            )
+        })?;
+        let (reader, writer) = stream.into_split();
+        Ok(ControlClient {
+            lines: BufReader::new(reader).lines(),
+            writer,
+        })
+    }
+
+    /// Send one command and wait for its reply. An "error" reply is an Err.
+    pub async fn request(&mut self, command: &str) -> Result<Value> {
+        self.writer
+            .write_all(format!("{}\n", command).as_bytes())
+            .await?;
+        let line = self
+            .lines
+            .next_line()
+            .await?
+            .ok_or_else(|| anyhow!("The daemon closed the control socket"))?;
+        let reply: Value = serde_json::from_str(&line)?;
+        match reply["error"].as_str() {
+            Some(error) => Err(anyhow!("{}", error)),
+            None => Ok(reply),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gps::{parse_fixed_position, GpsSource};
+
+    #[tokio::test]
+    async fn test_status() {
+        let pos = parse_fixed_position("40.7128,-74.0060").unwrap();
+        let gps = Arc::new(GpsTracker::new(GpsSource::Fixed(pos)));
+        let ctx = ControlContext::new().with_gps(gps);
+
+        let status = ctx.handle_command("status").await;
+        assert_eq!(status["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(status["gps"]["source"], "fixed");
+        assert_eq!(status["gps"]["position"]["fix"], "2d");
+        assert_eq!(status["gps"]["trip"]["distance_km"], 0.0);
+
+        assert_eq!(ctx.handle_command("trip reset").await["ok"], true);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_command() {
+        let ctx = ControlContext::new();
+        assert!(ctx.handle_command("bogus").await["error"].is_string());
+        assert!(ctx.handle_command("status").await["gps"].is_null());
+        assert!(ctx.handle_command("trip reset").await["error"].is_string());
+        assert!(ctx.handle_command("queue nosuchport").await["error"].is_string());
+        assert!(ctx.handle_command("heard nosuchport").await["error"].is_string());
+        assert!(ctx.handle_command("msg N1CALL Hi").await["error"].is_string());
+        assert!(ctx.handle_command("kiss").await["error"].is_string());
+        assert!(ctx.handle_command("kiss nosuchport 6 01").await["error"].is_string());
+        assert!(ctx.handle_command("kiss nosuchport").await["error"].is_string());
+        assert!(ctx.handle_command("capture start").await["error"].is_string());
+        assert!(ctx.handle_command("track gpx NOSUCH-1").await["error"].is_string());
+        assert!(ctx.handle_command("track svg").await["error"].is_string());
+    }
+
+    #[test]
+    fn test_capture_path() {
+        let dir = Path::new("/var/lib/aprstx/captures");
+        assert_eq!(
+            capture_path(Some(dir), "today.log").unwrap(),
+            dir.join("today.log")
+        );
+        assert_eq!(
+            capture_path(Some(dir), "june/today.log").unwrap(),
+            dir.join("june/today.log")
+        );
+        assert!(capture_path(Some(dir), "/etc/passwd").is_err());
+        assert!(capture_path(Some(dir), "../aprstx.conf").is_err());
+        assert!(capture_path(Some(dir), "june/../../x").is_err());
+        assert!(capture_path(None, "today.log").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_socket_permissions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        let config = ControlConfig {
+            socket: path.display().to_string(),
+            mode: 0o600,
+            capture_dir: None,
+        };
+        // A stale socket from an earlier run is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let server = tokio::spawn(run_control_socket(
+            config.clone(),
+            Arc::new(ControlContext::new()),
+        ));
+        for _ in 0..50 {
+            if UnixStream::connect(&path).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // But a live one isn't
+        let error = run_control_socket(config, Arc::new(ControlContext::new()))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("in use"), "{}", error);
+        server.abort();
+
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        let config = ControlConfig {
+            socket: file.display().to_string(),
+            mode: 0o600,
+            capture_dir: None,
+        };
+        let error = run_control_socket(config, Arc::new(ControlContext::new()))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("isn't a socket"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_kiss_hardware_frames() {
+        PortStats::for_port("test-hardware").note_hardware(crate::serial::queue::HardwareFrame {
+            received: Utc::now(),
+            command: 6,
+            data: b"V=12.6".to_vec(),
+        });
+        let frames = ControlContext::new()
+            .handle_command("kiss test-hardware")
+            .await;
+        assert_eq!(frames[0]["command"], 6);
+        assert_eq!(frames[0]["hex"], "56 3D 31 32 2E 36");
+        assert_eq!(frames[0]["text"], "V=12.6");
+    }
+
+    #[tokio::test]
+    async fn test_send_message() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+        let messages = Arc::new(MessageHandler::new("N0CALL".to_string(), tx));
+        let ctx = ControlContext::new().with_messages(messages);
+
+        let reply = ctx.handle_command("msg N1CALL  Meet at  the hamfest").await;
+        assert_eq!(reply["to"], "N1CALL");
+        assert_eq!(reply["status"], "pending");
+        let packet = rx.recv().await.unwrap().packet;
+        assert!(packet
+            .information
+            .starts_with(":N1CALL   :Meet at  the hamfest{"));
+
+        let id = reply["id"].as_str().unwrap();
+        let status = ctx.handle_command(&format!("msgstatus {}", id)).await;
+        assert_eq!(status["attempts"], 1);
+        assert!(ctx.handle_command("msg N1CALL").await["error"].is_string());
+        assert!(ctx.handle_command("msgstatus 0").await["error"].is_string());
+    }
+}
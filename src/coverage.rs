@@ -0,0 +1,149 @@
+//! IGate coverage object.
+//!
+//! Beacons an object at the station's position whose range circle, given
+//! with the `RNG` extension, reaches the farthest station heard direct
+//! recently:
+//!
+//! ```text
+//! ;N0CALLRNG*011403z4903.50N/07201.75W&RNG0023 Heard direct by 12 stations
+//! ```
+//!
+//! Maps draw the circle, showing users how far the igate actually hears
+//! rather than how far it ought to.
+
+use crate::aprs::{AprsPacket, CallSign};
+use crate::beacon::{format_latitude, format_longitude};
+use crate::channel::{self, Overflow};
+use crate::config::CoverageConfig;
+use crate::gps::{distance_km, GpsTracker};
+use crate::heard::HEARD;
+use crate::router::{PacketSource, RoutedPacket, TAG_ISONLY};
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use log::{debug, info};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+const KM_PER_MILE: f64 = 1.609344;
+
+/// Object name when none is configured: the base callsign and "RNG"
+fn default_name(mycall: &str) -> String {
+    let base = mycall.split('-').next().unwrap_or(mycall);
+    format!("{}RNG", base).chars().take(9).collect()
+}
+
+/// Distance to the farthest of `heard` from `center`, in km
+fn farthest_km(center: [f64; 2], heard: &[[f64; 2]]) -> f64 {
+    heard
+        .iter()
+        .map(|[lat, lon]| distance_km(center[0], center[1], *lat, *lon))
+        .fold(0.0, f64::max)
+}
+
+fn coverage_report(
+    config: &CoverageConfig,
+    name: &str,
+    center: [f64; 2],
+    range_km: f64,
+    stations: usize,
+    now: DateTime<Utc>,
+) -> String {
+    let miles = ((range_km / KM_PER_MILE).ceil() as u32).min(9999);
+    format!(
+        ";{:<9}*{}{}{}{}{}RNG{:04} Heard direct by {} stations",
+        name,
+        now.format("%d%H%Mz"),
+        format_latitude(center[0]),
+        config.symbol_table,
+        format_longitude(center[1]),
+        config.symbol,
+        miles,
+        stations
+    )
+}
+
+pub async fn run_coverage_object(
+    config: CoverageConfig,
+    mycall: String,
+    gps: Arc<GpsTracker>,
+    tx: mpsc::Sender<RoutedPacket>,
+) -> Result<()> {
+    let name = config.name.clone().unwrap_or_else(|| default_name(&mycall));
+    info!(
+        "Starting coverage object {} with interval {}s",
+        name, config.interval
+    );
+
+    let source = CallSign::parse(&mycall).unwrap_or(CallSign::new("N0CALL", 0));
+    let window = Duration::minutes(config.window as i64);
+    let mut interval =
+        tokio::time::interval(tokio::time::Duration::from_secs(config.interval as u64));
+
+    loop {
+        interval.tick().await;
+
+        let Some(position) = gps.get_current_position().await else {
+            debug!("No position yet, not sending coverage");
+            continue;
+        };
+        let now = Utc::now();
+        let heard = HEARD.direct_positions(now, window);
+        if heard.len() < config.min_stations {
+            debug!(
+                "Only {} stations with positions heard direct, not sending coverage",
+                heard.len()
+            );
+            continue;
+        }
+        let center = [position.latitude, position.longitude];
+        let report = coverage_report(
+            &config,
+            &name,
+            center,
+            farthest_km(center, &heard),
+            heard.len(),
+            now,
+        );
+
+        let mut packet = AprsPacket::new(source.clone(), CallSign::new("APRS", 0), report);
+        if !config.rf {
+            packet.tags.push(TAG_ISONLY.to_string());
+        }
+
+        info!("Sending coverage: {}", packet.information);
+        let routed = RoutedPacket {
+            packet,
+            source: PacketSource::Internal,
+        };
+        channel::send(&tx, routed, channel::ROUTER, Overflow::Drop).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aprs::parse_packet;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_coverage_report() {
+        let config: CoverageConfig = toml::from_str("").unwrap();
+        assert_eq!(default_name("N0CALL-10"), "N0CALLRNG");
+        assert_eq!(default_name("VE3ABCD-1"), "VE3ABCDRN");
+
+        let center = [49.058333, -72.029167];
+        // About 20 and 36 km away
+        let heard = [[49.238333, -72.029167], [49.058333, -71.536]];
+        let range = farthest_km(center, &heard);
+        assert!((range - 36.0).abs() < 0.5, "{}", range);
+
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 14, 3, 9).unwrap();
+        let report = coverage_report(&config, "N0CALLRNG", center, range, 2, now);
+        assert_eq!(
+            report,
+            ";N0CALLRNG*011403z4903.50N/07201.75W&RNG0023 Heard direct by 2 stations"
+        );
+        let packet = parse_packet(&format!("N0CALL-10>APRS:{}", report)).unwrap();
+        assert!(packet.object().unwrap().alive);
+    }
+}
@@ -0,0 +1,246 @@
+use crate::aprs::{AprsPacket, CallSign};
+use chrono::{DateTime, Utc};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+/// Recently routed packets, for dropping duplicates.
+///
+/// Lookups go through a map keyed by packet hash, and a queue in arrival
+/// order lets old entries be evicted from the front without scanning.
+#[derive(Debug)]
+pub struct DupeCache {
+    seen: HashMap<u64, Instant>,
+    order: VecDeque<(u64, Instant)>,
+    capacity: usize,
+}
+
+impl DupeCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Whether the packet was seen within `window` of `now`
+    pub fn is_duplicate<K: Hash + ?Sized>(
+        &self,
+        packet: &K,
+        now: Instant,
+        window: Duration,
+    ) -> bool {
+        self.seen
+            .get(&packet_hash(packet))
+            .is_some_and(|t| now.duration_since(*t) < window)
+    }
+
+    /// Remember a packet, evicting the oldest entry once full
+    pub fn insert<K: Hash + ?Sized>(&mut self, packet: &K, now: Instant) {
+        let hash = packet_hash(packet);
+        self.seen.insert(hash, now);
+        self.order.push_back((hash, now));
+
+        while self.seen.len() > self.capacity {
+            self.evict_front();
+        }
+    }
+
+    /// Forget packets seen more than `max_age` ago
+    pub fn prune(&mut self, now: Instant, max_age: Duration) {
+        while self
+            .order
+            .front()
+            .is_some_and(|(_, t)| now.duration_since(*t) >= max_age)
+        {
+            self.evict_front();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+
+    fn evict_front(&mut self) {
+        if let Some((hash, t)) = self.order.pop_front() {
+            // A packet seen again has a newer entry further back; only the
+            // latest sighting removes it from the map.
+            if self.seen.get(&hash) == Some(&t) {
+                self.seen.remove(&hash);
+            }
+        }
+    }
+}
+
+/// Spots delayed retransmissions, like a tracker dumping a backlog of
+/// buffered positions when it gets back in range.
+///
+/// A packet is stale when the time it reports is older than `max_age`, or
+/// older than a report already heard from the same station or object.
+#[derive(Debug)]
+pub struct StaleCheck {
+    max_age: chrono::Duration,
+    latest: HashMap<String, DateTime<Utc>>,
+}
+
+impl StaleCheck {
+    pub fn new(max_age: chrono::Duration) -> Self {
+        Self {
+            max_age,
+            latest: HashMap::new(),
+        }
+    }
+
+    /// Whether the packet is stale, remembering its time if it isn't.
+    /// Packets without a timestamp are never stale.
+    pub fn is_stale(&mut self, packet: &AprsPacket, now: DateTime<Utc>) -> bool {
+        let Some(reported) = packet.reported_time() else {
+            return false;
+        };
+        if now - reported > self.max_age {
+            return true;
+        }
+        let key = match packet.object() {
+            Some(object) => format!("{};{}", packet.source, object.name),
+            None => packet.source.to_string(),
+        };
+        match self.latest.get(&key) {
+            Some(latest) if *latest > reported => true,
+            _ => {
+                self.latest.insert(key, reported);
+                false
+            }
+        }
+    }
+
+    /// Forget reports too old to matter
+    pub fn prune(&mut self, now: DateTime<Utc>) {
+        self.latest.retain(|_, t| now - *t <= self.max_age);
+    }
+}
+
+fn packet_hash<K: Hash + ?Sized>(packet: &K) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    packet.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Key for a packet in a [`DupeCache`], from the same fields its TNC2 text
+/// is made of but without formatting it. Packets that print the same get
+/// the same key.
+pub fn packet_key(packet: &AprsPacket) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hash_call(&packet.source, &mut hasher);
+    hash_call(&packet.destination, &mut hasher);
+    packet.path.len().hash(&mut hasher);
+    for hop in &packet.path {
+        hash_call(hop, &mut hasher);
+    }
+    packet.information.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn hash_call(call: &CallSign, hasher: &mut impl Hasher) {
+    call.call.hash(hasher);
+    call.ssid.0.hash(hasher);
+    call.digipeated.hash(hasher);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn test_duplicate_within_window() {
+        let mut cache = DupeCache::new(10);
+        let now = Instant::now();
+        cache.insert("N0CALL>APRS:>one", now);
+
+        assert!(cache.is_duplicate("N0CALL>APRS:>one", now + Duration::from_secs(4), WINDOW));
+        assert!(!cache.is_duplicate("N0CALL>APRS:>one", now + WINDOW, WINDOW));
+        assert!(!cache.is_duplicate("N0CALL>APRS:>two", now, WINDOW));
+    }
+
+    #[test]
+    fn test_capacity_evicts_oldest() {
+        let mut cache = DupeCache::new(2);
+        let now = Instant::now();
+        cache.insert("a", now);
+        cache.insert("b", now);
+        cache.insert("c", now);
+
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.is_duplicate("a", now, WINDOW));
+        assert!(cache.is_duplicate("b", now, WINDOW));
+        assert!(cache.is_duplicate("c", now, WINDOW));
+    }
+
+    #[test]
+    fn test_packet_key() {
+        let parse = |s: &str| crate::aprs::parse_packet(s).unwrap();
+        let key = |s: &str| packet_key(&parse(s));
+
+        let packet = parse("N0CALL-9>APRS,WIDE1-1:>Hello");
+        let mut copy = packet.clone();
+        copy.timestamp += chrono::Duration::seconds(5);
+        assert_eq!(packet_key(&packet), packet_key(&copy));
+
+        // Anything that changes the text changes the key
+        let base = packet_key(&packet);
+        assert_ne!(base, key("N0CALL>APRS,WIDE1-1:>Hello"));
+        assert_ne!(base, key("N0CALL-9>APZ,WIDE1-1:>Hello"));
+        assert_ne!(base, key("N0CALL-9>APRS,WIDE1-1*:>Hello"));
+        assert_ne!(base, key("N0CALL-9>APRS:>Hello"));
+        assert_ne!(base, key("N0CALL-9>APRS,WIDE1-1:>Hello!"));
+    }
+
+    #[test]
+    fn test_stale_packets() {
+        let parse = |s: &str| crate::aprs::parse_packet(s).unwrap();
+        let now = Utc::now();
+        let stamp = |age: i64| (now - chrono::Duration::minutes(age)).format("%d%H%Mz");
+        let mut check = StaleCheck::new(chrono::Duration::hours(1));
+
+        let position = |age| parse(&format!("N0CALL-9>APRS:@{}4903.50N/07201.75W>", stamp(age)));
+        assert!(!check.is_stale(&position(5), now));
+        assert!(check.is_stale(&position(90), now));
+        // Older than what's already been heard from the station
+        assert!(check.is_stale(&position(10), now));
+        assert!(!check.is_stale(&position(1), now));
+
+        // Objects are tracked apart from the station sending them
+        let object = parse(&format!(
+            "N0CALL-9>APRS:;OBJ      *{}4903.50N/07201.75W>",
+            stamp(5)
+        ));
+        assert!(!check.is_stale(&object, now));
+        assert!(!check.is_stale(&parse("N0CALL-9>APRS:>Status"), now));
+
+        check.prune(now + chrono::Duration::hours(2));
+        assert!(check.latest.is_empty());
+    }
+
+    #[test]
+    fn test_prune_keeps_resighted_packets() {
+        let mut cache = DupeCache::new(10);
+        let now = Instant::now();
+        cache.insert("a", now);
+        cache.insert("b", now);
+        cache.insert("a", now + Duration::from_secs(60));
+
+        cache.prune(now + Duration::from_secs(90), Duration::from_secs(60));
+        assert_eq!(cache.len(), 1);
+        assert!(cache.is_duplicate("a", now + Duration::from_secs(61), WINDOW));
+
+        cache.prune(now + Duration::from_secs(200), Duration::from_secs(60));
+        assert!(cache.is_empty());
+    }
+}
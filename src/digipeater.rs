@@ -1,4 +1,5 @@
-use crate::aprs::{AprsPacket, CallSign};
+use crate::aprs::{AprsPacket, CallSign, MAX_PATH_LEN};
+use crate::channel::{self, Overflow};
 use crate::config::DigipeaterConfig;
 use crate::router::{PacketSource, RoutedPacket};
 use anyhow::Result;
@@ -14,7 +15,7 @@ struct DigipeaterState {
 
 pub async fn run_digipeater(
     config: DigipeaterConfig,
-    mut rx: mpsc::Receiver<RoutedPacket>,
+    rx: &mut mpsc::Receiver<RoutedPacket>,
     tx: mpsc::Sender<RoutedPacket>,
 ) -> Result<()> {
     info!("Starting digipeater service with call {}", config.mycall);
@@ -22,28 +23,33 @@ pub async fn run_digipeater(
     let state = Arc::new(RwLock::new(DigipeaterState {
         recent_packets: HashMap::new(),
     }));
-
-    // Start cleanup task
-    let state_clone = state.clone();
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
-        loop {
-            interval.tick().await;
-            cleanup_old_packets(&state_clone).await;
-        }
-    });
-
-    while let Some(routed) = rx.recv().await {
+    let mut cleanup_interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
+
+    loop {
+        let routed = tokio::select! {
+            routed = rx.recv() => match routed {
+                Some(routed) => routed,
+                None => break,
+            },
+            _ = cleanup_interval.tick() => {
+                cleanup_old_packets(&state).await;
+                continue;
+            }
+        };
         if should_digipeat(&config, &routed.packet) {
             if let Some(digipeated) = process_packet(&config, &routed.packet, &state).await {
                 info!("Digipeating packet: {}", digipeated);
 
+                let ingress = match &routed.source {
+                    PacketSource::SerialPort(name) => name.clone(),
+                    _ => String::new(),
+                };
                 let routed_digi = RoutedPacket {
                     packet: digipeated,
-                    source: PacketSource::Internal,
+                    source: PacketSource::Digipeater(ingress),
                 };
 
-                let _ = tx.send(routed_digi).await;
+                channel::send(&tx, routed_digi, channel::ROUTER, Overflow::Drop).await;
             }
         }
     }
@@ -58,36 +64,40 @@ fn should_digipeat(config: &DigipeaterConfig, packet: &AprsPacket) -> bool {
     }
 
     // Check if packet has already been digipeated too many times
-    let digi_count = packet
-        .path
-        .iter()
-        .filter(|hop| hop.call.contains('*'))
-        .count();
+    let digi_count = used_hops(packet);
 
     if digi_count >= config.max_hops as usize {
         debug!("Packet has too many hops ({}), not digipeating", digi_count);
         return false;
     }
 
-    // Find the next unused hop in the path
-    for hop in &packet.path {
-        if !hop.call.contains('*') {
-            // Check if this hop is for us
-            if hop.call == config.mycall || config.aliases.contains(&hop.call) {
-                return true;
-            }
+    // Only the next unused hop in the path matters
+    let Some(hop) = packet.path.get(digi_count) else {
+        return false;
+    };
+    let name = hop_name(hop);
 
-            // Check for WIDEn-N pattern
-            if is_wide_pattern(&hop.call) {
-                return true;
-            }
+    // Check if this hop is for us, or a WIDEn-N pattern
+    name == config.mycall || config.aliases.contains(&name) || is_wide_pattern(&name)
+}
 
-            // If it's not for us and not a WIDE pattern, stop checking
-            return false;
-        }
-    }
+/// Number of hops already used. Often only the last used hop carries the
+/// '*', so everything up to and including it counts.
+pub(crate) fn used_hops(packet: &AprsPacket) -> usize {
+    packet
+        .path
+        .iter()
+        .rposition(|hop| hop.digipeated)
+        .map_or(0, |i| i + 1)
+}
 
-    false
+/// The hop as it appears in config and WIDEn-N matching: CALL-SSID, no '*'
+fn hop_name(hop: &CallSign) -> String {
+    if hop.ssid.0 == 0 {
+        hop.call.clone()
+    } else {
+        format!("{}-{}", hop.call, hop.ssid.0)
+    }
 }
 
 fn is_wide_pattern(call: &str) -> bool {
@@ -144,24 +154,28 @@ async fn process_packet(
     let mut new_packet = packet.clone();
     let mut new_path = Vec::new();
     let mut found_us = false;
+    let mycall = CallSign::parse(&format!("{}*", config.mycall))?;
+
+    let used = used_hops(packet);
 
-    for hop in &packet.path {
-        if !found_us && !hop.call.contains('*') {
+    for (i, hop) in packet.path.iter().enumerate() {
+        let name = hop_name(hop);
+        if !found_us && i >= used {
             // This is the hop we need to process
-            if hop.call == config.mycall || config.aliases.contains(&hop.call) {
+            if name == config.mycall || config.aliases.contains(&name) {
                 // Direct call to us - mark as used
-                new_path.push(CallSign::new(&format!("{}*", config.mycall), 0));
+                new_path.push(mycall.clone());
                 found_us = true;
-            } else if is_wide_pattern(&hop.call) {
+            } else if is_wide_pattern(&name) {
                 // Process WIDEn-N
-                let (wide_type, n) = parse_wide_pattern(&hop.call);
+                let (wide_type, n) = parse_wide_pattern(&name);
                 if n > 1 {
                     // Insert our call and decrement N
-                    new_path.push(CallSign::new(&format!("{}*", config.mycall), 0));
-                    new_path.push(CallSign::new(&format!("{}-{}", wide_type, n - 1), 0));
+                    new_path.push(mycall.clone());
+                    new_path.push(CallSign::new(&wide_type, n - 1));
                 } else {
                     // Last hop - just insert our call
-                    new_path.push(CallSign::new(&format!("{}*", config.mycall), 0));
+                    new_path.push(mycall.clone());
                 }
                 found_us = true;
             } else {
@@ -174,6 +188,11 @@ async fn process_packet(
         }
     }
 
+    if new_path.len() > MAX_PATH_LEN {
+        debug!("Path would exceed {} hops, not digipeating", MAX_PATH_LEN);
+        return None;
+    }
+
     if found_us {
         new_packet.path = new_path;
         Some(new_packet)
@@ -287,7 +306,7 @@ mod tests {
             CallSign::new("APRS", 0),
             ">Test".to_string(),
         );
-        packet.path.push(CallSign::new("N0CALL-10*", 0));
+        packet.path.push(CallSign::parse("N0CALL-10*").unwrap());
         packet.path.push(CallSign::new("WIDE1-1", 0));
 
         assert!(should_digipeat(&config, &packet));
@@ -302,9 +321,9 @@ mod tests {
             CallSign::new("APRS", 0),
             ">Test".to_string(),
         );
-        packet.path.push(CallSign::new("N0CALL*", 0));
-        packet.path.push(CallSign::new("N1CALL*", 0));
-        packet.path.push(CallSign::new("N2CALL*", 0));
+        packet.path.push(CallSign::parse("N0CALL*").unwrap());
+        packet.path.push(CallSign::parse("N1CALL*").unwrap());
+        packet.path.push(CallSign::parse("N2CALL*").unwrap());
         packet.path.push(CallSign::new("WIDE1-1", 0));
 
         assert!(!should_digipeat(&config, &packet));
@@ -349,7 +368,7 @@ mod tests {
         let result = process_packet(&config, &packet, &state).await.unwrap();
 
         assert_eq!(result.path.len(), 1);
-        assert_eq!(result.path[0].call, "N0CALL-10*");
+        assert_eq!(result.path[0].to_string(), "N0CALL-10*");
     }
 
     #[tokio::test]
@@ -369,8 +388,8 @@ mod tests {
         let result = process_packet(&config, &packet, &state).await.unwrap();
 
         assert_eq!(result.path.len(), 2);
-        assert_eq!(result.path[0].call, "N0CALL-10*");
-        assert_eq!(result.path[1].call, "WIDE2-1");
+        assert_eq!(result.path[0].to_string(), "N0CALL-10*");
+        assert_eq!(result.path[1].to_string(), "WIDE2-1");
     }
 
     #[tokio::test]
@@ -390,7 +409,30 @@ mod tests {
         let result = process_packet(&config, &packet, &state).await.unwrap();
 
         assert_eq!(result.path.len(), 1);
-        assert_eq!(result.path[0].call, "N0CALL-10*");
+        assert_eq!(result.path[0].to_string(), "N0CALL-10*");
+    }
+
+    #[tokio::test]
+    async fn test_process_packet_respects_path_limit() {
+        let config = create_test_config();
+        let state = Arc::new(RwLock::new(DigipeaterState {
+            recent_packets: HashMap::new(),
+        }));
+
+        let mut packet = AprsPacket::new(
+            CallSign::new("TEST", 0),
+            CallSign::new("APRS", 0),
+            ">Test".to_string(),
+        );
+        for i in 0..7 {
+            packet
+                .path
+                .push(CallSign::parse(&format!("N{}CALL*", i)).unwrap());
+        }
+        packet.path.push(CallSign::new("WIDE2-2", 0));
+
+        // Inserting our call ahead of WIDE2-1 would make 9 hops
+        assert!(process_packet(&config, &packet, &state).await.is_none());
     }
 
     #[tokio::test]
@@ -438,4 +480,25 @@ mod tests {
         assert_eq!(state_read.recent_packets.len(), 1);
         assert!(state_read.recent_packets.contains_key("new_packet"));
     }
+
+    #[tokio::test]
+    async fn test_process_parsed_rf_packet() {
+        let config = create_test_config();
+        let state = Arc::new(RwLock::new(DigipeaterState {
+            recent_packets: HashMap::new(),
+        }));
+
+        // As decoded off the air: WIDE1-1 already used, WIDE2-1 remaining
+        let packet = crate::aprs::parse_packet("TEST>APRS,N1ABC*,WIDE2-1:>Test").unwrap();
+        assert!(should_digipeat(&config, &packet));
+
+        let result = process_packet(&config, &packet, &state).await.unwrap();
+        assert_eq!(result.to_string(), "TEST>APRS,N1ABC*,N0CALL-10*:>Test");
+
+        // Fully used path is left alone, even if only the last hop is marked
+        let packet = crate::aprs::parse_packet("TEST>APRS,N1ABC*,WIDE2*:>Test").unwrap();
+        assert!(!should_digipeat(&config, &packet));
+        let packet = crate::aprs::parse_packet("TEST>APRS,N0CALL-10,WIDE2*:>Test").unwrap();
+        assert!(!should_digipeat(&config, &packet));
+    }
 }
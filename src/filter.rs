@@ -1,40 +1,234 @@
+use crate::aprs::packet::DataType;
 use crate::aprs::AprsPacket;
-use crate::config::{FilterAction, FilterConfig};
+use crate::config::{FilterAction, FilterConfig, FilterDirection, RateLimitConfig};
+use crate::gps::{distance_km, GpsTracker};
+use anyhow::{anyhow, Result};
+use log::info;
 use regex::Regex;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// Port name used for the APRS-IS connection's filter lists
+pub const APRS_IS_PORT: &str = "aprs-is";
+
+/// How often range filters pick up our latest position from the GPS
+const POSITION_REFRESH: Duration = Duration::from_secs(30);
 
 pub struct PacketFilter {
     filters: Vec<CompiledFilter>,
+    ports: HashMap<String, PortChains>,
+    own_position: RwLock<Option<(f64, f64)>>,
+    counters: HashMap<String, AtomicU64>,
+}
+
+/// A list of filters picked by name, evaluated like a port's chain.
+#[derive(Debug, Clone, Default)]
+pub struct FilterChain(Vec<usize>);
+
+#[derive(Default)]
+struct PortChains {
+    inbound: Vec<usize>,
+    outbound: Vec<usize>,
 }
 
 struct CompiledFilter {
+    name: String,
     action: FilterAction,
+    label: String,
+    direction: Option<FilterDirection>,
     regex: Regex,
+    types: Vec<DataType>,
+    symbols: Vec<String>,
+    addressees: Vec<String>,
+    range: Option<f64>,
+    callsigns: Vec<String>,
+    tagged: Vec<String>,
 }
 
 impl PacketFilter {
     pub fn new(configs: Vec<FilterConfig>) -> Result<Self, regex::Error> {
         let mut filters = Vec::new();
+        let mut counters = HashMap::new();
 
         for config in configs {
             let regex = Regex::new(&config.pattern)?;
+            let label = config.label.unwrap_or_else(|| config.name.clone());
+            if matches!(config.action, FilterAction::Count) {
+                counters.insert(label.clone(), AtomicU64::new(0));
+            }
             filters.push(CompiledFilter {
+                name: config.name,
                 action: config.action,
+                label,
+                direction: config.direction,
                 regex,
+                types: config.types,
+                symbols: config.symbols,
+                addressees: config.addressees,
+                range: config.range,
+                callsigns: config.callsigns.iter().map(|c| c.to_uppercase()).collect(),
+                tagged: config.tagged,
             });
         }
 
-        Ok(PacketFilter { filters })
+        Ok(PacketFilter {
+            filters,
+            ports: HashMap::new(),
+            own_position: RwLock::new(None),
+            counters,
+        })
+    }
+
+    /// Attach a port's inbound and outbound lists, given as filter names
+    pub fn with_port(
+        mut self,
+        port: &str,
+        inbound: &[String],
+        outbound: &[String],
+    ) -> Result<Self> {
+        let chains = PortChains {
+            inbound: self.resolve(&format!("Port {}", port), inbound)?,
+            outbound: self.resolve(&format!("Port {}", port), outbound)?,
+        };
+        self.ports.insert(port.to_string(), chains);
+        Ok(self)
+    }
+
+    /// Look up filters by name, for feeds that let each client pick its own
+    pub fn named_chain(&self, names: &[String]) -> Result<FilterChain> {
+        Ok(FilterChain(self.resolve("Filter list", names)?))
+    }
+
+    /// Evaluate a chain from [`named_chain`](Self::named_chain)
+    pub fn should_pass_chain(&self, chain: &FilterChain, packet: &AprsPacket) -> bool {
+        self.evaluate(self.indexed(&chain.0), packet, &mut Vec::new())
+    }
+
+    /// Whether any filter in the chain matches, whatever its action. For
+    /// features that pick packets out by filter.
+    pub fn matches_any(&self, chain: &FilterChain, packet: &AprsPacket) -> bool {
+        let text = packet.to_string();
+        let own = self.own_position();
+        self.indexed(&chain.0)
+            .any(|filter| filter.matches(packet, &text, own, &[]))
+    }
+
+    fn resolve(&self, owner: &str, names: &[String]) -> Result<Vec<usize>> {
+        names
+            .iter()
+            .map(|name| {
+                self.filters
+                    .iter()
+                    .position(|f| &f.name == name)
+                    .ok_or_else(|| anyhow!("{} references unknown filter {}", owner, name))
+            })
+            .collect()
+    }
+
+    /// Our position, as the centre for range filters
+    pub fn set_own_position(&self, latitude: f64, longitude: f64) {
+        *self.own_position.write().unwrap() = Some((latitude, longitude));
+    }
+
+    fn own_position(&self) -> Option<(f64, f64)> {
+        *self.own_position.read().unwrap()
     }
 
+    /// Values of the counters kept by `count` filters, by name
+    pub fn counts(&self) -> Vec<(String, u64)> {
+        let mut counts: Vec<_> = self
+            .counters
+            .iter()
+            .map(|(name, count)| (name.clone(), count.load(Ordering::Relaxed)))
+            .collect();
+        counts.sort();
+        counts
+    }
+
+    /// Run the chain that applies to every routed packet
     pub fn should_pass(&self, packet: &AprsPacket) -> bool {
-        let packet_str = packet.to_string();
+        self.evaluate(self.chain(None), packet, &mut Vec::new())
+    }
+
+    /// Run the global chain and attach any tags it hands out to the packet
+    pub fn apply(&self, packet: &mut AprsPacket) -> bool {
+        let mut tags = Vec::new();
+        let pass = self.evaluate(self.chain(None), packet, &mut tags);
+        for tag in tags {
+            if !packet.tags.contains(&tag) {
+                packet.tags.push(tag);
+            }
+        }
+        pass
+    }
+
+    /// Run the chain for one direction; it only sees packets that already
+    /// passed the global chain
+    pub fn should_pass_for(&self, direction: FilterDirection, packet: &AprsPacket) -> bool {
+        self.evaluate(self.chain(Some(direction)), packet, &mut Vec::new())
+    }
+
+    /// Run a port's inbound list against a packet heard on it
+    pub fn should_pass_inbound(&self, port: &str, packet: &AprsPacket) -> bool {
+        match self.ports.get(port) {
+            Some(chains) => self.evaluate(self.indexed(&chains.inbound), packet, &mut Vec::new()),
+            None => true,
+        }
+    }
+
+    /// Run a port's outbound list against a packet about to be sent on it
+    pub fn should_pass_outbound(&self, port: &str, packet: &AprsPacket) -> bool {
+        match self.ports.get(port) {
+            Some(chains) => self.evaluate(self.indexed(&chains.outbound), packet, &mut Vec::new()),
+            None => true,
+        }
+    }
+
+    pub fn has_chain(&self, direction: FilterDirection) -> bool {
+        self.filters.iter().any(|f| f.direction == Some(direction))
+    }
+
+    fn chain(
+        &self,
+        direction: Option<FilterDirection>,
+    ) -> impl Iterator<Item = &CompiledFilter> + '_ {
+        self.filters
+            .iter()
+            .filter(move |f| f.direction == direction)
+    }
 
-        for filter in &self.filters {
-            if filter.regex.is_match(&packet_str) {
-                match filter.action {
-                    FilterAction::Drop => return false,
-                    FilterAction::Pass => return true,
+    fn indexed<'a>(&'a self, chain: &'a [usize]) -> impl Iterator<Item = &'a CompiledFilter> {
+        chain.iter().map(|&i| &self.filters[i])
+    }
+
+    /// Walk a chain until a pass or drop filter matches. Log, count and tag
+    /// filters record the match and carry on.
+    fn evaluate<'a>(
+        &self,
+        chain: impl Iterator<Item = &'a CompiledFilter>,
+        packet: &AprsPacket,
+        tags: &mut Vec<String>,
+    ) -> bool {
+        let mut packet_str = None;
+        let own = self.own_position();
+
+        for filter in chain {
+            let text = packet_str.get_or_insert_with(|| packet.to_string());
+            if !filter.matches(packet, text, own, tags) {
+                continue;
+            }
+            match filter.action {
+                FilterAction::Drop => return false,
+                FilterAction::Pass => return true,
+                FilterAction::Log => info!("Filter {}: {}", filter.name, text),
+                FilterAction::Count => {
+                    if let Some(count) = self.counters.get(&filter.label) {
+                        count.fetch_add(1, Ordering::Relaxed);
+                    }
                 }
+                FilterAction::Tag => tags.push(filter.label.clone()),
             }
         }
 
@@ -42,6 +236,210 @@ impl PacketFilter {
     }
 }
 
+impl CompiledFilter {
+    /// Every predicate given must match; unset ones match anything
+    fn matches(
+        &self,
+        packet: &AprsPacket,
+        packet_str: &str,
+        own: Option<(f64, f64)>,
+        pending_tags: &[String],
+    ) -> bool {
+        if !self.tagged.is_empty()
+            && !self
+                .tagged
+                .iter()
+                .any(|t| packet.tags.contains(t) || pending_tags.contains(t))
+        {
+            return false;
+        }
+
+        if let Some(range) = self.range {
+            // Without both positions we can't say it is in range
+            let (Some((lat, lon)), Some(pos)) = (own, packet.position()) else {
+                return false;
+            };
+            if distance_km(lat, lon, pos.latitude, pos.longitude) > range {
+                return false;
+            }
+        }
+
+        if !self.types.is_empty() && !self.types.iter().any(|t| matches_type(packet, t)) {
+            return false;
+        }
+
+        if !self.symbols.is_empty() {
+            let Some(pos) = packet.position() else {
+                return false;
+            };
+            let matched = self.symbols.iter().any(|spec| {
+                let mut chars = spec.chars();
+                match (chars.next(), chars.next(), chars.next()) {
+                    (Some(symbol), None, _) => pos.symbol == symbol,
+                    (Some(table), Some(symbol), None) => {
+                        pos.symbol_table == table && pos.symbol == symbol
+                    }
+                    _ => false,
+                }
+            });
+            if !matched {
+                return false;
+            }
+        }
+
+        if !self.addressees.is_empty() {
+            let Some(msg) = packet.message() else {
+                return false;
+            };
+            let matched = self
+                .addressees
+                .iter()
+                .any(|want| match want.strip_suffix('*') {
+                    Some(prefix) => msg
+                        .addressee
+                        .to_uppercase()
+                        .starts_with(&prefix.to_uppercase()),
+                    None => msg.addressee.eq_ignore_ascii_case(want),
+                });
+            if !matched {
+                return false;
+            }
+        }
+
+        if !self.callsigns.is_empty() && !self.matches_callsign(packet) {
+            return false;
+        }
+
+        self.regex.is_match(packet_str)
+    }
+
+    fn matches_callsign(&self, packet: &AprsPacket) -> bool {
+        let mut calls = vec![packet.source.to_string(), packet.destination.to_string()];
+        if let Some(msg) = packet.message() {
+            calls.push(msg.addressee.to_uppercase());
+        }
+
+        self.callsigns
+            .iter()
+            .any(|pattern| calls.iter().any(|call| wildcard_match(pattern, call)))
+    }
+}
+
+/// Positions carrying the weather symbol count as weather too
+fn matches_type(packet: &AprsPacket, data_type: &DataType) -> bool {
+    if &packet.data_type == data_type {
+        return true;
+    }
+    *data_type == DataType::Weather && packet.position().is_some_and(|pos| pos.symbol == '_')
+}
+
+/// Match `text` against a pattern where '*' is any run of characters and
+/// '?' any single one.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // Let the last '*' swallow one more character
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, t));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Keep range filters centred on wherever the GPS says we are
+pub async fn follow_gps(filter: Arc<PacketFilter>, gps: Arc<GpsTracker>) -> Result<()> {
+    let mut interval = tokio::time::interval(POSITION_REFRESH);
+    loop {
+        interval.tick().await;
+        if let Some(pos) = gps.get_current_position().await {
+            filter.set_own_position(pos.latitude, pos.longitude);
+        }
+    }
+}
+
+/// Per-source token buckets, to stop one faulty tracker flooding RF or the
+/// APRS-IS uplink.
+pub struct RateLimiter {
+    per_second: f64,
+    burst: f64,
+    buckets: HashMap<String, Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    dropped: u64,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        RateLimiter {
+            per_second: config.per_minute / 60.0,
+            burst: config.burst.max(1) as f64,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Take a token for `source`, returning false if it has none left
+    pub fn allow(&mut self, source: &str, now: Instant) -> bool {
+        let bucket = self.buckets.entry(source.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+            dropped: 0,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            bucket.dropped += 1;
+            false
+        }
+    }
+
+    /// Packets dropped per source since the last call, most dropped first
+    pub fn take_dropped(&mut self) -> Vec<(String, u64)> {
+        let mut dropped: Vec<_> = self
+            .buckets
+            .iter_mut()
+            .filter(|(_, b)| b.dropped > 0)
+            .map(|(source, b)| (source.clone(), std::mem::take(&mut b.dropped)))
+            .collect();
+        dropped.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        dropped
+    }
+
+    /// Forget sources whose bucket would be full again by now
+    pub fn prune(&mut self, now: Instant) {
+        let refill = if self.per_second > 0.0 {
+            Duration::from_secs_f64(self.burst / self.per_second)
+        } else {
+            Duration::MAX
+        };
+        self.buckets
+            .retain(|_, b| now.saturating_duration_since(b.updated) < refill);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -53,6 +451,7 @@ mod tests {
             name: "test".to_string(),
             action: FilterAction::Drop,
             pattern: "RFONLY".to_string(),
+            ..Default::default()
         }];
 
         let filter = PacketFilter::new(configs).unwrap();
@@ -65,6 +464,7 @@ mod tests {
             name: "rfonly".to_string(),
             action: FilterAction::Drop,
             pattern: "RFONLY".to_string(),
+            ..Default::default()
         }];
 
         let filter = PacketFilter::new(configs).unwrap();
@@ -93,11 +493,13 @@ mod tests {
                 name: "emergency".to_string(),
                 action: FilterAction::Pass,
                 pattern: "EMERGENCY".to_string(),
+                ..Default::default()
             },
             FilterConfig {
                 name: "default".to_string(),
                 action: FilterAction::Drop,
                 pattern: ".*".to_string(),
+                ..Default::default()
             },
         ];
 
@@ -126,6 +528,7 @@ mod tests {
             name: "callsign".to_string(),
             action: FilterAction::Drop,
             pattern: r"^N0CALL.*".to_string(),
+            ..Default::default()
         }];
 
         let filter = PacketFilter::new(configs).unwrap();
@@ -154,16 +557,19 @@ mod tests {
                 name: "rfonly".to_string(),
                 action: FilterAction::Drop,
                 pattern: "RFONLY".to_string(),
+                ..Default::default()
             },
             FilterConfig {
                 name: "nogate".to_string(),
                 action: FilterAction::Drop,
                 pattern: "NOGATE".to_string(),
+                ..Default::default()
             },
             FilterConfig {
                 name: "tcpip".to_string(),
                 action: FilterAction::Drop,
                 pattern: "TCPIP".to_string(),
+                ..Default::default()
             },
         ];
 
@@ -207,8 +613,278 @@ mod tests {
             name: "bad".to_string(),
             action: FilterAction::Drop,
             pattern: "[invalid regex".to_string(),
+            ..Default::default()
         }];
 
         assert!(PacketFilter::new(configs).is_err());
     }
+
+    #[test]
+    fn test_direction_chains() {
+        let configs = vec![
+            FilterConfig {
+                name: "tcpip".to_string(),
+                action: FilterAction::Drop,
+                pattern: "TCPIP".to_string(),
+                direction: Some(FilterDirection::IsToRf),
+                ..Default::default()
+            },
+            FilterConfig {
+                name: "weather".to_string(),
+                action: FilterAction::Drop,
+                pattern: ":_".to_string(),
+                direction: Some(FilterDirection::Log),
+                ..Default::default()
+            },
+        ];
+
+        let filter = PacketFilter::new(configs).unwrap();
+
+        let mut packet = AprsPacket::new(
+            CallSign::new("N0CALL", 0),
+            CallSign::new("APRS", 0),
+            ">Test".to_string(),
+        );
+        packet.path.push(CallSign::new("TCPIP*", 0));
+
+        // Only the IS->RF chain drops it
+        assert!(filter.should_pass(&packet));
+        assert!(!filter.should_pass_for(FilterDirection::IsToRf, &packet));
+        assert!(filter.should_pass_for(FilterDirection::RfToIs, &packet));
+        assert!(filter.should_pass_for(FilterDirection::Log, &packet));
+
+        assert!(filter.has_chain(FilterDirection::Log));
+        assert!(!filter.has_chain(FilterDirection::Digipeat));
+    }
+
+    #[test]
+    fn test_port_chains() {
+        let configs = vec![
+            FilterConfig {
+                name: "sat-only-positions".to_string(),
+                action: FilterAction::Drop,
+                pattern: ":[^!=/@]".to_string(),
+                direction: Some(FilterDirection::Port),
+                ..Default::default()
+            },
+            FilterConfig {
+                name: "no-tcpip".to_string(),
+                action: FilterAction::Drop,
+                pattern: "TCPIP".to_string(),
+                direction: Some(FilterDirection::Port),
+                ..Default::default()
+            },
+        ];
+
+        let filter = PacketFilter::new(configs)
+            .unwrap()
+            .with_port(
+                "sat",
+                &["sat-only-positions".to_string()],
+                &["no-tcpip".to_string()],
+            )
+            .unwrap();
+
+        let status = AprsPacket::new(
+            CallSign::new("N0CALL", 0),
+            CallSign::new("APRS", 0),
+            ">Status".to_string(),
+        );
+
+        // Port-only filters stay out of the global chain
+        assert!(filter.should_pass(&status));
+        assert!(!filter.should_pass_inbound("sat", &status));
+        assert!(filter.should_pass_outbound("sat", &status));
+        assert!(filter.should_pass_inbound("vhf", &status));
+
+        let mut gated = status.clone();
+        gated.path.push(CallSign::new("TCPIP*", 0));
+        assert!(!filter.should_pass_outbound("sat", &gated));
+
+        let unknown =
+            PacketFilter::new(vec![])
+                .unwrap()
+                .with_port("sat", &["missing".to_string()], &[]);
+        assert!(unknown.is_err());
+
+        // The same filters picked by name
+        let chain = filter.named_chain(&["no-tcpip".to_string()]).unwrap();
+        assert!(filter.should_pass_chain(&chain, &status));
+        assert!(!filter.should_pass_chain(&chain, &gated));
+        assert!(filter.matches_any(&chain, &gated));
+        assert!(!filter.matches_any(&chain, &status));
+        assert!(filter.named_chain(&["missing".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_type_and_field_predicates() {
+        let configs = vec![
+            FilterConfig {
+                name: "weather".to_string(),
+                action: FilterAction::Drop,
+                types: vec![DataType::Weather],
+                ..Default::default()
+            },
+            FilterConfig {
+                name: "bulletins".to_string(),
+                action: FilterAction::Drop,
+                addressees: vec!["BLN*".to_string()],
+                ..Default::default()
+            },
+            FilterConfig {
+                name: "cars".to_string(),
+                action: FilterAction::Drop,
+                symbols: vec!["/>".to_string()],
+                ..Default::default()
+            },
+        ];
+        let filter = PacketFilter::new(configs).unwrap();
+
+        let pass = |s: &str| filter.should_pass(&crate::aprs::parse_packet(s).unwrap());
+
+        assert!(!pass("N0CALL>APRS:_10090556c220s004g005t077"));
+        assert!(!pass("N0CALL>APRS:!4903.50N/07201.75W_220/004g005t077"));
+        assert!(!pass("N0CALL>APRS::BLN1     :Net tonight"));
+        assert!(!pass("N0CALL>APRS:!4903.50N/07201.75W>"));
+
+        assert!(pass("N0CALL>APRS:!4903.50N/07201.75W-"));
+        assert!(pass("N0CALL>APRS:!4903.50N\\07201.75W>"));
+        assert!(pass("N0CALL>APRS::N1CALL   :Hello{1"));
+        assert!(pass("N0CALL>APRS:>Status"));
+    }
+
+    #[test]
+    fn test_range_filter() {
+        let configs = vec![
+            FilterConfig {
+                name: "local".to_string(),
+                action: FilterAction::Pass,
+                range: Some(50.0),
+                ..Default::default()
+            },
+            FilterConfig {
+                name: "everything-else".to_string(),
+                action: FilterAction::Drop,
+                ..Default::default()
+            },
+        ];
+        let filter = PacketFilter::new(configs).unwrap();
+
+        let near = crate::aprs::parse_packet("N0CALL>APRS:!4903.50N/07201.75W-").unwrap();
+        let far = crate::aprs::parse_packet("N1CALL>APRS:!4003.50N/07401.75W-").unwrap();
+        let status = crate::aprs::parse_packet("N2CALL>APRS:>Status").unwrap();
+
+        // No position of our own yet, so nothing is in range
+        assert!(!filter.should_pass(&near));
+
+        filter.set_own_position(49.0, -72.0);
+        assert!(filter.should_pass(&near));
+        assert!(!filter.should_pass(&far));
+        assert!(!filter.should_pass(&status));
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(&RateLimitConfig {
+            per_minute: 6.0,
+            burst: 2,
+        });
+        let start = Instant::now();
+
+        assert!(limiter.allow("N0CALL", start));
+        assert!(limiter.allow("N0CALL", start));
+        assert!(!limiter.allow("N0CALL", start));
+        assert!(limiter.allow("N1CALL", start));
+
+        // One token back every 10 seconds
+        assert!(!limiter.allow("N0CALL", start + Duration::from_secs(5)));
+        assert!(limiter.allow("N0CALL", start + Duration::from_secs(16)));
+
+        assert_eq!(limiter.take_dropped(), vec![("N0CALL".to_string(), 2)]);
+        assert!(limiter.take_dropped().is_empty());
+
+        limiter.prune(start + Duration::from_secs(60));
+        assert!(limiter.buckets.is_empty());
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("N0CALL*", "N0CALL"));
+        assert!(wildcard_match("N0CALL*", "N0CALL-9"));
+        assert!(wildcard_match("*-9", "N0CALL-9"));
+        assert!(!wildcard_match("*-9", "N0CALL-10"));
+        assert!(wildcard_match("K?ABC", "K1ABC"));
+        assert!(!wildcard_match("K?ABC", "KABC"));
+        assert!(wildcard_match("*", "ANYTHING"));
+        assert!(!wildcard_match("N0CALL", "N0CALL-1"));
+    }
+
+    #[test]
+    fn test_budlist() {
+        let configs = vec![
+            FilterConfig {
+                name: "buddies".to_string(),
+                action: FilterAction::Pass,
+                callsigns: vec!["n0call*".to_string(), "*-9".to_string()],
+                ..Default::default()
+            },
+            FilterConfig {
+                name: "everyone-else".to_string(),
+                action: FilterAction::Drop,
+                ..Default::default()
+            },
+        ];
+        let filter = PacketFilter::new(configs).unwrap();
+        let pass = |s: &str| filter.should_pass(&crate::aprs::parse_packet(s).unwrap());
+
+        assert!(pass("N0CALL-5>APRS:>Status"));
+        assert!(pass("K1ABC-9>APRS:>Mobile"));
+        assert!(pass("K1ABC>APRS::N0CALL   :Hi{1"));
+        assert!(!pass("K1ABC>APRS::W1XYZ    :Hi{1"));
+        assert!(!pass("K1ABC-10>APRS:>Status"));
+    }
+
+    #[test]
+    fn test_log_count_tag_actions() {
+        let configs = vec![
+            FilterConfig {
+                name: "count-weather".to_string(),
+                action: FilterAction::Count,
+                types: vec![DataType::Weather],
+                label: Some("weather".to_string()),
+                ..Default::default()
+            },
+            FilterConfig {
+                name: "log-all".to_string(),
+                action: FilterAction::Log,
+                ..Default::default()
+            },
+            FilterConfig {
+                name: "nogate".to_string(),
+                action: FilterAction::Tag,
+                pattern: "TEST".to_string(),
+                ..Default::default()
+            },
+            FilterConfig {
+                name: "drop-tagged".to_string(),
+                action: FilterAction::Drop,
+                tagged: vec!["nogate".to_string()],
+                direction: Some(FilterDirection::IsToRf),
+                ..Default::default()
+            },
+        ];
+        let filter = PacketFilter::new(configs).unwrap();
+
+        let mut weather = crate::aprs::parse_packet("N0CALL>APRS:_10090556c220s004").unwrap();
+        assert!(filter.apply(&mut weather));
+        assert!(weather.tags.is_empty());
+        assert_eq!(filter.counts(), vec![("weather".to_string(), 1)]);
+
+        // Tagging carries on down the chain and sticks to the packet
+        let mut test = crate::aprs::parse_packet("N0CALL>APRS:>TEST").unwrap();
+        assert!(filter.apply(&mut test));
+        assert_eq!(test.tags, vec!["nogate".to_string()]);
+        assert!(!filter.should_pass_for(FilterDirection::IsToRf, &test));
+        assert!(filter.should_pass_for(FilterDirection::IsToRf, &weather));
+    }
 }
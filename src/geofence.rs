@@ -0,0 +1,142 @@
+use crate::config::GeofenceConfig;
+use crate::gps::distance_km;
+use anyhow::{anyhow, Result};
+
+#[derive(Debug, Clone)]
+enum Region {
+    Circle { lat: f64, lon: f64, radius_km: f64 },
+    Polygon(Vec<(f64, f64)>),
+}
+
+/// A named region that switches the beacon to a different profile.
+#[derive(Debug, Clone)]
+pub struct Geofence {
+    region: Region,
+    config: GeofenceConfig,
+}
+
+impl Geofence {
+    pub fn from_config(config: &GeofenceConfig) -> Result<Self> {
+        let region = match (config.center, config.radius, config.polygon.len()) {
+            (Some([lat, lon]), Some(radius_km), 0) if radius_km > 0.0 => Region::Circle {
+                lat,
+                lon,
+                radius_km,
+            },
+            (None, None, n) if n >= 3 => {
+                Region::Polygon(config.polygon.iter().map(|p| (p[0], p[1])).collect())
+            }
+            (None, None, n) if n > 0 => {
+                return Err(anyhow!("a polygon needs at least 3 points"));
+            }
+            _ => {
+                return Err(anyhow!(
+                    "give either center and a positive radius, or a polygon"
+                ));
+            }
+        };
+
+        Ok(Geofence {
+            region,
+            config: config.clone(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    pub fn config(&self) -> &GeofenceConfig {
+        &self.config
+    }
+
+    pub fn contains(&self, lat: f64, lon: f64) -> bool {
+        match &self.region {
+            Region::Circle {
+                lat: clat,
+                lon: clon,
+                radius_km,
+            } => distance_km(*clat, *clon, lat, lon) <= *radius_km,
+            Region::Polygon(points) => point_in_polygon(points, lat, lon),
+        }
+    }
+}
+
+/// Ray-casting test treating lat/lon as planar, which is fine for regions
+/// the size of a city or county.
+fn point_in_polygon(points: &[(f64, f64)], lat: f64, lon: f64) -> bool {
+    let mut inside = false;
+    let mut j = points.len() - 1;
+
+    for i in 0..points.len() {
+        let (lat_i, lon_i) = points[i];
+        let (lat_j, lon_j) = points[j];
+
+        if (lat_i > lat) != (lat_j > lat)
+            && lon < (lon_j - lon_i) * (lat - lat_i) / (lat_j - lat_i) + lon_i
+        {
+            inside = !inside;
+        }
+        j = i;
+    }
+
+    inside
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn circle(lat: f64, lon: f64, radius: f64) -> GeofenceConfig {
+        GeofenceConfig {
+            name: "circle".to_string(),
+            center: Some([lat, lon]),
+            radius: Some(radius),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_circle_contains() {
+        let fence = Geofence::from_config(&circle(40.7128, -74.0060, 10.0)).unwrap();
+
+        assert!(fence.contains(40.7128, -74.0060));
+        assert!(fence.contains(40.75, -74.0)); // ~4 km away
+        assert!(!fence.contains(40.9, -74.0)); // ~20 km away
+    }
+
+    #[test]
+    fn test_polygon_contains() {
+        let config = GeofenceConfig {
+            name: "square".to_string(),
+            polygon: vec![[40.0, -75.0], [40.0, -74.0], [41.0, -74.0], [41.0, -75.0]],
+            ..Default::default()
+        };
+        let fence = Geofence::from_config(&config).unwrap();
+
+        assert!(fence.contains(40.5, -74.5));
+        assert!(!fence.contains(41.5, -74.5));
+        assert!(!fence.contains(40.5, -73.5));
+    }
+
+    #[test]
+    fn test_invalid_geofence() {
+        // No shape
+        assert!(Geofence::from_config(&GeofenceConfig::default()).is_err());
+
+        // Too few polygon points
+        let config = GeofenceConfig {
+            polygon: vec![[40.0, -75.0], [41.0, -74.0]],
+            ..Default::default()
+        };
+        assert!(Geofence::from_config(&config).is_err());
+
+        // Both shapes
+        let mut config = circle(40.0, -74.0, 5.0);
+        config.polygon = vec![[40.0, -75.0], [40.0, -74.0], [41.0, -74.0]];
+        assert!(Geofence::from_config(&config).is_err());
+
+        // Zero radius
+        assert!(Geofence::from_config(&circle(40.0, -74.0, 0.0)).is_err());
+    }
+}
@@ -1,13 +1,28 @@
+use crate::config::GpsSourceConfig;
 use crate::serial::pure_serial::SerialPort;
 use anyhow::{anyhow, Result};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDateTime, Utc};
 use log::{debug, error, info, warn};
 use nmea::Nmea;
+use serde::Serialize;
+use std::fmt;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader as AsyncBufReader};
 use tokio::net::TcpStream;
 use tokio::sync::RwLock;
 
+const GPSD_MIN_BACKOFF: Duration = Duration::from_secs(5);
+const GPSD_MAX_BACKOFF: Duration = Duration::from_secs(300);
+const GPSD_POLL_INTERVAL: Duration = Duration::from_secs(10);
+const TRIP_SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+const TRIP_MIN_SPEED: f32 = 1.0; // knots
+const TRIP_MIN_MOVE_KM: f64 = 0.02;
+const TRIP_MAX_GAP: Duration = Duration::from_secs(300);
+const SURVEY_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+const SURVEY_MIN_SAMPLES: usize = 10;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum GpsSource {
     None,
@@ -16,14 +31,149 @@ pub enum GpsSource {
     Gpsd(String, u16),       // host, port
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FixQuality {
+    NoFix,
+    Fix2D,
+    Fix3D,
+}
+
+impl FixQuality {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FixQuality::NoFix => "none",
+            FixQuality::Fix2D => "2d",
+            FixQuality::Fix3D => "3d",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct GpsPosition {
     pub latitude: f64,
     pub longitude: f64,
     pub altitude: Option<f32>,
-    pub speed: Option<f32>,  // knots
-    pub course: Option<f32>, // degrees
-    pub timestamp: DateTime<Utc>,
+    pub speed: Option<f32>,       // knots
+    pub course: Option<f32>,      // degrees
+    pub timestamp: DateTime<Utc>, // GPS time when the receiver reports it
+    pub fix: FixQuality,
+    pub received: Instant,       // System time the position was received
+    pub satellites: Option<u32>, // Satellites used in the fix
+    pub hdop: Option<f32>,
+}
+
+impl GpsPosition {
+    /// Time since this position was received from the GPS source.
+    pub fn age(&self) -> Duration {
+        self.received.elapsed()
+    }
+
+    pub fn has_fix(&self) -> bool {
+        self.fix != FixQuality::NoFix
+    }
+
+    /// Short receiver health summary, e.g. "3d fix, 9 sats, HDOP 1.1".
+    pub fn quality_summary(&self) -> String {
+        let mut summary = format!("{} fix", self.fix.as_str());
+        if let Some(sats) = self.satellites {
+            summary.push_str(&format!(", {} sats", sats));
+        }
+        if let Some(hdop) = self.hdop {
+            summary.push_str(&format!(", HDOP {:.1}", hdop));
+        }
+        summary
+    }
+}
+
+/// Distance, top speed and time spent moving, accumulated from the
+/// active GPS source.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct TripStats {
+    pub distance_km: f64,
+    pub max_speed: f32, // knots
+    pub moving_secs: u64,
+    #[serde(skip)]
+    last: Option<(f64, f64, Instant)>,
+}
+
+impl TripStats {
+    /// Fold a new position into the trip. Small position changes while the
+    /// receiver reports no speed are treated as jitter so a parked station
+    /// does not creep its odometer up.
+    pub fn update(&mut self, pos: &GpsPosition) {
+        if let Some(speed) = pos.speed {
+            self.max_speed = self.max_speed.max(speed);
+        }
+
+        if let Some((lat, lon, at)) = self.last {
+            let distance = distance_km(lat, lon, pos.latitude, pos.longitude);
+            let elapsed = pos.received.saturating_duration_since(at);
+            let moving = match pos.speed {
+                Some(speed) => speed >= TRIP_MIN_SPEED,
+                None => distance >= TRIP_MIN_MOVE_KM,
+            };
+
+            if moving {
+                self.distance_km += distance;
+                // A long gap means we lost the fix; the distance is real but
+                // we do not know how long the move took
+                if elapsed <= TRIP_MAX_GAP {
+                    self.moving_secs += elapsed.as_secs();
+                }
+            } else if distance < TRIP_MIN_MOVE_KM {
+                // Keep the anchor so slow drift accumulates into a real move
+                return;
+            }
+        }
+
+        self.last = Some((pos.latitude, pos.longitude, pos.received));
+    }
+}
+
+impl GpsSource {
+    /// Build a source from its configuration, or `GpsSource::None` if the
+    /// configuration is incomplete.
+    pub fn from_config(config: &GpsSourceConfig) -> Self {
+        match config.gps_type.as_str() {
+            "serial" => {
+                if let (Some(device), Some(baud)) = (&config.device, config.baud_rate) {
+                    GpsSource::SerialNmea(device.clone(), baud)
+                } else {
+                    GpsSource::None
+                }
+            }
+            "gpsd" => {
+                let host = config.host.as_deref().unwrap_or("localhost");
+                let port = config.port.unwrap_or(2947);
+                GpsSource::Gpsd(host.to_string(), port)
+            }
+            "fixed" => {
+                if let Some(pos_str) = &config.position {
+                    match parse_fixed_position(pos_str) {
+                        Ok(pos) => GpsSource::Fixed(pos),
+                        Err(e) => {
+                            error!("Invalid fixed position: {}", e);
+                            GpsSource::None
+                        }
+                    }
+                } else {
+                    GpsSource::None
+                }
+            }
+            _ => GpsSource::None,
+        }
+    }
+}
+
+impl fmt::Display for GpsSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GpsSource::None => write!(f, "none"),
+            GpsSource::Fixed(_) => write!(f, "fixed"),
+            GpsSource::SerialNmea(device, _) => write!(f, "serial {}", device),
+            GpsSource::Gpsd(host, port) => write!(f, "gpsd {}:{}", host, port),
+        }
+    }
 }
 
 impl PartialEq for GpsPosition {
@@ -33,29 +183,359 @@ impl PartialEq for GpsPosition {
     }
 }
 
+/// Tracks position from one or more GPS sources. Sources are kept in
+/// priority order and the first one with a current fix is used, so a fixed
+/// position can back up a live receiver.
 pub struct GpsTracker {
-    source: GpsSource,
-    position: Arc<RwLock<Option<GpsPosition>>>,
-    nmea_parser: Arc<RwLock<Nmea>>,
+    sources: Vec<SourceState>,
+    active: RwLock<Option<usize>>,
+    stale_after: Duration,
+    trip: RwLock<TripStats>,
+    survey: Option<Survey>,
+}
+
+/// Position survey in progress or completed.
+struct Survey {
+    duration: Duration,
+    file: PathBuf,
+    result: RwLock<Option<GpsPosition>>,
+}
+
+/// Running mean of surveyed fixes.
+#[derive(Debug, Default)]
+struct SurveyAverage {
+    samples: usize,
+    latitude: f64,
+    longitude: f64,
+    altitude: f64,
+    altitude_samples: usize,
+}
+
+impl SurveyAverage {
+    fn add(&mut self, pos: &GpsPosition) {
+        self.samples += 1;
+        self.latitude += pos.latitude;
+        self.longitude += pos.longitude;
+        if let Some(alt) = pos.altitude {
+            self.altitude += alt as f64;
+            self.altitude_samples += 1;
+        }
+    }
+
+    fn mean(&self) -> Option<GpsPosition> {
+        if self.samples == 0 {
+            return None;
+        }
+
+        let altitude = (self.altitude_samples > 0)
+            .then(|| (self.altitude / self.altitude_samples as f64) as f32);
+        Some(GpsPosition {
+            latitude: self.latitude / self.samples as f64,
+            longitude: self.longitude / self.samples as f64,
+            altitude,
+            speed: None,
+            course: None,
+            timestamp: Utc::now(),
+            fix: if altitude.is_some() {
+                FixQuality::Fix3D
+            } else {
+                FixQuality::Fix2D
+            },
+            received: Instant::now(),
+            satellites: None,
+            hdop: None,
+        })
+    }
 }
 
 impl GpsTracker {
     pub fn new(source: GpsSource) -> Self {
+        Self::with_failover(vec![source])
+    }
+
+    /// Create a tracker over several sources, highest priority first.
+    pub fn with_failover(sources: Vec<GpsSource>) -> Self {
         GpsTracker {
+            sources: sources.into_iter().map(SourceState::new).collect(),
+            active: RwLock::new(None),
+            stale_after: Duration::from_secs(30),
+            trip: RwLock::new(TripStats::default()),
+            survey: None,
+        }
+    }
+
+    /// Survey the position for `duration` and then report the average as a
+    /// fixed position. A result saved in `file` by an earlier run is used
+    /// straight away.
+    pub fn with_survey(mut self, duration: Duration, file: impl Into<PathBuf>) -> Self {
+        let file = file.into();
+        let result = match std::fs::read_to_string(&file) {
+            Ok(saved) => match parse_fixed_position(saved.trim()) {
+                Ok(pos) => {
+                    info!(
+                        "Using surveyed position {:.6}, {:.6} from {}",
+                        pos.latitude,
+                        pos.longitude,
+                        file.display()
+                    );
+                    Some(pos)
+                }
+                Err(e) => {
+                    warn!("Ignoring survey file {}: {}", file.display(), e);
+                    None
+                }
+            },
+            Err(_) => None,
+        };
+
+        self.survey = Some(Survey {
+            duration,
+            file,
+            result: RwLock::new(result),
+        });
+        self
+    }
+
+    /// Set how old a position may be before it is no longer considered current.
+    pub fn with_stale_after(mut self, stale_after: Duration) -> Self {
+        self.stale_after = stale_after;
+        self
+    }
+
+    /// Difference between GPS time and the system clock (GPS minus system)
+    /// as of the last time-bearing fix, or None if the receiver has not
+    /// reported time.
+    pub async fn clock_drift(&self) -> Option<chrono::Duration> {
+        for source in &self.sources {
+            if let Some(offset) = *source.clock_offset.read().await {
+                return Some(offset);
+            }
+        }
+        None
+    }
+
+    /// The last position seen, along with its fix quality and age. This may
+    /// be stale; use `get_current_position` when only a live fix will do.
+    pub async fn get_position(&self) -> Option<GpsPosition> {
+        if let Some(pos) = self.get_current_position().await {
+            return Some(pos);
+        }
+
+        // Nothing current, so report whichever source heard something last
+        let mut latest: Option<GpsPosition> = None;
+        for source in &self.sources {
+            if let Some(pos) = source.get_position().await {
+                if latest.is_none_or(|l| pos.received > l.received) {
+                    latest = Some(pos);
+                }
+            }
+        }
+        latest
+    }
+
+    /// The position from the highest-priority source that has a fix no older
+    /// than the staleness limit. Fixed and surveyed positions are always
+    /// current.
+    pub async fn get_current_position(&self) -> Option<GpsPosition> {
+        if let Some(pos) = self.surveyed_position().await {
+            return Some(pos);
+        }
+        self.live_position().await
+    }
+
+    /// The survey result, once the survey has finished.
+    pub async fn surveyed_position(&self) -> Option<GpsPosition> {
+        match &self.survey {
+            Some(survey) => *survey.result.read().await,
+            None => None,
+        }
+    }
+
+    async fn live_position(&self) -> Option<GpsPosition> {
+        for (idx, source) in self.sources.iter().enumerate() {
+            if let Some(pos) = source.get_current_position(self.stale_after).await {
+                self.set_active(Some(idx)).await;
+                return Some(pos);
+            }
+        }
+
+        self.set_active(None).await;
+        None
+    }
+
+    /// Description of the source currently supplying the position.
+    pub async fn active_source(&self) -> Option<String> {
+        if self.surveyed_position().await.is_some() {
+            return Some("survey".to_string());
+        }
+        let active = *self.active.read().await;
+        active.map(|idx| self.sources[idx].source.to_string())
+    }
+
+    async fn set_active(&self, idx: Option<usize>) {
+        let mut active = self.active.write().await;
+        if *active == idx {
+            return;
+        }
+
+        match idx {
+            Some(idx) if self.sources.len() > 1 => {
+                info!("GPS source now active: {}", self.sources[idx].source)
+            }
+            Some(_) => {}
+            None => warn!("No GPS source has a current fix"),
+        }
+        *active = idx;
+    }
+
+    /// Trip odometer accumulated since startup or the last reset.
+    pub async fn trip_stats(&self) -> TripStats {
+        *self.trip.read().await
+    }
+
+    pub async fn reset_trip(&self) {
+        *self.trip.write().await = TripStats::default();
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        let sources = futures::future::join_all(self.sources.iter().map(|source| source.run()));
+        if self
+            .sources
+            .iter()
+            .all(|s| matches!(s.source, GpsSource::None | GpsSource::Fixed(_)))
+        {
+            // Nothing moves, so there is no trip to track
+            return sources.await.into_iter().collect();
+        }
+
+        let (results, _, _) = tokio::join!(sources, self.track_trip(), self.run_survey());
+        results.into_iter().collect()
+    }
+
+    async fn run_survey(&self) {
+        let Some(survey) = &self.survey else {
+            return;
+        };
+        if survey.result.read().await.is_some() {
+            return;
+        }
+
+        info!(
+            "Surveying position for {}s before switching to a fixed position",
+            survey.duration.as_secs()
+        );
+
+        let mut average = SurveyAverage::default();
+        let mut started: Option<Instant> = None;
+        let mut last: Option<Instant> = None;
+        let mut interval = tokio::time::interval(SURVEY_SAMPLE_INTERVAL);
+
+        loop {
+            interval.tick().await;
+            let Some(pos) = self.live_position().await else {
+                continue;
+            };
+            // Skip fallback fixed positions and fixes we already counted
+            if self.active_is_fixed().await || last == Some(pos.received) {
+                continue;
+            }
+            last = Some(pos.received);
+            average.add(&pos);
+
+            let started = *started.get_or_insert(pos.received);
+            if started.elapsed() >= survey.duration && average.samples >= SURVEY_MIN_SAMPLES {
+                break;
+            }
+        }
+
+        let Some(pos) = average.mean() else {
+            return;
+        };
+        info!(
+            "Survey complete after {} fixes: {:.6}, {:.6} alt={:?}m",
+            average.samples, pos.latitude, pos.longitude, pos.altitude
+        );
+
+        let mut saved = format!("{:.7},{:.7}", pos.latitude, pos.longitude);
+        if let Some(alt) = pos.altitude {
+            saved.push_str(&format!(",{:.1}", alt));
+        }
+        saved.push('\n');
+        if let Err(e) = std::fs::write(&survey.file, saved) {
+            error!(
+                "Failed to save survey result to {}: {}",
+                survey.file.display(),
+                e
+            );
+        }
+
+        *survey.result.write().await = Some(pos);
+    }
+
+    async fn active_is_fixed(&self) -> bool {
+        let active = *self.active.read().await;
+        active.is_some_and(|idx| matches!(self.sources[idx].source, GpsSource::Fixed(_)))
+    }
+
+    async fn track_trip(&self) {
+        let mut interval = tokio::time::interval(TRIP_SAMPLE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let Some(pos) = self.live_position().await else {
+                continue;
+            };
+            // A fixed fallback position is not somewhere we drove to
+            if self.active_is_fixed().await {
+                continue;
+            }
+            self.trip.write().await.update(&pos);
+        }
+    }
+}
+
+/// Receiver state for one GPS source.
+struct SourceState {
+    source: GpsSource,
+    position: Arc<RwLock<Option<GpsPosition>>>,
+    nmea_parser: Arc<RwLock<Nmea>>,
+    nmea_fix_mode: Arc<RwLock<Option<FixQuality>>>,
+    clock_offset: Arc<RwLock<Option<chrono::Duration>>>,
+    gpsd_sky: Arc<RwLock<(Option<u32>, Option<f32>)>>, // satellites used, HDOP
+}
+
+impl SourceState {
+    fn new(source: GpsSource) -> Self {
+        SourceState {
             source,
             position: Arc::new(RwLock::new(None)),
             nmea_parser: Arc::new(RwLock::new(Nmea::default())),
+            nmea_fix_mode: Arc::new(RwLock::new(None)),
+            clock_offset: Arc::new(RwLock::new(None)),
+            gpsd_sky: Arc::new(RwLock::new((None, None))),
         }
     }
 
-    pub async fn get_position(&self) -> Option<GpsPosition> {
+    async fn get_position(&self) -> Option<GpsPosition> {
         match &self.source {
             GpsSource::Fixed(pos) => Some(*pos),
             _ => *self.position.read().await,
         }
     }
 
-    pub async fn run(&self) -> Result<()> {
+    async fn get_current_position(&self, stale_after: Duration) -> Option<GpsPosition> {
+        let pos = self.get_position().await?;
+        if matches!(self.source, GpsSource::Fixed(_)) {
+            return Some(pos);
+        }
+
+        if pos.has_fix() && pos.age() <= stale_after {
+            Some(pos)
+        } else {
+            None
+        }
+    }
+
+    async fn run(&self) -> Result<()> {
         match &self.source {
             GpsSource::None => {
                 info!("GPS disabled");
@@ -117,48 +597,88 @@ impl GpsTracker {
     async fn run_gpsd(&self, host: &str, port: u16) -> Result<()> {
         info!("Starting gpsd client connecting to {}:{}", host, port);
 
+        let mut delay = GPSD_MIN_BACKOFF;
+
         loop {
             match self.connect_gpsd(host, port).await {
-                Ok(_) => {
-                    warn!("gpsd connection closed, reconnecting in 5s...");
+                Ok(got_fix) => {
+                    // A session that delivered positions resets the backoff
+                    if got_fix {
+                        delay = GPSD_MIN_BACKOFF;
+                    }
+                    warn!(
+                        "gpsd connection closed, reconnecting in {}s...",
+                        delay.as_secs()
+                    );
                 }
                 Err(e) => {
-                    error!("gpsd connection error: {}, reconnecting in 5s...", e);
+                    error!(
+                        "gpsd connection error: {}, reconnecting in {}s...",
+                        e,
+                        delay.as_secs()
+                    );
                 }
             }
-            tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(GPSD_MAX_BACKOFF);
         }
     }
 
-    async fn connect_gpsd(&self, host: &str, port: u16) -> Result<()> {
+    /// Run one gpsd session. Returns whether any position was received.
+    async fn connect_gpsd(&self, host: &str, port: u16) -> Result<bool> {
         let stream = TcpStream::connect(format!("{}:{}", host, port)).await?;
-        let mut reader = AsyncBufReader::new(stream);
-        let mut line = String::new();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = AsyncBufReader::new(reader).lines();
 
         // Send watch command to start receiving data
-        reader
-            .get_mut()
+        writer
             .write_all(b"?WATCH={\"enable\":true,\"json\":true}\r\n")
             .await?;
 
+        let mut got_fix = false;
+        let mut last_tpv = Instant::now();
+        let mut poll_timer = tokio::time::interval(GPSD_POLL_INTERVAL);
+
         loop {
-            line.clear();
-            match reader.read_line(&mut line).await {
-                Ok(0) => break,
-                Ok(_) => {
-                    self.process_gpsd_json(&line).await;
+            tokio::select! {
+                result = lines.next_line() => {
+                    match result {
+                        Ok(Some(line)) => {
+                            if self.process_gpsd_json(&line).await {
+                                got_fix = true;
+                                last_tpv = Instant::now();
+                            }
+                        }
+                        Ok(None) => break,
+                        Err(e) => {
+                            error!("Error reading from gpsd: {}", e);
+                            break;
+                        }
+                    }
                 }
-                Err(e) => {
-                    error!("Error reading from gpsd: {}", e);
-                    break;
+                _ = poll_timer.tick() => {
+                    // Some gpsd setups never stream TPV on WATCH; fall back to polling
+                    if last_tpv.elapsed() >= GPSD_POLL_INTERVAL {
+                        debug!("No TPV from gpsd recently, sending ?POLL");
+                        writer.write_all(b"?POLL;\r\n").await?;
+                    }
                 }
             }
         }
 
-        Ok(())
+        Ok(got_fix)
     }
 
     async fn process_nmea_sentence(&self, sentence: &str) {
+        // The parser does not keep the GSA 2D/3D mode, so pick it out first
+        if let Ok(nmea::ParseResult::GSA(gsa)) = nmea::parse_str(sentence) {
+            *self.nmea_fix_mode.write().await = Some(match gsa.mode2 {
+                nmea::sentences::gsa::GsaMode2::NoFix => FixQuality::NoFix,
+                nmea::sentences::gsa::GsaMode2::Fix2D => FixQuality::Fix2D,
+                nmea::sentences::gsa::GsaMode2::Fix3D => FixQuality::Fix3D,
+            });
+        }
+
         let mut parser = self.nmea_parser.write().await;
 
         if let Err(e) = parser.parse(sentence) {
@@ -166,6 +686,23 @@ impl GpsTracker {
             return;
         }
 
+        let gps_time = match (parser.fix_date, parser.fix_time) {
+            (Some(date), Some(time)) => Some(NaiveDateTime::new(date, time).and_utc()),
+            _ => None,
+        };
+        if let Some(gps_time) = gps_time {
+            self.update_clock_offset(gps_time).await;
+        }
+
+        let fix = match parser.fix_type {
+            Some(fix_type) if fix_type.is_valid() => match *self.nmea_fix_mode.read().await {
+                Some(mode) => mode,
+                None if parser.altitude.is_some() => FixQuality::Fix3D,
+                None => FixQuality::Fix2D,
+            },
+            _ => FixQuality::NoFix,
+        };
+
         // Check if we have a fix and extract position
         if let Some(lat) = parser.latitude {
             if let Some(lon) = parser.longitude {
@@ -175,7 +712,11 @@ impl GpsTracker {
                     altitude: parser.altitude,
                     speed: parser.speed_over_ground,
                     course: parser.true_course,
-                    timestamp: Utc::now(),
+                    timestamp: gps_time.unwrap_or_else(Utc::now),
+                    fix,
+                    received: Instant::now(),
+                    satellites: parser.num_of_fix_satellites,
+                    hdop: parser.hdop,
                 };
 
                 self.update_position(pos).await;
@@ -183,30 +724,132 @@ impl GpsTracker {
         }
     }
 
-    async fn process_gpsd_json(&self, json_str: &str) {
-        match serde_json::from_str::<serde_json::Value>(json_str) {
-            Ok(json) => {
-                if json["class"] == "TPV" {
-                    if let (Some(lat), Some(lon)) = (json["lat"].as_f64(), json["lon"].as_f64()) {
-                        let pos = GpsPosition {
-                            latitude: lat,
-                            longitude: lon,
-                            altitude: json["alt"].as_f64().map(|a| a as f32),
-                            speed: json["speed"].as_f64().map(|s| (s * 1.94384) as f32), // m/s to knots
-                            course: json["track"].as_f64().map(|c| c as f32),
-                            timestamp: Utc::now(),
-                        };
+    /// Handle one line of gpsd JSON. Returns true if it updated the position.
+    async fn process_gpsd_json(&self, json_str: &str) -> bool {
+        let json = match serde_json::from_str::<serde_json::Value>(json_str) {
+            Ok(json) => json,
+            Err(e) => {
+                debug!("Failed to parse gpsd JSON: {}", e);
+                return false;
+            }
+        };
 
-                        self.update_position(pos).await;
+        match json["class"].as_str() {
+            Some("TPV") => self.process_gpsd_tpv(&json).await,
+            Some("SKY") => {
+                self.process_gpsd_sky(&json).await;
+                false
+            }
+            Some("POLL") => {
+                let mut updated = false;
+                if let Some(skys) = json["sky"].as_array() {
+                    for sky in skys {
+                        self.process_gpsd_sky(sky).await;
                     }
                 }
+                if let Some(tpvs) = json["tpv"].as_array() {
+                    for tpv in tpvs {
+                        updated |= self.process_gpsd_tpv(tpv).await;
+                    }
+                }
+                updated
             }
-            Err(e) => {
-                debug!("Failed to parse gpsd JSON: {}", e);
+            Some("VERSION") => {
+                info!(
+                    "Connected to gpsd {}",
+                    json["release"].as_str().unwrap_or("(unknown version)")
+                );
+                false
+            }
+            Some("DEVICES") => {
+                let count = json["devices"].as_array().map_or(0, |d| d.len());
+                if count == 0 {
+                    warn!("gpsd reports no GPS devices attached");
+                } else {
+                    info!("gpsd reports {} GPS device(s)", count);
+                }
+                false
+            }
+            Some("WATCH") => {
+                if json["enable"] == false {
+                    warn!("gpsd watch is not enabled, relying on ?POLL");
+                } else {
+                    debug!("gpsd watch enabled");
+                }
+                false
+            }
+            Some("ERROR") => {
+                warn!(
+                    "gpsd error: {}",
+                    json["message"].as_str().unwrap_or("(no message)")
+                );
+                false
+            }
+            _ => false,
+        }
+    }
+
+    async fn process_gpsd_tpv(&self, json: &serde_json::Value) -> bool {
+        let gps_time = json["time"]
+            .as_str()
+            .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+            .map(|t| t.with_timezone(&Utc));
+        if let Some(gps_time) = gps_time {
+            self.update_clock_offset(gps_time).await;
+        }
+
+        // Mode 0/1 means gpsd has no fix; its lat/lon are leftovers if present
+        let fix = match json["mode"].as_u64() {
+            Some(3) => FixQuality::Fix3D,
+            Some(2) => FixQuality::Fix2D,
+            _ => {
+                debug!("Ignoring gpsd TPV without a fix");
+                return false;
             }
+        };
+
+        let (Some(lat), Some(lon)) = (json["lat"].as_f64(), json["lon"].as_f64()) else {
+            return false;
+        };
+
+        let sky = *self.gpsd_sky.read().await;
+        let pos = GpsPosition {
+            latitude: lat,
+            longitude: lon,
+            altitude: json["alt"].as_f64().map(|a| a as f32),
+            speed: json["speed"].as_f64().map(|s| (s * 1.94384) as f32), // m/s to knots
+            course: json["track"].as_f64().map(|c| c as f32),
+            timestamp: gps_time.unwrap_or_else(Utc::now),
+            fix,
+            received: Instant::now(),
+            satellites: sky.0,
+            hdop: sky.1,
+        };
+
+        self.update_position(pos).await;
+        true
+    }
+
+    /// gpsd reports satellite usage and DOPs in SKY, separately from TPV.
+    async fn process_gpsd_sky(&self, json: &serde_json::Value) {
+        let satellites = json["uSat"].as_u64().map(|n| n as u32).or_else(|| {
+            json["satellites"]
+                .as_array()
+                .map(|sats| sats.iter().filter(|s| s["used"] == true).count() as u32)
+        });
+        let hdop = json["hdop"].as_f64().map(|h| h as f32);
+        *self.gpsd_sky.write().await = (satellites, hdop);
+
+        if let Some(pos) = self.position.write().await.as_mut() {
+            pos.satellites = satellites;
+            pos.hdop = hdop;
         }
     }
 
+    async fn update_clock_offset(&self, gps_time: DateTime<Utc>) {
+        *self.clock_offset.write().await = Some(gps_time.signed_duration_since(Utc::now()));
+    }
+
     async fn update_position(&self, new_pos: GpsPosition) {
         let mut position = self.position.write().await;
 
@@ -215,12 +858,15 @@ impl GpsTracker {
             Some(old_pos) => {
                 (new_pos.latitude - old_pos.latitude).abs() > 0.0001
                     || (new_pos.longitude - old_pos.longitude).abs() > 0.0001
+                    || new_pos.fix != old_pos.fix
             }
         };
 
         if should_log {
             info!(
-                "GPS position: {:.6}, {:.6} alt={:?}m speed={:?}kts course={:?}°",
+                "GPS position at {} ({:?}): {:.6}, {:.6} alt={:?}m speed={:?}kts course={:?}°",
+                new_pos.timestamp.format("%H:%M:%SZ"),
+                new_pos.fix,
                 new_pos.latitude,
                 new_pos.longitude,
                 new_pos.altitude,
@@ -233,6 +879,19 @@ impl GpsTracker {
     }
 }
 
+/// Great-circle distance in km between two points (haversine formula).
+pub fn distance_km(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let dlat = (lat2 - lat1).to_radians();
+    let dlon = (lon2 - lon1).to_radians();
+    let lat1 = lat1.to_radians();
+    let lat2 = lat2.to_radians();
+
+    let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    let c = 2.0 * a.sqrt().asin();
+
+    6371.0 * c // Earth radius in km
+}
+
 pub fn parse_fixed_position(pos_str: &str) -> Result<GpsPosition> {
     let parts: Vec<&str> = pos_str.split(',').collect();
     if parts.len() < 2 {
@@ -276,6 +935,14 @@ pub fn parse_fixed_position(pos_str: &str) -> Result<GpsPosition> {
         speed: None,
         course: None,
         timestamp: Utc::now(),
+        fix: if altitude.is_some() {
+            FixQuality::Fix3D
+        } else {
+            FixQuality::Fix2D
+        },
+        received: Instant::now(),
+        satellites: None,
+        hdop: None,
     })
 }
 
@@ -339,6 +1006,10 @@ mod tests {
             speed: Some(10.0),
             course: Some(180.0),
             timestamp: Utc::now(),
+            fix: FixQuality::Fix3D,
+            received: Instant::now(),
+            satellites: None,
+            hdop: None,
         };
 
         let pos2 = GpsPosition {
@@ -348,6 +1019,10 @@ mod tests {
             speed: Some(20.0),
             course: Some(90.0),
             timestamp: Utc::now(),
+            fix: FixQuality::Fix3D,
+            received: Instant::now(),
+            satellites: None,
+            hdop: None,
         };
 
         let pos3 = GpsPosition {
@@ -357,6 +1032,10 @@ mod tests {
             speed: Some(10.0),
             course: Some(180.0),
             timestamp: Utc::now(),
+            fix: FixQuality::Fix3D,
+            received: Instant::now(),
+            satellites: None,
+            hdop: None,
         };
 
         assert_eq!(pos1, pos2); // Same lat/lon
@@ -372,6 +1051,10 @@ mod tests {
             speed: None,
             course: None,
             timestamp: Utc::now(),
+            fix: FixQuality::Fix3D,
+            received: Instant::now(),
+            satellites: None,
+            hdop: None,
         };
 
         let tracker = GpsTracker::new(GpsSource::Fixed(pos));
@@ -388,33 +1071,298 @@ mod tests {
         assert!(tracker.get_position().await.is_none());
     }
 
+    #[tokio::test]
+    async fn test_nmea_processing() {
+        let tracker = GpsTracker::new(GpsSource::None);
+
+        // RMC carries both date and time, so the fix is stamped with GPS time
+        let rmc = "$GPRMC,225446,A,4916.45,N,12311.12,W,000.5,054.7,191194,020.3,E*68";
+        tracker.sources[0].process_nmea_sentence(rmc).await;
+
+        let pos = tracker.get_position().await.unwrap();
+        assert!((pos.latitude - 49.274167).abs() < 0.0001);
+        assert!((pos.longitude + 123.185333).abs() < 0.0001);
+        assert_eq!(
+            pos.timestamp.format("%Y-%m-%d %H:%M:%S").to_string(),
+            "1994-11-19 22:54:46"
+        );
+
+        // The system clock is decades ahead of this fix
+        let drift = tracker.clock_drift().await.unwrap();
+        assert!(drift.num_days() < -365);
+    }
+
+    #[tokio::test]
+    async fn test_nmea_satellites_and_hdop() {
+        let tracker = GpsTracker::new(GpsSource::None);
+
+        let gga = "$GPGGA,092750.000,5321.6802,N,00630.3372,W,1,8,1.03,61.7,M,55.2,M,,*76";
+        tracker.sources[0].process_nmea_sentence(gga).await;
+
+        let pos = tracker.get_position().await.unwrap();
+        assert_eq!(pos.satellites, Some(8));
+        assert_eq!(pos.hdop, Some(1.03));
+        assert_eq!(pos.quality_summary(), "3d fix, 8 sats, HDOP 1.0");
+    }
+
+    #[tokio::test]
+    async fn test_gpsd_sky() {
+        let tracker = GpsTracker::new(GpsSource::None);
+        let source = &tracker.sources[0];
+
+        let sky = r#"{"class":"SKY","hdop":0.9,"satellites":[{"PRN":1,"used":true},{"PRN":2,"used":false},{"PRN":3,"used":true}]}"#;
+        source.process_gpsd_json(sky).await;
+        let tpv = r#"{"class":"TPV","mode":3,"lat":40.0,"lon":-74.0}"#;
+        source.process_gpsd_json(tpv).await;
+
+        let pos = tracker.get_position().await.unwrap();
+        assert_eq!(pos.satellites, Some(2));
+        assert_eq!(pos.hdop, Some(0.9));
+
+        // Newer gpsd reports the count directly, and SKY updates in place
+        source
+            .process_gpsd_json(r#"{"class":"SKY","uSat":11,"hdop":0.7}"#)
+            .await;
+        let pos = tracker.get_position().await.unwrap();
+        assert_eq!(pos.satellites, Some(11));
+        assert_eq!(pos.hdop, Some(0.7));
+    }
+
+    #[tokio::test]
+    async fn test_gpsd_json_processing() {
+        let tracker = GpsTracker::new(GpsSource::None);
+
+        let json = r#"{"class":"TPV","device":"/dev/ttyUSB0","mode":3,"time":"2024-01-01T12:00:00Z","lat":40.7128,"lon":-74.0060,"alt":100.0,"speed":5.14444,"track":180.0}"#;
+        tracker.sources[0].process_gpsd_json(json).await;
+
+        let pos = tracker.get_position().await.unwrap();
+        assert_eq!(pos.latitude, 40.7128);
+        assert_eq!(pos.longitude, -74.0060);
+        assert!((pos.speed.unwrap() - 10.0).abs() < 0.01);
+        assert_eq!(pos.timestamp.to_rfc3339(), "2024-01-01T12:00:00+00:00");
+        assert!(tracker.clock_drift().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_no_clock_drift_without_gps_time() {
+        let tracker = GpsTracker::new(GpsSource::None);
+        tracker.sources[0]
+            .process_gpsd_json(r#"{"class":"TPV","mode":2,"lat":1.0,"lon":2.0}"#)
+            .await;
+
+        assert!(tracker.get_position().await.is_some());
+        assert!(tracker.clock_drift().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_fix_quality_from_gpsd_mode() {
+        let tracker = GpsTracker::new(GpsSource::None);
+
+        tracker.sources[0]
+            .process_gpsd_json(r#"{"class":"TPV","mode":2,"lat":1.0,"lon":2.0}"#)
+            .await;
+        assert_eq!(tracker.get_position().await.unwrap().fix, FixQuality::Fix2D);
+
+        tracker.sources[0]
+            .process_gpsd_json(r#"{"class":"TPV","mode":3,"lat":1.0,"lon":2.0,"alt":5.0}"#)
+            .await;
+        assert_eq!(tracker.get_position().await.unwrap().fix, FixQuality::Fix3D);
+
+        // TPV reports without a fix are ignored
+        assert!(
+            !tracker.sources[0]
+                .process_gpsd_json(r#"{"class":"TPV","mode":1,"lat":3.0,"lon":4.0}"#)
+                .await
+        );
+        let pos = tracker.get_position().await.unwrap();
+        assert_eq!(pos.latitude, 1.0);
+        assert_eq!(pos.fix, FixQuality::Fix3D);
+    }
+
+    #[tokio::test]
+    async fn test_gpsd_poll_response() {
+        let tracker = GpsTracker::new(GpsSource::None);
+        let poll = r#"{"class":"POLL","time":"2024-01-01T12:00:00Z","active":1,"tpv":[{"class":"TPV","mode":3,"lat":10.0,"lon":20.0}],"sky":[]}"#;
+
+        assert!(tracker.sources[0].process_gpsd_json(poll).await);
+        assert_eq!(tracker.get_position().await.unwrap().latitude, 10.0);
+    }
+
+    #[tokio::test]
+    async fn test_gpsd_handshake_messages() {
+        let tracker = GpsTracker::new(GpsSource::None);
+
+        assert!(
+            !tracker.sources[0]
+                .process_gpsd_json(r#"{"class":"VERSION","release":"3.22","proto_major":3}"#)
+                .await
+        );
+        assert!(
+            !tracker.sources[0]
+                .process_gpsd_json(r#"{"class":"DEVICES","devices":[{"path":"/dev/ttyACM0"}]}"#)
+                .await
+        );
+        assert!(
+            !tracker.sources[0]
+                .process_gpsd_json(r#"{"class":"WATCH","enable":true,"json":true}"#)
+                .await
+        );
+        assert!(!tracker.sources[0].process_gpsd_json("not json").await);
+        assert!(tracker.get_position().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_fix_quality_from_nmea_gsa() {
+        let tracker = GpsTracker::new(GpsSource::None);
+
+        // RMC has no altitude, so only GSA can tell us this is a 3D fix
+        tracker.sources[0]
+            .process_nmea_sentence("$GPGSA,A,3,04,05,,09,12,,,24,,,,,2.5,1.3,2.1*39")
+            .await;
+        tracker.sources[0]
+            .process_nmea_sentence(
+                "$GPRMC,225446,A,4916.45,N,12311.12,W,000.5,054.7,191194,020.3,E*68",
+            )
+            .await;
+
+        assert_eq!(tracker.get_position().await.unwrap().fix, FixQuality::Fix3D);
+    }
+
+    #[tokio::test]
+    async fn test_stale_position_not_current() {
+        let tracker = GpsTracker::new(GpsSource::None).with_stale_after(Duration::from_secs(10));
+        tracker.sources[0]
+            .process_gpsd_json(r#"{"class":"TPV","mode":3,"lat":1.0,"lon":2.0}"#)
+            .await;
+        assert!(tracker.get_current_position().await.is_some());
+
+        // Backdate the fix beyond the staleness limit
+        {
+            let mut position = tracker.sources[0].position.write().await;
+            let pos = position.as_mut().unwrap();
+            pos.received = Instant::now() - Duration::from_secs(11);
+        }
+
+        assert!(tracker.get_position().await.is_some());
+        assert!(tracker.get_current_position().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_fixed_position_always_current() {
+        let mut pos = parse_fixed_position("40.0,-74.0").unwrap();
+        pos.received = Instant::now() - Duration::from_secs(3600);
+
+        let tracker = GpsTracker::new(GpsSource::Fixed(pos));
+        assert!(tracker.get_current_position().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_failover_to_lower_priority_source() {
+        let fixed = parse_fixed_position("40.0,-74.0").unwrap();
+        let tracker = GpsTracker::with_failover(vec![
+            GpsSource::Gpsd("localhost".to_string(), 2947),
+            GpsSource::Fixed(fixed),
+        ]);
+
+        // gpsd has nothing yet, so the fixed position is used
+        let pos = tracker.get_current_position().await.unwrap();
+        assert_eq!(pos.latitude, 40.0);
+        assert_eq!(tracker.active_source().await.as_deref(), Some("fixed"));
+
+        // Once gpsd has a fix it takes over
+        tracker.sources[0]
+            .process_gpsd_json(r#"{"class":"TPV","mode":3,"lat":41.0,"lon":-75.0}"#)
+            .await;
+        let pos = tracker.get_current_position().await.unwrap();
+        assert_eq!(pos.latitude, 41.0);
+        assert_eq!(
+            tracker.active_source().await.as_deref(),
+            Some("gpsd localhost:2947")
+        );
+    }
+
     #[test]
-    fn test_nmea_processing() {
-        let _tracker = GpsTracker::new(GpsSource::None);
+    fn test_source_from_config() {
+        let config = GpsSourceConfig {
+            gps_type: "gpsd".to_string(),
+            device: None,
+            baud_rate: None,
+            host: None,
+            port: None,
+            position: None,
+        };
+        assert_eq!(
+            GpsSource::from_config(&config),
+            GpsSource::Gpsd("localhost".to_string(), 2947)
+        );
+
+        let config = GpsSourceConfig {
+            gps_type: "serial".to_string(),
+            ..config
+        };
+        assert_eq!(GpsSource::from_config(&config), GpsSource::None);
+    }
+
+    #[test]
+    fn test_trip_stats() {
+        let start = Instant::now();
+        let at = |lat: f64, speed: f32, secs: u64| GpsPosition {
+            latitude: lat,
+            longitude: -74.0,
+            altitude: None,
+            speed: Some(speed),
+            course: None,
+            timestamp: Utc::now(),
+            fix: FixQuality::Fix3D,
+            received: start + Duration::from_secs(secs),
+            satellites: None,
+            hdop: None,
+        };
+
+        let mut trip = TripStats::default();
+        trip.update(&at(40.0, 0.0, 0));
+        // About 1.1 km north in a minute
+        trip.update(&at(40.01, 35.0, 60));
+        trip.update(&at(40.02, 42.0, 120));
+        // Parked: no distance or moving time added
+        trip.update(&at(40.02, 0.0, 180));
 
-        // Test GGA sentence
-        let _gga = "$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47";
-        // Note: This would need the NMEA parser to be properly initialized
-        // and the function to be made testable
+        assert!((trip.distance_km - 2.22).abs() < 0.01);
+        assert_eq!(trip.max_speed, 42.0);
+        assert_eq!(trip.moving_secs, 120);
     }
 
     #[test]
-    fn test_gpsd_json_processing() {
-        let _tracker = GpsTracker::new(GpsSource::None);
-
-        // Test TPV JSON
-        let _json = r#"{
-            "class": "TPV",
-            "device": "/dev/ttyUSB0",
-            "mode": 3,
-            "time": "2024-01-01T12:00:00Z",
-            "lat": 40.7128,
-            "lon": -74.0060,
-            "alt": 100.0,
-            "speed": 5.14444,
-            "track": 180.0
-        }"#;
-
-        // This would need the process_gpsd_json to be made testable
+    fn test_survey_average() {
+        let mut average = SurveyAverage::default();
+        assert!(average.mean().is_none());
+
+        for (lat, alt) in [(40.0001, Some(10.0)), (39.9999, Some(20.0)), (40.0, None)] {
+            let mut pos = parse_fixed_position("0,-74.0").unwrap();
+            pos.latitude = lat;
+            pos.altitude = alt;
+            average.add(&pos);
+        }
+
+        let mean = average.mean().unwrap();
+        assert!((mean.latitude - 40.0).abs() < 1e-9);
+        assert!((mean.longitude + 74.0).abs() < 1e-9);
+        assert_eq!(mean.altitude, Some(15.0));
+        assert_eq!(mean.fix, FixQuality::Fix3D);
+    }
+
+    #[tokio::test]
+    async fn test_saved_survey_is_fixed_position() {
+        let file = std::env::temp_dir().join(format!("aprstx-survey-{}", std::process::id()));
+        std::fs::write(&file, "40.7128000,-74.0060000,12.5\n").unwrap();
+
+        let tracker = GpsTracker::new(GpsSource::Gpsd("localhost".to_string(), 2947))
+            .with_survey(Duration::from_secs(600), &file);
+        std::fs::remove_file(&file).unwrap();
+
+        let pos = tracker.get_current_position().await.unwrap();
+        assert_eq!(pos.latitude, 40.7128);
+        assert_eq!(pos.altitude, Some(12.5));
+        assert_eq!(tracker.active_source().await.as_deref(), Some("survey"));
     }
 }
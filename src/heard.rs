@@ -0,0 +1,357 @@
+//! Heard stations list (mheard).
+//!
+//! Every station heard on each interface: when it was first and last heard,
+//! how many packets it sent, where it last said it was, the path its last
+//! packet took and whether it was heard direct. It answers "is this station
+//! local?" for gating from APRS-IS to RF and for the IGATE beacon, and can
+//! be saved to a file so a restart doesn't forget who's around.
+
+use crate::aprs::grid::{grid_center, status_grid};
+use crate::aprs::AprsPacket;
+use crate::config::HeardConfig;
+use crate::filter::APRS_IS_PORT;
+use crate::shutdown::Shutdown;
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+lazy_static! {
+    pub static ref HEARD: HeardList = HeardList::default();
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HeardStation {
+    pub first_heard: DateTime<Utc>,
+    pub last_heard: DateTime<Utc>,
+    pub packets: u64,
+    pub position: Option<[f64; 2]>, // [lat, lon] from the last position report
+    pub path: String,               // Path of the last packet, comma separated
+    pub direct: bool,               // Last packet arrived without a digipeater
+    pub last_direct: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub direct_position: Option<[f64; 2]>, // [lat, lon] from the last position heard direct
+    #[serde(default)]
+    pub grid: Option<String>, // Maidenhead locator from the last status report with one
+}
+
+type Stations = HashMap<String, HashMap<String, HeardStation>>; // Port, then callsign
+
+#[derive(Default)]
+pub struct HeardList {
+    ports: Mutex<Stations>,
+}
+
+impl HeardList {
+    /// Record a packet heard on `port`
+    pub fn note(&self, port: &str, packet: &AprsPacket, direct: bool, now: DateTime<Utc>) {
+        let mut ports = self.ports.lock().unwrap();
+        let station = ports
+            .entry(port.to_string())
+            .or_default()
+            .entry(packet.source.to_string())
+            .or_insert_with(|| HeardStation {
+                first_heard: now,
+                last_heard: now,
+                packets: 0,
+                position: None,
+                path: String::new(),
+                direct: false,
+                last_direct: None,
+                direct_position: None,
+                grid: None,
+            });
+        station.last_heard = now;
+        station.packets += 1;
+        if let Some(pos) = packet.position() {
+            station.position = Some([pos.latitude, pos.longitude]);
+            if direct {
+                station.direct_position = station.position;
+            }
+        } else if let Some(grid) = status_grid(&packet.information) {
+            // A locator is only a rough position, better than none
+            if station.position.is_none() {
+                station.position = grid_center(&grid);
+            }
+            station.grid = Some(grid);
+        }
+        station.path = packet
+            .path
+            .iter()
+            .map(|hop| hop.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        station.direct = direct;
+        if direct {
+            station.last_direct = Some(now);
+        }
+    }
+
+    /// Everything heard, or just what was heard on one port
+    pub fn stations(&self, port: Option<&str>) -> Stations {
+        let ports = self.ports.lock().unwrap();
+        ports
+            .iter()
+            .filter(|(name, _)| port.is_none_or(|p| p == *name))
+            .map(|(name, stations)| (name.clone(), stations.clone()))
+            .collect()
+    }
+
+    /// Stations heard on each port
+    pub fn counts(&self) -> Vec<(String, usize)> {
+        let ports = self.ports.lock().unwrap();
+        let mut counts: Vec<_> = ports
+            .iter()
+            .map(|(name, stations)| (name.clone(), stations.len()))
+            .collect();
+        counts.sort();
+        counts
+    }
+
+    /// Whether `call` was heard on any RF port within `window`
+    pub fn heard_on_rf(&self, call: &str, now: DateTime<Utc>, window: Duration) -> bool {
+        let ports = self.ports.lock().unwrap();
+        ports
+            .iter()
+            .filter(|(name, _)| *name != APRS_IS_PORT)
+            .filter_map(|(_, stations)| stations.get(call))
+            .any(|station| now - station.last_heard < window)
+    }
+
+    /// Distinct stations heard direct on RF within `window`
+    pub fn direct_count(&self, now: DateTime<Utc>, window: Duration) -> usize {
+        let ports = self.ports.lock().unwrap();
+        let mut calls: Vec<&String> = ports
+            .iter()
+            .filter(|(name, _)| *name != APRS_IS_PORT)
+            .flat_map(|(_, stations)| stations.iter())
+            .filter(|(_, station)| station.last_direct.is_some_and(|t| now - t < window))
+            .map(|(call, _)| call)
+            .collect();
+        calls.sort();
+        calls.dedup();
+        calls.len()
+    }
+
+    /// Where stations heard direct on RF within `window` last said they
+    /// were, when heard direct
+    pub fn direct_positions(&self, now: DateTime<Utc>, window: Duration) -> Vec<[f64; 2]> {
+        let ports = self.ports.lock().unwrap();
+        let mut positions: HashMap<&String, [f64; 2]> = HashMap::new();
+        for (call, station) in ports
+            .iter()
+            .filter(|(name, _)| *name != APRS_IS_PORT)
+            .flat_map(|(_, stations)| stations.iter())
+            .filter(|(_, station)| station.last_direct.is_some_and(|t| now - t < window))
+        {
+            if let Some(position) = station.direct_position {
+                positions.insert(call, position);
+            }
+        }
+        positions.into_values().collect()
+    }
+
+    /// Stations heard on RF, or only those heard direct, most recent first
+    pub fn calls(&self, direct: bool) -> Vec<String> {
+        let ports = self.ports.lock().unwrap();
+        let mut heard: Vec<(DateTime<Utc>, &String)> = ports
+            .iter()
+            .filter(|(name, _)| *name != APRS_IS_PORT)
+            .flat_map(|(_, stations)| stations.iter())
+            .filter_map(|(call, station)| {
+                let when = if direct {
+                    station.last_direct?
+                } else {
+                    station.last_heard
+                };
+                Some((when, call))
+            })
+            .collect();
+        heard.sort_by(|a, b| b.cmp(a));
+        let mut calls: Vec<String> = Vec::new();
+        for (_, call) in heard {
+            if !calls.contains(call) {
+                calls.push(call.clone());
+            }
+        }
+        calls
+    }
+
+    /// Forget stations not heard for `max_age`
+    pub fn prune(&self, now: DateTime<Utc>, max_age: Duration) {
+        let mut ports = self.ports.lock().unwrap();
+        for stations in ports.values_mut() {
+            stations.retain(|_, station| now - station.last_heard < max_age);
+        }
+        ports.retain(|_, stations| !stations.is_empty());
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string(&*self.ports.lock().unwrap())?;
+        // Write then rename, so a crash mid-save leaves the old list intact
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, json)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Merge in a saved list, keeping anything already heard since
+    pub fn load(&self, path: &Path) -> Result<usize> {
+        let saved: Stations = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        let mut ports = self.ports.lock().unwrap();
+        let mut loaded = 0;
+        for (port, stations) in saved {
+            let known = ports.entry(port).or_default();
+            for (call, station) in stations {
+                if let std::collections::hash_map::Entry::Vacant(e) = known.entry(call) {
+                    e.insert(station);
+                    loaded += 1;
+                }
+            }
+        }
+        Ok(loaded)
+    }
+}
+
+/// Keep the heard list trimmed, and saved if it has a file, including once
+/// more on shutdown
+pub async fn run_heard_list(config: HeardConfig, shutdown: Shutdown) -> Result<()> {
+    if let Some(file) = &config.file {
+        match HEARD.load(Path::new(file)) {
+            Ok(count) => info!("Loaded {} heard stations from {}", count, file),
+            Err(e) => warn!("Failed to load heard stations from {}: {}", file, e),
+        }
+    }
+
+    let max_age = Duration::hours(config.expire as i64);
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(
+        config.save_interval.max(1) as u64,
+    ));
+    interval.tick().await;
+
+    loop {
+        let stopping = tokio::select! {
+            _ = interval.tick() => false,
+            _ = shutdown.wait() => true,
+        };
+        HEARD.prune(Utc::now(), max_age);
+        if let Some(file) = &config.file {
+            match HEARD.save(Path::new(file)) {
+                Ok(()) => debug!("Saved heard stations to {}", file),
+                Err(e) => warn!("Failed to save heard stations to {}: {}", file, e),
+            }
+        }
+        if stopping {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aprs::parse_packet;
+
+    #[test]
+    fn test_note() {
+        let heard = HeardList::default();
+        let start = Utc::now();
+        let later = start + Duration::minutes(5);
+
+        heard.note(
+            "vhf",
+            &parse_packet("N1CALL-9>APRS:!4903.50N/07201.75W>").unwrap(),
+            true,
+            start,
+        );
+        heard.note(
+            "vhf",
+            &parse_packet("N1CALL-9>APRS,WIDE1*,WIDE2-1:>Status").unwrap(),
+            false,
+            later,
+        );
+        heard.note(
+            APRS_IS_PORT,
+            &parse_packet("N2CALL>APRS,TCPIP*:>IO91SX/G Status").unwrap(),
+            false,
+            later,
+        );
+
+        let stations = heard.stations(Some("vhf"));
+        let station = &stations["vhf"]["N1CALL-9"];
+        assert_eq!(station.first_heard, start);
+        assert_eq!(station.last_heard, later);
+        assert_eq!(station.packets, 2);
+        assert_eq!(station.path, "WIDE1*,WIDE2-1");
+        assert!(!station.direct);
+        assert_eq!(station.last_direct, Some(start));
+        assert!((station.position.unwrap()[0] - 49.0583).abs() < 0.001);
+        assert_eq!(station.direct_position, station.position);
+        assert_eq!(
+            heard.direct_positions(later, Duration::minutes(30)).len(),
+            1
+        );
+        assert!(heard
+            .direct_positions(later, Duration::minutes(1))
+            .is_empty());
+
+        let station = &heard.stations(Some(APRS_IS_PORT))[APRS_IS_PORT]["N2CALL"];
+        assert_eq!(station.grid.as_deref(), Some("IO91SX"));
+        assert!((station.position.unwrap()[0] - 51.98).abs() < 0.01);
+
+        assert_eq!(
+            heard.counts(),
+            vec![(APRS_IS_PORT.to_string(), 1), ("vhf".to_string(), 1)]
+        );
+        assert!(heard.heard_on_rf("N1CALL-9", later, Duration::minutes(30)));
+        assert!(!heard.heard_on_rf("N2CALL", later, Duration::minutes(30)));
+    }
+
+    #[test]
+    fn test_direct_count_and_prune() {
+        let heard = HeardList::default();
+        let start = Utc::now();
+        let packet = parse_packet("N1CALL>APRS:>Status").unwrap();
+        heard.note("vhf", &packet, true, start);
+        heard.note("uhf", &packet, true, start);
+        heard.note(
+            "vhf",
+            &parse_packet("N2CALL>APRS:>Status").unwrap(),
+            true,
+            start + Duration::minutes(20),
+        );
+
+        let window = Duration::minutes(30);
+        assert_eq!(heard.direct_count(start + Duration::minutes(20), window), 2);
+        assert_eq!(heard.direct_count(start + Duration::minutes(40), window), 1);
+        assert_eq!(heard.calls(true), vec!["N2CALL", "N1CALL"]);
+
+        heard.prune(start + Duration::minutes(40), window);
+        assert_eq!(heard.counts(), vec![("vhf".to_string(), 1)]);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("heard.json");
+        let heard = HeardList::default();
+        let now = Utc::now();
+        heard.note(
+            "vhf",
+            &parse_packet("N1CALL>APRS:>Status").unwrap(),
+            true,
+            now,
+        );
+        heard.save(&file).unwrap();
+
+        let restored = HeardList::default();
+        assert_eq!(restored.load(&file).unwrap(), 1);
+        assert_eq!(restored.stations(None), heard.stations(None));
+        // Loading again doesn't overwrite what's there
+        assert_eq!(restored.load(&file).unwrap(), 0);
+    }
+}
@@ -0,0 +1,230 @@
+//! IGATE capability beacon.
+//!
+//! Sends `<IGATE,MSG_CNT=n,LOC_CNT=n` so APRS-IS clients and aprs.fi list
+//! us as a bidirectional igate. MSG_CNT is the messages gated from APRS-IS
+//! to RF since the last beacon, LOC_CNT the stations heard direct on RF in
+//! the last half hour.
+//!
+//! Also keeps the budget for gating the other way, from APRS-IS to RF.
+
+use crate::aprs::{AprsPacket, CallSign};
+use crate::channel::{self, Overflow};
+use crate::config::{IgateBeaconConfig, RfBudgetConfig};
+use crate::heard::HEARD;
+use crate::router::{PacketSource, RoutedPacket, TAG_ISONLY};
+use crate::telemetry::TELEMETRY_STATS;
+use anyhow::Result;
+use chrono::{Duration, Utc};
+use log::info;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use tokio::sync::mpsc;
+
+/// How long a station heard direct counts as local, in minutes
+const LOCAL_WINDOW: i64 = 30;
+
+fn capabilities(msg_cnt: u64, loc_cnt: usize) -> String {
+    format!("<IGATE,MSG_CNT={},LOC_CNT={}", msg_cnt, loc_cnt)
+}
+
+pub async fn run_igate_beacon(
+    config: IgateBeaconConfig,
+    callsign: String,
+    tx: mpsc::Sender<RoutedPacket>,
+) -> Result<()> {
+    info!(
+        "Starting IGATE capability beacon with interval {}s",
+        config.interval
+    );
+
+    let source = CallSign::parse(&callsign).unwrap_or(CallSign::new("N0CALL", 0));
+    let mut interval =
+        tokio::time::interval(tokio::time::Duration::from_secs(config.interval as u64));
+    let mut last_msg_cnt = 0;
+
+    loop {
+        interval.tick().await;
+
+        let gated = TELEMETRY_STATS
+            .messages_igate_is_to_rf
+            .load(Ordering::Relaxed);
+        let loc_cnt = HEARD.direct_count(Utc::now(), Duration::minutes(LOCAL_WINDOW));
+        let mut packet = AprsPacket::new(
+            source.clone(),
+            CallSign::new("APRS", 0),
+            capabilities(gated - last_msg_cnt, loc_cnt),
+        );
+        last_msg_cnt = gated;
+        if !config.rf {
+            packet.tags.push(TAG_ISONLY.to_string());
+        }
+
+        info!("Sending IGATE capabilities: {}", packet.information);
+        let routed = RoutedPacket {
+            packet,
+            source: PacketSource::Internal,
+        };
+        channel::send(&tx, routed, channel::ROUTER, Overflow::Drop).await;
+    }
+}
+
+/// Packets gated from APRS-IS to RF over the last minute and hour, held
+/// to the configured caps. Messages have a budget of their own, so a
+/// flood of positions can't hold up traffic for local stations.
+#[derive(Debug)]
+pub struct GateBudget {
+    messages: Budget,
+    positions: Budget,
+}
+
+/// How much of one budget has been used, for the status report
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BudgetUse {
+    pub last_minute: usize,
+    pub last_hour: usize,
+    pub per_minute: Option<u32>,
+    pub per_hour: Option<u32>,
+    pub refused: u64, // Packets kept off RF since startup
+}
+
+#[derive(Debug)]
+struct Budget {
+    per_minute: Option<u32>,
+    per_hour: Option<u32>,
+    sent: VecDeque<Instant>,
+    refused: u64,
+}
+
+const MINUTE: std::time::Duration = std::time::Duration::from_secs(60);
+const HOUR: std::time::Duration = std::time::Duration::from_secs(3600);
+
+impl GateBudget {
+    pub fn new(config: &RfBudgetConfig) -> Self {
+        GateBudget {
+            messages: Budget::new(config.messages_per_minute, config.messages_per_hour),
+            positions: Budget::new(config.positions_per_minute, config.positions_per_hour),
+        }
+    }
+
+    /// Spend from the packet's budget, or refuse it if that's used up
+    pub fn take(&mut self, packet: &AprsPacket, now: Instant) -> bool {
+        self.budget(packet).take(now)
+    }
+
+    /// Use of the message and position budgets, in that order
+    pub fn used(&mut self, now: Instant) -> (BudgetUse, BudgetUse) {
+        (self.messages.used(now), self.positions.used(now))
+    }
+
+    fn budget(&mut self, packet: &AprsPacket) -> &mut Budget {
+        match packet.message() {
+            Some(_) => &mut self.messages,
+            None => &mut self.positions,
+        }
+    }
+}
+
+impl Budget {
+    fn new(per_minute: Option<u32>, per_hour: Option<u32>) -> Self {
+        Budget {
+            per_minute,
+            per_hour,
+            sent: VecDeque::new(),
+            refused: 0,
+        }
+    }
+
+    fn take(&mut self, now: Instant) -> bool {
+        self.prune(now);
+        let full = |cap: Option<u32>, sent: usize| cap.is_some_and(|cap| sent >= cap as usize);
+        if full(self.per_minute, self.last_minute(now)) || full(self.per_hour, self.sent.len()) {
+            self.refused += 1;
+            return false;
+        }
+        self.sent.push_back(now);
+        true
+    }
+
+    fn used(&mut self, now: Instant) -> BudgetUse {
+        self.prune(now);
+        BudgetUse {
+            last_minute: self.last_minute(now),
+            last_hour: self.sent.len(),
+            per_minute: self.per_minute,
+            per_hour: self.per_hour,
+            refused: self.refused,
+        }
+    }
+
+    fn last_minute(&self, now: Instant) -> usize {
+        self.sent
+            .iter()
+            .rev()
+            .take_while(|t| now.duration_since(**t) < MINUTE)
+            .count()
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while self
+            .sent
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= HOUR)
+        {
+            self.sent.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aprs::parse_packet;
+
+    #[test]
+    fn test_gate_budget() {
+        let mut budget = GateBudget::new(&RfBudgetConfig {
+            messages_per_minute: Some(2),
+            positions_per_minute: Some(1),
+            positions_per_hour: Some(3),
+            ..Default::default()
+        });
+        let message = parse_packet("N1CALL>APRS::N2CALL   :Hi{1").unwrap();
+        let position = parse_packet("N1CALL>APRS:!4903.50N/07201.75W>").unwrap();
+        let start = Instant::now();
+        let at = |secs| start + std::time::Duration::from_secs(secs);
+
+        assert!(budget.take(&position, at(0)));
+        assert!(!budget.take(&position, at(30)));
+        // Messages don't count against positions
+        assert!(budget.take(&message, at(30)));
+        assert!(budget.take(&message, at(30)));
+        assert!(!budget.take(&message, at(31)));
+
+        assert!(budget.take(&position, at(60)));
+        assert!(budget.take(&position, at(120)));
+        // Within the minute's cap but over the hour's
+        assert!(!budget.take(&position, at(180)));
+        assert!(budget.take(&position, at(3600)));
+
+        let (messages, positions) = budget.used(at(3600));
+        assert_eq!(messages.last_hour, 2);
+        assert_eq!(messages.refused, 1);
+        assert_eq!(
+            positions,
+            BudgetUse {
+                last_minute: 1,
+                last_hour: 3,
+                per_minute: Some(1),
+                per_hour: Some(3),
+                refused: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_capabilities() {
+        assert_eq!(capabilities(3, 12), "<IGATE,MSG_CNT=3,LOC_CNT=12");
+    }
+}
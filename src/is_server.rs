@@ -0,0 +1,641 @@
+//! APRS-IS server for local clients.
+//!
+//! Speaks the client side of the APRS-IS protocol on a local port, so
+//! programs like Xastir, YAAC or APRSIS32 can log in as they would to an
+//! APRS-IS server and share this station's radio and uplink:
+//!
+//! ```text
+//! # aprstx 0.1.0
+//! user N0CALL-7 pass 12345 vers Xastir 2.1.8 filter r/49.06/-72.03/50
+//! # logresp N0CALL-7 verified, server N0CALL-10
+//! ```
+//!
+//! Clients get the packets aprstx hears on RF and APRS-IS that pass their
+//! filter, in the server-side filter syntax: `r/`, `p/`, `b/`, `o/`, `t/`,
+//! `d/`, `u/`, `a/` and `m/`, each of which can be negated with a leading
+//! `-`. A `#filter` line changes it after login. Packets a verified client
+//! sends are routed like this station's own, and seen by the other clients.
+
+use crate::aprs::packet::DataType;
+use crate::aprs::{parse_packet, AprsPacket};
+use crate::channel::{self, Overflow};
+use crate::config::IsServerConfig;
+use crate::gps::distance_km;
+use crate::network::calculate_passcode;
+use crate::router::{PacketSource, RoutedPacket, TAG_ISONLY};
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use log::{debug, info, warn};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::timeout;
+
+/// Drops counted against the server when a client falls behind
+const IS_SERVER_CHANNEL: &str = "aprs-is-server";
+
+/// How long a client has to log in
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+const KEEPALIVE: Duration = Duration::from_secs(20);
+
+/// Longest line we take from a client
+const MAX_LINE: usize = 512;
+
+/// Packets from clients held for the others
+const UPLOAD_BUFFER: usize = 100;
+
+pub async fn run_is_server(
+    config: IsServerConfig,
+    mycall: String,
+    heard: broadcast::Sender<RoutedPacket>,
+    tx: mpsc::Sender<RoutedPacket>,
+) -> Result<()> {
+    let listener = TcpListener::bind(&config.listen).await?;
+    info!("APRS-IS server listening on {}", config.listen);
+
+    let config = Arc::new(config);
+    let (uploads, _) = broadcast::channel(UPLOAD_BUFFER);
+    let clients = Arc::new(AtomicUsize::new(0));
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        if clients.load(Ordering::Relaxed) >= config.max_clients {
+            warn!("APRS-IS server full, turning away {}", addr);
+            continue;
+        }
+        clients.fetch_add(1, Ordering::Relaxed);
+        let client = Client {
+            config: config.clone(),
+            mycall: mycall.clone(),
+            addr,
+            tx: tx.clone(),
+            uploads: uploads.clone(),
+        };
+        let (heard_rx, clients) = (heard.subscribe(), clients.clone());
+        tokio::spawn(async move {
+            match client.run(stream, heard_rx).await {
+                Ok(()) => info!("APRS-IS client {} disconnected", addr),
+                Err(e) => info!("APRS-IS client {} disconnected: {}", addr, e),
+            }
+            clients.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+}
+
+/// A client's upload, tagged with who sent it so it isn't sent back
+#[derive(Clone)]
+struct Upload {
+    from: SocketAddr,
+    packet: AprsPacket,
+}
+
+struct Client {
+    config: Arc<IsServerConfig>,
+    mycall: String,
+    addr: SocketAddr,
+    tx: mpsc::Sender<RoutedPacket>,
+    uploads: broadcast::Sender<Upload>,
+}
+
+/// What a client said when logging in
+#[derive(Debug, PartialEq)]
+struct Login {
+    callsign: String,
+    verified: bool,
+    software: String,
+    filter: Option<String>,
+}
+
+impl Client {
+    async fn run(
+        &self,
+        stream: TcpStream,
+        mut heard_rx: broadcast::Receiver<RoutedPacket>,
+    ) -> Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader);
+        writer
+            .write_all(format!("# aprstx {}\r\n", env!("CARGO_PKG_VERSION")).as_bytes())
+            .await?;
+
+        let mut line = String::new();
+        let login = loop {
+            line.clear();
+            if timeout(LOGIN_TIMEOUT, read_line(&mut lines, &mut line)).await?? == 0 {
+                return Ok(());
+            }
+            if let Some(login) = parse_login(line.trim()) {
+                break login;
+            }
+        };
+        writer
+            .write_all(
+                format!(
+                    "# logresp {} {}, server {}\r\n",
+                    login.callsign,
+                    if login.verified {
+                        "verified"
+                    } else {
+                        "unverified"
+                    },
+                    self.mycall
+                )
+                .as_bytes(),
+            )
+            .await?;
+        info!(
+            "APRS-IS client {} logged in as {} ({}, {})",
+            self.addr,
+            login.callsign,
+            login.software,
+            if login.verified {
+                "verified"
+            } else {
+                "unverified"
+            }
+        );
+
+        let mut filter = ClientFilter::default();
+        if let Some(text) = &login.filter {
+            self.set_filter(&mut filter, text, &mut writer).await?;
+        }
+
+        let mut uploads_rx = self.uploads.subscribe();
+        let mut keepalive = tokio::time::interval(KEEPALIVE);
+        loop {
+            line.clear();
+            tokio::select! {
+                result = read_line(&mut lines, &mut line) => {
+                    if result? == 0 {
+                        return Ok(());
+                    }
+                    let line = line.trim();
+                    if let Some(text) = line.strip_prefix("#filter") {
+                        self.set_filter(&mut filter, text.trim(), &mut writer).await?;
+                    } else if !line.is_empty() && !line.starts_with('#') {
+                        self.upload(&login, &mut filter, line).await;
+                    }
+                }
+
+                result = heard_rx.recv() => match result {
+                    Ok(routed) => {
+                        if filter.matches(&routed.packet) {
+                            writer.write_all(format!("{}\r\n", routed.packet).as_bytes()).await?;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("APRS-IS client {} fell behind, dropped {} packets", self.addr, missed);
+                        channel::record_drops(IS_SERVER_CHANNEL, missed);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+
+                Ok(upload) = uploads_rx.recv() => {
+                    if upload.from != self.addr && filter.matches(&upload.packet) {
+                        writer.write_all(format!("{}\r\n", upload.packet).as_bytes()).await?;
+                    }
+                }
+
+                _ = keepalive.tick() => {
+                    let line = format!(
+                        "# aprstx {} {}\r\n",
+                        Utc::now().format("%d %b %Y %H:%M:%S GMT"),
+                        self.mycall
+                    );
+                    writer.write_all(line.as_bytes()).await?;
+                }
+            }
+        }
+    }
+
+    async fn set_filter(
+        &self,
+        filter: &mut ClientFilter,
+        text: &str,
+        writer: &mut (impl AsyncWriteExt + Unpin),
+    ) -> Result<()> {
+        let reply = match ClientFilter::parse(text) {
+            Ok(parsed) => {
+                debug!("APRS-IS client {} filter: {}", self.addr, text);
+                // Keep the client's own position for m/
+                let own = filter.own_position;
+                *filter = parsed;
+                filter.own_position = own;
+                format!("# filter {} active\r\n", text)
+            }
+            Err(e) => format!("# filter {} rejected: {}\r\n", text, e),
+        };
+        writer.write_all(reply.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Route a packet the client sent, if it may send
+    async fn upload(&self, login: &Login, filter: &mut ClientFilter, line: &str) {
+        if !login.verified {
+            debug!("Ignoring packet from unverified client {}", login.callsign);
+            return;
+        }
+        let mut packet = match parse_packet(line) {
+            Ok(packet) => packet,
+            Err(e) => {
+                debug!("Bad packet from APRS-IS client {}: {}", login.callsign, e);
+                return;
+            }
+        };
+        if packet
+            .source
+            .to_string()
+            .eq_ignore_ascii_case(&login.callsign)
+        {
+            if let Some(pos) = packet.position() {
+                filter.own_position = Some([pos.latitude, pos.longitude]);
+            }
+        }
+        // What the client put there for APRS-IS has no place on RF
+        packet.path.retain(|hop| {
+            let call = hop.call.to_ascii_uppercase();
+            !(call.starts_with("TCPIP") || call.starts_with("TCPXX") || call.starts_with("QA"))
+        });
+        info!("RX [APRS-IS client {}]: {}", login.callsign, packet);
+
+        // Sending only fails when no other client is connected
+        self.uploads
+            .send(Upload {
+                from: self.addr,
+                packet: packet.clone(),
+            })
+            .ok();
+        if !self.config.rf {
+            packet.tags.push(TAG_ISONLY.to_string());
+        }
+        let routed = RoutedPacket {
+            packet,
+            source: PacketSource::Internal,
+        };
+        channel::send(&self.tx, routed, channel::ROUTER, Overflow::Block).await;
+    }
+}
+
+/// Read a line, refusing to buffer one longer than any packet
+async fn read_line(lines: &mut (impl AsyncBufReadExt + Unpin), line: &mut String) -> Result<usize> {
+    let read = lines.read_line(line).await?;
+    if line.len() > MAX_LINE {
+        return Err(anyhow!("line too long"));
+    }
+    Ok(read)
+}
+
+/// `user CALL pass CODE vers SOFTWARE VERSION [filter ...]`
+fn parse_login(line: &str) -> Option<Login> {
+    let mut words = line.split_whitespace();
+    if !words.next()?.eq_ignore_ascii_case("user") {
+        return None;
+    }
+    let callsign = words.next()?.to_uppercase();
+    let mut passcode = None;
+    let mut software = Vec::new();
+    let mut filter = None;
+    while let Some(word) = words.next() {
+        match word.to_ascii_lowercase().as_str() {
+            "pass" => passcode = words.next().and_then(|p| p.parse::<i32>().ok()),
+            "vers" => {
+                software = words.by_ref().take(2).collect();
+            }
+            "filter" => {
+                filter = Some(words.by_ref().collect::<Vec<_>>().join(" "));
+            }
+            _ => {}
+        }
+    }
+    let verified = passcode.is_some_and(|p| p >= 0 && p == calculate_passcode(&callsign));
+    Some(Login {
+        callsign,
+        verified,
+        software: software.join(" "),
+        filter: filter.filter(|f| !f.is_empty()),
+    })
+}
+
+/// One term of a server-side filter
+#[derive(Debug, Clone, PartialEq)]
+enum Term {
+    Range {
+        center: [f64; 2],
+        km: f64,
+    },
+    Prefix(Vec<String>),
+    Budlist(Vec<String>),
+    Object(Vec<String>),
+    Type(Vec<char>),
+    Digipeater(Vec<String>),
+    Unproto(Vec<String>),
+    Area {
+        north: f64,
+        west: f64,
+        south: f64,
+        east: f64,
+    },
+    MyRange(f64),
+}
+
+/// A client's filter: packets matching any term, except those matching a
+/// negated term. An empty filter passes nothing, as on APRS-IS.
+#[derive(Debug, Clone, Default)]
+struct ClientFilter {
+    include: Vec<Term>,
+    exclude: Vec<Term>,
+    own_position: Option<[f64; 2]>, // From the client's own uploads, for m/
+}
+
+impl ClientFilter {
+    fn parse(text: &str) -> Result<Self> {
+        let mut filter = ClientFilter::default();
+        for word in text.split_whitespace() {
+            let (negated, word) = match word.strip_prefix('-') {
+                Some(word) => (true, word),
+                None => (false, word),
+            };
+            let term = Term::parse(word)?;
+            if negated {
+                filter.exclude.push(term);
+            } else {
+                filter.include.push(term);
+            }
+        }
+        Ok(filter)
+    }
+
+    fn matches(&self, packet: &AprsPacket) -> bool {
+        self.include
+            .iter()
+            .any(|t| t.matches(packet, self.own_position))
+            && !self
+                .exclude
+                .iter()
+                .any(|t| t.matches(packet, self.own_position))
+    }
+}
+
+impl Term {
+    fn parse(word: &str) -> Result<Self> {
+        let mut parts = word.split('/');
+        let kind = parts.next().unwrap_or_default();
+        let args: Vec<&str> = parts.collect();
+        let number = |i: usize| -> Result<f64> {
+            args.get(i)
+                .and_then(|a| a.parse::<f64>().ok())
+                .filter(|n| n.is_finite())
+                .ok_or_else(|| anyhow!("bad number in {}", word))
+        };
+        let names = || -> Result<Vec<String>> {
+            if args.is_empty() || args.iter().any(|a| a.is_empty()) {
+                return Err(anyhow!("{} needs names", word));
+            }
+            Ok(args.iter().map(|a| a.to_uppercase()).collect())
+        };
+        Ok(match kind {
+            "r" => Term::Range {
+                center: [number(0)?, number(1)?],
+                km: number(2)?,
+            },
+            "p" => Term::Prefix(names()?),
+            "b" => Term::Budlist(names()?),
+            "o" => Term::Object(names()?),
+            "d" => Term::Digipeater(names()?),
+            "u" => Term::Unproto(names()?),
+            "t" => {
+                let types: Vec<char> = args.first().copied().unwrap_or_default().chars().collect();
+                if types.is_empty() || types.iter().any(|c| !"poimqstunw".contains(*c)) {
+                    return Err(anyhow!("bad types in {}", word));
+                }
+                Term::Type(types)
+            }
+            "a" => Term::Area {
+                north: number(0)?,
+                west: number(1)?,
+                south: number(2)?,
+                east: number(3)?,
+            },
+            "m" => Term::MyRange(number(0)?),
+            _ => return Err(anyhow!("unsupported filter {}", word)),
+        })
+    }
+
+    fn matches(&self, packet: &AprsPacket, own_position: Option<[f64; 2]>) -> bool {
+        let position = packet.position().map(|p| [p.latitude, p.longitude]);
+        let within = |center: [f64; 2], km: f64| {
+            position.is_some_and(|[lat, lon]| distance_km(center[0], center[1], lat, lon) <= km)
+        };
+        match self {
+            Term::Range { center, km } => within(*center, *km),
+            Term::MyRange(km) => own_position.is_some_and(|center| within(center, *km)),
+            Term::Prefix(prefixes) => {
+                let source = packet.source.to_string().to_uppercase();
+                prefixes.iter().any(|p| source.starts_with(p.as_str()))
+            }
+            Term::Budlist(calls) => matches_any(calls, &packet.source.to_string()),
+            Term::Object(names) => packet
+                .object()
+                .is_some_and(|o| matches_any(names, o.name.trim_end())),
+            Term::Digipeater(calls) => packet
+                .path
+                .iter()
+                .filter(|hop| hop.digipeated)
+                .any(|hop| matches_any(calls, &hop.to_string().replace('*', ""))),
+            Term::Unproto(calls) => matches_any(calls, &packet.destination.to_string()),
+            Term::Type(types) => types.iter().any(|t| has_type(packet, *t)),
+            Term::Area {
+                north,
+                west,
+                south,
+                east,
+            } => position.is_some_and(|[lat, lon]| {
+                lat <= *north && lat >= *south && lon >= *west && lon <= *east
+            }),
+        }
+    }
+}
+
+/// Callsign or name patterns, exact or with a trailing `*`
+fn matches_any(patterns: &[String], name: &str) -> bool {
+    let name = name.to_uppercase();
+    patterns.iter().any(|p| match p.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == *p,
+    })
+}
+
+/// Whether a packet is of a `t/` type
+fn has_type(packet: &AprsPacket, kind: char) -> bool {
+    let message = packet.message();
+    match kind {
+        'p' => matches!(packet.data_type, DataType::Position | DataType::MicE),
+        'o' => packet.data_type == DataType::Object,
+        'i' => packet.data_type == DataType::Item,
+        'm' => message.is_some(),
+        'q' => message.is_some_and(|m| m.text.starts_with('?')),
+        's' => packet.data_type == DataType::Status,
+        't' => packet.data_type == DataType::Telemetry,
+        'u' => packet.data_type == DataType::UserDefined,
+        'n' => message.is_some_and(|m| m.addressee.starts_with("NWS")),
+        'w' => packet.data_type == DataType::Weather,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(filter: &str, packet: &str) -> bool {
+        ClientFilter::parse(filter)
+            .unwrap()
+            .matches(&parse_packet(packet).unwrap())
+    }
+
+    #[test]
+    fn test_parse_login() {
+        let passcode = calculate_passcode("N0CALL");
+        let login = parse_login(&format!(
+            "user n0call-7 pass {} vers Xastir 2.1.8 filter r/49/-72/50 t/m",
+            passcode
+        ))
+        .unwrap();
+        assert_eq!(
+            login,
+            Login {
+                callsign: "N0CALL-7".to_string(),
+                verified: true,
+                software: "Xastir 2.1.8".to_string(),
+                filter: Some("r/49/-72/50 t/m".to_string()),
+            }
+        );
+
+        assert!(
+            !parse_login("user N0CALL pass -1 vers test 1")
+                .unwrap()
+                .verified
+        );
+        assert!(
+            !parse_login("user N0CALL pass 1 vers test 1")
+                .unwrap()
+                .verified
+        );
+        assert_eq!(parse_login("N0CALL>APRS:>Hi"), None);
+    }
+
+    #[test]
+    fn test_filters() {
+        let position = "N1CALL-9>APRS,WIDE1*,WIDE2-1:!4903.50N/07201.75W>";
+        assert!(matches("r/49.05/-72.03/5", position));
+        assert!(!matches("r/40/-74/50", position));
+        assert!(matches("p/N1 p/K", position));
+        assert!(matches("b/N1CALL-9", position));
+        assert!(matches("b/N1C*", position));
+        assert!(!matches("b/N1CALL", position));
+        assert!(matches("d/WIDE1", position));
+        assert!(matches("t/p", position));
+        assert!(!matches("t/mw", position));
+        assert!(matches("a/50/-73/48/-71", position));
+        assert!(matches("u/APRS", position));
+
+        // Negated terms take out what the rest let in
+        assert!(!matches("t/p -b/N1CALL-9", position));
+        // Nothing asked for, nothing sent
+        assert!(!matches("", position));
+
+        let object = "N0CALL>APRS:;LEADER   *092345z4903.50N/07201.75W>";
+        assert!(matches("o/LEAD*", object));
+        assert!(matches("t/o", object));
+        assert!(matches("t/q", "N0CALL>APRS::N1CALL   :?APRSD"));
+
+        assert!(ClientFilter::parse("r/1/2").is_err());
+        assert!(ClientFilter::parse("t/x").is_err());
+        assert!(ClientFilter::parse("f/N0CALL/10").is_err());
+    }
+
+    #[test]
+    fn test_my_range() {
+        let mut filter = ClientFilter::parse("m/10").unwrap();
+        let packet = parse_packet("N1CALL>APRS:!4903.50N/07201.75W>").unwrap();
+        assert!(!filter.matches(&packet));
+        filter.own_position = Some([49.06, -72.0]);
+        assert!(filter.matches(&packet));
+    }
+
+    #[tokio::test]
+    async fn test_client_session() {
+        let config: IsServerConfig = toml::from_str("listen = \"127.0.0.1:0\"").unwrap();
+        let (tx, mut routed) = mpsc::channel(10);
+        let (heard, _) = broadcast::channel(10);
+        let client = Client {
+            config: Arc::new(config),
+            mycall: "N0CALL-10".to_string(),
+            addr: "127.0.0.1:1".parse().unwrap(),
+            tx,
+            uploads: broadcast::channel(10).0,
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let heard_rx = heard.subscribe();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            client.run(stream, heard_rx).await
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        assert!(lines
+            .next_line()
+            .await
+            .unwrap()
+            .unwrap()
+            .starts_with("# aprstx"));
+        let login = format!(
+            "user N0CALL-7 pass {} vers test 1.0 filter t/s\r\n",
+            calculate_passcode("N0CALL")
+        );
+        writer.write_all(login.as_bytes()).await.unwrap();
+        assert_eq!(
+            lines.next_line().await.unwrap().unwrap(),
+            "# logresp N0CALL-7 verified, server N0CALL-10"
+        );
+        assert_eq!(
+            lines.next_line().await.unwrap().unwrap(),
+            "# filter t/s active"
+        );
+
+        // Only what passes the filter comes back
+        let heard_packet = |text: &str| RoutedPacket {
+            packet: parse_packet(text).unwrap(),
+            source: PacketSource::SerialPort("vhf".to_string()),
+        };
+        heard
+            .send(heard_packet("N1CALL>APRS:!4903.50N/07201.75W>"))
+            .unwrap();
+        heard.send(heard_packet("N2CALL>APRS:>Status")).unwrap();
+        let mut next = lines.next_line().await.unwrap().unwrap();
+        while next.starts_with('#') {
+            next = lines.next_line().await.unwrap().unwrap();
+        }
+        assert_eq!(next, "N2CALL>APRS:>Status");
+
+        // The client's packet goes to the router, without its TCPIP path
+        writer
+            .write_all(b"N0CALL-7>APRS,TCPIP*:>On the air\r\n")
+            .await
+            .unwrap();
+        let sent = routed.recv().await.unwrap();
+        assert_eq!(sent.packet.to_string(), "N0CALL-7>APRS:>On the air");
+        assert_eq!(sent.source, PacketSource::Internal);
+
+        drop(writer);
+        server.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,221 @@
+//! Spool for packets bound for APRS-IS while the connection is down.
+//!
+//! Packets are appended to a file as they are held, one per line after
+//! the time they were received, so a restart during an outage keeps them:
+//!
+//! ```text
+//! 2024-06-01T14:03:09.120Z N1CALL>APRS,WIDE1-1:>Hello
+//! ```
+//!
+//! That is a form `aprstx replay` reads too. Once the connection is back
+//! the backlog is uploaded oldest first, leaving out anything older than
+//! the configured age: a position reported long after it was heard is
+//! worse than none, since APRS-IS shows it as current.
+
+use crate::aprs::AprsPacket;
+use crate::config::IsSpoolConfig;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Packets in the spool, for the control socket
+static SPOOLED: AtomicUsize = AtomicUsize::new(0);
+
+pub fn spooled() -> usize {
+    SPOOLED.load(Ordering::Relaxed)
+}
+
+pub struct IsSpool {
+    path: PathBuf,
+    max_packets: usize,
+    max_age: chrono::Duration,
+    packets: VecDeque<(DateTime<Utc>, String)>,
+}
+
+impl IsSpool {
+    /// Open the spool, picking up whatever an earlier run left in it
+    pub fn open(config: &IsSpoolConfig) -> Result<Self> {
+        let path = PathBuf::from(&config.file);
+        let mut spool = IsSpool {
+            path,
+            max_packets: config.max_packets.max(1),
+            max_age: chrono::Duration::seconds(config.max_age as i64),
+            packets: VecDeque::new(),
+        };
+        match std::fs::read_to_string(&spool.path) {
+            Ok(text) => {
+                spool.packets = text
+                    .lines()
+                    .filter_map(|line| {
+                        let (time, packet) = line.split_once(' ')?;
+                        let time = DateTime::parse_from_rfc3339(time).ok()?;
+                        Some((time.with_timezone(&Utc), packet.to_string()))
+                    })
+                    .collect();
+                spool.expire(Utc::now());
+                spool.trim();
+                spool.rewrite()?;
+                if !spool.packets.is_empty() {
+                    info!(
+                        "{} packets waiting in {} for APRS-IS",
+                        spool.packets.len(),
+                        spool.path.display()
+                    );
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(anyhow!("Failed to read {}: {}", spool.path.display(), e)),
+        }
+        spool.count();
+        Ok(spool)
+    }
+
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// Hold a packet until the connection is back. A full spool loses its
+    /// oldest packets.
+    pub fn push(&mut self, packet: &AprsPacket) {
+        let entry = (packet.timestamp, packet.to_string());
+        self.packets.push_back(entry);
+        let result = if self.trim() {
+            self.rewrite()
+        } else {
+            self.append()
+        };
+        if let Err(e) = result {
+            warn!("Failed to write {}: {}", self.path.display(), e);
+        }
+        self.count();
+    }
+
+    /// Drop packets too old to be worth uploading
+    pub fn expire(&mut self, now: DateTime<Utc>) {
+        let before = self.packets.len();
+        self.packets.retain(|(time, _)| now - *time <= self.max_age);
+        let expired = before - self.packets.len();
+        if expired > 0 {
+            info!("Dropped {} spooled packets too old for APRS-IS", expired);
+        }
+        self.count();
+    }
+
+    /// Packets to upload, oldest first
+    pub fn packets(&self) -> impl Iterator<Item = &str> {
+        self.packets.iter().map(|(_, packet)| packet.as_str())
+    }
+
+    /// Forget the first `count` packets, now that they are uploaded
+    pub fn remove(&mut self, count: usize) -> Result<()> {
+        self.packets.drain(..count.min(self.packets.len()));
+        self.count();
+        self.rewrite()
+    }
+
+    /// Keep to the size limit, dropping a tenth at a time so a full
+    /// spool isn't rewritten for every packet
+    fn trim(&mut self) -> bool {
+        if self.packets.len() <= self.max_packets {
+            return false;
+        }
+        let keep = self.max_packets - self.max_packets / 10;
+        let dropped = self.packets.len() - keep;
+        self.packets.drain(..dropped);
+        warn!("APRS-IS spool full, dropped its {} oldest packets", dropped);
+        true
+    }
+
+    fn append(&self) -> Result<()> {
+        let Some((time, packet)) = self.packets.back() else {
+            return Ok(());
+        };
+        let mut out = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        out.write_all(spool_line(*time, packet).as_bytes())?;
+        Ok(())
+    }
+
+    fn rewrite(&self) -> Result<()> {
+        let text: String = self
+            .packets
+            .iter()
+            .map(|(time, packet)| spool_line(*time, packet))
+            .collect();
+        let mut out = File::create(&self.path)
+            .map_err(|e| anyhow!("Failed to open {}: {}", self.path.display(), e))?;
+        out.write_all(text.as_bytes())?;
+        Ok(())
+    }
+
+    fn count(&self) {
+        SPOOLED.store(self.packets.len(), Ordering::Relaxed);
+    }
+}
+
+fn spool_line(time: DateTime<Utc>, packet: &str) -> String {
+    format!("{} {}\n", time.format("%Y-%m-%dT%H:%M:%S%.3fZ"), packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aprs::parse_packet;
+
+    fn config(dir: &tempfile::TempDir, max_packets: usize) -> IsSpoolConfig {
+        IsSpoolConfig {
+            file: dir.path().join("aprs-is.spool").display().to_string(),
+            max_packets,
+            max_age: 300,
+        }
+    }
+
+    fn packet(info: &str, age: i64) -> AprsPacket {
+        let mut packet = parse_packet(&format!("N1CALL>APRS:>{}", info)).unwrap();
+        packet.timestamp = Utc::now() - chrono::Duration::seconds(age);
+        packet
+    }
+
+    #[test]
+    fn test_spool_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut spool = IsSpool::open(&config(&dir, 10)).unwrap();
+        spool.push(&packet("Old", 600));
+        spool.push(&packet("One", 10));
+        spool.push(&packet("Two", 5));
+        drop(spool);
+
+        // What expired while we were down isn't uploaded
+        let mut spool = IsSpool::open(&config(&dir, 10)).unwrap();
+        let packets: Vec<&str> = spool.packets().collect();
+        assert_eq!(packets, ["N1CALL>APRS:>One", "N1CALL>APRS:>Two"]);
+
+        spool.remove(1).unwrap();
+        let spool = IsSpool::open(&config(&dir, 10)).unwrap();
+        let packets: Vec<&str> = spool.packets().collect();
+        assert_eq!(packets, ["N1CALL>APRS:>Two"]);
+    }
+
+    #[test]
+    fn test_full_spool_drops_oldest() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut spool = IsSpool::open(&config(&dir, 20)).unwrap();
+        for i in 0..21 {
+            spool.push(&packet(&i.to_string(), 0));
+        }
+        assert_eq!(spool.len(), 18);
+        assert_eq!(spool.packets().next(), Some("N1CALL>APRS:>3"));
+        assert_eq!(IsSpool::open(&config(&dir, 20)).unwrap().len(), 18);
+    }
+}
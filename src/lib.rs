@@ -1,11 +1,44 @@
+pub mod alert;
 pub mod aprs;
 pub mod beacon;
+pub mod capture;
+pub mod channel;
 pub mod config;
+pub mod control;
+pub mod coverage;
+pub mod dedup;
 pub mod digipeater;
 pub mod filter;
+pub mod geofence;
 pub mod gps;
+pub mod heard;
+pub mod igate;
+pub mod is_server;
+pub mod is_spool;
 pub mod message;
+pub mod metrics;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "nats")]
+pub mod nats;
 pub mod network;
+pub mod objects;
+pub mod plugin;
+pub mod proxy;
+pub mod replay;
+pub mod rig;
 pub mod router;
 pub mod serial;
+pub mod shutdown;
+pub mod station_id;
+pub mod status;
+pub mod storage;
+pub mod supervisor;
 pub mod telemetry;
+pub mod template;
+pub mod track;
+pub mod tracklog;
+pub mod udp;
+pub mod units;
+pub mod waypoint;
+pub mod websocket;
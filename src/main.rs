@@ -1,26 +1,30 @@
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use log::info;
 use std::path::PathBuf;
 use tokio::signal;
 
-mod aprs;
-mod beacon;
-mod config;
-mod digipeater;
-mod filter;
-mod gps;
-mod message;
-mod network;
-mod router;
-mod serial;
-mod telemetry;
-
-use config::Config;
-use filter::PacketFilter;
-use router::PacketRouter;
+use aprstx::config::{self, Config};
+use aprstx::filter::{PacketFilter, APRS_IS_PORT};
+use aprstx::plugin::{Context, Registry};
+use aprstx::replay::{self, ReplayOptions};
+use aprstx::router::PacketRouter;
+use aprstx::shutdown::Shutdown;
+use aprstx::supervisor::{supervise, Policy};
+use aprstx::track::TrackOptions;
+use aprstx::{
+    alert, beacon, control, coverage, digipeater, gps, heard, igate, is_server, message, metrics,
+    network, objects, rig, serial, station_id, status, telemetry, tracklog, udp, waypoint,
+    websocket,
+};
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, Mutex};
+
+/// How long tasks get to finish up after a shutdown signal
+const SHUTDOWN_GRACE: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Seconds a replay waits after the log for the last packets to go out
+const REPLAY_SETTLE: u64 = 2;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -33,12 +37,42 @@ struct Args {
 
     #[arg(short, long)]
     foreground: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run a simple tracker without a config file
+    Track(TrackOptions),
+    /// Replay a TNC2 log through the router, printing what would be sent
+    Replay(ReplayOptions),
+    /// Send an APRS message through the running daemon and wait for the ack
+    Msg {
+        /// Callsign to send to
+        to: String,
+        /// Message text, up to 67 characters
+        text: String,
+    },
+    /// Print the configuration in effect, with defaults filled in
+    DumpConfig {
+        /// Print the commented default configuration instead
+        #[arg(long)]
+        defaults: bool,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    aprstx::template::start();
     let args = Args::parse();
 
+    if let Some(Command::DumpConfig { defaults: true }) = &args.command {
+        print!("{}", config::EXAMPLE_CONFIG);
+        return Ok(());
+    }
+
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(if args.debug {
         "debug"
     } else {
@@ -46,136 +80,612 @@ async fn main() -> Result<()> {
     }))
     .init();
 
-    info!("Starting aprstx daemon...");
-
-    let config = match Config::load(&args.config) {
+    let loaded = match &args.command {
+        Some(Command::Track(options)) => options.config(),
+        _ => Config::load(&args.config),
+    };
+    let config = match loaded {
         Ok(config) => Arc::new(config),
         Err(e) => {
             eprintln!("Error: {}", e);
             std::process::exit(1);
         }
     };
-    info!("Loaded configuration from {:?}", args.config);
+
+    if let Some(Command::DumpConfig { .. }) = &args.command {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+
+    if let Some(Command::Msg { to, text }) = &args.command {
+        if let Err(e) = send_message(&config, to, text).await {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    info!("Starting aprstx daemon...");
+    match &args.command {
+        Some(Command::Track(options)) => info!("Tracking as {}", options.call),
+        _ => info!("Loaded configuration from {:?}", args.config),
+    }
 
     // Create packet filter
-    let filter = Arc::new(PacketFilter::new(config.filters.clone())?);
+    let mut filter = PacketFilter::new(config.filters.clone())?;
+    for port in &config.serial_ports {
+        filter = filter.with_port(&port.name, &port.inbound_filters, &port.outbound_filters)?;
+    }
+    if let Some(aprs_is) = &config.aprs_is {
+        filter = filter.with_port(
+            APRS_IS_PORT,
+            &aprs_is.inbound_filters,
+            &aprs_is.outbound_filters,
+        )?;
+    }
+    let filter = Arc::new(filter);
 
     // Create main packet channel
     let (packet_tx, packet_rx) = mpsc::channel(1000);
 
     // Create router
-    let (router, channels) = PacketRouter::new(config.clone(), filter, packet_rx);
-
+    let plugins = Registry::new().load(&Context {
+        config: &config,
+        filter: &filter,
+    })?;
+    // Tasks that tidy up and finish once shutdown is triggered go in
+    // stopping rather than handles
+    let shutdown = Shutdown::new();
     let mut handles = vec![];
+    let mut stopping = vec![];
 
-    // Start router
-    let handle = tokio::spawn(router.run());
-    handles.push(handle);
+    let (router, mut channels) = PacketRouter::new(config.clone(), filter.clone(), packet_rx);
+    let mut router = router.with_plugins(plugins).with_shutdown(shutdown.clone());
+    let gate_budget = config
+        .aprs_is
+        .as_ref()
+        .and_then(|a| a.rf_budget.as_ref())
+        .map(|budget| Arc::new(std::sync::Mutex::new(igate::GateBudget::new(budget))));
+    if let Some(budget) = &gate_budget {
+        router = router.with_gate_budget(budget.clone());
+    }
+
+    // Start router. The supervisor holds the only reference, so once the
+    // router stops on shutdown its channels close behind it.
+    let router = Arc::new(Mutex::new(router));
+    let handle = supervise(
+        "Packet router",
+        Policy::Critical,
+        shutdown.clone(),
+        move || {
+            let router = router.clone();
+            async move { router.lock_owned().await.run().await }
+        },
+    );
+    stopping.push(handle);
+
+    // A replay stands in for the serial ports and APRS-IS
+    let replaying = match &args.command {
+        Some(Command::Replay(options)) => Some(options.clone()),
+        _ => None,
+    };
 
     // Start serial ports
     for serial_config in &config.serial_ports {
-        let tx = packet_tx.clone();
-        let rf_rx = channels.rf_tx.subscribe();
-        let handle = tokio::spawn(serial::run_serial_port(serial_config.clone(), tx, rf_rx));
-        handles.push(handle);
+        let Some(rf_rx) = channels.rf_rx.remove(&serial_config.name) else {
+            anyhow::bail!("Duplicate serial port name {}", serial_config.name);
+        };
+        if replaying.is_some() {
+            let handle = tokio::spawn(replay::print_transmissions(
+                serial_config.name.clone(),
+                rf_rx,
+            ));
+            stopping.push(handle);
+            continue;
+        }
+        let name = format!("Serial port {}", serial_config.name);
+        let handle = supervise(name, Policy::Restart, shutdown.clone(), {
+            let (serial_config, filter, tx, shutdown) = (
+                serial_config.clone(),
+                filter.clone(),
+                packet_tx.clone(),
+                shutdown.clone(),
+            );
+            let rf_rx = Arc::new(Mutex::new(rf_rx));
+            move || {
+                let (config, filter, tx, rf_rx, shutdown) = (
+                    serial_config.clone(),
+                    filter.clone(),
+                    tx.clone(),
+                    rf_rx.clone(),
+                    shutdown.clone(),
+                );
+                async move {
+                    let mut rf_rx = rf_rx.lock_owned().await;
+                    serial::run_serial_port(config, filter, tx, &mut rf_rx, shutdown).await
+                }
+            }
+        });
+        stopping.push(handle);
     }
 
+    // Start GPS if configured
+    let gps_tracker = if let Some(gps_config) = &config.gps {
+        let sources = gps_config
+            .sources()
+            .iter()
+            .map(gps::GpsSource::from_config)
+            .collect();
+
+        let mut tracker = gps::GpsTracker::with_failover(sources).with_stale_after(
+            std::time::Duration::from_secs(gps_config.stale_after as u64),
+        );
+        if let Some(survey) = &gps_config.survey {
+            tracker = tracker.with_survey(
+                std::time::Duration::from_secs(survey.duration as u64),
+                &survey.file,
+            );
+        }
+        let tracker = Arc::new(tracker);
+        let handle = supervise("GPS tracker", Policy::Restart, shutdown.clone(), {
+            let tracker = tracker.clone();
+            move || {
+                let tracker = tracker.clone();
+                async move { tracker.run().await }
+            }
+        });
+        handles.push(handle);
+        Some(tracker)
+    } else {
+        None
+    };
+
     // Start APRS-IS connection
-    if let Some(aprs_is_config) = &config.aprs_is {
-        let tx = packet_tx.clone();
-        let is_rx = channels.is_tx.subscribe();
-        let handle = tokio::spawn(network::run_aprs_is_connection(
-            aprs_is_config.clone(),
-            tx,
-            is_rx,
-        ));
+    if let (Some(_), Some(_)) = (&config.aprs_is, &replaying) {
+        let handle = tokio::spawn(replay::print_gated(channels.is_tx.subscribe()));
+        stopping.push(handle);
+    } else if let Some(aprs_is_config) = &config.aprs_is {
+        let handle = supervise("APRS-IS connection", Policy::Restart, shutdown.clone(), {
+            let (aprs_is_config, tx, is_rx, gps, shutdown) = (
+                aprs_is_config.clone(),
+                packet_tx.clone(),
+                channels.is_tx.subscribe(),
+                gps_tracker.clone(),
+                shutdown.clone(),
+            );
+            move || {
+                network::run_aprs_is_connection(
+                    aprs_is_config.clone(),
+                    tx.clone(),
+                    is_rx.resubscribe(),
+                    gps.clone(),
+                    shutdown.clone(),
+                )
+            }
+        });
+        stopping.push(handle);
+    }
+
+    // Start MQTT feed
+    #[cfg(feature = "mqtt")]
+    if let Some(mqtt_config) = &config.mqtt {
+        let handle = supervise("MQTT feed", Policy::Restart, shutdown.clone(), {
+            let (mqtt_config, mycall, heard_rx, tx) = (
+                mqtt_config.clone(),
+                config.mycall.clone(),
+                channels.heard.subscribe(),
+                packet_tx.clone(),
+            );
+            move || {
+                aprstx::mqtt::run_mqtt(
+                    mqtt_config.clone(),
+                    mycall.clone(),
+                    heard_rx.resubscribe(),
+                    tx.clone(),
+                )
+            }
+        });
         handles.push(handle);
     }
+    #[cfg(not(feature = "mqtt"))]
+    if config.mqtt.is_some() {
+        log::warn!("MQTT feed configured but aprstx was built without the mqtt feature");
+    }
 
-    // Start digipeater
-    if config.digipeater.enabled {
-        let tx = packet_tx.clone();
-        let handle = tokio::spawn(digipeater::run_digipeater(
-            config.digipeater.clone(),
-            channels.digipeater_rx,
-            tx,
-        ));
+    // Start NATS output
+    #[cfg(feature = "nats")]
+    if let Some(nats_config) = &config.nats {
+        let handle = supervise("NATS output", Policy::Restart, shutdown.clone(), {
+            let (nats_config, heard_rx, is_rx) = (
+                nats_config.clone(),
+                channels.heard.subscribe(),
+                channels.is_tx.subscribe(),
+            );
+            move || {
+                aprstx::nats::run_nats(
+                    nats_config.clone(),
+                    heard_rx.resubscribe(),
+                    is_rx.resubscribe(),
+                )
+            }
+        });
         handles.push(handle);
     }
+    #[cfg(not(feature = "nats"))]
+    if config.nats.is_some() {
+        log::warn!("NATS output configured but aprstx was built without the nats feature");
+    }
 
-    // Start telemetry
-    if config.telemetry.enabled {
-        let tx = packet_tx.clone();
-        let handle = tokio::spawn(telemetry::run_telemetry(
-            config.telemetry.clone(),
-            config.mycall.clone(),
-            tx,
-        ));
+    // Start packet storage
+    #[cfg(feature = "postgres")]
+    if let Some(postgres_config) = &config.postgres {
+        let handle = supervise("PostgreSQL storage", Policy::Restart, shutdown.clone(), {
+            let (postgres_config, heard_rx) = (postgres_config.clone(), channels.heard.subscribe());
+            move || {
+                aprstx::storage::postgres::run_postgres(
+                    postgres_config.clone(),
+                    heard_rx.resubscribe(),
+                )
+            }
+        });
+        handles.push(handle);
+    }
+    #[cfg(not(feature = "postgres"))]
+    if config.postgres.is_some() {
+        log::warn!(
+            "PostgreSQL storage configured but aprstx was built without the postgres feature"
+        );
+    }
+    #[cfg(feature = "sqlite")]
+    if let Some(sqlite_config) = &config.sqlite {
+        let handle = supervise("SQLite storage", Policy::Restart, shutdown.clone(), {
+            let (sqlite_config, heard_rx) = (sqlite_config.clone(), channels.heard.subscribe());
+            move || {
+                aprstx::storage::sqlite::run_sqlite(sqlite_config.clone(), heard_rx.resubscribe())
+            }
+        });
+        handles.push(handle);
+    }
+    #[cfg(not(feature = "sqlite"))]
+    if config.sqlite.is_some() {
+        log::warn!("SQLite storage configured but aprstx was built without the sqlite feature");
+    }
+
+    // Start WebSocket stream
+    if let Some(ws_config) = &config.websocket {
+        let handle = supervise("WebSocket server", Policy::Restart, shutdown.clone(), {
+            let (ws_config, filter, heard) =
+                (ws_config.clone(), filter.clone(), channels.heard.clone());
+            move || {
+                websocket::run_websocket_server(ws_config.clone(), filter.clone(), heard.clone())
+            }
+        });
+        handles.push(handle);
+    }
+
+    // Serve Prometheus metrics
+    if let Some(metrics_config) = &config.metrics {
+        let handle = supervise("Metrics server", Policy::Restart, shutdown.clone(), {
+            let metrics_config = metrics_config.clone();
+            move || metrics::run_metrics_server(metrics_config.clone())
+        });
+        handles.push(handle);
+    }
+
+    // Start the APRS-IS server for local clients
+    if let Some(server_config) = &config.is_server {
+        let handle = supervise("APRS-IS server", Policy::Restart, shutdown.clone(), {
+            let (server_config, mycall, heard, tx) = (
+                server_config.clone(),
+                config.mycall.clone(),
+                channels.heard.clone(),
+                packet_tx.clone(),
+            );
+            move || {
+                is_server::run_is_server(
+                    server_config.clone(),
+                    mycall.clone(),
+                    heard.clone(),
+                    tx.clone(),
+                )
+            }
+        });
+        handles.push(handle);
+    }
+
+    // Start UDP output
+    if let Some(udp_config) = &config.udp_output {
+        let handle = supervise("UDP output", Policy::Restart, shutdown.clone(), {
+            let (udp_config, heard_rx) = (udp_config.clone(), channels.heard.subscribe());
+            move || udp::run_udp_output(udp_config.clone(), heard_rx.resubscribe())
+        });
+        handles.push(handle);
+    }
+
+    // Start NMEA waypoint output
+    if let Some(waypoint_config) = &config.waypoints {
+        if waypoint_config.device.is_none() && waypoint_config.listen.is_none() {
+            log::warn!("Waypoint output needs a device or listen address");
+        } else {
+            let handle = supervise("Waypoint output", Policy::Restart, shutdown.clone(), {
+                let (waypoint_config, heard_rx) =
+                    (waypoint_config.clone(), channels.heard.subscribe());
+                move || {
+                    waypoint::run_waypoint_output(waypoint_config.clone(), heard_rx.resubscribe())
+                }
+            });
+            handles.push(handle);
+        }
+    }
+
+    // Record tracks for export
+    if let Some(track_config) = &config.track_log {
+        if gps_tracker.is_none() && track_config.stations.is_empty() {
+            log::warn!("Track log needs a GPS or stations to record");
+        } else {
+            let handle = supervise("Track log", Policy::Restart, shutdown.clone(), {
+                let (track_config, mycall, gps, heard_rx) = (
+                    track_config.clone(),
+                    config.mycall.clone(),
+                    gps_tracker.clone(),
+                    channels.heard.subscribe(),
+                );
+                move || {
+                    tracklog::run_track_log(
+                        track_config.clone(),
+                        mycall.clone(),
+                        gps.clone(),
+                        heard_rx.resubscribe(),
+                    )
+                }
+            });
+            handles.push(handle);
+        }
+    }
+
+    // Start IGATE capability beacon
+    if let Some(igate_config) = &config.igate_beacon {
+        let callsign = match &config.aprs_is {
+            Some(aprs_is) => aprs_is.callsign.clone(),
+            None => config.mycall.clone(),
+        };
+        let handle = supervise("IGATE beacon", Policy::Restart, shutdown.clone(), {
+            let (igate_config, tx) = (igate_config.clone(), packet_tx.clone());
+            move || igate::run_igate_beacon(igate_config.clone(), callsign.clone(), tx.clone())
+        });
+        handles.push(handle);
+    }
+
+    if let Some(id_config) = &config.station_id {
+        let ports: Vec<String> = if id_config.ports.is_empty() {
+            config.serial_ports.iter().map(|p| p.name.clone()).collect()
+        } else {
+            id_config.ports.clone()
+        };
+        let handle = supervise("Station ID", Policy::Restart, shutdown.clone(), {
+            let (id_config, mycall, tx) =
+                (id_config.clone(), config.mycall.clone(), packet_tx.clone());
+            move || {
+                station_id::run_station_id(
+                    id_config.clone(),
+                    mycall.clone(),
+                    ports.clone(),
+                    tx.clone(),
+                )
+            }
+        });
+        handles.push(handle);
+    }
+
+    // Keep the heard stations list trimmed and saved
+    let handle = supervise("Heard list", Policy::Restart, shutdown.clone(), {
+        let (heard_config, shutdown) = (config.heard.clone().unwrap_or_default(), shutdown.clone());
+        move || heard::run_heard_list(heard_config.clone(), shutdown.clone())
+    });
+    stopping.push(handle);
+
+    // Start object server for the object file and repeaters
+    if let Some(objects_config) = &config.objects {
+        let handle = supervise("Object server", Policy::Restart, shutdown.clone(), {
+            let (objects_config, mycall, tx) = (
+                objects_config.clone(),
+                config.mycall.clone(),
+                packet_tx.clone(),
+            );
+            move || objects::run_object_server(objects_config.clone(), mycall.clone(), tx.clone())
+        });
+        handles.push(handle);
+    }
+
+    // Start digipeater
+    if config.digipeater.enabled {
+        let handle = supervise("Digipeater", Policy::Restart, shutdown.clone(), {
+            let (digi_config, tx) = (config.digipeater.clone(), packet_tx.clone());
+            let rx = Arc::new(Mutex::new(channels.digipeater_rx));
+            move || {
+                let (config, rx, tx) = (digi_config.clone(), rx.clone(), tx.clone());
+                async move { digipeater::run_digipeater(config, &mut *rx.lock_owned().await, tx).await }
+            }
+        });
         handles.push(handle);
     }
 
     // Start message handler
-    let message_handler = message::MessageHandler::new(config.mycall.clone());
-    let tx = packet_tx.clone();
-    let handle = tokio::spawn(message_handler.run(channels.message_rx, tx));
+    let mut handler = message::MessageHandler::new(config.mycall.clone(), packet_tx.clone())
+        .with_retries(config.messaging.clone());
+    if let Some(alert) = &config.messaging.group_alert {
+        handler = handler.with_group_alerter(alert::Alerter::new(alert.clone(), &config.mycall)?);
+    }
+    let messages = Arc::new(handler);
+    let handle = supervise("Message handler", Policy::Restart, shutdown.clone(), {
+        let messages = messages.clone();
+        let rx = Arc::new(Mutex::new(channels.message_rx));
+        move || {
+            let (messages, rx) = (messages.clone(), rx.clone());
+            async move { messages.run(&mut *rx.lock_owned().await).await }
+        }
+    });
     handles.push(handle);
 
-    // Start GPS if configured
-    let gps_tracker = if let Some(gps_config) = &config.gps {
-        let source = match gps_config.gps_type.as_str() {
-            "serial" => {
-                if let (Some(device), Some(baud)) = (&gps_config.device, gps_config.baud_rate) {
-                    gps::GpsSource::SerialNmea(device.clone(), baud)
-                } else {
-                    gps::GpsSource::None
-                }
+    if let Some(status_config) = &config.status_beacon {
+        if status_config.grid && gps_tracker.is_none() {
+            log::warn!("The status beacon's locator needs a [gps] position, sending it without");
+        }
+        let handle = supervise("Status beacon", Policy::Restart, shutdown.clone(), {
+            let (status_config, station, gps, tx) = (
+                status_config.clone(),
+                config.clone(),
+                gps_tracker.clone(),
+                packet_tx.clone(),
+            );
+            move || {
+                status::run_status_beacon(
+                    status_config.clone(),
+                    station.clone(),
+                    gps.clone(),
+                    tx.clone(),
+                )
             }
-            "gpsd" => {
-                let host = gps_config.host.as_deref().unwrap_or("localhost");
-                let port = gps_config.port.unwrap_or(2947);
-                gps::GpsSource::Gpsd(host.to_string(), port)
-            }
-            "fixed" => {
-                if let Some(pos_str) = &gps_config.position {
-                    match gps::parse_fixed_position(pos_str) {
-                        Ok(pos) => gps::GpsSource::Fixed(pos),
-                        Err(e) => {
-                            log::error!("Invalid fixed position: {}", e);
-                            gps::GpsSource::None
-                        }
-                    }
-                } else {
-                    gps::GpsSource::None
-                }
+        });
+        handles.push(handle);
+    }
+
+    // Keep range filters centred on our position
+    if let Some(gps) = &gps_tracker {
+        let handle = supervise("GPS filter follower", Policy::Restart, shutdown.clone(), {
+            let (filter, gps) = (filter.clone(), gps.clone());
+            move || aprstx::filter::follow_gps(filter.clone(), gps.clone())
+        });
+        handles.push(handle);
+    }
+
+    // Start telemetry
+    if config.telemetry.enabled {
+        let handle = supervise("Telemetry", Policy::Restart, shutdown.clone(), {
+            let (telemetry_config, mycall, gps, tx) = (
+                config.telemetry.clone(),
+                config.mycall.clone(),
+                gps_tracker.clone(),
+                packet_tx.clone(),
+            );
+            move || {
+                telemetry::run_telemetry(
+                    telemetry_config.clone(),
+                    mycall.clone(),
+                    gps.clone(),
+                    tx.clone(),
+                )
             }
-            _ => gps::GpsSource::None,
-        };
+        });
+        handles.push(handle);
+    }
 
-        let tracker = Arc::new(gps::GpsTracker::new(source));
-        let tracker_clone = tracker.clone();
-        let handle = tokio::spawn(async move {
-            if let Err(e) = tracker_clone.run().await {
-                log::error!("GPS tracker error: {}", e);
-                return Err(e);
+    // Follow the radio's frequency if configured
+    let rig = if let Some(rig_config) = &config.rig {
+        let rig = Arc::new(rig::Rig::new());
+        let handle = supervise("Rig control", Policy::Restart, shutdown.clone(), {
+            let (rig, rig_config) = (rig.clone(), rig_config.clone());
+            move || {
+                let (rig, rig_config) = (rig.clone(), rig_config.clone());
+                async move { rig.run(rig_config).await }
             }
-            Ok(())
         });
         handles.push(handle);
-        Some(tracker)
+        Some(rig)
     } else {
         None
     };
 
     // Start beacon if configured
-    if let (Some(beacon_config), Some(gps)) = (&config.beacon, gps_tracker) {
+    if let (Some(beacon_config), Some(gps)) = (&config.beacon, gps_tracker.clone()) {
         if beacon_config.enabled {
-            let tx = packet_tx.clone();
-            let beacon = beacon::BeaconService::new(beacon_config.clone(), gps);
-            let handle = tokio::spawn(beacon.run(tx));
+            let handle = supervise("Beacon", Policy::Restart, shutdown.clone(), {
+                let (beacon_config, rig, tx, shutdown) = (
+                    beacon_config.clone(),
+                    rig.clone(),
+                    packet_tx.clone(),
+                    shutdown.clone(),
+                );
+                move || {
+                    let mut service =
+                        beacon::BeaconService::new(beacon_config.clone(), gps.clone());
+                    if let Some(rig) = &rig {
+                        service = service.with_rig(rig.clone());
+                    }
+                    service.run(tx.clone(), shutdown.clone())
+                }
+            });
+            stopping.push(handle);
+        }
+    }
+
+    // The coverage object is placed at our position, so needs GPS
+    match (&config.coverage, gps_tracker.clone()) {
+        (Some(coverage_config), Some(gps)) => {
+            let handle = supervise("Coverage object", Policy::Restart, shutdown.clone(), {
+                let (coverage_config, mycall, tx) = (
+                    coverage_config.clone(),
+                    config.mycall.clone(),
+                    packet_tx.clone(),
+                );
+                move || {
+                    coverage::run_coverage_object(
+                        coverage_config.clone(),
+                        mycall.clone(),
+                        gps.clone(),
+                        tx.clone(),
+                    )
+                }
+            });
             handles.push(handle);
         }
+        (Some(_), None) => log::warn!("The coverage object needs a [gps] position, not sending it"),
+        _ => {}
     }
 
+    // Start control socket if configured
+    if let Some(control_config) = &config.control {
+        let mut ctx = control::ControlContext::new()
+            .with_filter(filter.clone())
+            .with_messages(messages.clone());
+        if let Some(gps) = gps_tracker {
+            ctx = ctx.with_gps(gps);
+        }
+        if let Some(budget) = gate_budget {
+            ctx = ctx.with_gate_budget(budget);
+        }
+        if let Some(dir) = &control_config.capture_dir {
+            ctx = ctx.with_capture_dir(dir);
+        }
+        let ctx = Arc::new(ctx);
+        let handle = supervise("Control socket", Policy::Restart, shutdown.clone(), {
+            let control_config = control_config.clone();
+            move || control::run_control_socket(control_config.clone(), ctx.clone())
+        });
+        handles.push(handle);
+    }
+
+    // Once the router stops, APRS-IS should see the end of its feed
+    drop(channels.is_tx);
+
+    // A replay ends the run once the log is done and whatever it set off,
+    // like viscous digipeats, has had time to go out
+    let replay_done = async {
+        let Some(options) = replaying else {
+            return std::future::pending().await;
+        };
+        let port = config
+            .serial_ports
+            .first()
+            .map(|port| port.name.clone())
+            .unwrap_or_else(|| "replay".to_string());
+        if let Err(e) = replay::run_replay(options, port, packet_tx.clone()).await {
+            log::error!("Replay failed: {}", e);
+        }
+        let settle = config.digipeater.viscous_delay as u64 + REPLAY_SETTLE;
+        tokio::time::sleep(std::time::Duration::from_secs(settle)).await;
+    };
+
     let ctrl_c = async {
         signal::ctrl_c()
             .await
@@ -200,7 +710,64 @@ async fn main() -> Result<()> {
         _ = terminate => {
             info!("Received terminate signal, shutting down...");
         },
+        // A critical task gave up
+        _ = shutdown.wait() => {},
+        _ = replay_done => {
+            info!("Replay finished, shutting down...");
+        },
+    }
+
+    shutdown.trigger();
+    if tokio::time::timeout(SHUTDOWN_GRACE, futures::future::join_all(stopping))
+        .await
+        .is_err()
+    {
+        log::warn!("Gave up waiting for tasks to finish");
     }
 
     Ok(())
 }
+
+/// `aprstx msg`: hand a message to the daemon and follow it until it's
+/// acked, rejected or given up on
+async fn send_message(config: &Config, to: &str, text: &str) -> Result<()> {
+    let control = config
+        .control
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("No [control] socket configured to reach the daemon"))?;
+    let mut client = control::ControlClient::connect(control.socket.as_ref()).await?;
+
+    let sent = client.request(&format!("msg {} {}", to, text)).await?;
+    let id = sent["id"].as_str().unwrap_or_default().to_string();
+    let to = sent["to"].as_str().unwrap_or(to).to_string();
+    match sent["parts"].as_array() {
+        Some(parts) => println!(
+            "Sent message {} to {} in {} parts, waiting for acks...",
+            id,
+            to,
+            parts.len()
+        ),
+        None => println!("Sent message {} to {}, waiting for an ack...", id, to),
+    }
+
+    loop {
+        tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+        let status = client.request(&format!("msgstatus {}", id)).await?;
+        match status["status"].as_str() {
+            Some("pending") => continue,
+            Some("acked") => println!("Delivered: {} acked", to),
+            Some("sent") => println!("Sent to {}, which as a group doesn't ack", to),
+            Some("rejected") => {
+                return Err(anyhow::anyhow!("{} rejected the message", to));
+            }
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "No ack from {} after {} tries",
+                    to,
+                    status["attempts"]
+                ));
+            }
+        }
+        return Ok(());
+    }
+}
@@ -1,66 +1,248 @@
+use crate::alert::Alerter;
 use crate::aprs::packet::DataType;
-use crate::aprs::{AprsPacket, CallSign};
+use crate::aprs::{AprsPacket, CallSign, Message};
+use crate::channel::{self, Overflow};
+use crate::config::{MessageSpoolConfig, MessagingConfig};
+use crate::heard::HEARD;
 use crate::router::{PacketSource, RoutedPacket};
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use chrono::{DateTime, Utc};
 use log::{debug, info, warn};
+use serde::Serialize;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
-use tokio::sync::{mpsc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch, RwLock};
 
-#[derive(Debug, Clone)]
+/// Where a message we sent has got to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Delivery {
+    Pending,  // Waiting for an ack, retrying
+    Acked,    // The addressee acknowledged it
+    Rejected, // The addressee rejected it
+    Failed,   // Retries ran out with no answer
+    Sent,     // Sent to a group, which doesn't ack
+}
+
+#[derive(Debug)]
 struct PendingMessage {
     packet: AprsPacket,
+    addressee: String,
     attempts: u8,
     last_attempt: DateTime<Utc>,
+    expires: DateTime<Utc>,
+    delivery: watch::Sender<Delivery>, // Watched by anyone waiting on it
+}
+
+impl PendingMessage {
+    fn delivery(&self) -> Delivery {
+        *self.delivery.borrow()
+    }
+}
+
+/// What `status` reports about a message we sent
+#[derive(Debug, Clone, Serialize)]
+pub struct SentMessage {
+    pub id: String,
+    pub to: String,
+    pub status: Delivery,
+    pub attempts: u8,
+    pub expires: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub parts: Vec<String>, // Ids of each part of a split message, in order
+}
+
+/// Parts of a long message received so far, waiting for the rest
+#[derive(Debug)]
+struct Fragments {
+    parts: Vec<Option<String>>,
+    updated: DateTime<Utc>,
 }
 
 pub struct MessageHandler {
     mycall: String,
+    tx: mpsc::Sender<RoutedPacket>,
+    retries: MessagingConfig,
     pending_acks: Arc<RwLock<HashMap<String, PendingMessage>>>,
     received_messages: Arc<RwLock<HashMap<String, DateTime<Utc>>>>,
+    split: RwLock<HashMap<String, Vec<String>>>, // First part's id, then every part's
+    fragments: RwLock<HashMap<String, Fragments>>, // Sender and part count
+    group_alerter: Option<Alerter>,
+    next_id: AtomicU32,
 }
 
 impl MessageHandler {
-    pub fn new(mycall: String) -> Self {
+    pub fn new(mycall: String, tx: mpsc::Sender<RoutedPacket>) -> Self {
         MessageHandler {
             mycall,
+            tx,
+            retries: MessagingConfig::default(),
             pending_acks: Arc::new(RwLock::new(HashMap::new())),
             received_messages: Arc::new(RwLock::new(HashMap::new())),
+            split: RwLock::new(HashMap::new()),
+            fragments: RwLock::new(HashMap::new()),
+            group_alerter: None,
+            // Start somewhere different each run so a restart doesn't reuse
+            // ids the other station has already acked
+            next_id: AtomicU32::new(Utc::now().timestamp() as u32 % MAX_MESSAGE_ID),
         }
     }
 
-    pub async fn run(
-        self,
-        mut rx: mpsc::Receiver<RoutedPacket>,
-        tx: mpsc::Sender<RoutedPacket>,
-    ) -> Result<()> {
-        info!("Starting message handler for {}", self.mycall);
+    pub fn with_retries(mut self, retries: MessagingConfig) -> Self {
+        self.retries = retries;
+        self
+    }
 
-        // Start retry timer
-        let pending_acks = self.pending_acks.clone();
-        let tx_clone = tx.clone();
-        tokio::spawn(async move {
-            let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(30));
-            loop {
-                interval.tick().await;
-                retry_pending_messages(&pending_acks, &tx_clone).await;
-            }
-        });
+    /// Raise an alert for every message to a group
+    pub fn with_group_alerter(mut self, alerter: Alerter) -> Self {
+        self.group_alerter = Some(alerter);
+        self
+    }
 
-        // Start cleanup task
-        let received_messages = self.received_messages.clone();
-        tokio::spawn(async move {
-            let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(300));
-            loop {
-                interval.tick().await;
-                cleanup_old_messages(&received_messages).await;
-            }
-        });
+    /// Send a message, retrying until it's acked. Returns the message id to
+    /// follow it with `status`. Text too long for one message is sent in
+    /// numbered parts, each acked on its own, and followed by the first
+    /// part's id. A message to a group is sent once, with no id on the air,
+    /// since no one acks it.
+    pub async fn send(&self, to: &str, text: &str) -> Result<String> {
+        let to = CallSign::parse(to)
+            .filter(|call| {
+                let call = call.to_string();
+                call.len() <= 9 && call.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            })
+            .ok_or_else(|| anyhow!("Invalid addressee: {}", to))?;
+        let parts = split_message(text)?;
+        let mut ids = Vec::with_capacity(parts.len());
+        for part in &parts {
+            ids.push(self.send_part(&to, part).await);
+        }
+        let id = ids[0].clone();
+        if ids.len() > 1 {
+            info!("Sent message {} to {} in {} parts", id, to, ids.len());
+            self.split.write().await.insert(id.clone(), ids);
+        }
+        Ok(id)
+    }
+
+    async fn send_part(&self, to: &CallSign, text: &str) -> String {
+        let id = (self.next_id.fetch_add(1, Ordering::Relaxed) % MAX_MESSAGE_ID + 1).to_string();
+        let group = is_group(&to.to_string(), &self.retries.groups);
+        let info = if group {
+            format!(":{:<9}:{}", to.to_string(), text)
+        } else {
+            format!(":{:<9}:{}{{{}", to.to_string(), text, id)
+        };
+        let packet = AprsPacket::new(
+            CallSign::parse(&self.mycall).unwrap_or(CallSign::new("N0CALL", 0)),
+            CallSign::new("APRS", 0),
+            info,
+        );
+
+        info!("Sending message {} to {}: {}", id, to, text);
+        let now = Utc::now();
+        self.pending_acks.write().await.insert(
+            id.clone(),
+            PendingMessage {
+                packet: packet.clone(),
+                addressee: to.to_string(),
+                attempts: 1,
+                last_attempt: now,
+                expires: now + chrono::Duration::seconds(self.retries.expiry as i64),
+                delivery: watch::Sender::new(if group {
+                    Delivery::Sent
+                } else {
+                    Delivery::Pending
+                }),
+            },
+        );
+        let routed = RoutedPacket {
+            packet,
+            source: PacketSource::Internal,
+        };
+        channel::send(&self.tx, routed, channel::ROUTER, Overflow::Block).await;
+        id
+    }
+
+    /// Ids of every part of the message `id`, which is just `id` unless it
+    /// was split
+    async fn part_ids(&self, id: &str) -> Vec<String> {
+        match self.split.read().await.get(id) {
+            Some(ids) => ids.clone(),
+            None => vec![id.to_string()],
+        }
+    }
+
+    /// How a message sent with `send` is getting on. A split message is
+    /// delivered once every part is acked.
+    pub async fn status(&self, id: &str) -> Option<SentMessage> {
+        let ids = self.part_ids(id).await;
+        let pending = self.pending_acks.read().await;
+        let parts: Vec<&PendingMessage> = ids
+            .iter()
+            .map(|id| pending.get(id))
+            .collect::<Option<_>>()?;
+        Some(SentMessage {
+            id: id.to_string(),
+            to: parts[0].addressee.clone(),
+            status: combined(parts.iter().map(|m| m.delivery())),
+            attempts: parts.iter().map(|m| m.attempts).max().unwrap_or(0),
+            expires: parts.iter().map(|m| m.expires).max()?,
+            parts: if ids.len() > 1 { ids } else { Vec::new() },
+        })
+    }
+
+    /// Send a message and wait until it's acked, rejected or retries run
+    /// out, for request and response over APRS messaging
+    pub async fn send_message(&self, to: &str, text: &str) -> Result<Delivery> {
+        let id = self.send(to, text).await?;
+        Ok(self.delivery(&id).await.unwrap_or(Delivery::Failed))
+    }
+
+    /// Wait for the outcome of a message sent with `send`. None if there's
+    /// no such message, or it was settled long enough ago to be forgotten.
+    pub async fn delivery(&self, id: &str) -> Option<Delivery> {
+        let mut outcomes = Vec::new();
+        for id in self.part_ids(id).await {
+            let mut rx = self
+                .pending_acks
+                .read()
+                .await
+                .get(&id)?
+                .delivery
+                .subscribe();
+            let delivery = rx.wait_for(|d| *d != Delivery::Pending).await.ok()?;
+            outcomes.push(*delivery);
+        }
+        Some(combined(outcomes))
+    }
+
+    pub async fn run(&self, rx: &mut mpsc::Receiver<RoutedPacket>) -> Result<()> {
+        info!("Starting message handler for {}", self.mycall);
+        let tx = self.tx.clone();
+
+        // Often enough to keep to the shortest retry spacing
+        let mut retry_interval = tokio::time::interval(tokio::time::Duration::from_secs(5));
+        let mut cleanup_interval = tokio::time::interval(tokio::time::Duration::from_secs(300));
 
-        while let Some(routed) = rx.recv().await {
-            if routed.packet.data_type == DataType::Message {
-                self.handle_message(routed, &tx).await?;
+        loop {
+            tokio::select! {
+                routed = rx.recv() => {
+                    let Some(routed) = routed else { break };
+                    if routed.packet.data_type == DataType::Message {
+                        self.handle_message(routed, &tx).await?;
+                    }
+                }
+                _ = retry_interval.tick() => {
+                    retry_pending_messages(&self.pending_acks, &self.retries, &tx, Utc::now())
+                        .await;
+                }
+                _ = cleanup_interval.tick() => {
+                    cleanup_old_messages(&self.received_messages).await;
+                    cleanup_sent_messages(&self.pending_acks).await;
+                    self.cleanup_parts().await;
+                }
             }
         }
 
@@ -72,26 +254,22 @@ impl MessageHandler {
         routed: RoutedPacket,
         tx: &mpsc::Sender<RoutedPacket>,
     ) -> Result<()> {
-        let info = routed.packet.information.clone();
+        let Some(msg) = routed.packet.message().cloned() else {
+            return Ok(());
+        };
 
-        // Parse message format ":ADDRESSEE:Message text{msgid"
-        if !info.starts_with(':') || info.len() < 11 {
+        if is_group(&msg.addressee, &self.retries.groups) {
+            self.handle_group_message(&routed, &msg, tx).await;
             return Ok(());
         }
-
-        let addressee = info[1..10].trim();
-        if addressee != self.mycall && !addressee.starts_with(&self.mycall) {
+        if msg.addressee != self.mycall && !msg.addressee.starts_with(&self.mycall) {
             return Ok(());
         }
 
-        let remaining = info[11..].to_string();
-
-        // Check if this is an ack or rej
-        if remaining.starts_with("ack") || remaining.starts_with("rej") {
-            self.handle_ack_rej(routed, &remaining).await?;
+        if msg.is_ack_or_rej() {
+            self.handle_ack_rej(routed, &msg).await?;
         } else {
-            // Regular message
-            self.handle_incoming_message(routed, &remaining, tx).await?;
+            self.handle_incoming_message(routed, &msg, tx).await?;
         }
 
         Ok(())
@@ -100,21 +278,16 @@ impl MessageHandler {
     async fn handle_incoming_message(
         &self,
         routed: RoutedPacket,
-        message_text: &str,
+        msg: &Message,
         tx: &mpsc::Sender<RoutedPacket>,
     ) -> Result<()> {
-        // Extract message ID if present
-        let (text, msg_id) = if let Some(id_pos) = message_text.rfind('{') {
-            let text = &message_text[..id_pos];
-            let id = &message_text[id_pos + 1..];
-            (text, Some(id))
-        } else {
-            (message_text, None)
-        };
+        let text = &msg.text;
+        let msg_id = msg.msg_id.as_deref();
 
         info!("Received message from {}: {}", routed.packet.source, text);
 
         // Check for duplicate
+        let mut duplicate = false;
         if let Some(msg_id) = msg_id {
             let msg_key = format!("{}:{}", routed.packet.source, msg_id);
             let mut received = self.received_messages.write().await;
@@ -125,6 +298,7 @@ impl MessageHandler {
                 }
                 std::collections::hash_map::Entry::Occupied(_) => {
                     debug!("Duplicate message, resending ack");
+                    duplicate = true;
                 }
             }
 
@@ -144,21 +318,38 @@ impl MessageHandler {
                 source: PacketSource::Internal,
             };
 
-            let _ = tx.send(routed_ack).await;
+            channel::send(tx, routed_ack, channel::ROUTER, Overflow::Block).await;
+        }
+
+        // A retry only needed the ack; everything else was done the first time
+        if duplicate {
+            return Ok(());
+        }
+
+        if let Some(whole) = self.reassemble(&routed.packet.source, text).await {
+            info!(
+                "Reassembled message from {}: {}",
+                routed.packet.source, whole
+            );
         }
 
         // Process special commands
-        if text.trim().to_uppercase() == "?APRST" {
-            // Send telemetry status
-            self.send_status_reply(&routed.packet.source, tx).await?;
+        let reply = match text.trim().to_uppercase().as_str() {
+            "?APRST" => Some("aprstx daemon running".to_string()),
+            "?APRSD" => Some(station_list("Directs=", &HEARD.calls(true))),
+            "?APRSL" => Some(station_list("Heard=", &HEARD.calls(false))),
+            _ => None,
+        };
+        if let Some(reply) = reply {
+            self.send_reply(&routed.packet.source, &reply, tx).await?;
         }
 
         Ok(())
     }
 
-    async fn handle_ack_rej(&self, routed: RoutedPacket, ack_text: &str) -> Result<()> {
-        let is_ack = ack_text.starts_with("ack");
-        let msg_id = &ack_text[3..];
+    async fn handle_ack_rej(&self, routed: RoutedPacket, msg: &Message) -> Result<()> {
+        let is_ack = msg.is_ack;
+        let msg_id = msg.msg_id.as_deref().unwrap_or_default();
 
         info!(
             "Received {} from {} for msg {}",
@@ -167,20 +358,89 @@ impl MessageHandler {
             msg_id
         );
 
-        // Remove from pending
         let mut pending = self.pending_acks.write().await;
-        pending.remove(msg_id);
+        if let Some(sent) = pending.get_mut(msg_id) {
+            if sent.delivery() == Delivery::Pending
+                && sent
+                    .addressee
+                    .eq_ignore_ascii_case(&routed.packet.source.to_string())
+            {
+                sent.delivery.send_replace(if is_ack {
+                    Delivery::Acked
+                } else {
+                    Delivery::Rejected
+                });
+            }
+        }
 
         Ok(())
     }
 
-    async fn send_status_reply(
+    /// A message to everyone in a group: passed on if configured, but never
+    /// acked or answered, as every station in the group would do the same
+    async fn handle_group_message(
+        &self,
+        routed: &RoutedPacket,
+        msg: &Message,
+        tx: &mpsc::Sender<RoutedPacket>,
+    ) {
+        let summary = format!(
+            "{} to {}: {}",
+            routed.packet.source, msg.addressee, msg.text
+        );
+        info!("Group message from {}", summary);
+        let Some(alerter) = &self.group_alerter else {
+            return;
+        };
+        let mut inject = Vec::new();
+        alerter.fire("group_message", &summary, &routed.packet, &mut inject);
+        for routed in inject {
+            channel::send(tx, routed, channel::ROUTER, Overflow::Block).await;
+        }
+    }
+
+    /// Collect the parts of a long message, returning its whole text once
+    /// every part has arrived
+    async fn reassemble(&self, from: &CallSign, text: &str) -> Option<String> {
+        let (part, count, text) = message_part(text)?;
+        debug!("Part {} of {} from {}", part, count, from);
+        let key = format!("{}/{}", from, count);
+        let mut fragments = self.fragments.write().await;
+        let entry = fragments.entry(key.clone()).or_insert_with(|| Fragments {
+            parts: vec![None; count],
+            updated: Utc::now(),
+        });
+        entry.parts[part - 1] = Some(text.to_string());
+        entry.updated = Utc::now();
+        if entry.parts.iter().any(Option::is_none) {
+            return None;
+        }
+        let whole = fragments.remove(&key)?.parts.into_iter().flatten();
+        Some(whole.collect::<Vec<_>>().join(" "))
+    }
+
+    /// Forget split messages once their parts are, and parts of long
+    /// messages whose rest never came
+    async fn cleanup_parts(&self) {
+        let pending = self.pending_acks.read().await;
+        self.split
+            .write()
+            .await
+            .retain(|_, ids| ids.iter().all(|id| pending.contains_key(id)));
+        let now = Utc::now();
+        self.fragments
+            .write()
+            .await
+            .retain(|_, f| now.signed_duration_since(f.updated) < chrono::Duration::hours(1));
+    }
+
+    async fn send_reply(
         &self,
         to: &CallSign,
+        text: &str,
         tx: &mpsc::Sender<RoutedPacket>,
     ) -> Result<()> {
-        let status = "aprstx daemon running";
-        let msg_text = format!(":{:<9}:{}", to.to_string(), status);
+        let msg_text = format!(":{:<9}:{}", to.to_string(), text);
 
         let packet = AprsPacket::new(
             CallSign::parse(&self.mycall).unwrap_or(CallSign::new("N0CALL", 0)),
@@ -193,50 +453,204 @@ impl MessageHandler {
             source: PacketSource::Internal,
         };
 
-        let _ = tx.send(routed).await;
+        channel::send(tx, routed, channel::ROUTER, Overflow::Block).await;
 
         Ok(())
     }
 }
 
+/// Longest APRS message text
+const MAX_MESSAGE_TEXT: usize = 67;
+/// Message ids we send run from 1 to this, to stay within five characters
+const MAX_MESSAGE_ID: u32 = 99999;
+
+/// Most parts a long message is split into, so the marker is one digit
+const MAX_MESSAGE_PARTS: usize = 9;
+/// Room for the text of each part after its "(1/3) " marker
+const MESSAGE_PART_TEXT: usize = MAX_MESSAGE_TEXT - 6;
+
+/// Text has to do without the characters that delimit a message
+fn check_message_text(text: &str) -> Result<()> {
+    if text.is_empty() {
+        return Err(anyhow!("Message is empty"));
+    }
+    if text.contains(['|', '~', '{']) || text.chars().any(|c| c.is_control()) {
+        return Err(anyhow!(
+            "Message can't contain |, ~, {{ or control characters"
+        ));
+    }
+    Ok(())
+}
+
+/// The text of each message to send: the text itself if it fits in one,
+/// or else numbered parts like "(1/3) ...", split between words where
+/// there are any
+fn split_message(text: &str) -> Result<Vec<String>> {
+    check_message_text(text)?;
+    if text.chars().count() <= MAX_MESSAGE_TEXT {
+        return Ok(vec![text.to_string()]);
+    }
+
+    let mut chunks: Vec<String> = Vec::new();
+    let mut rest = text.trim();
+    while !rest.is_empty() {
+        let end = rest
+            .char_indices()
+            .nth(MESSAGE_PART_TEXT)
+            .map_or(rest.len(), |(i, _)| i);
+        let cut = if end == rest.len() || rest[end..].starts_with(' ') {
+            end
+        } else {
+            // Break at the last space that fits, or mid-word if none does
+            match rest[..end].rfind(' ') {
+                Some(space) if space > 0 => space,
+                _ => end,
+            }
+        };
+        chunks.push(rest[..cut].trim_end().to_string());
+        rest = rest[cut..].trim_start();
+    }
+    if chunks.len() > MAX_MESSAGE_PARTS {
+        return Err(anyhow!(
+            "Message is too long to send in {} parts",
+            MAX_MESSAGE_PARTS
+        ));
+    }
+    let count = chunks.len();
+    Ok(chunks
+        .into_iter()
+        .enumerate()
+        .map(|(i, chunk)| format!("({}/{}) {}", i + 1, count, chunk))
+        .collect())
+}
+
+/// The part number, part count and text of one part of a long message
+fn message_part(text: &str) -> Option<(usize, usize, &str)> {
+    let rest = text.strip_prefix('(')?;
+    let (marker, text) = rest.split_once(") ")?;
+    let (part, count) = marker.split_once('/')?;
+    let part: usize = part.parse().ok()?;
+    let count: usize = count.parse().ok()?;
+    if !(2..=MAX_MESSAGE_PARTS).contains(&count) || !(1..=count).contains(&part) {
+        return None;
+    }
+    Some((part, count, text))
+}
+
+/// Addressees every station reads
+const GROUP_ADDRESSEES: [&str; 3] = ["ALL", "CQ", "QST"];
+
+/// Whether a message to `addressee` is for a group rather than one
+/// station, as ALL, CQ, QST and the configured `groups` are
+pub fn is_group(addressee: &str, groups: &[String]) -> bool {
+    let addressee = addressee.trim();
+    GROUP_ADDRESSEES
+        .iter()
+        .any(|g| g.eq_ignore_ascii_case(addressee))
+        || groups.iter().any(|g| g.eq_ignore_ascii_case(addressee))
+}
+
+/// Whether the message handler wants a message: one to us, or to a group
+pub fn addressed_here(msg: &Message, mycall: &str, groups: &[String]) -> bool {
+    msg.addressee.starts_with(mycall) || is_group(&msg.addressee, groups)
+}
+
+/// How a message in parts is getting on, from how each part is
+fn combined(parts: impl IntoIterator<Item = Delivery>) -> Delivery {
+    let parts: Vec<Delivery> = parts.into_iter().collect();
+    [
+        Delivery::Pending,
+        Delivery::Rejected,
+        Delivery::Failed,
+        Delivery::Sent,
+    ]
+    .into_iter()
+    .find(|d| parts.contains(d))
+    .unwrap_or(Delivery::Acked)
+}
+
+/// A query reply listing as many whole callsigns as fit in one message
+fn station_list(label: &str, calls: &[String]) -> String {
+    let mut reply = label.to_string();
+    for call in calls {
+        if reply.len() + 1 + call.len() > MAX_MESSAGE_TEXT {
+            break;
+        }
+        reply.push(' ');
+        reply.push_str(call);
+    }
+    reply
+}
+
 async fn retry_pending_messages(
     pending_acks: &Arc<RwLock<HashMap<String, PendingMessage>>>,
+    retries: &MessagingConfig,
     tx: &mpsc::Sender<RoutedPacket>,
+    now: DateTime<Utc>,
 ) {
     let mut pending = pending_acks.write().await;
-    let now = Utc::now();
-    let mut to_remove = Vec::new();
 
     for (msg_id, pending_msg) in pending.iter_mut() {
+        if pending_msg.delivery() != Delivery::Pending {
+            continue;
+        }
+        if now >= pending_msg.expires {
+            warn!(
+                "Message {} to {} expired after {} attempts, giving up",
+                msg_id, pending_msg.addressee, pending_msg.attempts
+            );
+            pending_msg.delivery.send_replace(Delivery::Failed);
+            pending_msg.last_attempt = now;
+            continue;
+        }
         let elapsed = now.signed_duration_since(pending_msg.last_attempt);
+        if elapsed < retry_delay(retries, pending_msg.attempts) {
+            continue;
+        }
 
-        // Retry after 30 seconds
-        if elapsed.num_seconds() >= 30 {
-            if pending_msg.attempts >= 3 {
-                warn!("Message {} failed after 3 attempts, giving up", msg_id);
-                to_remove.push(msg_id.clone());
-            } else {
-                pending_msg.attempts += 1;
-                pending_msg.last_attempt = now;
-
-                info!(
-                    "Retrying message {} (attempt {})",
-                    msg_id, pending_msg.attempts
-                );
+        if pending_msg.attempts >= retries.max_attempts {
+            warn!(
+                "Message {} to {} failed after {} attempts, giving up",
+                msg_id, pending_msg.addressee, pending_msg.attempts
+            );
+            pending_msg.delivery.send_replace(Delivery::Failed);
+            pending_msg.last_attempt = now;
+        } else {
+            pending_msg.attempts += 1;
+            pending_msg.last_attempt = now;
 
-                let routed = RoutedPacket {
-                    packet: pending_msg.packet.clone(),
-                    source: PacketSource::Internal,
-                };
+            info!(
+                "Retrying message {} (attempt {})",
+                msg_id, pending_msg.attempts
+            );
 
-                let _ = tx.send(routed).await;
-            }
+            let routed = RoutedPacket {
+                packet: pending_msg.packet.clone(),
+                source: PacketSource::Internal,
+            };
+
+            channel::send(tx, routed, channel::ROUTER, Overflow::Block).await;
         }
     }
+}
 
-    for msg_id in to_remove {
-        pending.remove(&msg_id);
-    }
+/// How long to wait for an ack after the given number of tries, doubling
+/// each time up to the longest allowed
+fn retry_delay(retries: &MessagingConfig, attempts: u8) -> chrono::Duration {
+    let doublings = attempts.saturating_sub(1).min(16) as u32;
+    let delay = (retries.retry_interval as i64) << doublings;
+    chrono::Duration::seconds(
+        delay.min(retries.max_retry_interval.max(retries.retry_interval) as i64),
+    )
+}
+
+/// Forget how sent messages turned out after an hour
+async fn cleanup_sent_messages(pending_acks: &Arc<RwLock<HashMap<String, PendingMessage>>>) {
+    let now = Utc::now();
+    pending_acks.write().await.retain(|_, msg| {
+        msg.delivery() == Delivery::Pending
+            || now.signed_duration_since(msg.last_attempt) < chrono::Duration::hours(1)
+    });
 }
 
 async fn cleanup_old_messages(received_messages: &Arc<RwLock<HashMap<String, DateTime<Utc>>>>) {
@@ -248,3 +662,507 @@ async fn cleanup_old_messages(received_messages: &Arc<RwLock<HashMap<String, Dat
 
     debug!("Cleaned up old messages, {} remaining", messages.len());
 }
+
+/// Store-and-forward spool for messages gated from APRS-IS to stations we
+/// have recently heard on RF. Held messages are retransmitted on a schedule
+/// until the recipient acks or the hold time runs out.
+pub struct MessageSpool {
+    config: MessageSpoolConfig,
+    heard: RwLock<HashMap<String, Instant>>,
+    held: RwLock<HashMap<String, HeldMessage>>,
+}
+
+#[derive(Debug, Clone)]
+struct HeldMessage {
+    packet: AprsPacket,
+    held_since: Instant,
+    last_sent: Instant,
+    attempts: u32,
+}
+
+impl MessageSpool {
+    pub fn new(config: MessageSpoolConfig) -> Self {
+        MessageSpool {
+            config,
+            heard: RwLock::new(HashMap::new()),
+            held: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Record that a station was heard on RF.
+    pub async fn note_heard(&self, call: &CallSign) {
+        let key = CallSign::new(&call.call, call.ssid.0).to_string();
+        self.heard.write().await.insert(key, Instant::now());
+    }
+
+    /// Hold a message that was just gated to RF if its addressee was heard
+    /// recently. Returns true if the message was spooled.
+    pub async fn hold(&self, packet: &AprsPacket) -> bool {
+        let Some(msg) = packet.message() else {
+            return false;
+        };
+        if msg.is_ack_or_rej() {
+            return false;
+        }
+        let Some(msg_id) = msg.msg_id.as_deref() else {
+            return false;
+        };
+        let addressee = msg.addressee.as_str();
+
+        let heard_window = Duration::from_secs(self.config.heard_window as u64 * 3600);
+        let recently_heard = self
+            .heard
+            .read()
+            .await
+            .get(&addressee.to_uppercase())
+            .is_some_and(|t| t.elapsed() < heard_window);
+        if !recently_heard {
+            return false;
+        }
+
+        let key = spool_key(&packet.source.to_string(), addressee, msg_id);
+        let mut held = self.held.write().await;
+        if held.contains_key(&key) {
+            return true;
+        }
+
+        debug!("Holding message {} for store-and-forward", key);
+        let now = Instant::now();
+        held.insert(
+            key,
+            HeldMessage {
+                packet: packet.clone(),
+                held_since: now,
+                last_sent: now,
+                attempts: 1,
+            },
+        );
+
+        true
+    }
+
+    /// Release any held message acknowledged (or rejected) by this packet.
+    pub async fn acknowledge(&self, packet: &AprsPacket) {
+        let Some(msg) = packet.message() else {
+            return;
+        };
+        if !msg.is_ack_or_rej() {
+            return;
+        }
+        let Some(msg_id) = msg.msg_id.as_deref() else {
+            return;
+        };
+
+        let addressee = msg.addressee.as_str();
+        let source = CallSign::new(&packet.source.call, packet.source.ssid.0).to_string();
+        let key = spool_key(addressee, &source, msg_id);
+
+        if self.held.write().await.remove(&key).is_some() {
+            info!("Held message {} acknowledged, releasing", key);
+        }
+    }
+
+    /// Return the held messages that are due for retransmission, dropping
+    /// any whose hold time has expired.
+    pub async fn due_messages(&self) -> Vec<AprsPacket> {
+        let hold_time = Duration::from_secs(self.config.hold_time as u64);
+        let retry_interval = Duration::from_secs(self.config.retry_interval as u64);
+        let now = Instant::now();
+        let mut held = self.held.write().await;
+
+        held.retain(|key, msg| {
+            let keep = now.duration_since(msg.held_since) < hold_time;
+            if !keep {
+                warn!(
+                    "Held message {} expired after {} attempts",
+                    key, msg.attempts
+                );
+            }
+            keep
+        });
+
+        let mut due = Vec::new();
+        for msg in held.values_mut() {
+            if now.duration_since(msg.last_sent) >= retry_interval {
+                msg.last_sent = now;
+                msg.attempts += 1;
+                due.push(msg.packet.clone());
+            }
+        }
+
+        // Stations not heard within the window can be forgotten
+        let heard_window = Duration::from_secs(self.config.heard_window as u64 * 3600);
+        self.heard
+            .write()
+            .await
+            .retain(|_, t| now.duration_since(*t) < heard_window);
+
+        due
+    }
+
+    pub async fn held_count(&self) -> usize {
+        self.held.read().await.len()
+    }
+}
+
+fn spool_key(sender: &str, addressee: &str, msg_id: &str) -> String {
+    format!(
+        "{}>{}{{{}",
+        sender.to_uppercase(),
+        addressee.to_uppercase(),
+        msg_id
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spool_config() -> MessageSpoolConfig {
+        MessageSpoolConfig {
+            enabled: true,
+            heard_window: 2,
+            retry_interval: 0,
+            hold_time: 3600,
+        }
+    }
+
+    fn message(from: &str, info: &str) -> AprsPacket {
+        AprsPacket::new(
+            CallSign::parse(from).unwrap(),
+            CallSign::new("APRS", 0),
+            info.to_string(),
+        )
+    }
+
+    #[test]
+    fn test_station_list_truncates() {
+        assert_eq!(station_list("Directs=", &[]), "Directs=");
+
+        let calls: Vec<String> = (0..20).map(|i| format!("N{}CALL-1", i)).collect();
+        let reply = station_list("Heard=", &calls);
+        assert!(reply.starts_with("Heard= N0CALL-1 N1CALL-1 "));
+        assert!(reply.len() <= MAX_MESSAGE_TEXT);
+        assert!(reply.ends_with("CALL-1"));
+        assert_eq!(reply.split(' ').count(), 7);
+    }
+
+    #[tokio::test]
+    async fn test_send_until_acked() {
+        let (tx, mut sent) = mpsc::channel(10);
+        let handler = MessageHandler::new("N0CALL".to_string(), tx);
+
+        assert!(handler.send("N1CALL", "Hi | there").await.is_err());
+        assert!(handler.send("not a call", "Hi").await.is_err());
+
+        let id = handler.send("n1call-9", "Hello").await.unwrap();
+        let packet = sent.recv().await.unwrap().packet;
+        assert_eq!(packet.information, format!(":N1CALL-9 :Hello{{{}", id));
+        assert_eq!(handler.status(&id).await.unwrap().status, Delivery::Pending);
+
+        // Only the addressee can ack it
+        let ack = |from: &str| RoutedPacket {
+            packet: message(from, &format!(":N0CALL   :ack{}", id)),
+            source: PacketSource::AprsIs,
+        };
+        handler
+            .handle_message(ack("N2CALL"), &handler.tx)
+            .await
+            .unwrap();
+        assert_eq!(handler.status(&id).await.unwrap().status, Delivery::Pending);
+        handler
+            .handle_message(ack("N1CALL-9"), &handler.tx)
+            .await
+            .unwrap();
+        let status = handler.status(&id).await.unwrap();
+        assert_eq!(status.status, Delivery::Acked);
+        assert_eq!(status.to, "N1CALL-9");
+        assert_eq!(handler.delivery(&id).await, Some(Delivery::Acked));
+    }
+
+    #[test]
+    fn test_split_message() {
+        assert_eq!(split_message("Hello").unwrap(), ["Hello"]);
+        let exact = "x".repeat(MAX_MESSAGE_TEXT);
+        assert_eq!(split_message(&exact).unwrap(), vec![exact]);
+
+        let text = "The net starts at 8pm on the club repeater. Check in with your \
+                    call and location, and traffic if you have any.";
+        let parts = split_message(text).unwrap();
+        assert_eq!(
+            parts,
+            [
+                "(1/2) The net starts at 8pm on the club repeater. Check in with",
+                "(2/2) your call and location, and traffic if you have any."
+            ]
+        );
+        assert!(parts.iter().all(|p| p.chars().count() <= MAX_MESSAGE_TEXT));
+        assert_eq!(
+            message_part(&parts[1]),
+            Some((2, 2, "your call and location, and traffic if you have any."))
+        );
+
+        // A word too long for a part is broken
+        let parts = split_message(&"é".repeat(100)).unwrap();
+        assert_eq!(parts[0].chars().count(), MAX_MESSAGE_TEXT);
+        assert_eq!(parts.len(), 2);
+
+        assert!(split_message(&"word ".repeat(200)).is_err());
+        assert!(split_message("").is_err());
+        assert_eq!(message_part("(3/2) Not a part"), None);
+        assert_eq!(message_part("(1/1) Not a part"), None);
+    }
+
+    #[tokio::test]
+    async fn test_split_message_acked_by_part() {
+        let (tx, mut sent) = mpsc::channel(10);
+        let handler = MessageHandler::new("N0CALL".to_string(), tx);
+        let id = handler.send("N1CALL", &"word ".repeat(20)).await.unwrap();
+
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let info = sent.recv().await.unwrap().packet.information;
+            ids.push(info.rsplit('{').next().unwrap().to_string());
+        }
+        let status = handler.status(&id).await.unwrap();
+        assert_eq!(status.parts, ids);
+        assert_eq!(status.status, Delivery::Pending);
+
+        let ack = |id: &str| RoutedPacket {
+            packet: message("N1CALL", &format!(":N0CALL   :ack{}", id)),
+            source: PacketSource::AprsIs,
+        };
+        handler
+            .handle_message(ack(&ids[0]), &handler.tx)
+            .await
+            .unwrap();
+        assert_eq!(handler.status(&id).await.unwrap().status, Delivery::Pending);
+        handler
+            .handle_message(ack(&ids[1]), &handler.tx)
+            .await
+            .unwrap();
+        assert_eq!(handler.status(&id).await.unwrap().status, Delivery::Acked);
+        assert_eq!(handler.delivery(&id).await, Some(Delivery::Acked));
+    }
+
+    #[tokio::test]
+    async fn test_group_messages_are_not_acked() {
+        let (tx, mut sent) = mpsc::channel(10);
+        let handler = MessageHandler::new("N0CALL".to_string(), tx).with_retries(MessagingConfig {
+            groups: vec!["CLUB".to_string()],
+            ..Default::default()
+        });
+        assert!(is_group("cq", &[]));
+        assert!(!is_group("CLUB", &[]));
+
+        for to in ["ALL", "CLUB"] {
+            let group = RoutedPacket {
+                packet: message("N1CALL", &format!(":{:<9}:Net tonight{{12", to)),
+                source: PacketSource::SerialPort("vhf".to_string()),
+            };
+            handler.handle_message(group, &handler.tx).await.unwrap();
+        }
+        assert!(sent.try_recv().is_err());
+
+        // Sent once, with no id to ack
+        let id = handler.send("QST", "Net tonight").await.unwrap();
+        assert_eq!(
+            sent.recv().await.unwrap().packet.information,
+            ":QST      :Net tonight"
+        );
+        assert_eq!(handler.delivery(&id).await, Some(Delivery::Sent));
+        retry_pending_messages(
+            &handler.pending_acks,
+            &handler.retries,
+            &handler.tx,
+            Utc::now() + chrono::Duration::hours(1),
+        )
+        .await;
+        assert!(sent.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_retried_query_is_only_acked() {
+        let (tx, mut sent) = mpsc::channel(10);
+        let handler = MessageHandler::new("N0CALL".to_string(), tx);
+        for _ in 0..2 {
+            let query = RoutedPacket {
+                packet: message("N1CALL", ":N0CALL   :?APRST{7"),
+                source: PacketSource::SerialPort("vhf".to_string()),
+            };
+            handler.handle_message(query, &handler.tx).await.unwrap();
+        }
+
+        let mut sent_info = Vec::new();
+        while let Ok(routed) = sent.try_recv() {
+            sent_info.push(routed.packet.information);
+        }
+        let acks = sent_info.iter().filter(|i| i.ends_with(":ack7")).count();
+        let replies = sent_info
+            .iter()
+            .filter(|i| i.contains("aprstx daemon running"))
+            .count();
+        assert_eq!((acks, replies), (2, 1), "{:?}", sent_info);
+    }
+
+    #[tokio::test]
+    async fn test_reassemble() {
+        let (tx, _sent) = mpsc::channel(10);
+        let handler = MessageHandler::new("N0CALL".to_string(), tx);
+        let from = CallSign::parse("N1CALL").unwrap();
+
+        assert_eq!(handler.reassemble(&from, "(2/2) world").await, None);
+        assert_eq!(handler.reassemble(&from, "Unrelated").await, None);
+        assert_eq!(
+            handler.reassemble(&from, "(1/2) Hello").await.as_deref(),
+            Some("Hello world")
+        );
+        assert!(handler.fragments.read().await.is_empty());
+    }
+
+    #[test]
+    fn test_retry_delay_doubles() {
+        let retries = MessagingConfig::default();
+        let delays: Vec<i64> = (1..=7)
+            .map(|n| retry_delay(&retries, n).num_seconds())
+            .collect();
+        assert_eq!(delays, [30, 60, 120, 240, 480, 600, 600]);
+    }
+
+    #[tokio::test]
+    async fn test_retries_back_off_then_fail() {
+        let (tx, mut sent) = mpsc::channel(10);
+        let handler = MessageHandler::new("N0CALL".to_string(), tx).with_retries(MessagingConfig {
+            max_attempts: 3,
+            ..Default::default()
+        });
+        let id = handler.send("N1CALL", "Anyone there?").await.unwrap();
+        sent.recv().await.unwrap();
+        let start = handler.pending_acks.read().await[&id].last_attempt;
+        let handler = &handler;
+        let retry_at = |secs| async move {
+            let at = start + chrono::Duration::seconds(secs);
+            retry_pending_messages(&handler.pending_acks, &handler.retries, &handler.tx, at).await;
+        };
+
+        retry_at(29).await;
+        assert!(sent.try_recv().is_err());
+        retry_at(30).await;
+        assert!(sent.try_recv().is_ok());
+        // Twice as long before the next
+        retry_at(89).await;
+        assert!(sent.try_recv().is_err());
+        retry_at(90).await;
+        assert!(sent.try_recv().is_ok());
+        assert_eq!(handler.status(&id).await.unwrap().attempts, 3);
+
+        // Out of tries, with no answer by the time the last one was due one
+        retry_at(209).await;
+        assert_eq!(handler.status(&id).await.unwrap().status, Delivery::Pending);
+        retry_at(210).await;
+        assert!(sent.try_recv().is_err());
+        assert_eq!(handler.delivery(&id).await, Some(Delivery::Failed));
+    }
+
+    #[tokio::test]
+    async fn test_messages_expire() {
+        let (tx, mut sent) = mpsc::channel(10);
+        let handler = MessageHandler::new("N0CALL".to_string(), tx).with_retries(MessagingConfig {
+            expiry: 60,
+            ..Default::default()
+        });
+        let id = handler.send("N1CALL", "Anyone there?").await.unwrap();
+        sent.recv().await.unwrap();
+        let status = handler.status(&id).await.unwrap();
+
+        retry_pending_messages(
+            &handler.pending_acks,
+            &handler.retries,
+            &handler.tx,
+            status.expires,
+        )
+        .await;
+        assert!(sent.try_recv().is_err());
+        assert_eq!(handler.delivery(&id).await, Some(Delivery::Failed));
+    }
+
+    #[tokio::test]
+    async fn test_send_message_waits_for_ack() {
+        let (tx, mut sent) = mpsc::channel(10);
+        let handler = Arc::new(MessageHandler::new("N0CALL".to_string(), tx));
+
+        let sender = handler.clone();
+        let delivery = tokio::spawn(async move { sender.send_message("N1CALL", "Ping").await });
+        let info = sent.recv().await.unwrap().packet.information;
+        let id = info.rsplit('{').next().unwrap().to_string();
+
+        let rej = RoutedPacket {
+            packet: message("N1CALL", &format!(":N0CALL   :rej{}", id)),
+            source: PacketSource::AprsIs,
+        };
+        handler.handle_message(rej, &handler.tx).await.unwrap();
+        assert_eq!(delivery.await.unwrap().unwrap(), Delivery::Rejected);
+        assert_eq!(handler.delivery("nosuchid").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_spool_requires_recently_heard() {
+        let spool = MessageSpool::new(spool_config());
+        let msg = message("N1CALL", ":N2CALL-9 :Hello{42");
+
+        assert!(!spool.hold(&msg).await);
+
+        spool.note_heard(&CallSign::new("N2CALL", 9)).await;
+        assert!(spool.hold(&msg).await);
+        assert_eq!(spool.held_count().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_spool_ignores_messages_without_id() {
+        let spool = MessageSpool::new(spool_config());
+        spool.note_heard(&CallSign::new("N2CALL", 9)).await;
+
+        assert!(!spool.hold(&message("N1CALL", ":N2CALL-9 :Hello")).await);
+        assert!(!spool.hold(&message("N1CALL", ":N2CALL-9 :ack42")).await);
+    }
+
+    #[tokio::test]
+    async fn test_spool_retransmits_until_acked() {
+        let spool = MessageSpool::new(spool_config());
+        spool.note_heard(&CallSign::new("N2CALL", 9)).await;
+        spool.hold(&message("N1CALL", ":N2CALL-9 :Hello{42")).await;
+
+        let due = spool.due_messages().await;
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].information, ":N2CALL-9 :Hello{42");
+
+        spool
+            .acknowledge(&message("N2CALL-9", ":N1CALL   :ack42"))
+            .await;
+        assert_eq!(spool.held_count().await, 0);
+        assert!(spool.due_messages().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_spool_expires_after_hold_time() {
+        let mut config = spool_config();
+        config.hold_time = 0;
+        let spool = MessageSpool::new(config);
+        spool.note_heard(&CallSign::new("N2CALL", 9)).await;
+        spool.hold(&message("N1CALL", ":N2CALL-9 :Hello{42")).await;
+
+        assert!(spool.due_messages().await.is_empty());
+        assert_eq!(spool.held_count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_spool_ignores_short_input() {
+        let spool = MessageSpool::new(spool_config());
+        spool.note_heard(&CallSign::new("N0CALL", 0)).await;
+
+        assert!(!spool.hold(&message("N1CALL", ":N0CALL")).await);
+        assert!(!spool.hold(&message("N1CALL", ">status")).await);
+        // Unpadded addressees are still messages
+        assert!(spool.hold(&message("N1CALL", ":N0CALL:Hi{1")).await);
+    }
+}
@@ -0,0 +1,242 @@
+//! Prometheus metrics.
+//!
+//! `GET /metrics` on the configured address answers with the daemon's
+//! counters in the Prometheus text format: packets, per-port transmit and
+//! error counts, drops, and the state of the APRS-IS link.
+
+use crate::channel;
+use crate::config::MetricsConfig;
+use crate::serial::queue::PortStats;
+use crate::telemetry::TELEMETRY_STATS;
+use anyhow::{anyhow, Result};
+use log::{debug, info};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Largest HTTP request we read
+const MAX_REQUEST: usize = 8192;
+
+const COUNTER: &str = "counter";
+const GAUGE: &str = "gauge";
+
+pub async fn run_metrics_server(config: MetricsConfig) -> Result<()> {
+    let listener = TcpListener::bind(&config.listen).await?;
+    info!("Prometheus metrics on http://{}/metrics", config.listen);
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(stream).await {
+                debug!("Metrics client {}: {}", addr, e);
+            }
+        });
+    }
+}
+
+async fn handle_client(mut stream: TcpStream) -> Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err(anyhow!("connection closed mid-request"));
+        }
+        request.extend_from_slice(&buf[..n]);
+        if request.len() > MAX_REQUEST {
+            return Err(anyhow!("request too large"));
+        }
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut start = request.split_whitespace();
+    let response = match (start.next(), start.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Everything we count, in the Prometheus text format
+pub fn render() -> String {
+    let mut out = String::new();
+    let stats = &TELEMETRY_STATS;
+    let kinds = |samples: &[(&str, &AtomicU64)]| -> Vec<(String, f64)> {
+        samples
+            .iter()
+            .map(|(kind, counter)| (format!("kind=\"{}\"", kind), count(counter)))
+            .collect()
+    };
+
+    let packets = kinds(&[
+        ("rx", &stats.packets_rx),
+        ("tx", &stats.packets_tx),
+        ("digipeated", &stats.packets_digipeated),
+        ("rf_to_is", &stats.packets_igate_rf_to_is),
+        ("is_to_rf", &stats.packets_igate_is_to_rf),
+        ("rate_limited", &stats.packets_rate_limited),
+        ("stale", &stats.packets_stale),
+    ]);
+    metric(
+        &mut out,
+        "aprstx_packets_total",
+        COUNTER,
+        "Packets by what happened to them",
+        packets,
+    );
+    let frames = kinds(&[
+        ("non_aprs", &stats.frames_non_aprs),
+        ("invalid", &stats.frames_invalid),
+    ]);
+    metric(
+        &mut out,
+        "aprstx_frames_total",
+        COUNTER,
+        "Frames heard that weren't APRS",
+        frames,
+    );
+    let drops = channel::drop_counts()
+        .into_iter()
+        .map(|(name, dropped)| (format!("channel=\"{}\"", escape(&name)), dropped as f64))
+        .collect();
+    metric(
+        &mut out,
+        "aprstx_dropped_total",
+        COUNTER,
+        "Packets dropped on full internal channels",
+        drops,
+    );
+
+    let ports = PortStats::all();
+    let per_port = |value: fn(&PortStats) -> f64| -> Vec<(String, f64)> {
+        ports
+            .iter()
+            .map(|(name, stats)| (format!("port=\"{}\"", escape(name)), value(stats)))
+            .collect()
+    };
+    metric(
+        &mut out,
+        "aprstx_port_sent_total",
+        COUNTER,
+        "Packets transmitted",
+        per_port(|s| count(&s.sent)),
+    );
+    metric(
+        &mut out,
+        "aprstx_port_shed_total",
+        COUNTER,
+        "Packets dropped from a full transmit queue",
+        per_port(|s| count(&s.shed)),
+    );
+    metric(
+        &mut out,
+        "aprstx_port_errors_total",
+        COUNTER,
+        "Read, write and framing errors",
+        per_port(|s| count(&s.errors)),
+    );
+    metric(
+        &mut out,
+        "aprstx_port_airtime_seconds_total",
+        COUNTER,
+        "Time spent transmitting",
+        per_port(|s| count(&s.airtime_ms) / 1000.0),
+    );
+    metric(
+        &mut out,
+        "aprstx_port_queued",
+        GAUGE,
+        "Packets waiting to transmit",
+        per_port(|s| s.depth() as f64),
+    );
+
+    let unlabelled = |value: f64| vec![(String::new(), value)];
+    metric(
+        &mut out,
+        "aprstx_aprs_is_reconnects_total",
+        COUNTER,
+        "Times the APRS-IS connection was made again",
+        unlabelled(count(&stats.is_reconnects)),
+    );
+    let bytes = vec![
+        ("direction=\"rx\"".to_string(), count(&stats.is_bytes_rx)),
+        ("direction=\"tx\"".to_string(), count(&stats.is_bytes_tx)),
+    ];
+    metric(
+        &mut out,
+        "aprstx_aprs_is_bytes_total",
+        COUNTER,
+        "Bytes over the APRS-IS connection",
+        bytes,
+    );
+    // Left out until a ping has come back, rather than reported as 0
+    let rtt = count(&stats.is_rtt_ms);
+    if rtt > 0.0 {
+        metric(
+            &mut out,
+            "aprstx_aprs_is_rtt_seconds",
+            GAUGE,
+            "Round trip of the last APRS-IS ping",
+            unlabelled(rtt / 1000.0),
+        );
+    }
+    metric(
+        &mut out,
+        "aprstx_aprs_is_queued",
+        GAUGE,
+        "Packets waiting to go to APRS-IS",
+        unlabelled(channel::APRS_IS_QUEUE.depth() as f64),
+    );
+    out
+}
+
+fn count(counter: &AtomicU64) -> f64 {
+    counter.load(Ordering::Relaxed) as f64
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, samples: Vec<(String, f64)>) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{} {}", name, value);
+        } else {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
+
+/// A label value with its quotes and backslashes escaped
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        PortStats::for_port("metrics-test")
+            .errors
+            .fetch_add(2, Ordering::Relaxed);
+        let text = render();
+        assert!(text.contains("# TYPE aprstx_packets_total counter\n"));
+        assert!(text.contains("aprstx_packets_total{kind=\"rx\"} "));
+        assert!(text.contains("aprstx_port_errors_total{port=\"metrics-test\"} 2\n"));
+        assert!(text.contains("aprstx_aprs_is_reconnects_total "));
+        assert_eq!(escape("a\"b\\c"), "a\\\"b\\\\c");
+    }
+}
@@ -0,0 +1,296 @@
+//! MQTT feed of heard packets, and a topic for sending through us.
+//!
+//! Each packet heard on RF or APRS-IS goes out as JSON at QoS 0 on
+//! `<prefix>/rx/<port>/<source call>`, and with `tx_enable` JSON packets on
+//! `<prefix>/tx` are checked and routed as our own.
+
+use crate::aprs::{parse_packet, AprsPacket, CallSign};
+use crate::channel::{self, Overflow};
+use crate::config::MqttConfig;
+use crate::filter::APRS_IS_PORT;
+use crate::router::{PacketSource, RoutedPacket};
+use anyhow::{anyhow, Result};
+use log::{debug, error, info, warn};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinSet;
+
+/// Requests waiting for the event loop to send them
+const MQTT_QUEUE: usize = 64;
+
+/// Drops counted against the MQTT feed when it falls behind
+const MQTT_CHANNEL: &str = "mqtt";
+
+pub async fn run_mqtt(
+    config: MqttConfig,
+    mycall: String,
+    heard_rx: broadcast::Receiver<RoutedPacket>,
+    packet_tx: mpsc::Sender<RoutedPacket>,
+) -> Result<()> {
+    info!(
+        "Connecting to MQTT broker {}:{}",
+        config.server, config.port
+    );
+    let mut options = MqttOptions::new(&config.client_id, &config.server, config.port);
+    options
+        .set_keep_alive(Duration::from_secs(config.keepalive as u64))
+        .set_clean_session(true);
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.as_deref().unwrap_or_default());
+    }
+    let (client, mut events) = AsyncClient::new(options, MQTT_QUEUE);
+
+    // Polling the event loop can't be interrupted without dropping the
+    // connection, so heard packets are queued from a task of their own,
+    // stopped when this one is
+    let mut publisher = JoinSet::new();
+    publisher.spawn(publish_heard(
+        client.clone(),
+        config.topic_prefix.clone(),
+        heard_rx,
+    ));
+
+    // The event loop connects, and connects again after each failure
+    loop {
+        match events.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker");
+                // A clean session forgets subscriptions, so make it again
+                if config.tx_enable {
+                    let topic = tx_topic(&config.topic_prefix);
+                    client.try_subscribe(&topic, QoS::AtMostOnce)?;
+                    info!("Taking packets to send from MQTT topic {}", topic);
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) if config.tx_enable => {
+                match tx_packet(&mycall, &publish.payload) {
+                    Ok(packet) => {
+                        info!("Sending from MQTT {}: {}", publish.topic, packet);
+                        let routed = RoutedPacket {
+                            packet,
+                            source: PacketSource::Internal,
+                        };
+                        channel::send(&packet_tx, routed, channel::ROUTER, Overflow::Drop).await;
+                    }
+                    Err(e) => warn!("Not sending from MQTT {}: {}", publish.topic, e),
+                }
+            }
+            Ok(event) => debug!("MQTT {:?}", event),
+            Err(e) => {
+                error!("MQTT connection error: {}, reconnecting in 30s...", e);
+                tokio::time::sleep(Duration::from_secs(30)).await;
+            }
+        }
+        // The router is gone
+        if publisher.try_join_next().is_some() {
+            return Ok(());
+        }
+    }
+}
+
+/// Queue each heard packet for the broker, dropping it if the queue is full
+async fn publish_heard(
+    client: AsyncClient,
+    prefix: String,
+    mut heard_rx: broadcast::Receiver<RoutedPacket>,
+) {
+    loop {
+        let routed = match heard_rx.recv().await {
+            Ok(routed) => routed,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!("MQTT publisher fell behind, dropped {} packets", missed);
+                channel::record_drops(MQTT_CHANNEL, missed);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let topic = rx_topic(&prefix, &routed);
+        let payload = routed.packet.to_json().to_string();
+        if client
+            .try_publish(topic, QoS::AtMostOnce, false, payload)
+            .is_err()
+        {
+            channel::record_drops(MQTT_CHANNEL, 1);
+        }
+    }
+}
+
+/// Topic a heard packet is published on
+pub fn rx_topic(prefix: &str, routed: &RoutedPacket) -> String {
+    let port = match &routed.source {
+        PacketSource::SerialPort(name) => name.as_str(),
+        PacketSource::AprsIs => APRS_IS_PORT,
+        PacketSource::Internal | PacketSource::Digipeater(_) => "local",
+    };
+    format!(
+        "{}/rx/{}/{}",
+        prefix,
+        topic_level(port),
+        topic_level(&routed.packet.source.to_string())
+    )
+}
+
+/// One topic level from a name we don't control: a level separator or
+/// wildcard in it would make extra levels or a topic the broker rejects
+fn topic_level(name: &str) -> String {
+    name.replace(['/', '+', '#', '\0'], "_")
+}
+
+/// Topic we take packets to send from
+pub fn tx_topic(prefix: &str) -> String {
+    format!("{}/tx", prefix)
+}
+
+/// Turn a JSON request from the tx topic into a packet from us. Either
+/// `{"raw": "N0CALL>APRS:..."}`, or `{"info": "..."}` with optional
+/// `source` (default mycall), `destination` (default APRS) and `path`.
+/// The source must be mycall, with any SSID.
+pub fn tx_packet(mycall: &str, payload: &[u8]) -> Result<AprsPacket> {
+    let request: serde_json::Value = serde_json::from_slice(payload)?;
+    let field = |name: &str| request.get(name).and_then(|v| v.as_str());
+
+    let packet = match field("raw") {
+        Some(raw) => parse_packet(raw)?,
+        None => {
+            let info = field("info").ok_or_else(|| anyhow!("needs raw or info"))?;
+            let call = |name: &str, default: &str| {
+                let call = field(name).unwrap_or(default);
+                CallSign::parse(call).ok_or_else(|| anyhow!("invalid {}: {}", name, call))
+            };
+            let mut packet = AprsPacket::new(
+                call("source", mycall)?,
+                call("destination", "APRS")?,
+                info.to_string(),
+            );
+            if let Some(path) = request.get("path").and_then(|v| v.as_array()) {
+                packet.path = path
+                    .iter()
+                    .map(|hop| {
+                        hop.as_str()
+                            .and_then(CallSign::parse)
+                            .ok_or_else(|| anyhow!("invalid path entry: {}", hop))
+                    })
+                    .collect::<Result<_>>()?;
+            }
+            packet
+        }
+    };
+
+    let base = |call: &str| call.split('-').next().unwrap_or("").to_uppercase();
+    if packet.source.call.to_uppercase() != base(mycall) {
+        return Err(anyhow!("source {} isn't {}", packet.source, mycall));
+    }
+    if packet.information.is_empty() {
+        return Err(anyhow!("empty information field"));
+    }
+    packet.validate_ax25()?;
+    Ok(packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aprs::parse_packet;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn config(port: u16) -> MqttConfig {
+        toml::from_str(&format!(
+            r#"
+            server = "127.0.0.1"
+            port = {}
+            client_id = "aprstx-test"
+            username = "user"
+            password = "secret"
+            "#,
+            port
+        ))
+        .unwrap()
+    }
+
+    fn heard(port: &str) -> RoutedPacket {
+        RoutedPacket {
+            packet: parse_packet("N0CALL-9>APRS,WIDE1-1:!4903.50N/07201.75W>").unwrap(),
+            source: PacketSource::SerialPort(port.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_tx_packet() {
+        let packet = tx_packet("N0CALL-10", br#"{"raw": "N0CALL-5>APRS,WIDE1-1:>Hello"}"#).unwrap();
+        assert_eq!(packet.to_string(), "N0CALL-5>APRS,WIDE1-1:>Hello");
+
+        let packet = tx_packet(
+            "N0CALL-10",
+            br#"{"info": ";LEADER   *092345z4903.50N/07201.75W>", "path": ["WIDE2-1"]}"#,
+        )
+        .unwrap();
+        assert_eq!(packet.source.to_string(), "N0CALL-10");
+        assert_eq!(packet.path.len(), 1);
+        assert!(packet.object().is_some());
+
+        assert!(tx_packet("N0CALL-10", br#"{"raw": "N1CALL>APRS:>Not us"}"#).is_err());
+        assert!(tx_packet("N0CALL-10", br#"{"info": ""}"#).is_err());
+        assert!(tx_packet("N0CALL-10", br#"{"text": "hi"}"#).is_err());
+        assert!(tx_packet("N0CALL-10", b"not json").is_err());
+    }
+
+    #[test]
+    fn test_rx_topic() {
+        assert_eq!(rx_topic("aprs", &heard("vhf")), "aprs/rx/vhf/N0CALL-9");
+
+        let mut odd = heard("vhf");
+        odd.packet.source = CallSign::new("N0/#+", 0);
+        assert_eq!(rx_topic("aprs", &odd), "aprs/rx/vhf/N0___");
+    }
+
+    /// One control packet as the broker reads it: type and body
+    async fn read_packet(broker: &mut TcpStream) -> (u8, Vec<u8>) {
+        let kind = broker.read_u8().await.unwrap() & 0xF0;
+        let (mut len, mut shift) = (0usize, 0);
+        loop {
+            let byte = broker.read_u8().await.unwrap();
+            len |= ((byte & 0x7F) as usize) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; len];
+        broker.read_exact(&mut body).await.unwrap();
+        (kind, body)
+    }
+
+    #[tokio::test]
+    async fn test_publishes_heard_packets() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = config(listener.local_addr().unwrap().port());
+        let (heard_tx, heard_rx) = broadcast::channel(10);
+
+        let (packet_tx, _packet_rx) = mpsc::channel(10);
+        let client = tokio::spawn(run_mqtt(config, "N0CALL".into(), heard_rx, packet_tx));
+
+        let (mut broker, _) = listener.accept().await.unwrap();
+        let (kind, connect) = read_packet(&mut broker).await;
+        assert_eq!(kind, 0x10); // CONNECT
+        assert!(connect.ends_with(b"\x00\x06secret"));
+        broker.write_all(&[0x20, 2, 0, 0]).await.unwrap(); // CONNACK
+
+        // Skip over keepalive pings
+        heard_tx.send(heard("vhf")).unwrap();
+        let body = loop {
+            let (kind, body) = read_packet(&mut broker).await;
+            if kind == 0x30 {
+                break body;
+            }
+            assert_eq!(kind, 0xC0); // PINGREQ
+        };
+        let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+        assert_eq!(&body[2..2 + topic_len], b"aprs/rx/vhf/N0CALL-9");
+        let json: serde_json::Value = serde_json::from_slice(&body[2 + topic_len..]).unwrap();
+        assert_eq!(json["source"], "N0CALL-9");
+
+        client.abort();
+    }
+}
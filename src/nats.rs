@@ -0,0 +1,266 @@
+//! NATS streaming output.
+//!
+//! Publishes every packet heard on RF or APRS-IS as JSON on
+//! `<prefix>.rx.<port>` and every packet gated to APRS-IS on
+//! `<prefix>.is.tx`. The client reconnects on its own; packets are buffered
+//! while the server is unreachable and sent once it's back, dropping the
+//! oldest when the buffer fills.
+
+use crate::channel;
+use crate::config::NatsConfig;
+use crate::filter::APRS_IS_PORT;
+use crate::router::{PacketSource, RoutedPacket};
+use anyhow::Result;
+use async_nats::{Client, ConnectOptions, Event};
+use log::{debug, info, warn};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::sync::{broadcast, watch};
+
+const NATS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Drops counted against the NATS output when its buffer overflows
+const NATS_CHANNEL: &str = "nats";
+
+/// Messages waiting for the server, oldest first
+struct Outbox {
+    pending: VecDeque<(String, String)>, // Subject and payload
+    capacity: usize,
+}
+
+impl Outbox {
+    fn new(capacity: usize) -> Self {
+        Outbox {
+            pending: VecDeque::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    fn push(&mut self, subject: String, payload: String) {
+        if self.pending.len() >= self.capacity {
+            self.pending.pop_front();
+            channel::record_drops(NATS_CHANNEL, 1);
+        }
+        self.pending.push_back((subject, payload));
+    }
+}
+
+/// Packets to publish, as they come from the router
+struct Feeds {
+    heard: broadcast::Receiver<RoutedPacket>,
+    gated: broadcast::Receiver<RoutedPacket>,
+}
+
+impl Feeds {
+    /// Wait for the next packet from either feed and add it to the outbox
+    async fn collect(&mut self, prefix: &str, outbox: &mut Outbox) {
+        let (result, gated) = tokio::select! {
+            result = self.heard.recv() => (result, false),
+            result = self.gated.recv() => (result, true),
+        };
+        match result {
+            Ok(routed) => {
+                let subject = if gated {
+                    format!("{}.is.tx", prefix)
+                } else {
+                    rx_subject(prefix, &routed)
+                };
+                outbox.push(subject, routed.packet.to_json().to_string());
+            }
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!("NATS output fell behind, dropped {} packets", missed);
+                channel::record_drops(NATS_CHANNEL, missed);
+            }
+            // The router is gone; nothing more will come
+            Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+        }
+    }
+}
+
+pub async fn run_nats(
+    config: NatsConfig,
+    heard: broadcast::Receiver<RoutedPacket>,
+    gated: broadcast::Receiver<RoutedPacket>,
+) -> Result<()> {
+    let (client, connected) = connect(&config).await?;
+    let mut outbox = Outbox::new(config.buffer);
+    let mut feeds = Feeds { heard, gated };
+    run_session(&config, &client, connected, &mut feeds, &mut outbox).await
+}
+
+/// A client that keeps reconnecting, and whether it's connected right now
+async fn connect(config: &NatsConfig) -> Result<(Client, watch::Receiver<bool>)> {
+    info!(
+        "Connecting to NATS server {}:{}",
+        config.server, config.port
+    );
+    let (connected_tx, connected) = watch::channel(false);
+    let mut options = ConnectOptions::new()
+        .name("aprstx")
+        .connection_timeout(NATS_TIMEOUT)
+        .retry_on_initial_connect()
+        .event_callback(move |event| {
+            let connected_tx = connected_tx.clone();
+            async move {
+                match event {
+                    Event::Connected => {
+                        info!("Connected to NATS server");
+                        connected_tx.send_replace(true);
+                    }
+                    Event::Disconnected => {
+                        warn!("NATS connection lost, reconnecting...");
+                        connected_tx.send_replace(false);
+                    }
+                    Event::ServerError(e) => warn!("NATS server error: {}", e),
+                    Event::ClientError(e) => warn!("NATS client error: {}", e),
+                    other => debug!("NATS {}", other),
+                }
+            }
+        });
+    if let (Some(user), Some(password)) = (&config.user, &config.password) {
+        options = options.user_and_password(user.clone(), password.clone());
+    }
+    if let Some(token) = &config.token {
+        options = options.token(token.clone());
+    }
+    let client = options
+        .connect(format!("nats://{}:{}", config.server, config.port))
+        .await?;
+    Ok((client, connected))
+}
+
+async fn run_session(
+    config: &NatsConfig,
+    client: &Client,
+    mut connected: watch::Receiver<bool>,
+    feeds: &mut Feeds,
+    outbox: &mut Outbox,
+) -> Result<()> {
+    loop {
+        // Hand over everything waiting while the server is there to take it
+        if *connected.borrow() {
+            while let Some((subject, payload)) = outbox.pending.pop_front() {
+                client.publish(subject, payload.into()).await?;
+            }
+        } else if !outbox.pending.is_empty() {
+            debug!("{} packets buffered for NATS", outbox.pending.len());
+        }
+
+        tokio::select! {
+            _ = connected.changed() => {}
+            _ = feeds.collect(&config.subject_prefix, outbox) => {}
+        }
+    }
+}
+
+/// Subject a heard packet is published on
+fn rx_subject(prefix: &str, routed: &RoutedPacket) -> String {
+    let port = match &routed.source {
+        PacketSource::SerialPort(name) => name.as_str(),
+        PacketSource::AprsIs => APRS_IS_PORT,
+        PacketSource::Internal | PacketSource::Digipeater(_) => "local",
+    };
+    // Dots and spaces would split or break the subject
+    let port: String = port
+        .chars()
+        .map(|c| {
+            if c == '.' || c.is_whitespace() {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    format!("{}.rx.{}", prefix, port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aprs::parse_packet;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    fn config(port: u16) -> NatsConfig {
+        toml::from_str(&format!(
+            r#"
+            server = "127.0.0.1"
+            port = {}
+            token = "secret"
+            buffer = 2
+            "#,
+            port
+        ))
+        .unwrap()
+    }
+
+    fn heard(port: &str) -> RoutedPacket {
+        RoutedPacket {
+            packet: parse_packet("N0CALL-9>APRS:>Status").unwrap(),
+            source: PacketSource::SerialPort(port.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_rx_subject() {
+        assert_eq!(rx_subject("aprs", &heard("vhf")), "aprs.rx.vhf");
+        assert_eq!(rx_subject("aprs", &heard("port 1.a")), "aprs.rx.port_1_a");
+    }
+
+    #[test]
+    fn test_outbox_drops_oldest() {
+        let mut outbox = Outbox::new(2);
+        outbox.push("a".into(), "1".into());
+        outbox.push("a".into(), "2".into());
+        outbox.push("a".into(), "3".into());
+
+        let payloads: Vec<_> = outbox.pending.iter().map(|(_, p)| p.as_str()).collect();
+        assert_eq!(payloads, ["2", "3"]);
+    }
+
+    #[tokio::test]
+    async fn test_publishes_buffered_packets() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = config(listener.local_addr().unwrap().port());
+        let (heard_tx, heard_rx) = broadcast::channel(10);
+        let (_gated_tx, gated_rx) = broadcast::channel(10);
+
+        // Queued before the server is up
+        let mut outbox = Outbox::new(10);
+        let mut feeds = Feeds {
+            heard: heard_rx,
+            gated: gated_rx,
+        };
+        heard_tx.send(heard("vhf")).unwrap();
+        feeds.collect("aprs", &mut outbox).await;
+        assert_eq!(outbox.pending.len(), 1);
+
+        let client = tokio::spawn(async move {
+            let (client, connected) = connect(&config).await.unwrap();
+            run_session(&config, &client, connected, &mut feeds, &mut outbox).await
+        });
+
+        let (server, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = server.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        writer
+            .write_all(b"INFO {\"server_id\":\"test\",\"max_payload\":1048576}\r\n")
+            .await
+            .unwrap();
+        let connect = lines.next_line().await.unwrap().unwrap();
+        assert!(connect.starts_with("CONNECT "));
+        assert!(connect.contains(r#""auth_token":"secret""#));
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "PING");
+        writer.write_all(b"PONG\r\n").await.unwrap();
+
+        let publish = lines.next_line().await.unwrap().unwrap();
+        assert!(publish.starts_with("PUB aprs.rx.vhf "));
+        let len: usize = publish.rsplit(' ').next().unwrap().parse().unwrap();
+        let mut payload = vec![0; len];
+        lines.get_mut().read_exact(&mut payload).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(json["source"], "N0CALL-9");
+
+        client.abort();
+    }
+}
@@ -1,10 +1,26 @@
-use crate::aprs::parse_packet;
-use crate::config::AprsIsConfig;
+use crate::aprs::{parse_packet, AprsPacket};
+use crate::capture::{self, Direction};
+use crate::channel::{self, Overflow};
+use crate::config::{AprsIsConfig, FollowFilter};
+use crate::filter::APRS_IS_PORT;
+use crate::gps::{distance_km, GpsTracker};
+use crate::is_spool::IsSpool;
+use crate::proxy::Proxy;
 use crate::router::{PacketSource, RoutedPacket};
+use crate::shutdown::Shutdown;
+use crate::telemetry::TELEMETRY_STATS;
 use anyhow::{anyhow, Result};
-use log::{debug, error, info, warn};
-use std::time::Duration;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use chrono::Utc;
+use log::{debug, error, info, log, warn, Level};
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf};
 use tokio::net::TcpStream;
 use tokio::sync::{broadcast, mpsc};
 use tokio::time::{interval, timeout};
@@ -12,13 +28,48 @@ use tokio::time::{interval, timeout};
 const APRS_IS_TIMEOUT: Duration = Duration::from_secs(30);
 const APRS_IS_KEEPALIVE: Duration = Duration::from_secs(20);
 
+/// Read buffer for the full feed
+const FULL_FEED_BUFFER: usize = 1 << 20;
+/// Most lines handed to a parsing worker at once
+const FULL_FEED_BATCH: usize = 256;
+
+/// How often to see whether a GPS-following filter needs moving
+const FOLLOW_CHECK: Duration = Duration::from_secs(60);
+
 pub async fn run_aprs_is_connection(
     config: AprsIsConfig,
     packet_tx: mpsc::Sender<RoutedPacket>,
-    is_rx: broadcast::Receiver<RoutedPacket>,
+    mut is_rx: broadcast::Receiver<RoutedPacket>,
+    gps: Option<Arc<GpsTracker>>,
+    shutdown: Shutdown,
 ) -> Result<()> {
+    let gps = gps.as_deref();
+    // Nothing is sent to APRS-IS without tx_enable, so there's nothing to hold
+    let mut spool = match &config.spool {
+        Some(spool) if config.tx_enable => Some(IsSpool::open(spool)?),
+        _ => None,
+    };
     loop {
-        match connect_and_run(&config, packet_tx.clone(), is_rx.resubscribe()).await {
+        let result = match &mut spool {
+            // Keep the receiver, so what was sent while connecting is kept too
+            Some(spool) => {
+                connect_and_run(&config, packet_tx.clone(), &mut is_rx, gps, Some(spool)).await
+            }
+            None => {
+                // A fresh receiver starts with nothing waiting
+                channel::APRS_IS_QUEUE.clear();
+                let mut is_rx = is_rx.resubscribe();
+                connect_and_run(&config, packet_tx.clone(), &mut is_rx, gps, None).await
+            }
+        };
+        if shutdown.is_triggered() {
+            return Ok(());
+        }
+        TELEMETRY_STATS
+            .is_reconnects
+            .fetch_add(1, Ordering::Relaxed);
+        TELEMETRY_STATS.is_rtt_ms.store(0, Ordering::Relaxed);
+        match result {
             Ok(_) => {
                 warn!("APRS-IS connection closed normally, reconnecting in 30s...");
             }
@@ -26,30 +77,52 @@ pub async fn run_aprs_is_connection(
                 error!("APRS-IS connection error: {}, reconnecting in 30s...", e);
             }
         }
-        tokio::time::sleep(Duration::from_secs(30)).await;
+        let reconnect = tokio::time::sleep(Duration::from_secs(30));
+        tokio::pin!(reconnect);
+        loop {
+            tokio::select! {
+                _ = &mut reconnect => break,
+                _ = shutdown.wait() => return Ok(()),
+                result = is_rx.recv(), if spool.is_some() => {
+                    let Some(spool) = &mut spool else { break };
+                    match result {
+                        Ok(routed) => {
+                            channel::APRS_IS_QUEUE.pop(1);
+                            debug!("Spooling for APRS-IS: {}", routed.packet);
+                            spool.push(&routed.packet);
+                        }
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            channel::record_drops(channel::APRS_IS, missed);
+                            channel::APRS_IS_QUEUE.pop(missed);
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                    }
+                }
+            }
+        }
     }
 }
 
 async fn connect_and_run(
     config: &AprsIsConfig,
     packet_tx: mpsc::Sender<RoutedPacket>,
-    mut is_rx: broadcast::Receiver<RoutedPacket>,
+    is_rx: &mut broadcast::Receiver<RoutedPacket>,
+    gps: Option<&GpsTracker>,
+    mut spool: Option<&mut IsSpool>,
 ) -> Result<()> {
     info!(
         "Connecting to APRS-IS server {}:{}",
         config.server, config.port
     );
 
-    let stream = timeout(
-        APRS_IS_TIMEOUT,
-        TcpStream::connect(format!("{}:{}", config.server, config.port)),
-    )
-    .await??;
-
-    info!("Connected to APRS-IS server");
+    let stream = connect(config).await?;
 
-    let (reader, mut writer) = stream.into_split();
-    let mut reader = BufReader::new(reader);
+    let (reader, mut writer) = tokio::io::split(Metered(stream));
+    let mut reader = if config.full_feed {
+        BufReader::with_capacity(FULL_FEED_BUFFER, reader)
+    } else {
+        BufReader::new(reader)
+    };
     let mut line = String::new();
 
     reader.read_line(&mut line).await?;
@@ -65,13 +138,26 @@ async fn connect_and_run(
             .unwrap_or_else(|_| calculate_passcode(&config.callsign))
     };
 
+    // Where an r/ filter following the GPS was last centred
+    let follows = gps.filter(|_| {
+        config
+            .follow_gps
+            .as_ref()
+            .is_some_and(|f| f.kind == FollowFilter::Range)
+    });
+    let mut centre = match follows {
+        Some(gps) => gps
+            .get_current_position()
+            .await
+            .map(|pos| [pos.latitude, pos.longitude]),
+        None => None,
+    };
+
     let login = format!(
         "user {} pass {} vers aprstx 0.1.0{}\r\n",
         config.callsign,
         passcode,
-        config
-            .filter
-            .as_ref()
+        login_filter(config, centre)
             .map(|f| format!(" filter {}", f))
             .unwrap_or_default()
     );
@@ -86,7 +172,23 @@ async fn connect_and_run(
     info!("APRS-IS login successful: {}", line.trim());
     line.clear();
 
+    if let Some(spool) = spool.as_deref_mut() {
+        upload_spool(spool, &mut writer).await?;
+    }
+
     let mut keepalive_timer = interval(APRS_IS_KEEPALIVE);
+    let mut ping = Ping::default();
+    let mut follow_timer = interval(FOLLOW_CHECK);
+
+    // The full feed is parsed in batches on blocking threads, collected in
+    // order so packets reach the router as they arrived
+    let rx_level = if config.full_feed {
+        Level::Debug
+    } else {
+        Level::Info
+    };
+    let workers = std::thread::available_parallelism().map_or(2, |n| n.get());
+    let mut parsing = VecDeque::new();
 
     loop {
         tokio::select! {
@@ -96,21 +198,37 @@ async fn connect_and_run(
                         info!("APRS-IS connection closed by server");
                         break;
                     }
+                    Ok(_) if config.full_feed => {
+                        // Take every complete line already buffered
+                        let mut batch = vec![std::mem::take(&mut line)];
+                        while batch.len() < FULL_FEED_BATCH && reader.buffer().contains(&b'\n') {
+                            reader.read_line(&mut line).await?;
+                            batch.push(std::mem::take(&mut line));
+                        }
+                        for line in batch.iter().filter(|l| l.starts_with('#')) {
+                            ping.answered(line.trim(), Instant::now());
+                        }
+                        parsing.push_back(tokio::task::spawn_blocking(move || parse_batch(&batch)));
+
+                        // Deliver once every worker is busy, or the socket has
+                        // nothing more for now
+                        while parsing.len() >= workers
+                            || (!parsing.is_empty() && !reader.buffer().contains(&b'\n'))
+                        {
+                            let Some(batch) = parsing.pop_front() else { break };
+                            for packet in batch.await? {
+                                deliver(config, &packet_tx, packet, rx_level).await;
+                            }
+                        }
+                    }
                     Ok(_) => {
                         let trimmed = line.trim();
                         if trimmed.starts_with('#') {
                             debug!("APRS-IS server message: {}", trimmed);
+                            ping.answered(trimmed, Instant::now());
                         } else if !trimmed.is_empty() {
                             if let Ok(packet) = parse_packet(trimmed) {
-                                info!("RX [APRS-IS]: {}", packet);
-
-                                if config.rx_enable {
-                                    let routed = RoutedPacket {
-                                        packet,
-                                        source: PacketSource::AprsIs,
-                                    };
-                                    let _ = packet_tx.send(routed).await;
-                                }
+                                deliver(config, &packet_tx, packet, rx_level).await;
                             }
                         }
                         line.clear();
@@ -122,21 +240,61 @@ async fn connect_and_run(
                 }
             }
 
-            Ok(routed) = is_rx.recv() => {
+            result = is_rx.recv() => {
+                let routed = match result {
+                    Ok(routed) => {
+                        channel::APRS_IS_QUEUE.pop(1);
+                        routed
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("APRS-IS sender fell behind, dropped {} packets", missed);
+                        channel::record_drops(channel::APRS_IS, missed);
+                        channel::APRS_IS_QUEUE.pop(missed);
+                        continue;
+                    }
+                    // The router has stopped and everything it sent is out
+                    Err(broadcast::error::RecvError::Closed) => {
+                        info!("Disconnecting from APRS-IS");
+                        writer.shutdown().await?;
+                        break;
+                    }
+                };
                 if config.tx_enable {
                     let aprs_line = format!("{}\r\n", routed.packet);
                     if let Err(e) = writer.write_all(aprs_line.as_bytes()).await {
                         error!("Failed to send to APRS-IS: {}", e);
+                        if let Some(spool) = spool.as_deref_mut() {
+                            spool.push(&routed.packet);
+                        }
                         break;
                     } else {
                         info!("TX [APRS-IS]: {}", routed.packet);
+                        capture::record(APRS_IS_PORT, Direction::Tx, &routed.packet);
                     }
                 }
             }
 
+            _ = follow_timer.tick(), if follows.is_some() => {
+                let Some(gps) = follows else { continue };
+                let Some(pos) = gps.get_current_position().await else { continue };
+                let here = [pos.latitude, pos.longitude];
+                let min_move = config.follow_gps.as_ref().map_or(0.0, |f| f.min_move);
+                if centre.is_some_and(|c| distance_km(c[0], c[1], here[0], here[1]) < min_move) {
+                    continue;
+                }
+                centre = Some(here);
+                let filter = login_filter(config, centre).unwrap_or_default();
+                info!("Moving APRS-IS filter: {}", filter);
+                if let Err(e) = writer.write_all(format!("#filter {}\r\n", filter).as_bytes()).await {
+                    error!("Failed to send filter: {}", e);
+                    break;
+                }
+            }
+
             _ = keepalive_timer.tick() => {
                 debug!("Sending APRS-IS keepalive");
-                if let Err(e) = writer.write_all(b"# keepalive\r\n").await {
+                let keepalive = ping.send(Instant::now());
+                if let Err(e) = writer.write_all(keepalive.as_bytes()).await {
                     error!("Failed to send keepalive: {}", e);
                     break;
                 }
@@ -147,7 +305,234 @@ async fn connect_and_run(
     Ok(())
 }
 
-fn calculate_passcode(callsign: &str) -> i32 {
+/// Connect to the first of the server's addresses that answers
+async fn connect(config: &AprsIsConfig) -> Result<TcpStream> {
+    if let Some(proxy) = &config.proxy {
+        return connect_by_proxy(config, proxy).await;
+    }
+    let mut error = anyhow!("{} has no addresses", config.server);
+    for addr in resolve(config).await? {
+        match timeout(APRS_IS_TIMEOUT, TcpStream::connect(addr)).await {
+            Ok(Ok(stream)) => {
+                info!("Connected to APRS-IS server at {}", addr);
+                return Ok(stream);
+            }
+            Ok(Err(e)) => error = anyhow!("Failed to connect to {}: {}", addr, e),
+            Err(_) => error = anyhow!("Timed out connecting to {}", addr),
+        }
+        warn!("{}", error);
+    }
+    Err(error)
+}
+
+/// Connect through a proxy, which looks the server up itself unless
+/// `addresses` pins where it is
+async fn connect_by_proxy(config: &AprsIsConfig, proxy: &Proxy) -> Result<TcpStream> {
+    let targets = if config.addresses.is_empty() {
+        vec![config.server.clone()]
+    } else {
+        config.addresses.iter().map(|ip| ip.to_string()).collect()
+    };
+    let mut error = anyhow!("{} has no addresses", config.server);
+    for target in targets {
+        match timeout(APRS_IS_TIMEOUT, proxy.connect(&target, config.port)).await {
+            Ok(Ok(stream)) => {
+                info!(
+                    "Connected to APRS-IS server at {}:{} through {}",
+                    target,
+                    config.port,
+                    proxy.address()
+                );
+                return Ok(stream);
+            }
+            Ok(Err(e)) => error = e,
+            Err(_) => {
+                error = anyhow!(
+                    "Timed out connecting to {} through {}",
+                    target,
+                    proxy.address()
+                )
+            }
+        }
+        warn!("{}", error);
+    }
+    Err(error)
+}
+
+/// The addresses to try for the server. The name is looked up again for
+/// every connection, as rotate.aprs2.net answers with a different set each
+/// time, unless `addresses` pins them.
+async fn resolve(config: &AprsIsConfig) -> Result<Vec<SocketAddr>> {
+    if !config.addresses.is_empty() {
+        return Ok(config
+            .addresses
+            .iter()
+            .map(|ip| SocketAddr::new(*ip, config.port))
+            .collect());
+    }
+    let lookup = tokio::net::lookup_host((config.server.as_str(), config.port));
+    let addrs: Vec<SocketAddr> = timeout(Duration::from_secs(config.dns_timeout as u64), lookup)
+        .await
+        .map_err(|_| anyhow!("Timed out looking up {}", config.server))?
+        .map_err(|e| anyhow!("Failed to look up {}: {}", config.server, e))?
+        .collect();
+    debug!("{} is at {:?}", config.server, addrs);
+    Ok(addrs)
+}
+
+/// Send what was spooled while disconnected, oldest first, keeping
+/// whatever the connection drops before it is written
+async fn upload_spool(spool: &mut IsSpool, writer: &mut (impl AsyncWrite + Unpin)) -> Result<()> {
+    spool.expire(Utc::now());
+    if spool.is_empty() {
+        return Ok(());
+    }
+    info!("Uploading {} packets spooled for APRS-IS", spool.len());
+    let mut sent = 0;
+    let result = async {
+        for packet in spool.packets() {
+            writer
+                .write_all(format!("{}\r\n", packet).as_bytes())
+                .await?;
+            sent += 1;
+        }
+        Ok::<_, std::io::Error>(())
+    }
+    .await;
+    spool.remove(sent)?;
+    Ok(result?)
+}
+
+async fn deliver(
+    config: &AprsIsConfig,
+    packet_tx: &mpsc::Sender<RoutedPacket>,
+    packet: AprsPacket,
+    level: Level,
+) {
+    log!(level, "RX [APRS-IS]: {}", packet);
+    capture::record(APRS_IS_PORT, Direction::Rx, &packet);
+
+    if config.rx_enable {
+        let routed = RoutedPacket {
+            packet,
+            source: PacketSource::AprsIs,
+        };
+        channel::send(packet_tx, routed, channel::ROUTER, Overflow::Block).await;
+    }
+}
+
+/// The configured filter, with the GPS-following range added. An r/ range
+/// waits for a position.
+fn login_filter(config: &AprsIsConfig, centre: Option<[f64; 2]>) -> Option<String> {
+    let follow = config.follow_gps.as_ref().and_then(|f| match f.kind {
+        FollowFilter::Range => {
+            centre.map(|[lat, lon]| format!("r/{:.2}/{:.2}/{}", lat, lon, f.range))
+        }
+        FollowFilter::MyRange => Some(format!("m/{}", f.range)),
+    });
+    let terms: Vec<&str> = follow
+        .iter()
+        .chain(&config.filter)
+        .map(String::as_str)
+        .collect();
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" "))
+    }
+}
+
+/// Parse a batch of lines from the feed, skipping server comments and
+/// anything that isn't a packet
+/// Keepalives double as pings: servers that support it answer `#ping N`
+/// with `# pong N`, which gives the round trip over the link.
+#[derive(Debug, Default)]
+struct Ping {
+    sequence: u64,
+    sent: Option<(u64, Instant)>,
+}
+
+impl Ping {
+    /// The keepalive line to send, noting when it went
+    fn send(&mut self, now: Instant) -> String {
+        self.sequence += 1;
+        self.sent = Some((self.sequence, now));
+        format!("#ping aprstx {}\r\n", self.sequence)
+    }
+
+    /// Record the round trip if `line` answers the last ping
+    fn answered(&mut self, line: &str, now: Instant) -> Option<Duration> {
+        let id = line
+            .strip_prefix("# pong aprstx ")?
+            .split_whitespace()
+            .next()?;
+        let (sequence, sent) = self.sent?;
+        if id.parse::<u64>().ok()? != sequence {
+            return None;
+        }
+        self.sent = None;
+        let rtt = now.duration_since(sent);
+        TELEMETRY_STATS
+            .is_rtt_ms
+            .store(rtt.as_millis().max(1) as u64, Ordering::Relaxed);
+        debug!("APRS-IS round trip {} ms", rtt.as_millis());
+        Some(rtt)
+    }
+}
+
+/// The APRS-IS connection, counting bytes each way
+struct Metered<S>(S);
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.0).poll_read(cx, buf);
+        let read = buf.filled().len() - before;
+        TELEMETRY_STATS
+            .is_bytes_rx
+            .fetch_add(read as u64, Ordering::Relaxed);
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.0).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            TELEMETRY_STATS
+                .is_bytes_tx
+                .fetch_add(written as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+fn parse_batch(lines: &[String]) -> Vec<AprsPacket> {
+    lines
+        .iter()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| parse_packet(line).ok())
+        .collect()
+}
+
+pub fn calculate_passcode(callsign: &str) -> i32 {
     let call_upper = callsign.split('-').next().unwrap_or("").to_uppercase();
     let mut hash: i32 = 0x73e2;
 
@@ -161,3 +546,92 @@ fn calculate_passcode(callsign: &str) -> i32 {
 
     hash & 0x7fff
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ping() {
+        let mut ping = Ping::default();
+        let start = Instant::now();
+        assert_eq!(ping.send(start), "#ping aprstx 1\r\n");
+        let later = start + Duration::from_millis(140);
+        assert_eq!(ping.answered("# keepalive", later), None);
+        assert_eq!(ping.answered("# pong aprstx 2", later), None);
+        assert_eq!(
+            ping.answered("# pong aprstx 1", later),
+            Some(Duration::from_millis(140))
+        );
+        // Only the first answer counts
+        assert_eq!(ping.answered("# pong aprstx 1", later), None);
+    }
+
+    #[tokio::test]
+    async fn test_metered() {
+        let (near, mut far) = tokio::io::duplex(64);
+        let mut near = Metered(near);
+        let (rx, tx) = (
+            TELEMETRY_STATS.is_bytes_rx.load(Ordering::Relaxed),
+            TELEMETRY_STATS.is_bytes_tx.load(Ordering::Relaxed),
+        );
+        near.write_all(b"user N0CALL\r\n").await.unwrap();
+        far.write_all(b"# aprsc\r\n").await.unwrap();
+        let mut line = String::new();
+        BufReader::new(&mut near)
+            .read_line(&mut line)
+            .await
+            .unwrap();
+        assert!(TELEMETRY_STATS.is_bytes_tx.load(Ordering::Relaxed) >= tx + 13);
+        assert!(TELEMETRY_STATS.is_bytes_rx.load(Ordering::Relaxed) >= rx + 9);
+    }
+
+    #[test]
+    fn test_login_filter() {
+        let mut config: AprsIsConfig = toml::from_str("filter = \"t/m\"").unwrap();
+        assert_eq!(login_filter(&config, None).as_deref(), Some("t/m"));
+
+        config.follow_gps = Some(toml::from_str("range = 30").unwrap());
+        assert_eq!(login_filter(&config, None).as_deref(), Some("t/m"));
+        assert_eq!(
+            login_filter(&config, Some([49.0583, -72.0292])).as_deref(),
+            Some("r/49.06/-72.03/30 t/m")
+        );
+
+        config.filter = None;
+        config.follow_gps = Some(toml::from_str("kind = \"m\"").unwrap());
+        assert_eq!(login_filter(&config, None).as_deref(), Some("m/50"));
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let mut config: AprsIsConfig = toml::from_str("server = \"127.0.0.1\"").unwrap();
+        let local: SocketAddr = "127.0.0.1:14580".parse().unwrap();
+        assert_eq!(resolve(&config).await.unwrap(), [local]);
+
+        // Pinned addresses are used without a lookup
+        config.server = "no-such-host.invalid".to_string();
+        config.addresses = vec!["192.0.2.1".parse().unwrap(), "2001:db8::1".parse().unwrap()];
+        let addrs = resolve(&config).await.unwrap();
+        assert_eq!(addrs[0], "192.0.2.1:14580".parse().unwrap());
+        assert_eq!(addrs[1], "[2001:db8::1]:14580".parse().unwrap());
+    }
+
+    #[test]
+    fn test_parse_batch() {
+        let lines: Vec<String> = [
+            "# aprsc 2.1.14\r\n",
+            "N0CALL>APRS,TCPIP*,qAC,T2TEST:>Status\r\n",
+            "garbage\r\n",
+            "N1CALL>APRS,TCPIP*,qAC,T2TEST:>Second\r\n",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+
+        let packets = parse_batch(&lines);
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].source.call, "N0CALL");
+        assert_eq!(packets[1].information, ">Second");
+    }
+}
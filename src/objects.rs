@@ -0,0 +1,406 @@
+//! Object file server.
+//!
+//! Beacons the objects and items listed in a separate TOML file, one at a
+//! time in turn, each at its own interval. The file is checked for changes
+//! before every transmission, so net control can add, move or remove
+//! objects without restarting; removed objects are killed on the air.
+//!
+//! Repeaters listed in the config are served alongside, as frequency
+//! objects in the APRS local info convention: named for the output
+//! frequency, with the tone, offset and range leading the comment.
+
+use crate::aprs::{AprsPacket, CallSign};
+use crate::beacon::{format_latitude, format_longitude};
+use crate::channel::{self, Overflow};
+use crate::config::{ObjectsConfig, RepeaterConfig};
+use crate::rig;
+use crate::router::{PacketSource, RoutedPacket};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::mpsc;
+
+#[derive(Debug, Deserialize)]
+struct ObjectFile {
+    #[serde(default)]
+    objects: Vec<ObjectEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct ObjectEntry {
+    name: String,
+    position: [f64; 2], // [lat, lon]
+    #[serde(default = "default_symbol_table")]
+    symbol_table: char,
+    symbol: char,
+    #[serde(default)]
+    comment: String,
+    #[serde(default, deserialize_with = "crate::units::opt_seconds")]
+    interval: Option<u32>, // seconds; unset uses the file-wide default
+    #[serde(default)]
+    item: bool, // Send as an item, which has no timestamp
+}
+
+fn default_symbol_table() -> char {
+    '/'
+}
+
+impl ObjectEntry {
+    fn validate(&self) -> Result<()> {
+        let max = 9;
+        let min = if self.item { 3 } else { 1 };
+        let len = self.name.chars().count();
+        if len < min || len > max {
+            return Err(anyhow!(
+                "{} name must be {} to {} characters",
+                self.name,
+                min,
+                max
+            ));
+        }
+        if self.item && self.name.contains(['!', '_']) {
+            return Err(anyhow!("Item name {} can't contain ! or _", self.name));
+        }
+        let [lat, lon] = self.position;
+        if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+            return Err(anyhow!("{} has an invalid position", self.name));
+        }
+        Ok(())
+    }
+
+    /// The object or item report, live or killed
+    fn report(&self, alive: bool, now: DateTime<Utc>) -> String {
+        let [lat, lon] = self.position;
+        let position = format!(
+            "{}{}{}{}{}",
+            format_latitude(lat),
+            self.symbol_table,
+            format_longitude(lon),
+            self.symbol,
+            self.comment
+        );
+        if self.item {
+            let marker = if alive { '!' } else { '_' };
+            format!("){}{}{}", self.name, marker, position)
+        } else {
+            let marker = if alive { '*' } else { '_' };
+            format!(
+                ";{:<9}{}{}{}",
+                self.name,
+                marker,
+                now.format("%d%H%Mz"),
+                position
+            )
+        }
+    }
+}
+
+impl ObjectEntry {
+    /// The frequency object for a repeater
+    fn repeater(config: &RepeaterConfig) -> Result<Self> {
+        if !(config.frequency > 0.0 && config.frequency < 10_000.0) {
+            return Err(anyhow!("Invalid repeater frequency {}", config.frequency));
+        }
+        if config.suffix.chars().count() > 2 {
+            return Err(anyhow!(
+                "Repeater suffix {} is more than two characters",
+                config.suffix
+            ));
+        }
+        let hz = (config.frequency * 1_000_000.0).round() as u64;
+        let name = format!("{}{}", rig::format_mhz(hz), config.suffix);
+
+        let mut comment = vec![match (config.dcs, config.tone) {
+            (Some(code), _) => format!("D{:03}", code),
+            (None, Some(tone)) => format!("T{:03}", tone as u32),
+            (None, None) => "Toff".to_string(),
+        }];
+        if let Some(offset) = config.offset {
+            // In tens of kHz
+            let sign = if offset < 0 { '-' } else { '+' };
+            comment.push(format!("{}{:03}", sign, offset.unsigned_abs() / 10));
+        }
+        if let Some(range) = config.range {
+            comment.push(format!("R{:02}m", (range / 1.609344).round() as u32));
+        }
+        if !config.comment.is_empty() {
+            comment.push(config.comment.clone());
+        }
+
+        let entry = ObjectEntry {
+            name,
+            position: config.position,
+            symbol_table: '/',
+            symbol: 'r',
+            comment: comment.join(" "),
+            interval: config.interval,
+            item: false,
+        };
+        entry.validate()?;
+        Ok(entry)
+    }
+}
+
+fn load(path: &Path) -> Result<Vec<ObjectEntry>> {
+    let contents = std::fs::read_to_string(path)?;
+    let file: ObjectFile = toml::from_str(&contents)?;
+    for entry in &file.objects {
+        entry.validate()?;
+    }
+    Ok(file.objects)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// The objects being served, and when each last went out
+struct Schedule {
+    entries: Vec<(ObjectEntry, Option<Instant>)>,
+    next: usize,
+    interval: Duration,
+}
+
+impl Schedule {
+    fn new(interval: Duration) -> Self {
+        Schedule {
+            entries: Vec::new(),
+            next: 0,
+            interval,
+        }
+    }
+
+    /// Swap in a new list, keeping the timing of objects that didn't
+    /// change. Returns the objects that are gone, to be killed.
+    fn replace(&mut self, entries: Vec<ObjectEntry>) -> Vec<ObjectEntry> {
+        let old = std::mem::take(&mut self.entries);
+        self.entries = entries
+            .into_iter()
+            .map(|entry| {
+                let sent = old.iter().find(|(e, _)| *e == entry).and_then(|(_, t)| *t);
+                (entry, sent)
+            })
+            .collect();
+        self.next = 0;
+        old.into_iter()
+            .map(|(entry, _)| entry)
+            .filter(|entry| !self.entries.iter().any(|(e, _)| e.name == entry.name))
+            .collect()
+    }
+
+    /// The next object due, in turn after the last one sent
+    fn due(&mut self, now: Instant) -> Option<&ObjectEntry> {
+        let count = self.entries.len();
+        let i = (0..count)
+            .map(|offset| (self.next + offset) % count)
+            .find(|&i| {
+                let (entry, sent) = &self.entries[i];
+                let interval = entry
+                    .interval
+                    .map_or(self.interval, |s| Duration::from_secs(s as u64));
+                sent.is_none_or(|t| now.duration_since(t) >= interval)
+            })?;
+        self.next = i + 1;
+        let (entry, sent) = &mut self.entries[i];
+        *sent = Some(now);
+        Some(entry)
+    }
+}
+
+pub async fn run_object_server(
+    config: ObjectsConfig,
+    mycall: String,
+    tx: mpsc::Sender<RoutedPacket>,
+) -> Result<()> {
+    let repeaters: Vec<ObjectEntry> = config
+        .repeaters
+        .iter()
+        .filter_map(|c| match ObjectEntry::repeater(c) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                error!("Ignoring repeater {}: {}", c.frequency, e);
+                None
+            }
+        })
+        .collect();
+    match &config.file {
+        Some(file) => info!("Serving objects from {}", file),
+        None => info!("Serving {} repeater objects", repeaters.len()),
+    }
+
+    let source = CallSign::parse(config.callsign.as_deref().unwrap_or(&mycall))
+        .unwrap_or(CallSign::new("N0CALL", 0));
+    let path_hops: Vec<CallSign> = config
+        .path
+        .split(',')
+        .filter_map(|p| CallSign::parse(p.trim()))
+        .collect();
+    let mut schedule = Schedule::new(Duration::from_secs(config.interval as u64));
+    schedule.replace(repeaters.clone());
+    let mut loaded = None;
+    let mut spacing = tokio::time::interval(tokio::time::Duration::from_secs(
+        config.spacing.max(1) as u64,
+    ));
+
+    loop {
+        spacing.tick().await;
+
+        let mut reports = Vec::new();
+        let now = Utc::now();
+
+        if let Some(file) = &config.file {
+            let path = Path::new(file);
+            let stamp = modified(path);
+            if stamp != loaded {
+                loaded = stamp;
+                match load(path) {
+                    Ok(mut entries) => {
+                        info!("Loaded {} objects from {}", entries.len(), file);
+                        entries.extend(repeaters.iter().cloned());
+                        for gone in schedule.replace(entries) {
+                            info!("Killing object {}", gone.name);
+                            reports.push(gone.report(false, now));
+                        }
+                    }
+                    // Keep serving the last good list until the file is fixed
+                    Err(e) => warn!("Failed to load objects from {}: {}", file, e),
+                }
+            }
+        }
+
+        if let Some(entry) = schedule.due(Instant::now()) {
+            reports.push(entry.report(true, now));
+        }
+
+        for report in reports {
+            let mut packet = AprsPacket::new(source.clone(), CallSign::new("APRS", 0), report);
+            packet.path = path_hops.clone();
+            debug!("Sending object: {}", packet);
+            let routed = RoutedPacket {
+                packet,
+                source: PacketSource::Internal,
+            };
+            channel::send(&tx, routed, channel::ROUTER, Overflow::Drop).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aprs::parse_packet;
+
+    fn entries(toml: &str) -> Vec<ObjectEntry> {
+        toml::from_str::<ObjectFile>(toml).unwrap().objects
+    }
+
+    const NET: &str = r#"
+        [[objects]]
+        name = "NET-CTRL"
+        position = [40.7128, -74.0060]
+        symbol = "n"
+        comment = "Net control 146.520"
+
+        [[objects]]
+        name = "AID1"
+        position = [40.72, -74.01]
+        symbol_table = "\\"
+        symbol = "+"
+        item = true
+        interval = 60
+    "#;
+
+    #[test]
+    fn test_reports_parse_back() {
+        let objects = entries(NET);
+        let now = Utc::now();
+
+        let object =
+            parse_packet(&format!("N0CALL>APRS:{}", objects[0].report(true, now))).unwrap();
+        let report = object.object().unwrap();
+        assert_eq!(report.name, "NET-CTRL");
+        assert!(report.alive);
+        assert!((report.position.latitude - 40.7128).abs() < 0.001);
+
+        let item = parse_packet(&format!("N0CALL>APRS:{}", objects[1].report(false, now))).unwrap();
+        let report = item.object().unwrap();
+        assert_eq!(report.name, "AID1");
+        assert!(!report.alive);
+    }
+
+    #[test]
+    fn test_repeater_objects() {
+        let config: RepeaterConfig = toml::from_str(
+            r#"
+            frequency = 146.94
+            suffix = "-A"
+            position = [40.7128, -74.0060]
+            tone = 88.5
+            offset = -600
+            range = "25 mi"
+            comment = "Net Mon 8pm"
+            "#,
+        )
+        .unwrap();
+        let entry = ObjectEntry::repeater(&config).unwrap();
+        assert_eq!(entry.name, "146.940-A");
+        assert_eq!(entry.comment, "T088 -060 R25m Net Mon 8pm");
+
+        let packet =
+            parse_packet(&format!("N0CALL>APRS:{}", entry.report(true, Utc::now()))).unwrap();
+        let report = packet.object().unwrap();
+        assert_eq!(report.name, "146.940-A");
+        assert!(report.alive);
+
+        // Carrier access with the standard offset; names stay nine characters
+        let config: RepeaterConfig = toml::from_str(
+            "frequency = 444.1
+position = [40.0, -74.0]",
+        )
+        .unwrap();
+        assert_eq!(ObjectEntry::repeater(&config).unwrap().comment, "Toff");
+        let config: RepeaterConfig = toml::from_str(
+            "frequency = 146.94
+suffix = \"abc\"
+position = [40.0, -74.0]",
+        )
+        .unwrap();
+        assert!(ObjectEntry::repeater(&config).is_err());
+    }
+
+    #[test]
+    fn test_validation() {
+        let mut entry = entries(NET).remove(1);
+        assert!(entry.validate().is_ok());
+        entry.name = "AB".to_string();
+        assert!(entry.validate().is_err());
+        entry.name = "TOOLONGNAME".to_string();
+        assert!(entry.validate().is_err());
+    }
+
+    #[test]
+    fn test_round_robin_and_kills() {
+        let mut schedule = Schedule::new(Duration::from_secs(600));
+        assert!(schedule.replace(entries(NET)).is_empty());
+
+        let start = Instant::now();
+        assert_eq!(schedule.due(start).unwrap().name, "NET-CTRL");
+        assert_eq!(schedule.due(start).unwrap().name, "AID1");
+        assert!(schedule.due(start + Duration::from_secs(30)).is_none());
+
+        // The item's own interval is shorter
+        let later = start + Duration::from_secs(60);
+        assert_eq!(schedule.due(later).unwrap().name, "AID1");
+
+        // Dropping an object from the file kills it; the rest keep their timing
+        let mut remaining = entries(NET);
+        remaining.remove(0);
+        let gone = schedule.replace(remaining);
+        assert_eq!(gone.len(), 1);
+        assert_eq!(gone[0].name, "NET-CTRL");
+        assert!(schedule.due(later).is_none());
+    }
+}
@@ -0,0 +1,147 @@
+//! Answers messages to a callsign with a fixed reply, at most once per
+//! station per holdoff.
+
+use super::{Context, PacketProcessor, Verdict};
+use crate::aprs::{AprsPacket, CallSign};
+use crate::config::PluginConfig;
+use crate::router::{PacketSource, RoutedPacket};
+use anyhow::{anyhow, Result};
+use log::info;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Options {
+    #[serde(default)]
+    callsign: Option<String>, // Station to answer for; defaults to mycall
+    text: String,
+    #[serde(
+        default = "default_holdoff",
+        deserialize_with = "crate::units::seconds"
+    )]
+    holdoff: u64, // Seconds before the same station gets another reply
+}
+
+fn default_holdoff() -> u64 {
+    3600
+}
+
+pub struct AutoReply {
+    name: String,
+    callsign: String,
+    text: String,
+    holdoff: Duration,
+    replied: Mutex<HashMap<String, Instant>>,
+}
+
+impl AutoReply {
+    pub fn build(entry: &PluginConfig, context: &Context) -> Result<Box<dyn PacketProcessor>> {
+        let config = context.config;
+        let options: Options = entry.options()?;
+        let callsign = options.callsign.unwrap_or_else(|| config.mycall.clone());
+        if CallSign::parse(&callsign).is_none() {
+            return Err(anyhow!("Invalid callsign: {}", callsign));
+        }
+        Ok(Box::new(AutoReply {
+            name: format!("autoreply for {}", callsign),
+            callsign,
+            text: options.text,
+            holdoff: Duration::from_secs(options.holdoff),
+            replied: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    fn reply(&self, to: &str) -> RoutedPacket {
+        let packet = AprsPacket::new(
+            CallSign::parse(&self.callsign).unwrap_or(CallSign::new("N0CALL", 0)),
+            CallSign::new("APRS", 0),
+            format!(":{:<9}:{}", to, self.text),
+        );
+        RoutedPacket {
+            packet,
+            source: PacketSource::Internal,
+        }
+    }
+}
+
+impl PacketProcessor for AutoReply {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn on_receive(&self, routed: &mut RoutedPacket, inject: &mut Vec<RoutedPacket>) -> Verdict {
+        let Some(msg) = routed.packet.message() else {
+            return Verdict::Keep;
+        };
+        if msg.is_ack || msg.is_rej || !msg.addressee.eq_ignore_ascii_case(&self.callsign) {
+            return Verdict::Keep;
+        }
+
+        let from = routed.packet.source.to_string();
+        let now = Instant::now();
+        let mut replied = self.replied.lock().unwrap();
+        replied.retain(|_, t| now.duration_since(*t) < self.holdoff);
+        if replied.contains_key(&from) {
+            return Verdict::Keep;
+        }
+        replied.insert(from.clone(), now);
+
+        info!("Auto-replying to {}", from);
+        inject.push(self.reply(&from));
+        Verdict::Keep
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aprs::parse_packet;
+
+    fn autoreply() -> AutoReply {
+        AutoReply {
+            name: "autoreply".to_string(),
+            callsign: "N0CALL-10".to_string(),
+            text: "Away from the radio".to_string(),
+            holdoff: Duration::from_secs(3600),
+            replied: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn heard(packet: &str) -> RoutedPacket {
+        RoutedPacket {
+            packet: parse_packet(packet).unwrap(),
+            source: PacketSource::SerialPort("vhf".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_replies_once_per_station() {
+        let plugin = autoreply();
+        let mut inject = Vec::new();
+
+        plugin.on_receive(&mut heard("N1CALL>APRS::N0CALL-10:Hello{1"), &mut inject);
+        assert_eq!(inject.len(), 1);
+        assert_eq!(
+            inject[0].packet.information,
+            ":N1CALL   :Away from the radio"
+        );
+        assert_eq!(inject[0].packet.source.to_string(), "N0CALL-10");
+
+        plugin.on_receive(&mut heard("N1CALL>APRS::N0CALL-10:Again{2"), &mut inject);
+        assert_eq!(inject.len(), 1);
+    }
+
+    #[test]
+    fn test_ignores_acks_and_other_stations() {
+        let plugin = autoreply();
+        let mut inject = Vec::new();
+
+        plugin.on_receive(&mut heard("N1CALL>APRS::N0CALL-10:ack1"), &mut inject);
+        plugin.on_receive(&mut heard("N1CALL>APRS::N2CALL   :Hello{1"), &mut inject);
+        plugin.on_receive(&mut heard("N1CALL>APRS:>Status"), &mut inject);
+        assert!(inject.is_empty());
+    }
+}
@@ -0,0 +1,144 @@
+//! Raises an alert for Mic-E Emergency reports and EMERGENCY messages, at
+//! most once per station per holdoff.
+
+use super::{Context, PacketProcessor, Verdict};
+use crate::alert::{AlertConfig, Alerter};
+use crate::config::PluginConfig;
+use crate::router::RoutedPacket;
+use anyhow::Result;
+use log::error;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Deserialize)]
+struct Options {
+    #[serde(flatten)]
+    alert: AlertConfig,
+    #[serde(
+        default = "default_holdoff",
+        deserialize_with = "crate::units::seconds"
+    )]
+    holdoff: u64, // Seconds before the same station raises another alert
+}
+
+fn default_holdoff() -> u64 {
+    600
+}
+
+pub struct Emergency {
+    mycall: String,
+    alerter: Alerter,
+    holdoff: Duration,
+    alerted: Mutex<HashMap<String, Instant>>,
+}
+
+impl Emergency {
+    pub fn build(entry: &PluginConfig, context: &Context) -> Result<Box<dyn PacketProcessor>> {
+        let config = context.config;
+        let options: Options = entry.options()?;
+        Ok(Box::new(Emergency {
+            mycall: config.mycall.clone(),
+            alerter: Alerter::new(options.alert, &config.mycall)?,
+            holdoff: Duration::from_secs(options.holdoff),
+            alerted: Mutex::new(HashMap::new()),
+        }))
+    }
+}
+
+fn summary(routed: &RoutedPacket) -> String {
+    let packet = &routed.packet;
+    let mut summary = format!("EMERGENCY from {}", packet.source);
+    if let Some(pos) = packet.position() {
+        summary.push_str(&format!(" at {:.4},{:.4}", pos.latitude, pos.longitude));
+    }
+    if let Some(msg) = packet.message() {
+        summary.push_str(&format!(": {}", msg.text));
+    }
+    summary
+}
+
+impl PacketProcessor for Emergency {
+    fn name(&self) -> &str {
+        "emergency"
+    }
+
+    fn on_receive(&self, routed: &mut RoutedPacket, inject: &mut Vec<RoutedPacket>) -> Verdict {
+        // Our own alerts heard back mustn't raise more
+        let from = routed.packet.source.to_string();
+        if !routed.packet.is_emergency() || from == self.mycall {
+            return Verdict::Keep;
+        }
+
+        let now = Instant::now();
+        let mut alerted = self.alerted.lock().unwrap();
+        alerted.retain(|_, t| now.duration_since(*t) < self.holdoff);
+        if alerted.contains_key(&from) {
+            return Verdict::Keep;
+        }
+        alerted.insert(from, now);
+
+        let summary = summary(routed);
+        error!("{} ({})", summary, routed.packet);
+        self.alerter
+            .fire("emergency", &summary, &routed.packet, inject);
+        Verdict::Keep
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aprs::parse_packet;
+    use crate::router::PacketSource;
+
+    fn emergency() -> Emergency {
+        let alert = AlertConfig {
+            notify: Some("N0CALL-7".to_string()),
+            ..Default::default()
+        };
+        Emergency {
+            mycall: "N0CALL".to_string(),
+            alerter: Alerter::new(alert, "N0CALL").unwrap(),
+            holdoff: Duration::from_secs(600),
+            alerted: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn heard(packet: &str) -> RoutedPacket {
+        RoutedPacket {
+            packet: parse_packet(packet).unwrap(),
+            source: PacketSource::SerialPort("vhf".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_alerts_once_per_station() {
+        let plugin = emergency();
+        let mut inject = Vec::new();
+
+        let mut mic_e = heard("N1CALL-9>332UVT:`(#fpPO>/");
+        assert_eq!(plugin.on_receive(&mut mic_e, &mut inject), Verdict::Keep);
+        assert_eq!(inject.len(), 1);
+        let text = &inject[0].packet.message().unwrap().text;
+        assert!(text.starts_with("EMERGENCY from N1CALL-9 at 33."));
+
+        plugin.on_receive(&mut mic_e, &mut inject);
+        assert_eq!(inject.len(), 1);
+    }
+
+    #[test]
+    fn test_ignores_routine_and_own_traffic() {
+        let plugin = emergency();
+        let mut inject = Vec::new();
+
+        plugin.on_receive(&mut heard("N1CALL-9>T32UVT:`(#fpPO>/"), &mut inject);
+        plugin.on_receive(&mut heard("N1CALL>APRS::N2CALL   :Hello{1"), &mut inject);
+        plugin.on_receive(
+            &mut heard("N0CALL>APRS::N0CALL-7 :EMERGENCY from N1CALL-9"),
+            &mut inject,
+        );
+        assert!(inject.is_empty());
+    }
+}
@@ -0,0 +1,170 @@
+//! Runs an external program for every heard packet that matches any of a
+//! list of filters. The packet's fields are in `APRS_*` environment
+//! variables and its JSON form is on stdin. Runs past the concurrency limit
+//! are skipped rather than queued, so a stuck program can't pile up work.
+
+use super::{Context, PacketProcessor, Verdict};
+use crate::alert::{packet_env, run_command, shell};
+use crate::config::PluginConfig;
+use crate::filter::{FilterChain, PacketFilter};
+use crate::router::RoutedPacket;
+use anyhow::{anyhow, Result};
+use log::{debug, warn};
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Options {
+    filters: Vec<String>, // Run when any of these filters matches
+    command: String,      // Run with sh -c
+    #[serde(default = "default_concurrency")]
+    concurrency: usize, // Most runs at once
+    #[serde(
+        default = "default_timeout",
+        deserialize_with = "crate::units::seconds"
+    )]
+    timeout: u64, // Seconds before a run is killed
+}
+
+fn default_concurrency() -> usize {
+    4
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+pub struct Exec {
+    name: String,
+    command: String,
+    filter: Arc<PacketFilter>,
+    chain: FilterChain,
+    running: Arc<Semaphore>,
+    timeout: Duration,
+}
+
+impl Exec {
+    pub fn build(entry: &PluginConfig, context: &Context) -> Result<Box<dyn PacketProcessor>> {
+        let options: Options = entry.options()?;
+        if options.filters.is_empty() {
+            return Err(anyhow!("exec needs at least one filter"));
+        }
+        Ok(Box::new(Exec {
+            name: format!("exec {}", options.command),
+            chain: context.filter.named_chain(&options.filters)?,
+            filter: context.filter.clone(),
+            command: options.command,
+            running: Arc::new(Semaphore::new(options.concurrency.max(1))),
+            timeout: Duration::from_secs(options.timeout),
+        }))
+    }
+}
+
+impl PacketProcessor for Exec {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn on_receive(&self, routed: &mut RoutedPacket, _inject: &mut Vec<RoutedPacket>) -> Verdict {
+        let packet = &routed.packet;
+        if !self.filter.matches_any(&self.chain, packet) {
+            return Verdict::Keep;
+        }
+        let Ok(permit) = self.running.clone().try_acquire_owned() else {
+            warn!("{} is busy, skipping {}", self.name, packet);
+            return Verdict::Keep;
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return Verdict::Keep;
+        };
+
+        debug!("Running {} for {}", self.name, packet);
+        let mut command = shell(&self.command);
+        command.envs(packet_env(packet));
+        let input = format!("{}\n", packet.to_json());
+        let name = self.name.clone();
+        let limit = self.timeout;
+        runtime.spawn(async move {
+            if let Err(e) = run_command(command, Some(input), limit).await {
+                warn!("{} failed: {}", name, e);
+            }
+            drop(permit);
+        });
+        Verdict::Keep
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aprs::packet::DataType;
+    use crate::aprs::parse_packet;
+    use crate::config::{FilterAction, FilterConfig, FilterDirection};
+    use crate::router::PacketSource;
+
+    fn exec(command: &str, concurrency: usize) -> Exec {
+        let filter = Arc::new(
+            PacketFilter::new(vec![FilterConfig {
+                name: "weather".to_string(),
+                action: FilterAction::Log,
+                types: vec![DataType::Weather],
+                direction: Some(FilterDirection::Port),
+                ..Default::default()
+            }])
+            .unwrap(),
+        );
+        Exec {
+            name: "exec test".to_string(),
+            command: command.to_string(),
+            chain: filter.named_chain(&["weather".to_string()]).unwrap(),
+            filter,
+            running: Arc::new(Semaphore::new(concurrency)),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    fn heard(packet: &str) -> RoutedPacket {
+        RoutedPacket {
+            packet: parse_packet(packet).unwrap(),
+            source: PacketSource::SerialPort("vhf".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_runs_for_matching_packets() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let plugin = exec(
+            &format!("echo \"$APRS_SOURCE $(cat)\" >> {}", out.display()),
+            4,
+        );
+        let mut inject = Vec::new();
+
+        plugin.on_receive(&mut heard("N0CALL>APRS:>Status"), &mut inject);
+        plugin.on_receive(
+            &mut heard("N1CALL>APRS:_10090556c220s004g005t077"),
+            &mut inject,
+        );
+
+        // The run gives its permit back once it's done
+        let _done = plugin.running.acquire_many(4).await.unwrap();
+        let written = std::fs::read_to_string(&out).unwrap();
+        assert!(written.starts_with("N1CALL {"));
+        assert_eq!(written.lines().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_skips_when_busy() {
+        let plugin = exec("sleep 5", 1);
+        let mut inject = Vec::new();
+        let mut wx = heard("N1CALL>APRS:_10090556c220s004g005t077");
+
+        plugin.on_receive(&mut wx, &mut inject);
+        assert_eq!(plugin.running.available_permits(), 0);
+        plugin.on_receive(&mut wx, &mut inject);
+        assert_eq!(plugin.running.available_permits(), 0);
+    }
+}
@@ -0,0 +1,261 @@
+//! Packet processors that hook into the router.
+//!
+//! A processor sees every packet heard on RF or APRS-IS before the filters
+//! and again once they have let it through, and every packet just before it
+//! goes out a port, and can rewrite it, drop it, or queue new packets of its
+//! own. Processors are listed under
+//! `[[plugins]]` in the config and built from the registry by type name.
+
+mod autoreply;
+mod emergency;
+mod exec;
+mod script;
+mod watchlist;
+
+use crate::config::{Config, PluginConfig};
+use crate::filter::PacketFilter;
+use crate::router::RoutedPacket;
+use anyhow::{anyhow, Result};
+use log::info;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// What to do with a packet after a processor has seen it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Keep,
+    Drop,
+}
+
+/// Hooks called by the router. All default to keeping the packet as is.
+pub trait PacketProcessor: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// A packet heard on RF or APRS-IS. Packets pushed to `inject` are
+    /// routed after this one.
+    fn on_receive(&self, _routed: &mut RoutedPacket, _inject: &mut Vec<RoutedPacket>) -> Verdict {
+        Verdict::Keep
+    }
+
+    /// A heard packet the filters have let through, with any tags they gave it
+    fn on_filter(&self, _routed: &mut RoutedPacket, _inject: &mut Vec<RoutedPacket>) -> Verdict {
+        Verdict::Keep
+    }
+
+    /// A packet about to go out `port`, or [`APRS_IS_PORT`](crate::filter::APRS_IS_PORT)
+    fn on_transmit(
+        &self,
+        _port: &str,
+        _routed: &mut RoutedPacket,
+        _inject: &mut Vec<RoutedPacket>,
+    ) -> Verdict {
+        Verdict::Keep
+    }
+}
+
+/// What a processor can draw on when it's built
+pub struct Context<'a> {
+    pub config: &'a Config,
+    pub filter: &'a Arc<PacketFilter>,
+}
+
+/// Builds a processor from its `[[plugins]]` entry
+pub type Factory = fn(&PluginConfig, &Context) -> Result<Box<dyn PacketProcessor>>;
+
+/// Processor types by the name used in the config's `plugin` key
+pub struct Registry {
+    factories: HashMap<String, Factory>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Registry {
+    /// A registry with the processors that ship with aprstx
+    pub fn new() -> Self {
+        let mut registry = Registry {
+            factories: HashMap::new(),
+        };
+        registry.register("autoreply", autoreply::AutoReply::build);
+        registry.register("emergency", emergency::Emergency::build);
+        registry.register("exec", exec::Exec::build);
+        registry.register("script", script::Script::build);
+        registry.register("watchlist", watchlist::Watchlist::build);
+        registry
+    }
+
+    pub fn register(&mut self, plugin: &str, factory: Factory) {
+        self.factories.insert(plugin.to_string(), factory);
+    }
+
+    /// Build every processor the config lists, in order
+    pub fn load(&self, context: &Context) -> Result<Plugins> {
+        let mut processors = Vec::new();
+        for entry in &context.config.plugins {
+            let factory = self
+                .factories
+                .get(&entry.plugin)
+                .ok_or_else(|| anyhow!("Unknown plugin type: {}", entry.plugin))?;
+            let processor =
+                factory(entry, context).map_err(|e| anyhow!("Plugin {}: {}", entry.plugin, e))?;
+            info!("Loaded plugin {}", processor.name());
+            processors.push(processor);
+        }
+        Ok(Plugins { processors })
+    }
+}
+
+/// The loaded processors, run in config order
+#[derive(Default)]
+pub struct Plugins {
+    processors: Vec<Box<dyn PacketProcessor>>,
+}
+
+impl Plugins {
+    pub fn new(processors: Vec<Box<dyn PacketProcessor>>) -> Self {
+        Plugins { processors }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.processors.is_empty()
+    }
+
+    /// Run every processor's receive hook, stopping at the first drop
+    pub fn on_receive(&self, routed: &mut RoutedPacket, inject: &mut Vec<RoutedPacket>) -> Verdict {
+        for processor in &self.processors {
+            if processor.on_receive(routed, inject) == Verdict::Drop {
+                return Verdict::Drop;
+            }
+        }
+        Verdict::Keep
+    }
+
+    /// Run every processor's filter hook, stopping at the first drop
+    pub fn on_filter(&self, routed: &mut RoutedPacket, inject: &mut Vec<RoutedPacket>) -> Verdict {
+        for processor in &self.processors {
+            if processor.on_filter(routed, inject) == Verdict::Drop {
+                return Verdict::Drop;
+            }
+        }
+        Verdict::Keep
+    }
+
+    /// Run every processor's transmit hook, stopping at the first drop
+    pub fn on_transmit(
+        &self,
+        port: &str,
+        routed: &mut RoutedPacket,
+        inject: &mut Vec<RoutedPacket>,
+    ) -> Verdict {
+        for processor in &self.processors {
+            if processor.on_transmit(port, routed, inject) == Verdict::Drop {
+                return Verdict::Drop;
+            }
+        }
+        Verdict::Keep
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aprs::parse_packet;
+    use crate::router::PacketSource;
+
+    struct DropStatus;
+
+    impl PacketProcessor for DropStatus {
+        fn name(&self) -> &str {
+            "drop-status"
+        }
+
+        fn on_receive(&self, routed: &mut RoutedPacket, _: &mut Vec<RoutedPacket>) -> Verdict {
+            if routed.packet.information.starts_with('>') {
+                Verdict::Drop
+            } else {
+                Verdict::Keep
+            }
+        }
+    }
+
+    struct Comment;
+
+    impl PacketProcessor for Comment {
+        fn name(&self) -> &str {
+            "comment"
+        }
+
+        fn on_transmit(
+            &self,
+            _port: &str,
+            routed: &mut RoutedPacket,
+            _: &mut Vec<RoutedPacket>,
+        ) -> Verdict {
+            routed.packet.information.push_str(" via aprstx");
+            Verdict::Keep
+        }
+    }
+
+    fn routed(packet: &str) -> RoutedPacket {
+        RoutedPacket {
+            packet: parse_packet(packet).unwrap(),
+            source: PacketSource::AprsIs,
+        }
+    }
+
+    #[test]
+    fn test_plugins_run_in_order() {
+        let plugins = Plugins::new(vec![Box::new(DropStatus), Box::new(Comment)]);
+        let mut inject = Vec::new();
+
+        let mut status = routed("N0CALL>APRS:>Status");
+        assert_eq!(plugins.on_receive(&mut status, &mut inject), Verdict::Drop);
+
+        let mut position = routed("N0CALL>APRS:!4903.50N/07201.75W-");
+        assert_eq!(
+            plugins.on_receive(&mut position, &mut inject),
+            Verdict::Keep
+        );
+        assert_eq!(
+            plugins.on_transmit("vhf", &mut position, &mut inject),
+            Verdict::Keep
+        );
+        assert!(position.packet.information.ends_with(" via aprstx"));
+    }
+
+    #[test]
+    fn test_unknown_plugin() {
+        let config: Config = toml::from_str(
+            r#"
+            mycall = "N0CALL"
+            serial_ports = []
+            filters = []
+
+            [digipeater]
+            enabled = false
+            mycall = "N0CALL"
+            aliases = []
+            viscous_delay = 5
+            max_hops = 3
+
+            [telemetry]
+            enabled = false
+            interval = 1200
+            comment = ""
+
+            [[plugins]]
+            plugin = "nonesuch"
+            "#,
+        )
+        .unwrap();
+        let filter = Arc::new(PacketFilter::new(Vec::new()).unwrap());
+        let context = Context {
+            config: &config,
+            filter: &filter,
+        };
+        assert!(Registry::new().load(&context).is_err());
+    }
+}
@@ -0,0 +1,385 @@
+//! Site-specific behaviour written in [Rhai](https://rhai.rs), loaded from
+//! a file.
+//!
+//! A script defines any of three hooks, each taking the port as its only
+//! parameter and seeing the packet as `this`:
+//!
+//! ```text
+//! // Answer pings to us
+//! fn on_rx(port) {
+//!     if this.type == "message" && this.addressee == "N0CALL-10" && this.text.starts_with("ping") {
+//!         let to = this.source;
+//!         to.pad(9, ' ');
+//!         send(`N0CALL-10>APRS::${to}:pong`);
+//!     }
+//! }
+//! // Keep balloons off the low bands
+//! fn on_tx(port) {
+//!     if port == "hf" && this.symbol == "/O" { return false; }
+//! }
+//! ```
+//!
+//! `on_rx` sees packets heard on RF or APRS-IS before the filters,
+//! `on_filter` the ones the filters let through (with their tags), and
+//! `on_tx` each packet about to go out a port. Returning `false` drops the
+//! packet. The packet's fields are read-only apart from `tags`, an array of
+//! strings; fields the packet doesn't have, like `speed` on a status, are
+//! `()`. `send("...")` queues a TNC2 packet from mycall, and `print` logs.
+
+use super::{Context, PacketProcessor, Verdict};
+use crate::aprs::parse_packet;
+use crate::config::PluginConfig;
+use crate::filter::APRS_IS_PORT;
+use crate::router::{PacketSource, RoutedPacket};
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use rhai::{Array, CallFnOptions, Dynamic, Engine, Map, NativeCallContext, Scope, AST};
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+
+/// Work a hook can do before it's stopped, so a runaway loop can't stall
+/// routing
+const MAX_OPERATIONS: u64 = 100_000;
+
+const HOOKS: [&str; 3] = ["on_rx", "on_filter", "on_tx"];
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Options {
+    file: String, // The script to run
+}
+
+/// Packets a hook has sent, handed to it in the call's tag
+type Outbox = Arc<Mutex<Vec<String>>>;
+
+#[derive(Debug)]
+pub struct Script {
+    name: String,
+    mycall: String,
+    engine: Engine,
+    ast: AST,
+    hooks: [bool; 3], // Which of HOOKS the script defines
+}
+
+impl Script {
+    pub fn build(entry: &PluginConfig, context: &Context) -> Result<Box<dyn PacketProcessor>> {
+        let options: Options = entry.options()?;
+        let source = std::fs::read_to_string(&options.file)
+            .map_err(|e| anyhow!("{}: {}", options.file, e))?;
+        let name = format!("script {}", options.file);
+        let script = Script::compile(&name, &source, &context.config.mycall)
+            .map_err(|e| anyhow!("{}: {}", options.file, e))?;
+        Ok(Box::new(script))
+    }
+
+    fn compile(name: &str, source: &str, mycall: &str) -> Result<Script> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let (print_name, debug_name) = (name.to_string(), name.to_string());
+        engine.on_print(move |text| info!("{}: {}", print_name, text));
+        engine.on_debug(move |text, _, _| debug!("{}: {}", debug_name, text));
+        engine.register_fn("send", |context: NativeCallContext, text: &str| {
+            if let Some(outbox) = context
+                .tag()
+                .and_then(|tag| tag.clone().try_cast::<Outbox>())
+            {
+                outbox.lock().unwrap().push(text.to_string());
+            }
+        });
+
+        let ast = engine.compile(source)?;
+        let mut hooks = [false; 3];
+        for function in ast.iter_functions() {
+            if let Some(i) = HOOKS.iter().position(|&hook| hook == function.name) {
+                if function.params.len() != 1 {
+                    return Err(anyhow!("{} takes one parameter, the port", function.name));
+                }
+                hooks[i] = true;
+            }
+        }
+        Ok(Script {
+            name: name.to_string(),
+            mycall: mycall.to_string(),
+            engine,
+            ast,
+            hooks,
+        })
+    }
+
+    /// Call a hook if the script has it, keeping the tags it sets
+    fn run(
+        &self,
+        hook: usize,
+        port: &str,
+        routed: &mut RoutedPacket,
+        inject: &mut Vec<RoutedPacket>,
+    ) -> Verdict {
+        if !self.hooks[hook] {
+            return Verdict::Keep;
+        }
+        let outbox = Outbox::default();
+        let mut this = Dynamic::from_map(packet_map(routed));
+        let options = CallFnOptions::new()
+            .eval_ast(false)
+            .bind_this_ptr(&mut this)
+            .with_tag(outbox.clone());
+        let result = self.engine.call_fn_with_options::<Dynamic>(
+            options,
+            &mut Scope::new(),
+            &self.ast,
+            HOOKS[hook],
+            (port.to_string(),),
+        );
+
+        if let Some(tags) = this
+            .try_cast::<Map>()
+            .and_then(|mut map| map.remove("tags"))
+        {
+            if let Some(tags) = tags.try_cast::<Array>() {
+                routed.packet.tags = tags.into_iter().map(|tag| tag.to_string()).collect();
+            }
+        }
+        for text in outbox.lock().unwrap().drain(..) {
+            match self.packet(&text) {
+                Ok(packet) => inject.push(packet),
+                Err(e) => warn!("{}: not sending {}: {}", self.name, text, e),
+            }
+        }
+        match result {
+            Ok(verdict) if verdict.as_bool() == Ok(false) => Verdict::Drop,
+            Ok(_) => Verdict::Keep,
+            Err(e) => {
+                warn!("{}: {}: {}", self.name, HOOKS[hook], e);
+                Verdict::Keep
+            }
+        }
+    }
+
+    /// A packet to send, which must come from mycall with any SSID
+    fn packet(&self, text: &str) -> Result<RoutedPacket> {
+        let packet = parse_packet(text)?;
+        let base = self.mycall.split('-').next().unwrap_or_default();
+        if !packet.source.call.eq_ignore_ascii_case(base) {
+            return Err(anyhow!("source {} isn't {}", packet.source, self.mycall));
+        }
+        packet.validate_ax25()?;
+        Ok(RoutedPacket {
+            packet,
+            source: PacketSource::Internal,
+        })
+    }
+}
+
+/// The packet's fields, as the script sees them
+fn packet_map(routed: &RoutedPacket) -> Map {
+    let packet = &routed.packet;
+    let mut map = Map::new();
+    let mut set = |name: &str, value: Dynamic| {
+        map.insert(name.into(), value);
+    };
+    set("source", packet.source.to_string().into());
+    set("destination", packet.destination.to_string().into());
+    set(
+        "path",
+        Dynamic::from_array(
+            packet
+                .path
+                .iter()
+                .map(|hop| hop.to_string().into())
+                .collect(),
+        ),
+    );
+    if let Some(data_type) = serde_json::to_value(&packet.data_type)
+        .ok()
+        .and_then(|v| v.as_str().map(str::to_string))
+    {
+        set("type", data_type.into());
+    }
+    set("info", packet.information.clone().into());
+    set(
+        "tags",
+        Dynamic::from_array(packet.tags.iter().map(|tag| tag.clone().into()).collect()),
+    );
+    set("raw", packet.to_string().into());
+    if let Some(pos) = packet.position() {
+        set("latitude", pos.latitude.into());
+        set("longitude", pos.longitude.into());
+        if let Some(altitude) = pos.altitude {
+            set("altitude", (altitude as f64).into());
+        }
+        if let Some(speed) = pos.speed {
+            set("speed", (speed as f64).into());
+        }
+        if let Some(course) = pos.course {
+            set("course", (course as i64).into());
+        }
+        set(
+            "symbol",
+            format!("{}{}", pos.symbol_table, pos.symbol).into(),
+        );
+        set("comment", pos.comment.clone().into());
+    }
+    if let Some(message) = packet.message() {
+        set("addressee", message.addressee.trim_end().to_string().into());
+        set("text", message.text.clone().into());
+        if let Some(msg_id) = &message.msg_id {
+            set("msgid", msg_id.clone().into());
+        }
+    }
+    map
+}
+
+/// The port a heard packet came in on
+fn arrival_port(routed: &RoutedPacket) -> &str {
+    match &routed.source {
+        PacketSource::SerialPort(name) => name,
+        PacketSource::AprsIs => APRS_IS_PORT,
+        PacketSource::Internal | PacketSource::Digipeater(_) => "local",
+    }
+}
+
+impl PacketProcessor for Script {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn on_receive(&self, routed: &mut RoutedPacket, inject: &mut Vec<RoutedPacket>) -> Verdict {
+        let port = arrival_port(routed).to_string();
+        self.run(0, &port, routed, inject)
+    }
+
+    fn on_filter(&self, routed: &mut RoutedPacket, inject: &mut Vec<RoutedPacket>) -> Verdict {
+        let port = arrival_port(routed).to_string();
+        self.run(1, &port, routed, inject)
+    }
+
+    fn on_transmit(
+        &self,
+        port: &str,
+        routed: &mut RoutedPacket,
+        inject: &mut Vec<RoutedPacket>,
+    ) -> Verdict {
+        self.run(2, port, routed, inject)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heard(packet: &str) -> RoutedPacket {
+        RoutedPacket {
+            packet: parse_packet(packet).unwrap(),
+            source: PacketSource::SerialPort("vhf".to_string()),
+        }
+    }
+
+    fn script(source: &str, mycall: &str) -> Script {
+        Script::compile("script", source, mycall).unwrap()
+    }
+
+    #[test]
+    fn test_rx_send() {
+        let script = script(
+            r#"
+            // Answer pings to us
+            fn on_rx(port) {
+                if this.type == "message" && this.addressee == "N0CALL-10" && this.text.starts_with("ping") {
+                    let to = this.source;
+                    to.pad(9, ' ');
+                    send(`N0CALL-10>APRS::${to}:pong ${port}`);
+                }
+            }
+            "#,
+            "N0CALL-10",
+        );
+        let mut inject = Vec::new();
+
+        let mut ping = heard("N1CALL>APRS::N0CALL-10:ping{12");
+        assert_eq!(script.on_receive(&mut ping, &mut inject), Verdict::Keep);
+        assert_eq!(inject.len(), 1);
+        assert_eq!(
+            inject[0].packet.to_string(),
+            "N0CALL-10>APRS::N1CALL   :pong vhf"
+        );
+
+        let mut other = heard("N1CALL>APRS::N2CALL   :ping");
+        script.on_receive(&mut other, &mut inject);
+        assert_eq!(inject.len(), 1);
+    }
+
+    #[test]
+    fn test_tx_and_filter_hooks() {
+        let script = script(
+            r#"
+            fn on_filter(port) {
+                if this.speed > 50.0 || this.latitude == () { this.tags.push("fast"); }
+            }
+            fn on_tx(port) {
+                if port == "hf" && "fast" in this.tags { return false; }
+                if port == "hf" { print(`${this.source} on hf`); }
+            }
+            "#,
+            "N0CALL",
+        );
+        let mut inject = Vec::new();
+
+        let mut fast = heard("N1CALL-9>APRS:!4903.50N/07201.75W>090/080");
+        assert_eq!(script.on_receive(&mut fast, &mut inject), Verdict::Keep);
+        assert!(fast.packet.tags.is_empty());
+        script.on_filter(&mut fast, &mut inject);
+        assert_eq!(fast.packet.tags, ["fast"]);
+        assert_eq!(
+            script.on_transmit("hf", &mut fast, &mut inject),
+            Verdict::Drop
+        );
+        assert_eq!(
+            script.on_transmit("vhf", &mut fast, &mut inject),
+            Verdict::Keep
+        );
+
+        let mut slow = heard("N1CALL-9>APRS:!4903.50N/07201.75W>090/010");
+        script.on_filter(&mut slow, &mut inject);
+        assert!(slow.packet.tags.is_empty());
+        assert_eq!(
+            script.on_transmit("hf", &mut slow, &mut inject),
+            Verdict::Keep
+        );
+        assert!(inject.is_empty());
+    }
+
+    #[test]
+    fn test_send_must_be_from_mycall() {
+        let script = script(
+            r#"fn on_rx(port) { send("N9XXX>APRS:>spoofed"); }"#,
+            "N0CALL",
+        );
+        let mut inject = Vec::new();
+        script.on_receive(&mut heard("N1CALL>APRS:>Status"), &mut inject);
+        assert!(inject.is_empty());
+    }
+
+    #[test]
+    fn test_runaway_script_keeps_packet() {
+        let script = script("fn on_rx(port) { loop {} }", "N0CALL");
+        let mut inject = Vec::new();
+        assert_eq!(
+            script.on_receive(&mut heard("N1CALL>APRS:>Status"), &mut inject),
+            Verdict::Keep
+        );
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let error = |source: &str| {
+            Script::compile("script", source, "N0CALL")
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(error("fn on_rx(port) {\n  send(\"x\")\n  explode\n}").contains("line 3"));
+        assert_eq!(
+            error("fn on_tx() { false }"),
+            "on_tx takes one parameter, the port"
+        );
+    }
+}
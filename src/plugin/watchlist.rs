@@ -0,0 +1,172 @@
+//! Raises an alert when a watched station is heard for the first time, or
+//! again after being away longer than the absence threshold.
+
+use super::{Context, PacketProcessor, Verdict};
+use crate::alert::{AlertConfig, Alerter};
+use crate::config::PluginConfig;
+use crate::filter::APRS_IS_PORT;
+use crate::router::{PacketSource, RoutedPacket};
+use anyhow::{anyhow, Result};
+use log::info;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Deserialize)]
+struct Options {
+    callsigns: Vec<String>, // A call without an SSID watches every SSID
+    #[serde(
+        default = "default_absence",
+        deserialize_with = "crate::units::seconds"
+    )]
+    absence: u64, // Seconds unheard before a station counts as back
+    #[serde(flatten)]
+    alert: AlertConfig,
+}
+
+fn default_absence() -> u64 {
+    3600
+}
+
+pub struct Watchlist {
+    callsigns: Vec<String>,
+    absence: Duration,
+    alerter: Alerter,
+    last_heard: Mutex<HashMap<String, Instant>>,
+}
+
+impl Watchlist {
+    pub fn build(entry: &PluginConfig, context: &Context) -> Result<Box<dyn PacketProcessor>> {
+        let options: Options = entry.options()?;
+        if options.callsigns.is_empty() {
+            return Err(anyhow!("watchlist needs at least one callsign"));
+        }
+        Ok(Box::new(Watchlist {
+            callsigns: options
+                .callsigns
+                .iter()
+                .map(|c| c.to_ascii_uppercase())
+                .collect(),
+            absence: Duration::from_secs(options.absence),
+            alerter: Alerter::new(options.alert, &context.config.mycall)?,
+            last_heard: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    fn watching(&self, call: &str, base: &str) -> bool {
+        self.callsigns.iter().any(|c| c == call || c == base)
+    }
+}
+
+fn summary(routed: &RoutedPacket, away: Option<Duration>) -> String {
+    let packet = &routed.packet;
+    let via = match &routed.source {
+        PacketSource::SerialPort(port) => port.as_str(),
+        _ => APRS_IS_PORT,
+    };
+    let mut summary = format!("{} heard on {}", packet.source, via);
+    if let Some(pos) = packet.position() {
+        summary.push_str(&format!(" at {:.4},{:.4}", pos.latitude, pos.longitude));
+    }
+    if let Some(away) = away {
+        summary.push_str(&format!(" after {}m away", away.as_secs() / 60));
+    }
+    summary
+}
+
+impl PacketProcessor for Watchlist {
+    fn name(&self) -> &str {
+        "watchlist"
+    }
+
+    fn on_receive(&self, routed: &mut RoutedPacket, inject: &mut Vec<RoutedPacket>) -> Verdict {
+        let source = &routed.packet.source;
+        let call = source.to_string();
+        if !self.watching(&call, &source.call) {
+            return Verdict::Keep;
+        }
+
+        let now = Instant::now();
+        let previous = self.last_heard.lock().unwrap().insert(call, now);
+        let away = match previous {
+            None => None,
+            Some(last) if now.duration_since(last) >= self.absence => {
+                Some(now.duration_since(last))
+            }
+            Some(_) => return Verdict::Keep,
+        };
+
+        let summary = summary(routed, away);
+        info!("Watchlist: {}", summary);
+        self.alerter
+            .fire("watchlist", &summary, &routed.packet, inject);
+        Verdict::Keep
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aprs::parse_packet;
+
+    fn watchlist(absence: Duration) -> Watchlist {
+        let alert = AlertConfig {
+            notify: Some("N0CALL-7".to_string()),
+            ..Default::default()
+        };
+        Watchlist {
+            callsigns: vec!["N1CALL".to_string(), "N2CALL-9".to_string()],
+            absence,
+            alerter: Alerter::new(alert, "N0CALL").unwrap(),
+            last_heard: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn heard(packet: &str) -> RoutedPacket {
+        RoutedPacket {
+            packet: parse_packet(packet).unwrap(),
+            source: PacketSource::SerialPort("vhf".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_first_heard() {
+        let plugin = watchlist(Duration::from_secs(3600));
+        let mut inject = Vec::new();
+
+        plugin.on_receive(
+            &mut heard("N1CALL-5>APRS:!4903.50N/07201.75W>"),
+            &mut inject,
+        );
+        assert_eq!(inject.len(), 1);
+        assert_eq!(
+            inject[0].packet.message().unwrap().text,
+            "N1CALL-5 heard on vhf at 49.0583,-72.0292"
+        );
+
+        // Heard again straight away
+        plugin.on_receive(&mut heard("N1CALL-5>APRS:>Status"), &mut inject);
+        // Only N2CALL-9 was named with an SSID
+        plugin.on_receive(&mut heard("N2CALL-7>APRS:>Status"), &mut inject);
+        assert_eq!(inject.len(), 1);
+        plugin.on_receive(&mut heard("N2CALL-9>APRS:>Status"), &mut inject);
+        assert_eq!(inject.len(), 2);
+    }
+
+    #[test]
+    fn test_back_after_absence() {
+        let plugin = watchlist(Duration::ZERO);
+        let mut inject = Vec::new();
+
+        plugin.on_receive(&mut heard("N1CALL>APRS:>Status"), &mut inject);
+        plugin.on_receive(&mut heard("N1CALL>APRS:>Status"), &mut inject);
+        assert_eq!(inject.len(), 2);
+        assert!(inject[1]
+            .packet
+            .message()
+            .unwrap()
+            .text
+            .ends_with("after 0m away"));
+    }
+}
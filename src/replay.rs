@@ -0,0 +1,276 @@
+//! Replay of captured traffic.
+//!
+//! `aprstx replay LOG` feeds a TNC2 log through the router as if it were
+//! heard on a serial port, at the pace it was captured or faster. Nothing
+//! is transmitted or gated: what would have been is printed instead, so
+//! digipeat and igate decisions can be checked against real traffic.
+//!
+//! Log lines are a TNC2 packet, optionally after an RFC 3339 or Unix
+//! timestamp, a `[port]` and a direction, as captures write them:
+//!
+//! ```text
+//! 2024-06-01T14:03:09Z [vhf] N0CALL-9>APRS,WIDE1-1:!4903.50N/07201.75W>
+//! 2024-06-01T14:03:09.871Z [vhf] rx N0CALL-9>APRS,WIDE1-1:>Hello
+//! ```
+//!
+//! Only what was received is replayed; lines marked `tx` are what the
+//! station sent in response. Packets from the `aprs-is` port come in as
+//! if from APRS-IS.
+
+use crate::aprs::parse_packet;
+use crate::capture::Direction;
+use crate::channel::{self, Overflow};
+use crate::filter::APRS_IS_PORT;
+use crate::router::{PacketSource, RoutedPacket};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, TimeZone, Utc};
+use clap::Args;
+use log::{info, warn};
+use std::path::PathBuf;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::Instant;
+
+#[derive(Args, Debug, Clone)]
+pub struct ReplayOptions {
+    /// TNC2 log to replay
+    pub log: PathBuf,
+
+    /// How many times faster than captured to replay, or 0 for no waiting
+    #[arg(long, default_value_t = 1.0)]
+    pub speed: f64,
+
+    /// Serial port the traffic is heard on, instead of the one in the log
+    #[arg(long)]
+    pub port: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+struct LogLine<'a> {
+    time: Option<DateTime<Utc>>,
+    port: Option<&'a str>,
+    direction: Option<Direction>,
+    packet: &'a str,
+}
+
+fn parse_line(line: &str) -> Option<LogLine<'_>> {
+    let mut rest = line.trim();
+    if rest.is_empty() || rest.starts_with('#') {
+        return None;
+    }
+
+    let mut time = None;
+    if let Some((first, after)) = rest.split_once(' ') {
+        let parsed = DateTime::parse_from_rfc3339(first)
+            .map(|t| t.with_timezone(&Utc))
+            .ok()
+            .or_else(|| {
+                first
+                    .parse::<i64>()
+                    .ok()
+                    .and_then(|t| Utc.timestamp_opt(t, 0).single())
+            });
+        if parsed.is_some() {
+            time = parsed;
+            rest = after.trim_start();
+        }
+    }
+
+    let mut port = None;
+    if let Some(after) = rest.strip_prefix('[') {
+        let (name, after) = after.split_once(']')?;
+        port = Some(name);
+        rest = after.trim_start();
+    }
+
+    let mut direction = None;
+    if let Some((first, after)) = rest.split_once(' ') {
+        if let Some(parsed) = Direction::parse(first) {
+            direction = Some(parsed);
+            rest = after.trim_start();
+        }
+    }
+
+    Some(LogLine {
+        time,
+        port,
+        direction,
+        packet: rest,
+    })
+}
+
+/// Send the log's packets to the router, spaced as they were captured.
+/// Packets are heard on `default_port` unless the log names one.
+pub async fn run_replay(
+    options: ReplayOptions,
+    default_port: String,
+    tx: mpsc::Sender<RoutedPacket>,
+) -> Result<()> {
+    if options.speed.is_nan() || options.speed < 0.0 {
+        return Err(anyhow!("Replay speed must be 0 or more"));
+    }
+    let file = tokio::fs::File::open(&options.log)
+        .await
+        .map_err(|e| anyhow!("Failed to open {}: {}", options.log.display(), e))?;
+    info!("Replaying {}", options.log.display());
+
+    let mut lines = BufReader::new(file).lines();
+    // The first timestamp in the log happens now
+    let mut start: Option<(DateTime<Utc>, Instant)> = None;
+    let mut number = 0;
+    let mut replayed = 0;
+    while let Some(line) = lines.next_line().await? {
+        number += 1;
+        let Some(entry) = parse_line(&line) else {
+            continue;
+        };
+        if entry.direction == Some(Direction::Tx) {
+            continue;
+        }
+        let packet = match parse_packet(entry.packet) {
+            Ok(packet) => packet,
+            Err(e) => {
+                warn!("Skipping line {}: {}", number, e);
+                continue;
+            }
+        };
+
+        if let (Some(time), true) = (entry.time, options.speed > 0.0) {
+            match start {
+                Some((first, started)) => {
+                    let offset = (time - first).to_std().unwrap_or_default();
+                    tokio::time::sleep_until(started + offset.div_f64(options.speed)).await;
+                }
+                None => start = Some((time, Instant::now())),
+            }
+        }
+
+        let port = options
+            .port
+            .as_deref()
+            .or(entry.port)
+            .unwrap_or(&default_port);
+        info!("Replay [{}]: {}", port, packet);
+        let source = if port == APRS_IS_PORT {
+            PacketSource::AprsIs
+        } else {
+            PacketSource::SerialPort(port.to_string())
+        };
+        let routed = RoutedPacket { packet, source };
+        channel::send(&tx, routed, channel::ROUTER, Overflow::Block).await;
+        replayed += 1;
+    }
+
+    info!(
+        "Replayed {} packets from {}",
+        replayed,
+        options.log.display()
+    );
+    Ok(())
+}
+
+/// Stand in for a serial port, printing what it would transmit
+pub async fn print_transmissions(port: String, mut rx: mpsc::Receiver<RoutedPacket>) -> Result<()> {
+    while let Some(routed) = rx.recv().await {
+        println!("TX [{}] {}", port, routed.packet);
+    }
+    Ok(())
+}
+
+/// Stand in for APRS-IS, printing what would be gated
+pub async fn print_gated(mut rx: broadcast::Receiver<RoutedPacket>) -> Result<()> {
+    loop {
+        match rx.recv().await {
+            Ok(routed) => println!("IS {}", routed.packet),
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                warn!("Replay output missed {} gated packets", n)
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(
+            parse_line("2024-06-01T14:03:09Z [vhf] N0CALL>APRS:>Hi"),
+            Some(LogLine {
+                time: Some(Utc.with_ymd_and_hms(2024, 6, 1, 14, 3, 9).unwrap()),
+                port: Some("vhf"),
+                direction: None,
+                packet: "N0CALL>APRS:>Hi",
+            })
+        );
+        assert_eq!(
+            parse_line("1717250589 N0CALL>APRS:>Hi"),
+            Some(LogLine {
+                time: Some(Utc.with_ymd_and_hms(2024, 6, 1, 14, 3, 9).unwrap()),
+                port: None,
+                direction: None,
+                packet: "N0CALL>APRS:>Hi",
+            })
+        );
+        // A space in the packet isn't mistaken for a timestamp
+        assert_eq!(
+            parse_line("N0CALL>APRS:>Hello there\r"),
+            Some(LogLine {
+                time: None,
+                port: None,
+                direction: None,
+                packet: "N0CALL>APRS:>Hello there",
+            })
+        );
+        // A capture's direction
+        assert_eq!(
+            parse_line("2024-06-01T14:03:09.120Z [aprs-is] rx N0CALL>APRS:>Hi")
+                .unwrap()
+                .direction,
+            Some(Direction::Rx)
+        );
+        assert_eq!(parse_line("# captured on the hill"), None);
+        assert_eq!(parse_line(""), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_replay_keeps_time() {
+        let mut log = tempfile::NamedTempFile::new().unwrap();
+        writeln!(log, "2024-06-01T14:03:00Z [uhf] N0CALL>APRS:>One").unwrap();
+        writeln!(log, "not a packet").unwrap();
+        writeln!(
+            log,
+            "2024-06-01T14:03:01Z [uhf] tx N0CALL>APRS,N0CALL-10*:>One"
+        )
+        .unwrap();
+        writeln!(log, "2024-06-01T14:03:20Z N1CALL>APRS:>Two").unwrap();
+        writeln!(
+            log,
+            "2024-06-01T14:03:20.500Z [aprs-is] rx N2CALL>APRS:>Three"
+        )
+        .unwrap();
+        let options = ReplayOptions {
+            log: log.path().to_path_buf(),
+            speed: 10.0,
+            port: None,
+        };
+
+        let (tx, mut rx) = mpsc::channel(10);
+        let started = Instant::now();
+        run_replay(options, "vhf".to_string(), tx).await.unwrap();
+
+        let one = rx.recv().await.unwrap();
+        assert_eq!(one.packet.information, ">One");
+        assert_eq!(one.source, PacketSource::SerialPort("uhf".to_string()));
+        let two = rx.recv().await.unwrap();
+        assert_eq!(two.source, PacketSource::SerialPort("vhf".to_string()));
+        let three = rx.recv().await.unwrap();
+        assert_eq!(three.source, PacketSource::AprsIs);
+        // Twenty seconds apart, ten times faster
+        assert_eq!(started.elapsed().as_secs(), 2);
+        assert!(rx.recv().await.is_none());
+    }
+}
@@ -0,0 +1,180 @@
+//! Radio frequency and mode from hamlib's rigctld.
+//!
+//! The rig is polled over rigctld's TCP protocol so beacons can say where
+//! the operator is listening, and go out promptly when they QSY. While
+//! rigctld can't be reached the frequency is unknown and left out.
+
+use crate::config::RigConfig;
+use anyhow::{anyhow, Result};
+use log::info;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::{interval, timeout};
+
+const RIG_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RigState {
+    pub frequency: u64, // Hz
+    pub mode: String,   // As hamlib names it, e.g. FM or USB
+}
+
+#[derive(Default)]
+pub struct Rig {
+    state: Mutex<Option<RigState>>,
+}
+
+impl Rig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn state(&self) -> Option<RigState> {
+        self.state.lock().unwrap().clone()
+    }
+
+    pub fn frequency(&self) -> Option<u64> {
+        self.state().map(|s| s.frequency)
+    }
+
+    pub(crate) fn set_state(&self, state: Option<RigState>) {
+        *self.state.lock().unwrap() = state;
+    }
+
+    /// Poll rigctld until the connection fails
+    pub async fn run(&self, config: RigConfig) -> Result<()> {
+        let result = self.poll(&config).await;
+        self.set_state(None);
+        result
+    }
+
+    async fn poll(&self, config: &RigConfig) -> Result<()> {
+        let address = format!("{}:{}", config.host, config.port);
+        let stream = timeout(RIG_TIMEOUT, TcpStream::connect(&address))
+            .await
+            .map_err(|_| anyhow!("Timed out connecting to rigctld at {}", address))??;
+        info!("Connected to rigctld at {}", address);
+
+        let mut stream = BufReader::new(stream);
+        let mut ticker = interval(Duration::from_secs(config.poll_interval.max(1) as u64));
+        loop {
+            ticker.tick().await;
+            let state = timeout(RIG_TIMEOUT, query(&mut stream))
+                .await
+                .map_err(|_| anyhow!("rigctld stopped answering"))??;
+            let mut current = self.state.lock().unwrap();
+            if current.as_ref() != Some(&state) {
+                info!(
+                    "Rig on {} {}",
+                    format_frequency(state.frequency),
+                    state.mode
+                );
+                *current = Some(state);
+            }
+        }
+    }
+}
+
+/// Ask for the frequency and mode
+async fn query<S: AsyncBufRead + AsyncWrite + Unpin>(stream: &mut S) -> Result<RigState> {
+    stream.write_all(b"f\n").await?;
+    let frequency = read_reply(stream).await?;
+    // Some rigs report fractional hertz
+    let frequency = frequency
+        .parse::<f64>()
+        .map_err(|_| anyhow!("Unexpected frequency from rigctld: {}", frequency))?
+        .round() as u64;
+
+    stream.write_all(b"m\n").await?;
+    let mode = read_reply(stream).await?;
+    let _passband = read_reply(stream).await?;
+
+    Ok(RigState { frequency, mode })
+}
+
+async fn read_reply<S: AsyncBufRead + Unpin>(stream: &mut S) -> Result<String> {
+    let mut line = String::new();
+    if stream.read_line(&mut line).await? == 0 {
+        return Err(anyhow!("rigctld closed the connection"));
+    }
+    let line = line.trim();
+    // Errors come back as RPRT and a negative code
+    if let Some(code) = line.strip_prefix("RPRT ") {
+        return Err(anyhow!("rigctld error {}", code));
+    }
+    Ok(line.to_string())
+}
+
+/// A frequency the way APRS comments give it, "146.520MHz"
+pub fn format_frequency(hz: u64) -> String {
+    format!("{}MHz", format_mhz(hz))
+}
+
+/// Megahertz in seven characters, "146.520", as frequency object names use
+pub fn format_mhz(hz: u64) -> String {
+    let mhz = hz as f64 / 1_000_000.0;
+    if mhz >= 1000.0 {
+        format!("{:.2}", mhz)
+    } else {
+        format!("{:07.3}", mhz)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_format_frequency() {
+        assert_eq!(format_frequency(145_500_000), "145.500MHz");
+        assert_eq!(format_frequency(144_390_000), "144.390MHz");
+        assert_eq!(format_frequency(50_125_000), "050.125MHz");
+        assert_eq!(format_frequency(1_296_100_000), "1296.10MHz");
+    }
+
+    #[tokio::test]
+    async fn test_polls_rigctld() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut command = String::new();
+            while stream.read_line(&mut command).await.unwrap() > 0 {
+                let reply = match command.trim() {
+                    "f" => "146520000\n",
+                    "m" => "FM\n15000\n",
+                    _ => "RPRT -1\n",
+                };
+                stream.get_mut().write_all(reply.as_bytes()).await.unwrap();
+                command.clear();
+            }
+        });
+
+        let rig = std::sync::Arc::new(Rig::new());
+        let polling = rig.clone();
+        let config = RigConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            poll_interval: 1,
+        };
+        let task = tokio::spawn(async move { polling.run(config).await });
+        for _ in 0..50 {
+            if rig.state().is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(
+            rig.state(),
+            Some(RigState {
+                frequency: 146_520_000,
+                mode: "FM".to_string()
+            })
+        );
+        task.abort();
+    }
+}
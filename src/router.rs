@@ -1,18 +1,48 @@
 use crate::aprs::AprsPacket;
-use crate::config::Config;
-use crate::filter::PacketFilter;
+use crate::channel::{self, Overflow};
+use crate::config::{Config, FilterDirection};
+use crate::dedup::{self, DupeCache, StaleCheck};
+use crate::digipeater;
+use crate::filter::{PacketFilter, RateLimiter, APRS_IS_PORT};
+use crate::heard::HEARD;
+use crate::igate::GateBudget;
+use crate::message::{self, MessageSpool};
+use crate::plugin::{Plugins, Verdict};
+use crate::shutdown::Shutdown;
 use crate::telemetry::TELEMETRY_STATS;
 use anyhow::Result;
-use log::{debug, info};
+use log::{debug, info, warn};
+use std::collections::HashMap;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use tokio::sync::{broadcast, mpsc, RwLock};
+use tokio::sync::{broadcast, mpsc, Mutex, RwLock};
+
+/// Tags the router treats like RFONLY and NOGATE in the path
+const TAG_RFONLY: &str = "rfonly";
+const TAG_NOGATE: &str = "nogate";
+/// Tag for our own packets that go to APRS-IS but not RF
+pub const TAG_ISONLY: &str = "isonly";
+/// Tags starting with this keep a packet to the interfaces they name, as
+/// in "port:vhf" or "port:aprs-is"
+pub const TAG_PORT_PREFIX: &str = "port:";
+
+/// Most packets remembered for duplicate detection
+const DEDUP_CAPACITY: usize = 1000;
+
+/// Most packets plugins may inject while one packet is routed
+const MAX_INJECTED: usize = 16;
+
+/// Names for packet origins in a port's `tx_from` list; port names stand
+/// for digipeats of packets heard on that port.
+pub const TX_FROM_DIGIPEATER: &str = "digipeater";
+pub const TX_FROM_LOCAL: &str = "local";
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum PacketSource {
     SerialPort(String),
     AprsIs,
     Internal,
+    Digipeater(String), // Repeated by us after being heard on this port
 }
 
 #[derive(Debug, Clone)]
@@ -25,11 +55,75 @@ pub struct PacketRouter {
     config: Arc<Config>,
     filter: Arc<PacketFilter>,
     rx_channel: mpsc::Receiver<RoutedPacket>,
-    rf_tx: broadcast::Sender<RoutedPacket>,
+    rf_ports: Vec<RfPort>,
     is_tx: broadcast::Sender<RoutedPacket>,
+    heard_tx: broadcast::Sender<RoutedPacket>,
     digipeater_tx: mpsc::Sender<RoutedPacket>,
     message_tx: mpsc::Sender<RoutedPacket>,
-    recent_packets: Arc<RwLock<Vec<(String, std::time::Instant)>>>,
+    recent_packets: Arc<RwLock<DupeCache>>,
+    spool: Option<MessageSpool>,
+    rate_limiter: Option<Mutex<RateLimiter>>,
+    stale: Option<Mutex<StaleCheck>>,
+    gate_budget: Option<Arc<std::sync::Mutex<GateBudget>>>,
+    plugins: Plugins,
+    injected: std::sync::Mutex<Vec<RoutedPacket>>, // From plugins, routed next
+    shutdown: Shutdown,
+}
+
+/// Where RF-bound packets can go, and which origins each port accepts.
+struct RfPort {
+    name: String,
+    tx_from: Option<Vec<String>>,
+    loopback: bool,
+    digipeat_to: Option<Vec<String>>,
+    digipeat: bool,
+    max_hops: Option<u8>,
+    tx: mpsc::Sender<RoutedPacket>,
+}
+
+impl RfPort {
+    fn accepts(&self, source: &PacketSource) -> bool {
+        // RF packets are only ever repeated via the digipeater
+        if let PacketSource::SerialPort(_) = source {
+            return false;
+        }
+        // Packets only go back out the port they were heard on if asked for,
+        // with loopback or by naming the port in its own tx_from
+        let own = matches!(source, PacketSource::Digipeater(port) if *port == self.name);
+        let Some(tx_from) = &self.tx_from else {
+            return !own || self.loopback;
+        };
+        let allowed = |name: &str| tx_from.iter().any(|t| t == name);
+        match source {
+            PacketSource::Digipeater(_) if own => {
+                allowed(&self.name) || (self.loopback && allowed(TX_FROM_DIGIPEATER))
+            }
+            PacketSource::AprsIs => allowed(APRS_IS_PORT),
+            PacketSource::Internal => allowed(TX_FROM_LOCAL),
+            PacketSource::Digipeater(port) => allowed(TX_FROM_DIGIPEATER) || allowed(port),
+            PacketSource::SerialPort(_) => false,
+        }
+    }
+
+    fn accepts_own_digipeats(&self) -> bool {
+        self.accepts(&PacketSource::Digipeater(self.name.clone()))
+            || self.digipeats_to(&self.name) == Some(true)
+    }
+
+    /// Whether digipeats of packets heard here may go out `port`, if this
+    /// port says. Naming itself repeats back out this port like loopback.
+    fn digipeats_to(&self, port: &str) -> Option<bool> {
+        let to = self.digipeat_to.as_ref()?;
+        Some(to.iter().any(|t| t == port))
+    }
+
+    /// Whether a packet heard here is offered to the digipeater
+    fn digipeats(&self, packet: &AprsPacket) -> bool {
+        self.digipeat
+            && self
+                .max_hops
+                .is_none_or(|max| digipeater::used_hops(packet) < max as usize)
+    }
 }
 
 impl PacketRouter {
@@ -38,93 +132,323 @@ impl PacketRouter {
         filter: Arc<PacketFilter>,
         rx_channel: mpsc::Receiver<RoutedPacket>,
     ) -> (Self, RouterChannels) {
-        let (rf_tx, _) = broadcast::channel(100);
-        let (is_tx, _) = broadcast::channel(100);
+        let (is_tx, _) = broadcast::channel(channel::APRS_IS_QUEUE_LEN);
+        let (heard_tx, _) = broadcast::channel(100);
         let (digipeater_tx, digipeater_rx) = mpsc::channel(100);
         let (message_tx, message_rx) = mpsc::channel(100);
 
+        let mut rf_ports = Vec::new();
+        let mut rf_rx = HashMap::new();
+        for port in &config.serial_ports {
+            let (tx, rx) = mpsc::channel(100);
+            rf_ports.push(RfPort {
+                name: port.name.clone(),
+                tx_from: port.tx_from.clone(),
+                loopback: port.loopback,
+                digipeat_to: port.digipeat_to.clone(),
+                digipeat: port.digipeat,
+                max_hops: port.max_hops,
+                tx,
+            });
+            rf_rx.insert(port.name.clone(), rx);
+        }
+
+        for port in &rf_ports {
+            for to in port.digipeat_to.iter().flatten() {
+                if !rf_ports.iter().any(|p| p.name == *to) {
+                    warn!(
+                        "{} digipeats to {}, which isn't a serial port",
+                        port.name, to
+                    );
+                }
+            }
+        }
+
+        if config.digipeater.enabled && !rf_ports.iter().any(|p| p.accepts_own_digipeats()) {
+            if let [port] = rf_ports.as_slice() {
+                warn!(
+                    "Digipeater enabled but {} doesn't repeat its own traffic; set loopback = true",
+                    port.name
+                );
+            }
+        }
+
         let channels = RouterChannels {
-            rf_tx: rf_tx.clone(),
+            rf_rx,
             is_tx: is_tx.clone(),
+            heard: heard_tx.clone(),
             digipeater_rx,
             message_rx,
         };
 
+        let spool = config
+            .message_spool
+            .as_ref()
+            .filter(|c| c.enabled)
+            .map(|c| MessageSpool::new(c.clone()));
+
+        let rate_limiter = config
+            .rate_limit
+            .as_ref()
+            .map(|c| Mutex::new(RateLimiter::new(c)));
+
+        let stale = config
+            .stale
+            .as_ref()
+            .map(|c| Mutex::new(StaleCheck::new(chrono::Duration::seconds(c.max_age as i64))));
+
         let router = PacketRouter {
             config,
             filter,
             rx_channel,
-            rf_tx,
+            rf_ports,
             is_tx,
+            heard_tx,
             digipeater_tx,
             message_tx,
-            recent_packets: Arc::new(RwLock::new(Vec::new())),
+            recent_packets: Arc::new(RwLock::new(DupeCache::new(DEDUP_CAPACITY))),
+            spool,
+            rate_limiter,
+            stale,
+            gate_budget: None,
+            plugins: Plugins::default(),
+            injected: std::sync::Mutex::new(Vec::new()),
+            shutdown: Shutdown::new(),
         };
 
         (router, channels)
     }
 
-    pub async fn run(mut self) -> Result<()> {
+    pub fn with_plugins(mut self, plugins: Plugins) -> Self {
+        self.plugins = plugins;
+        self
+    }
+
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Hold gating from APRS-IS to RF to a budget, shared so its use can
+    /// be reported
+    pub fn with_gate_budget(mut self, budget: Arc<std::sync::Mutex<GateBudget>>) -> Self {
+        self.gate_budget = Some(budget);
+        self
+    }
+
+    pub async fn run(&mut self) -> Result<()> {
         info!("Starting packet router");
 
         let mut cleanup_interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
+        let mut spool_interval = tokio::time::interval(tokio::time::Duration::from_secs(30));
 
         loop {
             tokio::select! {
                 Some(routed_packet) = self.rx_channel.recv() => {
                     self.route_packet(routed_packet).await?;
+                    self.route_injected().await?;
                 }
                 _ = cleanup_interval.tick() => {
                     self.cleanup_recent_packets().await;
                 }
+                _ = spool_interval.tick() => {
+                    self.retransmit_spooled().await;
+                }
+                // Route what's already waiting, then stop; the ports and
+                // APRS-IS see their channels close and send what they hold
+                _ = self.shutdown.wait() => {
+                    while let Ok(routed_packet) = self.rx_channel.try_recv() {
+                        self.route_packet(routed_packet).await?;
+                        self.route_injected().await?;
+                    }
+                    info!("Packet router stopped");
+                    return Ok(());
+                }
             }
         }
     }
 
-    async fn route_packet(&self, routed_packet: RoutedPacket) -> Result<()> {
-        let packet_str = routed_packet.packet.to_string();
+    async fn route_packet(&self, mut routed_packet: RoutedPacket) -> Result<()> {
         debug!(
             "Routing packet from {:?}: {}",
-            routed_packet.source, packet_str
+            routed_packet.source, routed_packet.packet
         );
 
-        // Check for duplicate packets (viscous delay)
-        if self.is_duplicate(&packet_str).await {
-            debug!("Dropping duplicate packet: {}", packet_str);
+        // Check for duplicate packets. The key is taken before plugins and
+        // filters get to change the packet.
+        let key = dedup::packet_key(&routed_packet.packet);
+        if self.is_duplicate(key).await {
+            debug!("Dropping duplicate packet: {}", routed_packet.packet);
             return Ok(());
         }
 
+        // Let plugins see what we hear before the filters do
+        if let PacketSource::SerialPort(_) | PacketSource::AprsIs = routed_packet.source {
+            let verdict =
+                self.run_plugins(|plugins, inject| plugins.on_receive(&mut routed_packet, inject));
+            if verdict == Verdict::Drop {
+                debug!("Packet dropped by plugin: {}", routed_packet.packet);
+                return Ok(());
+            }
+        }
+
         // Apply filters
-        if !self.filter.should_pass(&routed_packet.packet) {
-            debug!("Packet filtered out: {}", packet_str);
+        if !self.filter.apply(&mut routed_packet.packet) {
+            debug!("Packet filtered out: {}", routed_packet.packet);
             return Ok(());
         }
 
+        // Then the inbound list of the port it arrived on
+        let port = match &routed_packet.source {
+            PacketSource::SerialPort(name) => Some(name.as_str()),
+            PacketSource::AprsIs => Some(APRS_IS_PORT),
+            PacketSource::Internal | PacketSource::Digipeater(_) => None,
+        }
+        .map(str::to_string);
+        if let Some(port) = &port {
+            if !self.filter.should_pass_inbound(port, &routed_packet.packet) {
+                debug!("Packet filtered out on {}: {}", port, routed_packet.packet);
+                return Ok(());
+            }
+            // And plugins, on what the filters let through
+            let verdict =
+                self.run_plugins(|plugins, inject| plugins.on_filter(&mut routed_packet, inject));
+            if verdict == Verdict::Drop {
+                debug!(
+                    "Packet dropped by plugin after filters: {}",
+                    routed_packet.packet
+                );
+                return Ok(());
+            }
+        }
+        let port = port.as_deref();
+
+        // Rate limit each source heard on RF or APRS-IS
+        if let (Some(limiter), Some(port)) = (&self.rate_limiter, port) {
+            let source = routed_packet.packet.source.to_string();
+            if !limiter
+                .lock()
+                .await
+                .allow(&source, std::time::Instant::now())
+            {
+                TELEMETRY_STATS
+                    .packets_rate_limited
+                    .fetch_add(1, Ordering::Relaxed);
+                debug!(
+                    "Rate limited {} on {}: {}",
+                    source, port, routed_packet.packet
+                );
+                return Ok(());
+            }
+        }
+
+        // Pass what we hear on to feeds like MQTT. Sending only fails with
+        // nobody subscribed.
+        if let Some(port) = port {
+            let direct = port != APRS_IS_PORT && digipeater::used_hops(&routed_packet.packet) == 0;
+            HEARD.note(port, &routed_packet.packet, direct, chrono::Utc::now());
+            self.heard_tx.send(routed_packet.clone()).ok();
+        }
+
+        // The log chain picks out packets worth an entry in the traffic log
+        if self.filter.has_chain(FilterDirection::Log)
+            && self
+                .filter
+                .should_pass_for(FilterDirection::Log, &routed_packet.packet)
+        {
+            info!(
+                "Heard via {:?}: {}",
+                routed_packet.source, routed_packet.packet
+            );
+        }
+
+        // Delayed retransmissions can be kept off the air and the igate
+        let stale = match (&self.stale, port) {
+            (Some(check), Some(_)) => check
+                .lock()
+                .await
+                .is_stale(&routed_packet.packet, chrono::Utc::now()),
+            _ => false,
+        };
+        if stale {
+            TELEMETRY_STATS
+                .packets_stale
+                .fetch_add(1, Ordering::Relaxed);
+            debug!("Stale packet: {}", routed_packet.packet);
+        }
+        let (no_stale_digipeat, no_stale_gate) = match &self.config.stale {
+            Some(config) if stale => (config.digipeat, config.gate),
+            _ => (false, false),
+        };
+
         // Check for RFONLY or NOGATE
-        let is_rf_only = routed_packet.packet.has_rfonly();
-        let is_no_gate = routed_packet.packet.has_nogate();
+        // Tag filters can ask for the same treatment
+        let tagged = |tag: &str| routed_packet.packet.tags.iter().any(|t| t == tag);
+        let is_rf_only = routed_packet.packet.has_rfonly() || tagged(TAG_RFONLY);
+        let is_no_gate = routed_packet.packet.has_nogate() || tagged(TAG_NOGATE);
+
+        // Acks release held messages no matter which side they arrive from
+        if let Some(spool) = &self.spool {
+            spool.acknowledge(&routed_packet.packet).await;
+        }
 
         // Route based on source and packet properties
         match &routed_packet.source {
-            PacketSource::SerialPort(_) => {
+            PacketSource::SerialPort(port) => {
                 // RF packet received
                 TELEMETRY_STATS.packets_rx.fetch_add(1, Ordering::Relaxed);
 
+                if let Some(spool) = &self.spool {
+                    spool.note_heard(&routed_packet.packet.source).await;
+                }
+
                 // Send to digipeater if enabled
+                let digipeats = self
+                    .rf_ports
+                    .iter()
+                    .find(|p| p.name == *port)
+                    .is_none_or(|p| p.digipeats(&routed_packet.packet));
                 if self.config.digipeater.enabled
-                    && self.digipeater_tx.send(routed_packet.clone()).await.is_ok()
+                    && digipeats
+                    && !no_stale_digipeat
+                    && self
+                        .filter
+                        .should_pass_for(FilterDirection::Digipeat, &routed_packet.packet)
+                    && channel::send(
+                        &self.digipeater_tx,
+                        routed_packet.clone(),
+                        channel::DIGIPEATER,
+                        Overflow::Drop,
+                    )
+                    .await
                 {
                     TELEMETRY_STATS
                         .packets_digipeated
                         .fetch_add(1, Ordering::Relaxed);
                 }
 
-                // Send to APRS-IS if I-gate is enabled and packet allows it
-                if !is_rf_only && !is_no_gate {
+                // Send to APRS-IS if I-gate is enabled and packet allows it.
+                // Another igate has already uploaded anything that's been
+                // through the internet.
+                let via_internet = routed_packet.packet.via_internet();
+                if via_internet {
+                    debug!(
+                        "Not gating packet already on APRS-IS: {}",
+                        routed_packet.packet
+                    );
+                }
+                if !is_rf_only
+                    && !is_no_gate
+                    && !via_internet
+                    && !no_stale_gate
+                    && self
+                        .filter
+                        .should_pass_for(FilterDirection::RfToIs, &routed_packet.packet)
+                {
                     if let Some(aprs_is) = &self.config.aprs_is {
                         if aprs_is.rx_enable {
-                            info!("Gating to APRS-IS: {}", packet_str);
-                            if self.is_tx.send(routed_packet.clone()).is_ok() {
+                            info!("Gating to APRS-IS: {}", routed_packet.packet);
+                            if self.send_to_aprs_is(&routed_packet) {
                                 TELEMETRY_STATS
                                     .packets_igate_rf_to_is
                                     .fetch_add(1, Ordering::Relaxed);
@@ -133,85 +457,282 @@ impl PacketRouter {
                     }
                 }
 
-                // Check for messages addressed to us
-                if routed_packet.packet.destination.call == self.config.mycall {
-                    let _ = self.message_tx.send(routed_packet.clone()).await;
-                }
+                self.deliver_message(&routed_packet).await;
             }
             PacketSource::AprsIs => {
                 // APRS-IS packet received
 
                 // Send to RF if TX is enabled
                 if let Some(aprs_is) = &self.config.aprs_is {
-                    if aprs_is.tx_enable {
+                    if aprs_is.tx_enable && !no_stale_gate {
                         // Check if packet should be transmitted on RF
-                        if self.should_gate_to_rf(&routed_packet.packet).await {
-                            info!("Gating to RF: {}", packet_str);
-                            if self.rf_tx.send(routed_packet.clone()).is_ok() {
+                        if self.should_gate_to_rf(&routed_packet.packet).await
+                            && self.within_gate_budget(&routed_packet.packet)
+                        {
+                            info!("Gating to RF: {}", routed_packet.packet);
+                            if self.send_to_rf(&routed_packet) {
                                 TELEMETRY_STATS
                                     .packets_igate_is_to_rf
                                     .fetch_add(1, Ordering::Relaxed);
                                 TELEMETRY_STATS.packets_tx.fetch_add(1, Ordering::Relaxed);
+                                if routed_packet.packet.message().is_some() {
+                                    TELEMETRY_STATS
+                                        .messages_igate_is_to_rf
+                                        .fetch_add(1, Ordering::Relaxed);
+                                }
+
+                                if let Some(spool) = &self.spool {
+                                    spool.hold(&routed_packet.packet).await;
+                                }
                             }
                         }
                     }
                 }
+
+                self.deliver_message(&routed_packet).await;
             }
             PacketSource::Internal => {
                 // Internal packet (generated by us)
 
                 // Send to RF
-                if self.rf_tx.send(routed_packet.clone()).is_ok() {
+                if !tagged(TAG_ISONLY) && self.send_to_rf(&routed_packet) {
                     TELEMETRY_STATS.packets_tx.fetch_add(1, Ordering::Relaxed);
                 }
 
                 // Send to APRS-IS
                 if let Some(aprs_is) = &self.config.aprs_is {
-                    if aprs_is.tx_enable {
-                        let _ = self.is_tx.send(routed_packet.clone());
+                    if aprs_is.tx_enable && tagged_for(&routed_packet.packet, APRS_IS_PORT) {
+                        self.send_to_aprs_is(&routed_packet);
                     }
                 }
             }
+            PacketSource::Digipeater(_) => {
+                // Our digipeat of an RF packet; the original was already
+                // gated to APRS-IS when we heard it
+                if self.send_to_rf(&routed_packet) {
+                    TELEMETRY_STATS.packets_tx.fetch_add(1, Ordering::Relaxed);
+                }
+            }
         }
 
         // Store packet hash for duplicate detection
-        self.store_packet_hash(&packet_str).await;
+        self.store_packet_hash(key).await;
 
         Ok(())
     }
 
-    async fn is_duplicate(&self, packet_str: &str) -> bool {
-        let hash = calculate_packet_hash(packet_str);
-        let recent = self.recent_packets.read().await;
+    /// Hand messages to us or to a group to the message handler
+    async fn deliver_message(&self, routed: &RoutedPacket) {
+        let wanted = routed.packet.destination.call == self.config.mycall
+            || routed.packet.message().is_some_and(|msg| {
+                message::addressed_here(msg, &self.config.mycall, &self.config.messaging.groups)
+            });
+        if wanted {
+            channel::send(
+                &self.message_tx,
+                routed.clone(),
+                channel::MESSAGES,
+                Overflow::Block,
+            )
+            .await;
+        }
+    }
+
+    async fn is_duplicate(&self, key: u64) -> bool {
+        let window = std::time::Duration::from_secs(self.config.dedup_window as u64);
+
+        self.recent_packets
+            .read()
+            .await
+            .is_duplicate(&key, std::time::Instant::now(), window)
+    }
+
+    async fn store_packet_hash(&self, key: u64) {
+        self.recent_packets
+            .write()
+            .await
+            .insert(&key, std::time::Instant::now());
+    }
+
+    async fn cleanup_recent_packets(&self) {
         let now = std::time::Instant::now();
-        let viscous_delay =
-            std::time::Duration::from_secs(self.config.digipeater.viscous_delay as u64);
+        let window = std::time::Duration::from_secs(self.config.dedup_window as u64);
 
-        recent
-            .iter()
-            .any(|(h, t)| h == &hash && now.duration_since(*t) < viscous_delay)
+        self.recent_packets.write().await.prune(now, window);
+
+        if let Some(stale) = &self.stale {
+            stale.lock().await.prune(chrono::Utc::now());
+        }
+
+        if let Some(limiter) = &self.rate_limiter {
+            let mut limiter = limiter.lock().await;
+            for (source, dropped) in limiter.take_dropped() {
+                info!("Rate limited {} packets from {}", dropped, source);
+            }
+            limiter.prune(now);
+        }
     }
 
-    async fn store_packet_hash(&self, packet_str: &str) {
-        let hash = calculate_packet_hash(packet_str);
-        let mut recent = self.recent_packets.write().await;
-        recent.push((hash, std::time::Instant::now()));
+    async fn retransmit_spooled(&self) {
+        let Some(spool) = &self.spool else {
+            return;
+        };
 
-        // Keep list size reasonable
-        if recent.len() > 1000 {
-            recent.drain(0..100);
+        for packet in spool.due_messages().await {
+            info!("Retransmitting held message: {}", packet);
+            let routed = RoutedPacket {
+                packet,
+                source: PacketSource::AprsIs,
+            };
+            if self.send_to_rf(&routed) {
+                TELEMETRY_STATS.packets_tx.fetch_add(1, Ordering::Relaxed);
+            }
         }
+
+        debug!(
+            "{} messages held for store-and-forward",
+            spool.held_count().await
+        );
     }
 
-    async fn cleanup_recent_packets(&self) {
-        let mut recent = self.recent_packets.write().await;
-        let now = std::time::Instant::now();
-        let max_age = std::time::Duration::from_secs(300); // 5 minutes
+    /// Hand a packet to every RF port that takes packets from its origin.
+    /// Returns whether any port accepted it.
+    fn send_to_rf(&self, routed: &RoutedPacket) -> bool {
+        let Some(routed) = fit_for_tx(routed, "RF") else {
+            return false;
+        };
+        let routed = &routed;
+        let mut sent = false;
+        for port in self.rf_ports.iter().filter(|p| self.transmits(p, routed)) {
+            let mut routed = routed.clone();
+            let verdict = self.run_plugins(|plugins, inject| {
+                plugins.on_transmit(&port.name, &mut routed, inject)
+            });
+            if verdict == Verdict::Drop {
+                debug!(
+                    "Not queueing for {}, dropped by plugin: {}",
+                    port.name, routed.packet
+                );
+                continue;
+            }
+            match port.tx.try_send(routed) {
+                Ok(()) => sent = true,
+                Err(e) => {
+                    warn!("Not queueing for {}: {}", port.name, e);
+                    channel::record_drops(&format!("rf:{}", port.name), 1);
+                }
+            }
+        }
+        sent
+    }
+
+    /// Whether a packet from `source` goes out `port`. A digipeat has to
+    /// be allowed by the port it was heard on as well as the one it leaves.
+    fn transmits(&self, port: &RfPort, routed: &RoutedPacket) -> bool {
+        if !tagged_for(&routed.packet, &port.name) {
+            return false;
+        }
+        let source = &routed.source;
+        if let PacketSource::Digipeater(heard) = source {
+            let heard_on = self.rf_ports.iter().find(|p| p.name == *heard);
+            match heard_on.and_then(|p| p.digipeats_to(&port.name)) {
+                Some(false) => return false,
+                Some(true) if port.name == *heard => return true,
+                _ => {}
+            }
+        }
+        port.accepts(source)
+    }
+
+    fn send_to_aprs_is(&self, routed: &RoutedPacket) -> bool {
+        if !self
+            .filter
+            .should_pass_outbound(APRS_IS_PORT, &routed.packet)
+        {
+            debug!("Not sending to APRS-IS, filtered: {}", routed.packet);
+            return false;
+        }
+        // What we gate goes up as it was heard; only our own is fixed up
+        let mut routed = if routed.source == PacketSource::Internal {
+            match fit_for_tx(routed, "APRS-IS") {
+                Some(routed) => routed,
+                None => return false,
+            }
+        } else {
+            routed.clone()
+        };
+        let verdict = self
+            .run_plugins(|plugins, inject| plugins.on_transmit(APRS_IS_PORT, &mut routed, inject));
+        if verdict == Verdict::Drop {
+            debug!(
+                "Not sending to APRS-IS, dropped by plugin: {}",
+                routed.packet
+            );
+            return false;
+        }
+        let received = routed.packet.timestamp;
+        if self.is_tx.send(routed).is_err() {
+            return false;
+        }
+        channel::APRS_IS_QUEUE.push(received);
+        true
+    }
+
+    /// Run a plugin hook, keeping whatever it injects for `route_injected`
+    fn run_plugins(
+        &self,
+        hook: impl FnOnce(&Plugins, &mut Vec<RoutedPacket>) -> Verdict,
+    ) -> Verdict {
+        if self.plugins.is_empty() {
+            return Verdict::Keep;
+        }
+        let mut inject = Vec::new();
+        let verdict = hook(&self.plugins, &mut inject);
+        self.injected.lock().unwrap().extend(inject);
+        verdict
+    }
+
+    /// Route packets injected by plugins, up to a limit so plugins feeding
+    /// each other can't loop forever
+    async fn route_injected(&self) -> Result<()> {
+        for _ in 0..MAX_INJECTED {
+            let next = {
+                let mut injected = self.injected.lock().unwrap();
+                (!injected.is_empty()).then(|| injected.remove(0))
+            };
+            let Some(routed) = next else {
+                return Ok(());
+            };
+            self.route_packet(routed).await?;
+        }
+
+        let mut injected = self.injected.lock().unwrap();
+        if !injected.is_empty() {
+            warn!("Dropping {} packets injected by plugins", injected.len());
+            injected.clear();
+        }
+        Ok(())
+    }
 
-        recent.retain(|(_, t)| now.duration_since(*t) < max_age);
+    fn within_gate_budget(&self, packet: &AprsPacket) -> bool {
+        let Some(budget) = &self.gate_budget else {
+            return true;
+        };
+        let allowed = budget
+            .lock()
+            .unwrap()
+            .take(packet, std::time::Instant::now());
+        if !allowed {
+            debug!("Not gating to RF, over budget: {}", packet);
+        }
+        allowed
     }
 
     async fn should_gate_to_rf(&self, packet: &AprsPacket) -> bool {
+        if !self.filter.should_pass_for(FilterDirection::IsToRf, packet) {
+            return false;
+        }
+
         // Don't gate packets that came from TCPIP (already on RF)
         if packet.path.iter().any(|p| p.call.contains("TCPIP")) {
             return false;
@@ -222,25 +743,223 @@ impl PacketRouter {
             return false;
         }
 
-        // Gate messages addressed to local stations
-        // This is a simplified implementation - could be enhanced with
-        // local station tracking
-        true
+        // Optionally only gate messages addressed to local stations
+        let Some(window) = self.config.aprs_is.as_ref().and_then(|a| a.local_window) else {
+            return true;
+        };
+        let Some(msg) = packet.message() else {
+            return false;
+        };
+        HEARD.heard_on_rf(
+            &msg.addressee.to_uppercase(),
+            chrono::Utc::now(),
+            chrono::Duration::minutes(window as i64),
+        )
     }
 }
 
 pub struct RouterChannels {
-    pub rf_tx: broadcast::Sender<RoutedPacket>,
+    pub rf_rx: HashMap<String, mpsc::Receiver<RoutedPacket>>, // By serial port name
     pub is_tx: broadcast::Sender<RoutedPacket>,
+    pub heard: broadcast::Sender<RoutedPacket>, // Everything heard on RF and APRS-IS that passed the filters
     pub digipeater_rx: mpsc::Receiver<RoutedPacket>,
     pub message_rx: mpsc::Receiver<RoutedPacket>,
 }
 
-fn calculate_packet_hash(packet: &str) -> String {
-    use std::collections::hash_map::DefaultHasher;
-    use std::hash::{Hash, Hasher};
+/// A copy of the packet with its information field fit to transmit, or
+/// None if it can't be
+fn fit_for_tx(routed: &RoutedPacket, to: &str) -> Option<RoutedPacket> {
+    let mut routed = routed.clone();
+    match routed.packet.fit_information() {
+        Ok(changes) => {
+            if !changes.is_empty() {
+                warn!(
+                    "Fixed packet for {} ({}): {}",
+                    to,
+                    changes.join(", "),
+                    routed.packet
+                );
+            }
+            Some(routed)
+        }
+        Err(e) => {
+            warn!("Not sending to {}, {}: {}", to, e, routed.packet);
+            None
+        }
+    }
+}
+
+/// Whether a packet's port tags, if it has any, name `port`
+fn tagged_for(packet: &AprsPacket, port: &str) -> bool {
+    let mut ports = packet
+        .tags
+        .iter()
+        .filter_map(|t| t.strip_prefix(TAG_PORT_PREFIX))
+        .peekable();
+    ports.peek().is_none() || ports.any(|p| p == port)
+}
 
-    let mut hasher = DefaultHasher::new();
-    packet.hash(&mut hasher);
-    format!("{:x}", hasher.finish())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port(tx_from: Option<&[&str]>) -> RfPort {
+        RfPort {
+            name: "vhf".to_string(),
+            tx_from: tx_from.map(|t| t.iter().map(|s| s.to_string()).collect()),
+            loopback: false,
+            digipeat_to: None,
+            digipeat: true,
+            max_hops: None,
+            tx: mpsc::channel(1).0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_routes_what_is_waiting() {
+        let config: Config = toml::from_str(
+            "mycall = \"N0CALL\"\n[[serial_ports]]\nname = \"vhf\"\ndevice = \"/dev/null\"",
+        )
+        .unwrap();
+        let filter = Arc::new(PacketFilter::new(vec![]).unwrap());
+        let (packet_tx, packet_rx) = mpsc::channel(10);
+        let shutdown = Shutdown::new();
+        let (router, mut channels) = PacketRouter::new(Arc::new(config), filter, packet_rx);
+        let mut router = router.with_shutdown(shutdown.clone());
+
+        let routed = RoutedPacket {
+            packet: crate::aprs::parse_packet("N0CALL>APRS:>Going away").unwrap(),
+            source: PacketSource::Internal,
+        };
+        packet_tx.send(routed).await.unwrap();
+        shutdown.trigger();
+        router.run().await.unwrap();
+        drop(router);
+
+        // The packet made it out, then the port's channel closed
+        let mut rf_rx = channels.rf_rx.remove("vhf").unwrap();
+        assert_eq!(
+            rf_rx.recv().await.unwrap().packet.to_string(),
+            "N0CALL>APRS:>Going away"
+        );
+        assert!(rf_rx.recv().await.is_none());
+    }
+
+    #[test]
+    fn test_rf_port_accepts() {
+        let open = port(None);
+        assert!(open.accepts(&PacketSource::AprsIs));
+        assert!(open.accepts(&PacketSource::Internal));
+        assert!(open.accepts(&PacketSource::Digipeater("uhf".to_string())));
+        assert!(!open.accepts(&PacketSource::SerialPort("uhf".to_string())));
+
+        let hf = port(Some(&["local", "vhf"]));
+        assert!(!hf.accepts(&PacketSource::AprsIs));
+        assert!(hf.accepts(&PacketSource::Internal));
+        assert!(hf.accepts(&PacketSource::Digipeater("vhf".to_string())));
+        assert!(!hf.accepts(&PacketSource::Digipeater("uhf".to_string())));
+
+        let digi = port(Some(&["digipeater", "aprs-is"]));
+        assert!(digi.accepts(&PacketSource::AprsIs));
+        assert!(!digi.accepts(&PacketSource::Internal));
+        assert!(digi.accepts(&PacketSource::Digipeater("uhf".to_string())));
+    }
+
+    #[test]
+    fn test_rf_port_loopback() {
+        let own = PacketSource::Digipeater("vhf".to_string());
+        assert!(!port(None).accepts(&own));
+        assert!(!port(Some(&["digipeater"])).accepts(&own));
+        assert!(port(Some(&["vhf"])).accepts(&own));
+
+        let looped = RfPort {
+            loopback: true,
+            ..port(None)
+        };
+        assert!(looped.accepts(&own));
+        let looped_is_only = RfPort {
+            loopback: true,
+            ..port(Some(&["aprs-is"]))
+        };
+        assert!(!looped_is_only.accepts(&own));
+        assert!(!looped.accepts(&PacketSource::SerialPort("vhf".to_string())));
+    }
+
+    #[test]
+    fn test_cross_port_digipeats() {
+        let config: Config = toml::from_str(
+            r#"
+mycall = "N0CALL"
+[[serial_ports]]
+name = "vhf"
+device = "/dev/null"
+digipeat_to = ["uhf"]
+[[serial_ports]]
+name = "uhf"
+device = "/dev/null"
+digipeat_to = ["uhf", "hf"]
+[[serial_ports]]
+name = "hf"
+device = "/dev/null"
+tx_from = ["local"]
+"#,
+        )
+        .unwrap();
+        let filter = Arc::new(PacketFilter::new(vec![]).unwrap());
+        let (router, _channels) = PacketRouter::new(Arc::new(config), filter, mpsc::channel(1).1);
+        let routed = |source| RoutedPacket {
+            packet: crate::aprs::parse_packet("N0CALL>APRS:>Hi").unwrap(),
+            source,
+        };
+        let out = |heard: &str| -> Vec<&str> {
+            let digipeat = routed(PacketSource::Digipeater(heard.to_string()));
+            router
+                .rf_ports
+                .iter()
+                .filter(|p| router.transmits(p, &digipeat))
+                .map(|p| p.name.as_str())
+                .collect()
+        };
+
+        // Cross-band one way only
+        assert_eq!(out("vhf"), ["uhf"]);
+        // Back out the same port without loopback; hf's tx_from still
+        // keeps digipeats off it
+        assert_eq!(out("uhf"), ["uhf"]);
+        // Unset, the other ports' own settings decide
+        assert_eq!(out("hf"), ["vhf", "uhf"]);
+        let mut beacon = routed(PacketSource::Internal);
+        assert!(router.transmits(&router.rf_ports[2], &beacon));
+
+        // Port tags keep our own packets to the ports they name
+        beacon.packet.tags = vec!["port:vhf".to_string(), "port:hf".to_string()];
+        let to: Vec<&str> = router
+            .rf_ports
+            .iter()
+            .filter(|p| router.transmits(p, &beacon))
+            .map(|p| p.name.as_str())
+            .collect();
+        assert_eq!(to, ["vhf", "hf"]);
+        assert!(!tagged_for(&beacon.packet, APRS_IS_PORT));
+    }
+
+    #[test]
+    fn test_rf_port_digipeat_policy() {
+        let fresh = crate::aprs::parse_packet("N0CALL>APRS,WIDE1-1,WIDE2-1:>Hi").unwrap();
+        let used = crate::aprs::parse_packet("N0CALL>APRS,N1CALL*,WIDE2-1:>Hi").unwrap();
+        assert!(port(None).digipeats(&used));
+
+        let lora = RfPort {
+            max_hops: Some(1),
+            ..port(None)
+        };
+        assert!(lora.digipeats(&fresh));
+        assert!(!lora.digipeats(&used));
+
+        let quiet = RfPort {
+            digipeat: false,
+            ..port(None)
+        };
+        assert!(!quiet.digipeats(&fresh));
+    }
 }
@@ -10,10 +10,26 @@ const KISS_CMD_DATA: u8 = 0x00;
 #[cfg(test)]
 const KISS_CMD_TXDELAY: u8 = 0x01;
 
+/// A frame on one of the command codes rather than data, such as the
+/// hardware-specific 0x06 some TNCs report battery voltage with
+#[derive(Debug, Clone, PartialEq)]
+pub struct KissCommand {
+    pub port: u8,
+    pub command: u8,
+    pub data: Vec<u8>,
+}
+
 pub struct KissCodec {
     decode_buf: BytesMut,
     in_frame: bool,
     escaped: bool,
+    commands: Vec<KissCommand>,
+}
+
+impl Default for KissCodec {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl KissCodec {
@@ -22,9 +38,15 @@ impl KissCodec {
             decode_buf: BytesMut::with_capacity(1024),
             in_frame: false,
             escaped: false,
+            commands: Vec::new(),
         }
     }
 
+    /// Command frames decoded since last asked
+    pub fn take_commands(&mut self) -> Vec<KissCommand> {
+        std::mem::take(&mut self.commands)
+    }
+
     pub fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Vec<u8>>, io::Error> {
         while src.has_remaining() {
             let byte = src.get_u8();
@@ -54,6 +76,13 @@ impl KissCodec {
                             if cmd == KISS_CMD_DATA && port == 0 && frame.len() > 1 {
                                 return Ok(Some(frame[1..].to_vec()));
                             }
+                            if cmd != KISS_CMD_DATA {
+                                self.commands.push(KissCommand {
+                                    port,
+                                    command: cmd,
+                                    data: frame[1..].to_vec(),
+                                });
+                            }
                         }
                     } else {
                         self.in_frame = true;
@@ -77,10 +106,14 @@ impl KissCodec {
     }
 
     pub fn encode(&self, data: &[u8], port: u8) -> Vec<u8> {
+        self.encode_command(KISS_CMD_DATA, data, port)
+    }
+
+    pub fn encode_command(&self, command: u8, data: &[u8], port: u8) -> Vec<u8> {
         let mut output = Vec::with_capacity(data.len() + 4);
 
         output.push(KISS_FEND);
-        output.push((port << 4) | KISS_CMD_DATA);
+        output.push((port << 4) | (command & 0x0F));
 
         for &byte in data {
             match byte {
@@ -236,6 +269,22 @@ mod tests {
         // Different port data frame
         buf.extend_from_slice(&[KISS_FEND, 0x10, 0x41, 0x42, KISS_FEND]);
         assert!(codec.decode(&mut buf).unwrap().is_none());
+
+        // The command frame is kept aside, the other port's data isn't
+        assert_eq!(
+            codec.take_commands(),
+            vec![KissCommand {
+                port: 0,
+                command: KISS_CMD_TXDELAY,
+                data: vec![0x10],
+            }]
+        );
+        assert!(codec.take_commands().is_empty());
+
+        // Hardware frames round trip
+        let mut buf = BytesMut::from(&codec.encode_command(0x06, b"V=12.6", 0)[..]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert_eq!(codec.take_commands()[0].data, b"V=12.6");
     }
 
     #[test]
@@ -252,4 +301,40 @@ mod tests {
         let result = codec.decode(&mut buf).unwrap().unwrap();
         assert_eq!(result, vec![0x41]);
     }
+
+    proptest::proptest! {
+        #[test]
+        fn fuzz_decode(chunks in proptest::collection::vec(proptest::collection::vec(proptest::prelude::any::<u8>(), 0..64), 0..8)) {
+            // Frames and commands arriving in pieces, however they're cut
+            let mut codec = KissCodec::new();
+            let mut buf = BytesMut::new();
+            for chunk in chunks {
+                buf.extend_from_slice(&chunk);
+                while codec.decode(&mut buf).unwrap().is_some() {}
+                codec.take_commands();
+            }
+        }
+
+        #[test]
+        fn fuzz_round_trip(
+            frames in proptest::collection::vec(proptest::collection::vec(proptest::prelude::any::<u8>(), 1..100), 1..4),
+            cut in 0usize..400,
+        ) {
+            let mut codec = KissCodec::new();
+            let mut stream = Vec::new();
+            for frame in &frames {
+                stream.extend(codec.encode(frame, 0));
+            }
+            let cut = cut.min(stream.len());
+            let mut decoded = Vec::new();
+            let mut buf = BytesMut::new();
+            for part in [&stream[..cut], &stream[cut..]] {
+                buf.extend_from_slice(part);
+                while let Some(frame) = codec.decode(&mut buf).unwrap() {
+                    decoded.push(frame);
+                }
+            }
+            proptest::prop_assert_eq!(decoded, frames);
+        }
+    }
 }
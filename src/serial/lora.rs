@@ -0,0 +1,76 @@
+//! LoRa APRS framing.
+//!
+//! LoRa APRS sends packets as TNC2 text behind a three byte header rather
+//! than as AX.25. Serial LoRa TNCs pass those payloads through one per line,
+//! and KISS LoRa TNCs carry them as the data of a KISS frame.
+
+use crate::aprs::{AprsPacket, Charset};
+use anyhow::{anyhow, Result};
+
+/// Leads every LoRa APRS payload on the air
+pub const LORA_HEADER: &[u8] = b"<\xFF\x01";
+
+/// Longest payload a LoRa modem sends in one packet, header included
+pub const LORA_MAX_PAYLOAD: usize = 255;
+
+/// The TNC2 text of a received payload, with or without the header.
+pub fn decode(payload: &[u8], charset: Charset) -> Option<String> {
+    let text = payload.strip_prefix(LORA_HEADER).unwrap_or(payload);
+    let text = charset.decode(text);
+    let text = text.trim_end_matches(['\r', '\n']);
+    if text.is_empty() {
+        None
+    } else {
+        Some(text.to_string())
+    }
+}
+
+/// Whether a KISS frame holds LoRa text instead of AX.25
+pub fn is_lora_frame(frame: &[u8]) -> bool {
+    frame.starts_with(LORA_HEADER)
+}
+
+/// A packet as a LoRa APRS payload, header first
+pub fn encode(packet: &AprsPacket, charset: Charset) -> Result<Vec<u8>> {
+    packet.validate_ax25()?;
+    let mut payload = LORA_HEADER.to_vec();
+    payload.extend_from_slice(&charset.encode(&packet.to_string()));
+    if payload.len() > LORA_MAX_PAYLOAD {
+        return Err(anyhow!(
+            "{} bytes is more than a LoRa packet holds",
+            payload.len()
+        ));
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aprs::parse_packet;
+
+    #[test]
+    fn test_round_trip() {
+        let packet = parse_packet("N0CALL-7>APLRT1,WIDE1-1:!4903.50N/07201.75W>").unwrap();
+        let payload = encode(&packet, Charset::Utf8).unwrap();
+        assert_eq!(&payload[..3], LORA_HEADER);
+        assert!(is_lora_frame(&payload));
+        assert_eq!(
+            decode(&payload, Charset::Utf8).unwrap(),
+            "N0CALL-7>APLRT1,WIDE1-1:!4903.50N/07201.75W>"
+        );
+
+        // Some TNCs strip the header before handing packets over
+        assert_eq!(
+            decode(b"N0CALL>APRS:>Hi\r", Charset::Utf8).unwrap(),
+            "N0CALL>APRS:>Hi"
+        );
+        assert!(decode(LORA_HEADER, Charset::Utf8).is_none());
+    }
+
+    #[test]
+    fn test_rejects_oversized_packets() {
+        let packet = parse_packet(&format!("N0CALL>APRS:>{}", "x".repeat(250))).unwrap();
+        assert!(encode(&packet, Charset::Utf8).is_err());
+    }
+}
@@ -1,160 +1,686 @@
-mod kiss;
+pub mod kiss;
+mod lora;
 pub mod pure_serial;
+pub mod queue;
+mod remote;
 
-use crate::aprs::{parse_packet, AprsPacket};
+use crate::aprs::{parse_packet, AprsPacket, Charset};
+use crate::capture::{self, Direction};
+use crate::channel::{self, Overflow};
 use crate::config::{SerialPortConfig, SerialProtocol};
+use crate::filter::PacketFilter;
 use crate::router::{PacketSource, RoutedPacket};
+use crate::shutdown::Shutdown;
+use crate::telemetry::TELEMETRY_STATS;
 use anyhow::{anyhow, Result};
 use bytes::BytesMut;
 use kiss::KissCodec;
-use log::{debug, error, info};
+use lazy_static::lazy_static;
+use log::{debug, error, info, warn};
 use pure_serial::SerialPort;
+use queue::{ChannelAccess, HardwareFrame, PortStats, TxScheduler};
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use tokio::fs::{File, OpenOptions};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::mpsc;
+
+/// Longest a port spends sending what's queued when shutting down
+const FLUSH_TIME: std::time::Duration = std::time::Duration::from_secs(3);
+
+/// A KISS command code and its data
+type KissCommandFrame = (u8, Vec<u8>);
+
+lazy_static! {
+    /// Open KISS ports, for sending them command frames
+    static ref KISS_PORTS: Mutex<HashMap<String, mpsc::Sender<KissCommandFrame>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Send a KISS command frame, such as a hardware-specific 0x06 query, to
+/// an open KISS port
+pub fn send_kiss_command(port: &str, command: u8, data: Vec<u8>) -> Result<()> {
+    if command > 0x0F || command == 0 {
+        return Err(anyhow!("KISS command must be 1 to 15, not {}", command));
+    }
+    let sender = KISS_PORTS
+        .lock()
+        .unwrap()
+        .get(port)
+        .cloned()
+        .ok_or_else(|| anyhow!("no open KISS port {}", port))?;
+    sender
+        .try_send((command, data))
+        .map_err(|_| anyhow!("{} is busy, try again", port))
+}
+
+/// Registers a KISS port for commands while alive
+struct KissPort(String);
+
+impl KissPort {
+    fn register(name: &str) -> (Self, mpsc::Receiver<KissCommandFrame>) {
+        let (tx, rx) = mpsc::channel(8);
+        KISS_PORTS.lock().unwrap().insert(name.to_string(), tx);
+        (KissPort(name.to_string()), rx)
+    }
+}
+
+impl Drop for KissPort {
+    fn drop(&mut self) {
+        KISS_PORTS.lock().unwrap().remove(&self.0);
+    }
+}
 
 pub async fn run_serial_port(
     config: SerialPortConfig,
+    filter: Arc<PacketFilter>,
     packet_tx: mpsc::Sender<RoutedPacket>,
-    rf_rx: broadcast::Receiver<RoutedPacket>,
+    rf_rx: &mut mpsc::Receiver<RoutedPacket>,
+    shutdown: Shutdown,
 ) -> Result<()> {
     info!("Opening serial port {} on {}", config.name, config.device);
 
-    let port = SerialPort::open(&config.device, config.baud_rate).await?;
+    let commands = command_list(&config.init)
+        .map_err(|e| anyhow!("Bad init command for {}: {}", config.name, e))?;
+    let exit = command_list(&config.exit)
+        .map_err(|e| anyhow!("Bad exit command for {}: {}", config.name, e))?;
+    let delay = std::time::Duration::from_millis(config.init_delay as u64);
+
+    let mut port = SerialPort::open(&config.device, config.baud_rate).await?;
 
     info!("Serial port {} opened successfully", config.name);
 
-    match config.protocol {
-        SerialProtocol::Kiss => run_kiss_protocol(config, port, packet_tx, rf_rx).await,
-        SerialProtocol::Tnc2 => run_tnc2_protocol(config, port, packet_tx, rf_rx).await,
+    if !commands.is_empty() {
+        info!("Initializing TNC on {}", config.name);
+        send_commands(&mut port, &commands, delay).await?;
+        // Whatever the TNC said back isn't packets
+        port.discard_input()?;
+    }
+
+    let name = config.name.clone();
+    let charset = config.charset;
+    let result = match config.protocol {
+        SerialProtocol::Kiss | SerialProtocol::LoraKiss => {
+            let framing = KissFraming::new(&config).await?;
+            run_protocol(
+                config, filter, &mut port, packet_tx, rf_rx, &shutdown, framing,
+            )
+            .await
+        }
+        SerialProtocol::Tnc2 => {
+            let framing = Tnc2Framing {
+                line_buffer: Vec::new(),
+                charset,
+            };
+            run_protocol(
+                config, filter, &mut port, packet_tx, rf_rx, &shutdown, framing,
+            )
+            .await
+        }
+        SerialProtocol::Lora => {
+            let framing = LoraFraming {
+                line_buffer: Vec::new(),
+                charset,
+            };
+            run_protocol(
+                config, filter, &mut port, packet_tx, rf_rx, &shutdown, framing,
+            )
+            .await
+        }
+    };
+
+    if shutdown.is_triggered() && !exit.is_empty() {
+        info!("Restoring TNC on {}", name);
+        if let Err(e) = send_commands(&mut port, &exit, delay).await {
+            warn!("Failed to send exit commands to {}: {}", name, e);
+        }
     }
+    result
 }
 
-async fn run_kiss_protocol(
-    config: SerialPortConfig,
-    mut port: SerialPort,
-    packet_tx: mpsc::Sender<RoutedPacket>,
-    mut rf_rx: broadcast::Receiver<RoutedPacket>,
-) -> Result<()> {
-    let mut codec = KissCodec::new();
-    let mut read_buf = BytesMut::with_capacity(1024);
-    let mut temp_buf = [0u8; 256];
+/// How a protocol turns bytes from the port into packets, and packets
+/// into bytes, for the loop all ports share in `run_protocol`.
+trait Framing {
+    /// The packets completed by bytes just read from the port
+    async fn decode(&mut self, config: &SerialPortConfig, bytes: &[u8]) -> Result<Vec<AprsPacket>>;
 
-    loop {
-        tokio::select! {
-            // Handle incoming data from serial port
-            result = port.read(&mut temp_buf) => {
-                match result {
-                    Ok(n) if n > 0 => {
-                        read_buf.extend_from_slice(&temp_buf[..n]);
-
-                        while let Some(frame) = codec.decode(&mut read_buf)? {
-                            debug!("Received KISS frame: {} bytes", frame.len());
-
-                            if let Ok(ax25_frame) = ax25_to_aprs(&frame) {
-                                if let Ok(packet) = parse_packet(&ax25_frame) {
-                                    info!("RX [{}]: {}", config.name, packet);
-
-                                    if config.rx_enable {
-                                        let routed = RoutedPacket {
-                                            packet,
-                                            source: PacketSource::SerialPort(config.name.clone()),
-                                        };
-                                        let _ = packet_tx.send(routed).await;
-                                    }
-                                }
-                            }
-                        }
+    /// What to write to send a packet
+    fn encode(&self, packet: &AprsPacket) -> Result<Vec<u8>>;
+
+    /// Bytes to write straight out and what they are, for a log line.
+    /// Most protocols have none.
+    async fn command(&mut self) -> Option<(String, Vec<u8>)> {
+        std::future::pending().await
+    }
+}
+
+/// KISS frames holding AX.25, or LoRa APRS text with `lora_kiss`
+struct KissFraming {
+    codec: KissCodec,
+    read_buf: BytesMut,
+    lora: bool,
+    charset: Charset,
+    raw_tap: Option<File>,
+    stats: Arc<PortStats>,
+    commands: mpsc::Receiver<KissCommandFrame>,
+    _registered: KissPort,
+}
+
+impl KissFraming {
+    async fn new(config: &SerialPortConfig) -> Result<Self> {
+        let raw_tap = match &config.raw_tap {
+            Some(path) => Some(open_raw_tap(path).await?),
+            None => None,
+        };
+        let (registered, commands) = KissPort::register(&config.name);
+        Ok(KissFraming {
+            codec: KissCodec::new(),
+            read_buf: BytesMut::with_capacity(1024),
+            lora: matches!(config.protocol, SerialProtocol::LoraKiss),
+            charset: config.charset,
+            raw_tap,
+            stats: PortStats::for_port(&config.name),
+            commands,
+            _registered: registered,
+        })
+    }
+}
+
+impl Framing for KissFraming {
+    async fn decode(&mut self, config: &SerialPortConfig, bytes: &[u8]) -> Result<Vec<AprsPacket>> {
+        self.read_buf.extend_from_slice(bytes);
+        let mut packets = Vec::new();
+
+        while let Some(frame) = self.codec.decode(&mut self.read_buf)? {
+            debug!("Received KISS frame: {} bytes", frame.len());
+
+            let decoded = if lora::is_lora_frame(&frame) {
+                lora::decode(&frame, self.charset)
+                    .map(Ax25Frame::Aprs)
+                    .ok_or_else(|| anyhow!("Empty LoRa frame"))
+            } else {
+                decode_ax25(&frame, self.charset)
+            };
+            match decoded {
+                Ok(Ax25Frame::Aprs(ax25_frame)) => {
+                    if let Ok(packet) = parse_packet(&ax25_frame) {
+                        packets.push(packet);
                     }
-                    Ok(_) => {}
-                    Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {}
-                    Err(e) => {
-                        error!("Serial port read error: {}", e);
-                        return Err(e.into());
+                }
+                Ok(other) => {
+                    TELEMETRY_STATS
+                        .frames_non_aprs
+                        .fetch_add(1, Ordering::Relaxed);
+                    let line = other.to_string();
+                    debug!("RX non-APRS [{}]: {}", config.name, line);
+
+                    if let Some(tap) = self.raw_tap.as_mut() {
+                        let entry = format!(
+                            "{} [{}] {}\n",
+                            chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+                            config.name,
+                            line
+                        );
+                        if let Err(e) = tap.write_all(entry.as_bytes()).await {
+                            warn!("Failed to write raw tap for {}: {}", config.name, e);
+                        }
                     }
                 }
+                Err(e) => {
+                    TELEMETRY_STATS
+                        .frames_invalid
+                        .fetch_add(1, Ordering::Relaxed);
+                    self.stats.errors.fetch_add(1, Ordering::Relaxed);
+                    debug!("Invalid AX.25 frame on {}: {}", config.name, e);
+                }
             }
+        }
 
-            // Handle packets to transmit
-            Ok(routed) = rf_rx.recv() => {
-                if config.tx_enable {
-                    if let Ok(ax25_frame) = aprs_to_ax25(&routed.packet) {
-                        let kiss_frame = codec.encode(&ax25_frame, 0);
-                        if let Err(e) = port.write_all(&kiss_frame).await {
-                            error!("Failed to write to serial port: {}", e);
-                        } else {
-                            info!("TX [{}]: {}", config.name, routed.packet);
-                        }
+        for command in self.codec.take_commands() {
+            info!(
+                "KISS command {:#04x} from {}: {}",
+                command.command,
+                config.name,
+                String::from_utf8_lossy(&command.data)
+            );
+            self.stats.note_hardware(HardwareFrame {
+                received: chrono::Utc::now(),
+                command: command.command,
+                data: command.data,
+            });
+        }
+        Ok(packets)
+    }
+
+    fn encode(&self, packet: &AprsPacket) -> Result<Vec<u8>> {
+        let frame = if self.lora {
+            lora::encode(packet, self.charset)?
+        } else {
+            aprs_to_ax25(packet, self.charset)?
+        };
+        Ok(KissCodec::new().encode(&frame, 0))
+    }
+
+    // Command frames from the control socket go straight out
+    async fn command(&mut self) -> Option<(String, Vec<u8>)> {
+        let (command, data) = self.commands.recv().await?;
+        let frame = self.codec.encode_command(command, &data, 0);
+        Some((format!("KISS command {:#04x}", command), frame))
+    }
+}
+
+/// TNC2 monitor lines, one packet per line
+struct Tnc2Framing {
+    // Lines are split as bytes, so a character split between reads
+    // isn't lost
+    line_buffer: Vec<u8>,
+    charset: Charset,
+}
+
+impl Framing for Tnc2Framing {
+    async fn decode(
+        &mut self,
+        _config: &SerialPortConfig,
+        bytes: &[u8],
+    ) -> Result<Vec<AprsPacket>> {
+        self.line_buffer.extend_from_slice(bytes);
+        let mut packets = Vec::new();
+        while let Some(pos) = self.line_buffer.iter().position(|&b| b == b'\n') {
+            let text = self.charset.decode(&self.line_buffer[..pos]);
+            let line = text.trim_end_matches('\r');
+            if !line.is_empty() {
+                if let Ok(packet) = parse_packet(line) {
+                    packets.push(packet);
+                }
+            }
+            self.line_buffer.drain(..=pos);
+        }
+        Ok(packets)
+    }
+
+    fn encode(&self, packet: &AprsPacket) -> Result<Vec<u8>> {
+        tnc2_line(packet, self.charset)
+    }
+}
+
+/// LoRa APRS text packets, one per line after the `<\xff\x01` header
+struct LoraFraming {
+    // The LoRa header isn't UTF-8, so lines are split as bytes
+    line_buffer: Vec<u8>,
+    charset: Charset,
+}
+
+impl Framing for LoraFraming {
+    async fn decode(&mut self, config: &SerialPortConfig, bytes: &[u8]) -> Result<Vec<AprsPacket>> {
+        self.line_buffer.extend_from_slice(bytes);
+        let mut packets = Vec::new();
+        while let Some(pos) = self.line_buffer.iter().position(|&b| b == b'\n') {
+            if let Some(line) = lora::decode(&self.line_buffer[..pos], self.charset) {
+                match parse_packet(&line) {
+                    Ok(packet) => packets.push(packet),
+                    Err(_) => {
+                        TELEMETRY_STATS
+                            .frames_invalid
+                            .fetch_add(1, Ordering::Relaxed);
+                        count_error(&config.name);
+                        debug!("Invalid LoRa packet on {}: {}", config.name, line);
                     }
                 }
             }
+            self.line_buffer.drain(..=pos);
         }
+        Ok(packets)
+    }
+
+    fn encode(&self, packet: &AprsPacket) -> Result<Vec<u8>> {
+        lora_line(packet, self.charset)
     }
 }
 
-async fn run_tnc2_protocol(
+/// Read, queue and transmit on a port until shutdown, whatever its framing
+async fn run_protocol(
     config: SerialPortConfig,
-    mut port: SerialPort,
+    filter: Arc<PacketFilter>,
+    port: &mut SerialPort,
     packet_tx: mpsc::Sender<RoutedPacket>,
-    mut rf_rx: broadcast::Receiver<RoutedPacket>,
+    rf_rx: &mut mpsc::Receiver<RoutedPacket>,
+    shutdown: &Shutdown,
+    mut framing: impl Framing,
 ) -> Result<()> {
-    let mut line_buffer = String::new();
     let mut temp_buf = [0u8; 256];
+    let mut tx = TxScheduler::new(&config);
+    let mut access = ChannelAccess::new(&config);
 
     loop {
+        let next_tx = tx.next_slot();
         tokio::select! {
             // Handle incoming data from serial port
             result = port.read(&mut temp_buf) => {
                 match result {
                     Ok(n) if n > 0 => {
-                        let text = String::from_utf8_lossy(&temp_buf[..n]);
-                        line_buffer.push_str(&text);
-
-                        while let Some(pos) = line_buffer.find('\n') {
-                            let line = line_buffer[..pos].trim_end_matches('\r');
-
-                            if !line.is_empty() {
-                                if let Ok(packet) = parse_packet(line) {
-                                    info!("RX [{}]: {}", config.name, packet);
-
-                                    if config.rx_enable {
-                                        let routed = RoutedPacket {
-                                            packet,
-                                            source: PacketSource::SerialPort(config.name.clone()),
-                                        };
-                                        let _ = packet_tx.send(routed).await;
-                                    }
-                                }
-                            }
+                        access.heard(std::time::Instant::now());
 
-                            line_buffer.drain(..=pos);
+                        for packet in framing.decode(&config, &temp_buf[..n]).await? {
+                            info!("RX [{}]: {}", config.name, packet);
+                            capture::record(&config.name, Direction::Rx, &packet);
+
+                            if config.rx_enable {
+                                let routed = RoutedPacket {
+                                    packet,
+                                    source: PacketSource::SerialPort(config.name.clone()),
+                                };
+                                channel::send(&packet_tx, routed, channel::ROUTER, Overflow::Block).await;
+                            }
                         }
                     }
                     Ok(_) => {}
                     Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {}
                     Err(e) => {
                         error!("Serial port read error: {}", e);
+                        count_error(&config.name);
                         return Err(e.into());
                     }
                 }
             }
 
-            // Handle packets to transmit
-            Ok(routed) = rf_rx.recv() => {
-                if config.tx_enable {
-                    let tnc2_frame = format!("{}\r\n", routed.packet);
-                    if let Err(e) = port.write_all(tnc2_frame.as_bytes()).await {
+            // Queue packets to transmit
+            Some(routed) = rf_rx.recv() => {
+                enqueue(&config, &filter, &mut tx, routed, rf_rx);
+            }
+
+            Some((what, bytes)) = framing.command() => {
+                match port.write_all(&bytes).await {
+                    Ok(()) => info!("Sent {} to {}", what, config.name),
+                    Err(e) => {
                         error!("Failed to write to serial port: {}", e);
-                    } else {
-                        info!("TX [{}]: {}", config.name, routed.packet);
+                        count_error(&config.name);
+                    }
+                }
+            }
+
+            // Transmit the most important queued packet once the pacer allows
+            _ = sleep_until(next_tx), if next_tx.is_some() => {
+                if !clear_to_send(&access, port, &mut tx) {
+                    continue;
+                }
+                let Some(routed) = tx.pop() else { continue };
+                transmit(&config, port, &mut tx, routed, |p| framing.encode(p)).await;
+            }
+
+            _ = shutdown.wait() => {
+                flush(&config, &filter, port, &mut tx, rf_rx, |p| framing.encode(p)).await;
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn count_error(port: &str) {
+    PortStats::for_port(port)
+        .errors
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+fn tnc2_line(packet: &AprsPacket, charset: Charset) -> Result<Vec<u8>> {
+    packet.validate_ax25()?;
+    Ok(charset.encode(&format!("{}\r\n", packet)))
+}
+
+fn lora_line(packet: &AprsPacket, charset: Charset) -> Result<Vec<u8>> {
+    let mut line = lora::encode(packet, charset)?;
+    line.extend_from_slice(b"\r\n");
+    Ok(line)
+}
+
+/// Whether the channel is ours now. If not, the next packet is held back
+/// until it's worth trying again.
+fn clear_to_send(access: &ChannelAccess, port: &SerialPort, tx: &mut TxScheduler) -> bool {
+    let carrier = access.uses_carrier()
+        && port.carrier_detect().unwrap_or_else(|e| {
+            debug!("{}", e);
+            false
+        });
+    match access.defer(std::time::Instant::now(), carrier) {
+        Some(retry) => {
+            tx.hold(retry);
+            false
+        }
+        None => true,
+    }
+}
+
+/// Encode and write one packet
+async fn transmit(
+    config: &SerialPortConfig,
+    port: &mut SerialPort,
+    tx: &mut TxScheduler,
+    routed: RoutedPacket,
+    encode: impl Fn(&AprsPacket) -> Result<Vec<u8>>,
+) {
+    let frame = match encode(&routed.packet) {
+        Ok(frame) => frame,
+        Err(e) => {
+            warn!("Not transmitting {}: {}", routed.packet, e);
+            return;
+        }
+    };
+    if let Err(e) = port.write_all(&frame).await {
+        error!("Failed to write to serial port: {}", e);
+        count_error(&config.name);
+    } else {
+        info!("TX [{}]: {}", config.name, routed.packet);
+        capture::record(&config.name, Direction::Tx, &routed.packet);
+        tx.sent(&routed.packet);
+    }
+}
+
+/// On shutdown, take whatever the router still has for this port, which
+/// it hands over until it stops, and send it as the pacer allows. Anything
+/// not sent within FLUSH_TIME is dropped so the TNC still gets its exit
+/// commands.
+async fn flush(
+    config: &SerialPortConfig,
+    filter: &PacketFilter,
+    port: &mut SerialPort,
+    tx: &mut TxScheduler,
+    rf_rx: &mut mpsc::Receiver<RoutedPacket>,
+    encode: impl Fn(&AprsPacket) -> Result<Vec<u8>> + Copy,
+) {
+    let deadline = std::time::Instant::now() + FLUSH_TIME;
+    let _ = tokio::time::timeout(FLUSH_TIME, async {
+        while let Some(routed) = rf_rx.recv().await {
+            enqueue(config, filter, tx, routed, rf_rx);
+        }
+    })
+    .await;
+
+    while let Some(slot) = tx.next_slot().filter(|slot| *slot <= deadline) {
+        sleep_until(Some(slot)).await;
+        let Some(routed) = tx.pop() else { break };
+        transmit(config, port, tx, routed, encode).await;
+    }
+
+    let mut dropped = 0;
+    while tx.pop().is_some() {
+        dropped += 1;
+    }
+    if dropped > 0 {
+        warn!(
+            "Shutting down with {} packets unsent on {}",
+            dropped, config.name
+        );
+    }
+}
+
+/// Queue a packet for this port, along with anything else already waiting
+/// on the channel so a burst is sent in priority order.
+fn enqueue(
+    config: &SerialPortConfig,
+    filter: &PacketFilter,
+    tx: &mut TxScheduler,
+    routed: RoutedPacket,
+    rf_rx: &mut mpsc::Receiver<RoutedPacket>,
+) {
+    if !config.tx_enable {
+        return;
+    }
+
+    let mut next = Some(routed);
+    while let Some(routed) = next {
+        if filter.should_pass_outbound(&config.name, &routed.packet) {
+            if let Some(shed) = tx.push(routed) {
+                warn!("TX queue full on {}, dropped: {}", config.name, shed.packet);
+            }
+        }
+        next = rf_rx.try_recv().ok();
+    }
+}
+
+/// The bytes of a TNC command: `hex:` and hex bytes is sent exactly, as for
+/// a KISS frame; anything else is a line of text ended with a carriage return.
+fn command_bytes(command: &str) -> Result<Vec<u8>> {
+    match command.strip_prefix("hex:") {
+        Some(hex) => parse_hex(hex),
+        None => Ok(format!("{}\r", command).into_bytes()),
+    }
+}
+
+/// Bytes written as hex digits, spaces allowed between them
+pub fn parse_hex(hex: &str) -> Result<Vec<u8>> {
+    let digits: String = hex.chars().filter(|c| !c.is_whitespace()).collect();
+    if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!("invalid hex in {:?}", hex));
+    }
+    if !digits.len().is_multiple_of(2) {
+        return Err(anyhow!("odd number of hex digits in {:?}", hex));
+    }
+    Ok((0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap())
+        .collect())
+}
+
+fn command_list(commands: &[String]) -> Result<Vec<Vec<u8>>> {
+    commands
+        .iter()
+        .map(|command| command_bytes(command))
+        .collect()
+}
+
+async fn send_commands(
+    port: &mut SerialPort,
+    commands: &[Vec<u8>],
+    delay: std::time::Duration,
+) -> Result<()> {
+    for command in commands {
+        debug!(
+            "TNC command: {}",
+            String::from_utf8_lossy(command).trim_end()
+        );
+        port.write_all(command).await?;
+        tokio::time::sleep(delay).await;
+    }
+    Ok(())
+}
+
+async fn sleep_until(when: Option<std::time::Instant>) {
+    if let Some(when) = when {
+        tokio::time::sleep_until(when.into()).await;
+    }
+}
+
+async fn open_raw_tap(path: &str) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .map_err(|e| anyhow!("Failed to open raw tap {}: {}", path, e))
+}
+
+/// A received AX.25 frame, split into APRS traffic and everything else
+/// (connected-mode I/S/U frames, other layer 3 protocols).
+#[derive(Debug, PartialEq)]
+enum Ax25Frame {
+    /// A UI frame with no layer 3 protocol, in TNC2 text form
+    Aprs(String),
+    Other {
+        header: String,
+        control: u8,
+        pid: Option<u8>,
+        info: Vec<u8>,
+    },
+}
+
+impl Ax25Frame {
+    fn kind(control: u8) -> &'static str {
+        if control & 0x01 == 0 {
+            "I"
+        } else if control & 0x03 == 0x01 {
+            match control & 0x0F {
+                0x01 => "RR",
+                0x05 => "RNR",
+                0x09 => "REJ",
+                _ => "S",
+            }
+        } else {
+            match control & 0xEF {
+                0x03 => "UI",
+                0x2F => "SABM",
+                0x43 => "DISC",
+                0x0F => "DM",
+                0x63 => "UA",
+                0x87 => "FRMR",
+                _ => "U",
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Ax25Frame {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Ax25Frame::Aprs(text) => write!(f, "{}", text),
+            Ax25Frame::Other {
+                header,
+                control,
+                pid,
+                info,
+            } => {
+                write!(
+                    f,
+                    "{} <{} ctl=0x{:02X}",
+                    header,
+                    Self::kind(*control),
+                    control
+                )?;
+                if let Some(pid) = pid {
+                    write!(f, " pid=0x{:02X}", pid)?;
+                }
+                write!(f, " len={}>", info.len())?;
+                if !info.is_empty() {
+                    f.write_str(" ")?;
+                    for byte in info {
+                        write!(f, "{:02X}", byte)?;
                     }
                 }
+                Ok(())
             }
         }
     }
 }
 
+#[cfg(test)]
 fn ax25_to_aprs(frame: &[u8]) -> Result<String> {
-    if frame.len() < 16 {
+    match decode_ax25(frame, Charset::Utf8)? {
+        Ax25Frame::Aprs(text) => Ok(text),
+        other => Err(anyhow!("Not an APRS frame: {}", other)),
+    }
+}
+
+fn decode_ax25(frame: &[u8], charset: Charset) -> Result<Ax25Frame> {
+    if frame.len() < 15 {
         return Err(anyhow!("Frame too short"));
     }
 
@@ -176,23 +702,48 @@ fn ax25_to_aprs(frame: &[u8]) -> Result<String> {
     if last_bit == 0 {
         while i < frame.len() && (frame[i - 1] & 0x01) == 0 {
             if i + 7 > frame.len() {
-                break;
+                return Err(anyhow!("Truncated address field"));
             }
             result.push(',');
             let digi = decode_ax25_address(&frame[i..i + 7])?;
             result.push_str(&digi);
+            // The H bit marks a hop that has already repeated the frame
+            if frame[i + 6] & 0x80 != 0 {
+                result.push('*');
+            }
             i += 7;
         }
     }
 
-    // Check for control and PID fields
-    if i + 2 <= frame.len() && frame[i] == 0x03 && frame[i + 1] == 0xF0 {
-        i += 2;
+    let Some(&control) = frame.get(i) else {
+        return Err(anyhow!("Missing control field"));
+    };
+    i += 1;
+
+    // Only I and UI frames carry a PID
+    let pid = if control & 0x01 == 0 || control & 0xEF == 0x03 {
+        let pid = frame
+            .get(i)
+            .copied()
+            .ok_or_else(|| anyhow!("Missing PID field"))?;
+        i += 1;
+        Some(pid)
+    } else {
+        None
+    };
+
+    if control == 0x03 && pid == Some(0xF0) {
         result.push(':');
-        result.push_str(&String::from_utf8_lossy(&frame[i..]));
+        result.push_str(&charset.decode(&frame[i..]));
+        return Ok(Ax25Frame::Aprs(result));
     }
 
-    Ok(result)
+    Ok(Ax25Frame::Other {
+        header: result,
+        control,
+        pid,
+        info: frame[i..].to_vec(),
+    })
 }
 
 fn decode_ax25_address(data: &[u8]) -> Result<String> {
@@ -203,10 +754,17 @@ fn decode_ax25_address(data: &[u8]) -> Result<String> {
     let mut call = String::new();
     for &byte in data.iter().take(6) {
         let c = (byte >> 1) as char;
-        if c != ' ' {
-            call.push(c);
+        match c {
+            ' ' => {}
+            'A'..='Z' | '0'..='9' => call.push(c),
+            // Anything else could end the header early once the frame is
+            // written out as text
+            _ => return Err(anyhow!("Invalid character {:?} in AX.25 address", c)),
         }
     }
+    if call.is_empty() {
+        return Err(anyhow!("Empty AX.25 address"));
+    }
 
     let ssid = (data[6] >> 1) & 0x0F;
     if ssid > 0 {
@@ -216,7 +774,9 @@ fn decode_ax25_address(data: &[u8]) -> Result<String> {
     Ok(call)
 }
 
-fn aprs_to_ax25(packet: &AprsPacket) -> Result<Vec<u8>> {
+fn aprs_to_ax25(packet: &AprsPacket, charset: Charset) -> Result<Vec<u8>> {
+    packet.validate_ax25()?;
+
     let mut frame = Vec::new();
 
     // Encode destination
@@ -237,7 +797,7 @@ fn aprs_to_ax25(packet: &AprsPacket) -> Result<Vec<u8>> {
     frame.push(0xF0); // No layer 3 protocol
 
     // Add information field
-    frame.extend_from_slice(packet.information.as_bytes());
+    frame.extend_from_slice(&charset.encode(&packet.information));
 
     Ok(frame)
 }
@@ -247,16 +807,21 @@ fn encode_ax25_address(
     last: bool,
     frame: &mut Vec<u8>,
 ) -> Result<()> {
+    call.validate_ax25()?;
+
     let mut addr = [0x20u8 << 1; 7]; // Space-filled (0x20 shifted left = 0x40)
 
     // Encode callsign
     let call_bytes = call.call.as_bytes();
-    for (i, &b) in call_bytes.iter().take(6).enumerate() {
+    for (i, &b) in call_bytes.iter().enumerate() {
         addr[i] = b << 1;
     }
 
-    // Encode SSID
+    // Encode SSID, with the H bit for hops that have repeated the frame
     addr[6] = (call.ssid.0 << 1) | 0x60;
+    if call.digipeated {
+        addr[6] |= 0x80;
+    }
 
     // Set end-of-address bit if this is the last address
     if last {
@@ -272,6 +837,19 @@ mod tests {
     use super::*;
     use crate::aprs::CallSign;
 
+    #[test]
+    fn test_command_bytes() {
+        assert_eq!(command_bytes("KISS ON").unwrap(), b"KISS ON\r");
+        assert_eq!(command_bytes("").unwrap(), b"\r");
+        assert_eq!(
+            command_bytes("hex:C0 FF C0").unwrap(),
+            vec![0xC0, 0xFF, 0xC0]
+        );
+        assert_eq!(command_bytes("hex:1b40").unwrap(), vec![0x1B, 0x40]);
+        assert!(command_bytes("hex:C0F").is_err());
+        assert!(command_bytes("hex:ZZ").is_err());
+    }
+
     #[test]
     fn test_decode_ax25_address() {
         // Simple callsign
@@ -291,6 +869,11 @@ mod tests {
 
         // Invalid length
         assert!(decode_ax25_address(&[0x00; 6]).is_err());
+
+        // Only letters and digits, which can't break up a TNC2 header
+        let data = [0x9C, 0x74, 0x86, 0x82, 0x98, 0x98, 0x60]; // N:CALL
+        assert!(decode_ax25_address(&data).is_err());
+        assert!(decode_ax25_address(&[0x40; 7]).is_err());
     }
 
     #[test]
@@ -397,7 +980,7 @@ mod tests {
             ">Test".to_string(),
         );
 
-        let frame = aprs_to_ax25(&packet).unwrap();
+        let frame = aprs_to_ax25(&packet, Charset::Utf8).unwrap();
 
         // Check destination
         assert_eq!(&frame[0..7], &[0x82, 0xA0, 0xA4, 0xA6, 0x40, 0x40, 0x60]);
@@ -419,7 +1002,7 @@ mod tests {
         packet.path.push(CallSign::new("WIDE1", 1));
         packet.path.push(CallSign::new("WIDE2", 2));
 
-        let frame = aprs_to_ax25(&packet).unwrap();
+        let frame = aprs_to_ax25(&packet, Charset::Utf8).unwrap();
 
         // Check addresses count (dest + src + 2 digis)
         assert!(frame.len() >= 28); // 7*4 addresses
@@ -427,4 +1010,165 @@ mod tests {
         // Check last address bit is set on last digi
         assert_eq!(frame[27] & 0x01, 0x01);
     }
+
+    #[test]
+    fn test_aprs_to_ax25_rejects_invalid_addresses() {
+        let mut packet = AprsPacket::new(
+            CallSign::new("TEST", 0),
+            CallSign::new("APRS", 0),
+            "!".to_string(),
+        );
+        for _ in 0..9 {
+            packet.path.push(CallSign::new("WIDE1", 1));
+        }
+        assert!(aprs_to_ax25(&packet, Charset::Utf8).is_err());
+
+        packet.path.clear();
+        packet.source = CallSign::new("TOOLONG", 0);
+        assert!(aprs_to_ax25(&packet, Charset::Utf8).is_err());
+    }
+
+    #[test]
+    fn test_decode_non_aprs_frames() {
+        let mut frame = vec![
+            0x82, 0xA0, 0xA4, 0xA6, 0x40, 0x40, 0x60, // APRS
+            0x9C, 0x60, 0x86, 0x82, 0x98, 0x98, 0x6B, // N0CALL-5
+        ];
+
+        // SABM carries no PID or information
+        let mut sabm = frame.clone();
+        sabm.push(0x3F);
+        let decoded = decode_ax25(&sabm, Charset::Utf8).unwrap();
+        assert_eq!(decoded.to_string(), "N0CALL-5>APRS <SABM ctl=0x3F len=0>");
+        assert!(ax25_to_aprs(&sabm).is_err());
+
+        // I frame with NET/ROM PID
+        frame.extend_from_slice(&[0x00, 0xCF, 0x01, 0x02]);
+        match decode_ax25(&frame, Charset::Utf8).unwrap() {
+            Ax25Frame::Other {
+                control, pid, info, ..
+            } => {
+                assert_eq!(control, 0x00);
+                assert_eq!(pid, Some(0xCF));
+                assert_eq!(info, vec![0x01, 0x02]);
+            }
+            other => panic!("unexpected frame {:?}", other),
+        }
+        assert_eq!(
+            decode_ax25(&frame, Charset::Utf8).unwrap().to_string(),
+            "N0CALL-5>APRS <I ctl=0x00 pid=0xCF len=2> 0102"
+        );
+
+        // Missing control field
+        assert!(decode_ax25(&frame[..14], Charset::Utf8).is_err());
+    }
+
+    #[test]
+    fn test_ax25_charset() {
+        let packet = parse_packet("N0CALL>APRS:>Grüße").unwrap();
+        let frame = aprs_to_ax25(&packet, Charset::Latin1).unwrap();
+        assert_eq!(&frame[frame.len() - 6..], b">Gr\xFC\xDFe");
+        match decode_ax25(&frame, Charset::Latin1).unwrap() {
+            Ax25Frame::Aprs(text) => assert_eq!(text, "N0CALL>APRS:>Grüße"),
+            other => panic!("Not an APRS frame: {}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_line_framing() {
+        let config: SerialPortConfig =
+            toml::from_str("name = \"framing-test\"\ndevice = \"/dev/null\"").unwrap();
+        let mut tnc2 = Tnc2Framing {
+            line_buffer: Vec::new(),
+            charset: Charset::Utf8,
+        };
+        // A line split between reads, and a character split with it
+        let line = "N1CALL>APRS:>Grüße\r\n".as_bytes();
+        assert!(tnc2.decode(&config, &line[..16]).await.unwrap().is_empty());
+        let packets = tnc2.decode(&config, &line[16..]).await.unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].information, ">Grüße");
+
+        let mut lora = LoraFraming {
+            line_buffer: Vec::new(),
+            charset: Charset::Utf8,
+        };
+        let errors = PortStats::for_port("framing-test");
+        let packets = lora
+            .decode(&config, b"<\xff\x01N1CALL>APRS:>Hi\n<\xff\x01garbage\n")
+            .await
+            .unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(errors.errors.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            lora.encode(&packets[0]).unwrap(),
+            b"<\xff\x01N1CALL>APRS:>Hi\r\n"
+        );
+    }
+
+    #[test]
+    fn test_ax25_h_bit_round_trip() {
+        let mut packet = AprsPacket::new(
+            CallSign::new("TEST", 0),
+            CallSign::new("APRS", 0),
+            "!".to_string(),
+        );
+        packet.path.push(CallSign::parse("N0CALL-10*").unwrap());
+        packet.path.push(CallSign::new("WIDE2", 1));
+
+        let frame = aprs_to_ax25(&packet, Charset::Utf8).unwrap();
+        assert_eq!(frame[20] & 0x80, 0x80);
+        assert_eq!(frame[27] & 0x80, 0x00);
+
+        let text = ax25_to_aprs(&frame).unwrap();
+        assert_eq!(text, "TEST>APRS,N0CALL-10*,WIDE2-1:!");
+
+        let parsed = parse_packet(&text).unwrap();
+        assert!(parsed.path[0].digipeated);
+        assert!(!parsed.path[1].digipeated);
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn fuzz_decode_ax25(
+            addresses in proptest::collection::vec(proptest::collection::vec(proptest::prelude::any::<u8>(), 7), 2..6),
+            info in proptest::collection::vec(proptest::prelude::any::<u8>(), 0..80),
+        ) {
+            // Any address bytes, in a UI frame carrying APRS
+            let mut frame = Vec::new();
+            for (i, address) in addresses.iter().enumerate() {
+                frame.extend(&address[..6]);
+                let last = i == addresses.len() - 1;
+                frame.push(address[6] & 0xFE | last as u8);
+            }
+            frame.extend([0x03, 0xF0]);
+            frame.extend(&info);
+            if let Ok(Ax25Frame::Aprs(text)) = decode_ax25(&frame, Charset::Utf8) {
+                // Whatever the addresses held, the header reads back as
+                // the addresses and nothing leaks into the payload
+                let packet = parse_packet(&text).unwrap();
+                let source = decode_ax25_address(&frame[7..14]).unwrap();
+                proptest::prop_assert_eq!(packet.source.to_string(), source);
+            }
+        }
+
+        #[test]
+        fn fuzz_ax25_round_trip(
+            source in "[A-Z0-9]{1,6}(-[1-9]|-1[0-5])?",
+            dest in "[A-Z0-9]{1,6}",
+            path in proptest::collection::vec("[A-Z0-9]{1,6}(-[1-7])?\\*?", 0..4),
+            info in "[ -~]{0,60}",
+        ) {
+            let mut text = format!("{}>{}", source, dest);
+            for hop in &path {
+                text.push(',');
+                text.push_str(hop);
+            }
+            text.push(':');
+            text.push_str(&info);
+            let packet = parse_packet(&text).unwrap();
+            let frame = aprs_to_ax25(&packet, Charset::Utf8).unwrap();
+            proptest::prop_assert_eq!(ax25_to_aprs(&frame).unwrap(), packet.to_string());
+        }
+    }
 }
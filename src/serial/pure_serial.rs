@@ -2,19 +2,59 @@ use std::fs::{File, OpenOptions};
 use std::io::{self, Read, Write};
 use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::task;
 
 use anyhow::{Error, Result};
 
+use super::remote::{self, RemoteSerial};
+
 pub struct SerialPort {
-    file: File,
+    io: Io,
+    // Kept only to clean up on drop
+    _pty: Option<Pty>,
+}
+
+enum Io {
+    Device(File),
+    Remote(RemoteSerial),
+}
+
+/// The far end of a virtual port, held open so the port keeps working
+/// while nothing is attached
+struct Pty {
+    _slave: File,
+    link: Option<PathBuf>,
+}
+
+impl Drop for Pty {
+    fn drop(&mut self) {
+        if let Some(link) = &self.link {
+            let _ = std::fs::remove_file(link);
+        }
+    }
 }
 
 impl SerialPort {
+    /// Open a serial device, or with "pty" or "pty:PATH" a virtual port
+    /// whose other end, linked at PATH, can be driven like a TNC. A
+    /// tcp:// or rfc2217:// URL opens a port served over the network.
     pub async fn open(path: &str, baud_rate: u32) -> Result<Self, Error> {
+        if remote::is_remote(path) {
+            return Ok(SerialPort {
+                io: Io::Remote(RemoteSerial::connect(path, baud_rate).await?),
+                _pty: None,
+            });
+        }
+        if path == "pty" {
+            return Self::open_pty(None);
+        }
+        if let Some(link) = path.strip_prefix("pty:") {
+            return Self::open_pty(Some(Path::new(link)));
+        }
+
         let path = Path::new(path);
 
         // Open serial port with O_NOCTTY to prevent it from becoming controlling terminal
@@ -32,7 +72,89 @@ impl SerialPort {
             .await
             .map_err(|e| Error::msg(format!("Failed to configure serial port: {}", e)))??;
 
-        Ok(SerialPort { file })
+        Ok(SerialPort {
+            io: Io::Device(file),
+            _pty: None,
+        })
+    }
+
+    fn open_pty(link: Option<&Path>) -> Result<Self> {
+        let pty = nix::pty::openpty(None, None)
+            .map_err(|e| Error::msg(format!("Failed to open a PTY: {}", e)))?;
+        let master = File::from(pty.master);
+        let slave = File::from(pty.slave);
+        configure_serial_port(slave.as_raw_fd(), 9600)?;
+        // Whatever opens the other end should wait for data like it would
+        // on a real port, not read nothing straight away
+        let mut termios = nix::sys::termios::tcgetattr(&slave)
+            .map_err(|e| Error::msg(format!("Failed to set up PTY: {}", e)))?;
+        termios.control_chars[nix::sys::termios::SpecialCharacterIndices::VMIN as usize] = 1;
+        nix::sys::termios::tcsetattr(&slave, nix::sys::termios::SetArg::TCSANOW, &termios)
+            .map_err(|e| Error::msg(format!("Failed to set up PTY: {}", e)))?;
+        unsafe {
+            let flags = libc::fcntl(master.as_raw_fd(), libc::F_GETFL);
+            if flags < 0
+                || libc::fcntl(master.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) != 0
+            {
+                return Err(Error::msg(format!(
+                    "Failed to set up PTY: {}",
+                    std::io::Error::last_os_error()
+                )));
+            }
+        }
+
+        let name = std::fs::read_link(format!("/proc/self/fd/{}", slave.as_raw_fd()))
+            .map_err(|e| Error::msg(format!("Failed to name PTY: {}", e)))?;
+        if let Some(link) = link {
+            // Replace the link from an earlier run
+            if link.is_symlink() {
+                std::fs::remove_file(link)?;
+            }
+            std::os::unix::fs::symlink(&name, link)
+                .map_err(|e| Error::msg(format!("Failed to link {}: {}", link.display(), e)))?;
+            log::info!("Virtual port {} at {}", name.display(), link.display());
+        } else {
+            log::info!("Virtual port at {}", name.display());
+        }
+
+        Ok(SerialPort {
+            io: Io::Device(master),
+            _pty: Some(Pty {
+                _slave: slave,
+                link: link.map(Path::to_path_buf),
+            }),
+        })
+    }
+
+    /// Whether the carrier detect line is asserted
+    pub fn carrier_detect(&self) -> Result<bool> {
+        let file = match &self.io {
+            Io::Device(file) => file,
+            Io::Remote(remote) => return remote.carrier_detect(),
+        };
+        let mut lines: libc::c_int = 0;
+        if unsafe { libc::ioctl(file.as_raw_fd(), libc::TIOCMGET, &mut lines) } != 0 {
+            return Err(Error::msg(format!(
+                "Failed to read modem lines: {}",
+                std::io::Error::last_os_error()
+            )));
+        }
+        Ok(lines & libc::TIOCM_CAR != 0)
+    }
+
+    /// Throw away anything received but not yet read
+    pub fn discard_input(&mut self) -> Result<()> {
+        let file = match &mut self.io {
+            Io::Device(file) => file,
+            Io::Remote(remote) => return remote.discard_input(),
+        };
+        if unsafe { libc::tcflush(file.as_raw_fd(), libc::TCIFLUSH) } != 0 {
+            return Err(Error::msg(format!(
+                "Failed to flush input: {}",
+                std::io::Error::last_os_error()
+            )));
+        }
+        Ok(())
     }
 }
 
@@ -122,17 +244,26 @@ fn configure_serial_port(fd: RawFd, baud_rate: u32) -> Result<()> {
 
 impl Read for SerialPort {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.file.read(buf)
+        match &mut self.io {
+            Io::Device(file) => file.read(buf),
+            Io::Remote(remote) => remote.try_read(buf),
+        }
     }
 }
 
 impl Write for SerialPort {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.file.write(buf)
+        match &mut self.io {
+            Io::Device(file) => file.write(buf),
+            Io::Remote(remote) => remote.try_write(buf),
+        }
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.file.flush()
+        match &mut self.io {
+            Io::Device(file) => file.flush(),
+            Io::Remote(_) => Ok(()),
+        }
     }
 }
 
@@ -142,8 +273,12 @@ impl AsyncRead for SerialPort {
         cx: &mut std::task::Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> std::task::Poll<io::Result<()>> {
+        let file = match &mut self.io {
+            Io::Device(file) => file,
+            Io::Remote(remote) => return std::pin::Pin::new(remote).poll_read(cx, buf),
+        };
         let mut temp_buf = vec![0u8; buf.remaining()];
-        match self.file.read(&mut temp_buf) {
+        match file.read(&mut temp_buf) {
             Ok(n) => {
                 buf.put_slice(&temp_buf[..n]);
                 std::task::Poll::Ready(Ok(()))
@@ -163,7 +298,11 @@ impl AsyncWrite for SerialPort {
         cx: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> std::task::Poll<io::Result<usize>> {
-        match self.file.write(buf) {
+        let file = match &mut self.io {
+            Io::Device(file) => file,
+            Io::Remote(remote) => return std::pin::Pin::new(remote).poll_write(cx, buf),
+        };
+        match file.write(buf) {
             Ok(n) => std::task::Poll::Ready(Ok(n)),
             Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                 cx.waker().wake_by_ref();
@@ -177,7 +316,11 @@ impl AsyncWrite for SerialPort {
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<io::Result<()>> {
-        match self.file.flush() {
+        let file = match &mut self.io {
+            Io::Device(file) => file,
+            Io::Remote(remote) => return std::pin::Pin::new(remote).poll_flush(cx),
+        };
+        match file.flush() {
             Ok(()) => std::task::Poll::Ready(Ok(())),
             Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                 cx.waker().wake_by_ref();
@@ -188,9 +331,12 @@ impl AsyncWrite for SerialPort {
     }
 
     fn poll_shutdown(
-        self: std::pin::Pin<&mut Self>,
-        _cx: &mut std::task::Context<'_>,
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<io::Result<()>> {
-        std::task::Poll::Ready(Ok(()))
+        match &mut self.io {
+            Io::Device(_) => std::task::Poll::Ready(Ok(())),
+            Io::Remote(remote) => std::pin::Pin::new(remote).poll_shutdown(cx),
+        }
     }
 }
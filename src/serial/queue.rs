@@ -0,0 +1,677 @@
+//! Per-port transmit queue. Packets wait here between the router and the
+//! serial port so messages and acks go out ahead of our own beacons and
+//! telemetry, and the least important traffic is shed first when full.
+//! A pacer holds packets back to keep each port within its configured
+//! packet rate and airtime budget, and a random jitter keeps us from
+//! keying up in lockstep with neighbouring digis repeating the same packet.
+//! Before each transmission, channel access waits for a clear channel and
+//! takes its turn p-persistently, as a TNC's CSMA would.
+
+use crate::aprs::AprsPacket;
+use crate::channel;
+use crate::config::{DcdSource, OverflowPolicy, SerialPortConfig};
+use crate::router::{PacketSource, RoutedPacket};
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long the channel counts as busy after the TNC hands us a frame
+const RX_BUSY: Duration = Duration::from_millis(500);
+
+/// Window over which packet rate and duty cycle are measured
+const PACING_WINDOW: Duration = Duration::from_secs(60);
+
+/// Allowance for PTT, TXDELAY and the tail on every transmission
+const KEYUP_OVERHEAD: Duration = Duration::from_millis(250);
+
+lazy_static! {
+    static ref PORT_STATS: Mutex<HashMap<String, Arc<PortStats>>> = Mutex::new(HashMap::new());
+}
+
+/// Transmit statistics for one port, shared with the control socket.
+#[derive(Debug, Default)]
+pub struct PortStats {
+    pub sent: AtomicU64,
+    pub shed: AtomicU64,
+    pub deferred: AtomicU64, // Packets that had to wait for the pacer
+    pub airtime_ms: AtomicU64,
+    pub errors: AtomicU64, // Read and write failures and frames that didn't decode
+    pub queued: Mutex<Vec<QueuedPacket>>, // Waiting to transmit, in send order
+    pub hardware: Mutex<VecDeque<HardwareFrame>>, // Latest KISS command frames from the TNC
+}
+
+/// KISS command frames kept per port
+const HARDWARE_FRAMES: usize = 20;
+
+impl PortStats {
+    /// Packets waiting to transmit
+    pub fn depth(&self) -> usize {
+        self.queued.lock().unwrap().len()
+    }
+
+    /// How long the longest-waiting packet has been around
+    pub fn oldest_age(&self, now: DateTime<Utc>) -> Option<chrono::Duration> {
+        channel::oldest_age(self.queued.lock().unwrap().iter().map(|q| q.received), now)
+    }
+
+    /// Keep a command frame the TNC sent, dropping the oldest
+    pub fn note_hardware(&self, frame: HardwareFrame) {
+        let mut frames = self.hardware.lock().unwrap();
+        if frames.len() >= HARDWARE_FRAMES {
+            frames.pop_front();
+        }
+        frames.push_back(frame);
+    }
+
+    /// Stats for the named port, created on first use
+    pub fn for_port(name: &str) -> Arc<PortStats> {
+        PORT_STATS
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Every port's stats, by name
+    pub fn all() -> Vec<(String, Arc<PortStats>)> {
+        let mut all: Vec<_> = PORT_STATS
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, stats)| (name.clone(), stats.clone()))
+            .collect();
+        all.sort_by(|a, b| a.0.cmp(&b.0));
+        all
+    }
+}
+
+/// A packet waiting in a port's transmit queue, as shown on the control
+/// socket.
+#[derive(Debug, Clone)]
+pub struct QueuedPacket {
+    pub packet: String,
+    pub priority: Priority,
+    pub received: DateTime<Utc>,
+}
+
+/// A KISS frame on a command code other than data, as the TNC sent it
+#[derive(Debug, Clone)]
+pub struct HardwareFrame {
+    pub received: DateTime<Utc>,
+    pub command: u8,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,    // Our own beacons, status and telemetry
+    Normal, // Digipeats and gated traffic
+    High,   // Messages and acks
+}
+
+impl Priority {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+
+    pub fn of(routed: &RoutedPacket) -> Self {
+        let packet = &routed.packet;
+        if packet.message().is_some() {
+            return Priority::High;
+        }
+
+        if routed.source == PacketSource::Internal {
+            return Priority::Low;
+        }
+
+        Priority::Normal
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+pub struct TxQueue {
+    queues: [VecDeque<RoutedPacket>; 3],
+    capacity: usize,
+    policy: OverflowPolicy,
+    shed: u64,
+}
+
+impl TxQueue {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        TxQueue {
+            queues: Default::default(),
+            capacity: capacity.max(1),
+            policy,
+            shed: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.iter().all(VecDeque::is_empty)
+    }
+
+    /// Packets dropped so far to make room
+    pub fn shed(&self) -> u64 {
+        self.shed
+    }
+
+    /// Queue a packet, making room according to the overflow policy when
+    /// full. Returns the packet that was shed, if any.
+    pub fn push(&mut self, routed: RoutedPacket) -> Option<RoutedPacket> {
+        let priority = Priority::of(&routed);
+
+        if self.len() >= self.capacity {
+            self.shed += 1;
+            let victim = match self.policy {
+                // The oldest packet of the lowest priority no higher than
+                // this one, failing that this one
+                OverflowPolicy::ShedLowest => self.queues[..=priority.index()]
+                    .iter_mut()
+                    .find(|q| !q.is_empty())
+                    .and_then(VecDeque::pop_front),
+                OverflowPolicy::DropOldest => self
+                    .queues
+                    .iter_mut()
+                    .filter_map(|q| q.front().map(|r| r.packet.timestamp).zip(Some(q)))
+                    .min_by_key(|(received, _)| *received)
+                    .and_then(|(_, q)| q.pop_front()),
+                OverflowPolicy::DropNewest => None,
+            };
+            match victim {
+                Some(victim) => {
+                    self.queues[priority.index()].push_back(routed);
+                    return Some(victim);
+                }
+                None => return Some(routed),
+            }
+        }
+
+        self.queues[priority.index()].push_back(routed);
+        None
+    }
+
+    /// Queued packets in the order they'd be sent
+    pub fn iter(&self) -> impl Iterator<Item = &RoutedPacket> {
+        self.queues.iter().rev().flatten()
+    }
+
+    /// The packet `pop` would return
+    pub fn peek(&self) -> Option<&RoutedPacket> {
+        self.queues.iter().rev().find_map(VecDeque::front)
+    }
+
+    /// Next packet to transmit: highest priority first, oldest first
+    pub fn pop(&mut self) -> Option<RoutedPacket> {
+        self.queues.iter_mut().rev().find_map(VecDeque::pop_front)
+    }
+}
+
+/// Rough time on air for a packet: the AX.25 frame plus FCS and flags at
+/// the radio's bit rate, with some margin for bit stuffing.
+pub fn airtime(packet: &AprsPacket, air_baud: u32) -> Duration {
+    let frame_len = 7 * (2 + packet.path.len()) + 2 + packet.information.len() + 4;
+    let bits = frame_len as f64 * 8.0 * 1.05;
+    KEYUP_OVERHEAD + Duration::from_secs_f64(bits / air_baud.max(1) as f64)
+}
+
+/// Keeps a port under a packets-per-minute limit and an airtime budget.
+pub struct TxPacer {
+    per_minute: Option<u32>,
+    budget: Option<Duration>,
+    history: VecDeque<(Instant, Duration)>,
+}
+
+impl TxPacer {
+    /// `duty_cycle` is the percentage of each minute we may be keyed up
+    pub fn new(per_minute: Option<u32>, duty_cycle: Option<f64>) -> Self {
+        TxPacer {
+            per_minute,
+            budget: duty_cycle.map(|pct| PACING_WINDOW.mul_f64((pct / 100.0).clamp(0.0, 1.0))),
+            history: VecDeque::new(),
+        }
+    }
+
+    /// Earliest time a packet needing `airtime` may go out
+    pub fn ready_at(&mut self, now: Instant, airtime: Duration) -> Instant {
+        self.history
+            .retain(|(sent, _)| now.saturating_duration_since(*sent) < PACING_WINDOW);
+
+        let mut ready = now;
+
+        if let Some(limit) = self.per_minute {
+            let limit = limit.max(1) as usize;
+            if self.history.len() >= limit {
+                let (sent, _) = self.history[self.history.len() - limit];
+                ready = ready.max(sent + PACING_WINDOW);
+            }
+        }
+
+        if let Some(budget) = self.budget {
+            // Wait for enough old transmissions to age out of the window
+            let mut used: Duration = self.history.iter().map(|(_, a)| *a).sum();
+            let airtime = airtime.min(budget);
+            for (sent, spent) in &self.history {
+                if used + airtime <= budget {
+                    break;
+                }
+                used = used.saturating_sub(*spent);
+                ready = ready.max(*sent + PACING_WINDOW);
+            }
+        }
+
+        ready
+    }
+
+    pub fn record(&mut self, sent: Instant, airtime: Duration) {
+        self.history.push_back((sent, airtime));
+    }
+}
+
+/// A random duration up to `max`
+pub(crate) fn random_up_to(max: Duration) -> Duration {
+    // A fresh RandomState is randomly keyed, which is plenty for spreading
+    // out transmissions
+    let random = RandomState::new().build_hasher().finish();
+    Duration::from_nanos(random % (max.as_nanos() as u64 + 1))
+}
+
+/// A port's queue and pacer together, as the serial loop drives them.
+pub struct TxScheduler {
+    queue: TxQueue,
+    pacer: TxPacer,
+    stats: Arc<PortStats>,
+    air_baud: u32,
+    head_deferred: bool,
+    digipeat_jitter: Duration,
+    beacon_jitter: Duration,
+    head_not_before: Option<Instant>, // The head packet's jitter, once drawn
+}
+
+impl TxScheduler {
+    pub fn new(config: &SerialPortConfig) -> Self {
+        TxScheduler {
+            queue: TxQueue::new(config.tx_queue, config.tx_overflow),
+            pacer: TxPacer::new(config.tx_per_minute, config.duty_cycle),
+            stats: PortStats::for_port(&config.name),
+            air_baud: config.air_baud,
+            head_deferred: false,
+            digipeat_jitter: Duration::from_millis(config.digipeat_jitter as u64),
+            beacon_jitter: Duration::from_millis(config.beacon_jitter as u64),
+            head_not_before: None,
+        }
+    }
+
+    /// Queue a packet, returning whatever was shed to make room
+    pub fn push(&mut self, routed: RoutedPacket) -> Option<RoutedPacket> {
+        let shed = self.queue.push(routed);
+        if shed.is_some() {
+            self.stats.shed.fetch_add(1, Ordering::Relaxed);
+        }
+        self.publish();
+        shed
+    }
+
+    /// When the next queued packet may be sent, or None if nothing waits
+    pub fn next_slot(&mut self) -> Option<Instant> {
+        let head = self.queue.peek()?;
+        let now = Instant::now();
+        if self.head_not_before.is_none() {
+            let max = match head.source {
+                PacketSource::Internal => self.beacon_jitter,
+                _ => self.digipeat_jitter,
+            };
+            self.head_not_before = Some(now + random_up_to(max));
+        }
+        let ready = self
+            .pacer
+            .ready_at(now, airtime(&head.packet, self.air_baud));
+        if ready > now && !self.head_deferred {
+            self.head_deferred = true;
+            self.stats.deferred.fetch_add(1, Ordering::Relaxed);
+        }
+        Some(ready.max(self.head_not_before.unwrap_or(now)))
+    }
+
+    /// Keep the next packet back until `until`
+    pub fn hold(&mut self, until: Instant) {
+        self.head_not_before = Some(until);
+    }
+
+    pub fn pop(&mut self) -> Option<RoutedPacket> {
+        self.head_deferred = false;
+        self.head_not_before = None;
+        let routed = self.queue.pop();
+        self.publish();
+        routed
+    }
+
+    /// Copy the queue's contents to the shared stats
+    fn publish(&self) {
+        *self.stats.queued.lock().unwrap() = self
+            .queue
+            .iter()
+            .map(|routed| QueuedPacket {
+                packet: routed.packet.to_string(),
+                priority: Priority::of(routed),
+                received: routed.packet.timestamp,
+            })
+            .collect();
+    }
+
+    /// Charge a transmitted packet to the budget and stats
+    pub fn sent(&mut self, packet: &AprsPacket) {
+        let airtime = airtime(packet, self.air_baud);
+        self.pacer.record(Instant::now(), airtime);
+        self.stats.sent.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .airtime_ms
+            .fetch_add(airtime.as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+/// p-persistent CSMA. With the channel clear, each slot we transmit with a
+/// chance of (persist + 1) / 256, otherwise wait for the next slot.
+pub struct ChannelAccess {
+    dcd: DcdSource,
+    persist: Option<u8>,
+    slot_time: Duration,
+    last_heard: Option<Instant>,
+}
+
+impl ChannelAccess {
+    pub fn new(config: &SerialPortConfig) -> Self {
+        ChannelAccess {
+            dcd: config.dcd,
+            persist: config.persist,
+            slot_time: Duration::from_millis(config.slot_time.max(1) as u64),
+            last_heard: None,
+        }
+    }
+
+    /// Whether busy needs the carrier detect line read
+    pub fn uses_carrier(&self) -> bool {
+        self.dcd == DcdSource::Carrier
+    }
+
+    /// Note that the TNC just handed us something
+    pub fn heard(&mut self, now: Instant) {
+        self.last_heard = Some(now);
+    }
+
+    fn busy(&self, now: Instant, carrier: bool) -> bool {
+        match self.dcd {
+            DcdSource::Off => false,
+            DcdSource::Rx => self
+                .last_heard
+                .is_some_and(|heard| now.saturating_duration_since(heard) < RX_BUSY),
+            DcdSource::Carrier => carrier,
+        }
+    }
+
+    /// None to transmit now, or when to try again
+    pub fn defer(&self, now: Instant, carrier: bool) -> Option<Instant> {
+        let retry = Some(now + self.slot_time);
+        if self.busy(now, carrier) {
+            return retry;
+        }
+        match self.persist {
+            Some(persist) if persist < u8::MAX => {
+                let roll = random_up_to(Duration::from_nanos(255)).as_nanos() as u8;
+                if roll > persist {
+                    return retry;
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aprs::parse_packet;
+
+    fn routed(packet: &str, source: PacketSource) -> RoutedPacket {
+        RoutedPacket {
+            packet: parse_packet(packet).unwrap(),
+            source,
+        }
+    }
+
+    #[test]
+    fn test_priority() {
+        let beacon = routed("N0CALL>APRS:!4903.50N/07201.75W-", PacketSource::Internal);
+        let digi = routed(
+            "N1CALL>APRS,N0CALL*:!4903.50N/07201.75W-",
+            PacketSource::Digipeater("vhf".to_string()),
+        );
+        let message = routed("N1CALL>APRS::N0CALL   :ack12", PacketSource::AprsIs);
+        let gated = routed("N1CALL>APRS,TCPIP*:>Status", PacketSource::AprsIs);
+
+        assert_eq!(Priority::of(&beacon), Priority::Low);
+        assert_eq!(Priority::of(&digi), Priority::Normal);
+        assert_eq!(Priority::of(&message), Priority::High);
+        assert_eq!(Priority::of(&gated), Priority::Normal);
+    }
+
+    #[test]
+    fn test_messages_jump_ahead() {
+        let mut queue = TxQueue::new(10, OverflowPolicy::ShedLowest);
+        queue.push(routed("N0CALL>APRS:>Beacon", PacketSource::Internal));
+        queue.push(routed("N1CALL>APRS,TCPIP*:>Status", PacketSource::AprsIs));
+        queue.push(routed("N1CALL>APRS::N0CALL   :Hi{1", PacketSource::AprsIs));
+
+        assert_eq!(queue.len(), 3);
+        assert!(queue.pop().unwrap().packet.message().is_some());
+        assert_eq!(queue.pop().unwrap().source, PacketSource::AprsIs);
+        assert_eq!(queue.pop().unwrap().source, PacketSource::Internal);
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn test_low_priority_shed_first() {
+        let mut queue = TxQueue::new(2, OverflowPolicy::ShedLowest);
+        assert!(queue
+            .push(routed("N0CALL>APRS:>Beacon", PacketSource::Internal))
+            .is_none());
+        assert!(queue
+            .push(routed("N1CALL>APRS,TCPIP*:>One", PacketSource::AprsIs))
+            .is_none());
+
+        // A message pushes out our beacon
+        let shed = queue
+            .push(routed("N1CALL>APRS::N0CALL   :Hi{1", PacketSource::AprsIs))
+            .unwrap();
+        assert_eq!(shed.packet.information, ">Beacon");
+
+        // A beacon can't push out anything more important
+        let shed = queue
+            .push(routed("N0CALL>APRS:>Beacon 2", PacketSource::Internal))
+            .unwrap();
+        assert_eq!(shed.packet.information, ">Beacon 2");
+        assert_eq!(queue.shed(), 2);
+        assert_eq!(queue.len(), 2);
+    }
+
+    #[test]
+    fn test_overflow_policies() {
+        let mut queue = TxQueue::new(1, OverflowPolicy::DropNewest);
+        queue.push(routed("N1CALL>APRS,TCPIP*:>One", PacketSource::AprsIs));
+        let shed = queue
+            .push(routed("N1CALL>APRS::N0CALL   :Hi{1", PacketSource::AprsIs))
+            .unwrap();
+        assert!(shed.packet.message().is_some());
+
+        let mut queue = TxQueue::new(1, OverflowPolicy::DropOldest);
+        queue.push(routed("N1CALL>APRS,TCPIP*:>One", PacketSource::AprsIs));
+        let shed = queue
+            .push(routed("N0CALL>APRS:>Beacon", PacketSource::Internal))
+            .unwrap();
+        assert_eq!(shed.packet.information, ">One");
+        assert_eq!(queue.peek().unwrap().packet.information, ">Beacon");
+    }
+
+    #[test]
+    fn test_scheduler_publishes_queue() {
+        let config: SerialPortConfig = toml::from_str(
+            r#"
+            name = "test-queue"
+            device = "/dev/null"
+            baud_rate = 9600
+            protocol = "kiss"
+            tx_enable = true
+            rx_enable = true
+            "#,
+        )
+        .unwrap();
+        let mut tx = TxScheduler::new(&config);
+        tx.push(routed("N0CALL>APRS:>Beacon", PacketSource::Internal));
+        tx.push(routed("N1CALL>APRS::N0CALL   :Hi{1", PacketSource::AprsIs));
+
+        let stats = PortStats::for_port("test-queue");
+        assert_eq!(stats.depth(), 2);
+        {
+            let queued = stats.queued.lock().unwrap();
+            assert_eq!(queued[0].priority, Priority::High);
+            assert_eq!(queued[1].packet, "N0CALL>APRS:>Beacon");
+        }
+        assert!(stats.oldest_age(Utc::now()).unwrap() >= chrono::Duration::zero());
+
+        tx.pop();
+        tx.pop();
+        assert_eq!(stats.depth(), 0);
+        assert!(stats.oldest_age(Utc::now()).is_none());
+    }
+
+    #[test]
+    fn test_jitter() {
+        let config: SerialPortConfig = toml::from_str(
+            r#"
+            name = "test-jitter"
+            device = "/dev/null"
+            beacon_jitter = "2s"
+            "#,
+        )
+        .unwrap();
+        let mut tx = TxScheduler::new(&config);
+        let start = Instant::now();
+        tx.push(routed("N0CALL>APRS:>Beacon", PacketSource::Internal));
+        let slot = tx.next_slot().unwrap();
+        assert!(slot >= start && slot <= Instant::now() + Duration::from_secs(2));
+        // The delay is drawn once per packet, not every time we look
+        assert_eq!(tx.next_slot(), Some(slot));
+        assert_eq!(
+            PortStats::for_port("test-jitter")
+                .deferred
+                .load(Ordering::Relaxed),
+            0
+        );
+
+        // Digipeats have their own setting, off here
+        tx.pop();
+        tx.push(routed(
+            "N1CALL>APRS,N0CALL*:>Hi",
+            PacketSource::Digipeater("vhf".to_string()),
+        ));
+        assert!(tx.next_slot().unwrap() <= Instant::now());
+
+        for _ in 0..100 {
+            assert!(random_up_to(Duration::from_millis(5)) <= Duration::from_millis(5));
+        }
+        assert_eq!(random_up_to(Duration::ZERO), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_channel_access() {
+        let mut config: SerialPortConfig =
+            toml::from_str("name = \"csma\"\ndevice = \"/dev/null\"\ndcd = \"rx\"").unwrap();
+        let mut access = ChannelAccess::new(&config);
+        let now = Instant::now();
+        assert_eq!(access.defer(now, false), None);
+
+        // Just heard something, so wait a slot
+        access.heard(now);
+        assert_eq!(
+            access.defer(now, false),
+            Some(now + Duration::from_millis(100))
+        );
+        assert_eq!(access.defer(now + RX_BUSY, false), None);
+
+        config.dcd = DcdSource::Carrier;
+        let access = ChannelAccess::new(&config);
+        assert!(access.uses_carrier());
+        assert!(access.defer(now, true).is_some());
+        assert!(access.defer(now, false).is_none());
+
+        // A persist of 63 sends in about a quarter of clear slots
+        config.dcd = DcdSource::Off;
+        config.persist = Some(63);
+        let access = ChannelAccess::new(&config);
+        let sent = (0..1000)
+            .filter(|_| access.defer(now, false).is_none())
+            .count();
+        assert!((150..350).contains(&sent), "{}", sent);
+    }
+
+    #[test]
+    fn test_airtime() {
+        let packet = parse_packet("N0CALL>APRS,WIDE2-1:!4903.50N/07201.75W-").unwrap();
+        let at_1200 = airtime(&packet, 1200);
+        let at_9600 = airtime(&packet, 9600);
+        // 47 bytes of frame is about 330ms of audio at 1200 baud, plus key-up
+        assert!(at_1200 > Duration::from_millis(550) && at_1200 < Duration::from_millis(650));
+        assert!(at_9600 < at_1200);
+    }
+
+    #[test]
+    fn test_pacer_rate_limit() {
+        let mut pacer = TxPacer::new(Some(2), None);
+        let start = Instant::now();
+        let air = Duration::from_millis(500);
+
+        assert_eq!(pacer.ready_at(start, air), start);
+        pacer.record(start, air);
+        pacer.record(start + Duration::from_secs(10), air);
+
+        // Third packet waits until the first leaves the window
+        let ready = pacer.ready_at(start + Duration::from_secs(20), air);
+        assert_eq!(ready, start + PACING_WINDOW);
+    }
+
+    #[test]
+    fn test_pacer_duty_cycle() {
+        // 5% of a minute is 3 seconds of airtime
+        let mut pacer = TxPacer::new(None, Some(5.0));
+        let start = Instant::now();
+        let air = Duration::from_secs(1);
+
+        pacer.record(start, air);
+        pacer.record(start + Duration::from_secs(1), air);
+        assert_eq!(
+            pacer.ready_at(start + Duration::from_secs(2), air),
+            start + Duration::from_secs(2)
+        );
+        pacer.record(start + Duration::from_secs(2), air);
+
+        let ready = pacer.ready_at(start + Duration::from_secs(3), air);
+        assert_eq!(ready, start + PACING_WINDOW);
+    }
+}
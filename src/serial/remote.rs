@@ -0,0 +1,456 @@
+//! Serial ports at the far end of a TCP connection.
+//!
+//! `tcp://host:port` is a raw byte stream, as socat or ser2net's raw mode
+//! serve. `rfc2217://host:port` speaks telnet with the RFC 2217 com port
+//! option, so the baud rate and framing are set on the remote port and its
+//! carrier detect line is reported back.
+
+use anyhow::{anyhow, Result};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Telnet
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const BINARY: u8 = 0;
+const SGA: u8 = 3;
+
+// RFC 2217, with server replies 100 above the client's commands
+const COM_PORT: u8 = 44;
+const SET_BAUDRATE: u8 = 1;
+const SET_DATASIZE: u8 = 2;
+const SET_PARITY: u8 = 3;
+const SET_STOPSIZE: u8 = 4;
+const SET_MODEMSTATE_MASK: u8 = 11;
+const PURGE_DATA: u8 = 12;
+const NOTIFY_MODEMSTATE: u8 = 107;
+const PARITY_NONE: u8 = 1;
+const STOPSIZE_1: u8 = 1;
+const PURGE_RECEIVE: u8 = 1;
+const CARRIER_DETECT: u8 = 0x80;
+
+/// Whether a device names a remote port
+pub fn is_remote(device: &str) -> bool {
+    device.starts_with("tcp://") || device.starts_with("rfc2217://")
+}
+
+pub struct RemoteSerial {
+    stream: TcpStream,
+    telnet: Option<Telnet>,
+    // Written but not yet accepted by the socket
+    pending: Vec<u8>,
+}
+
+impl RemoteSerial {
+    pub async fn connect(device: &str, baud_rate: u32) -> Result<Self> {
+        let (address, rfc2217) = if let Some(address) = device.strip_prefix("tcp://") {
+            (address, false)
+        } else if let Some(address) = device.strip_prefix("rfc2217://") {
+            (address, true)
+        } else {
+            return Err(anyhow!("Not a remote serial port: {}", device));
+        };
+
+        let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(address))
+            .await
+            .map_err(|_| anyhow!("Timed out connecting to {}", device))?
+            .map_err(|e| anyhow!("Failed to connect to {}: {}", device, e))?;
+        // Frames are written whole, so there is nothing to gain by waiting
+        stream.set_nodelay(true)?;
+
+        let (telnet, pending) = if rfc2217 {
+            (Some(Telnet::default()), Telnet::open(baud_rate))
+        } else {
+            (None, Vec::new())
+        };
+        Ok(RemoteSerial {
+            stream,
+            telnet,
+            pending,
+        })
+    }
+
+    /// The carrier detect line, as the remote port last reported it
+    pub fn carrier_detect(&self) -> Result<bool> {
+        match &self.telnet {
+            Some(telnet) => Ok(telnet.carrier.unwrap_or(false)),
+            None => Err(anyhow!("Carrier detect isn't available over raw TCP")),
+        }
+    }
+
+    /// Drop what has arrived, and ask the remote port to do the same
+    pub fn discard_input(&mut self) -> Result<()> {
+        if self.telnet.is_some() {
+            self.pending
+                .extend_from_slice(&subnegotiation(PURGE_DATA, &[PURGE_RECEIVE]));
+        }
+        let mut buf = [0u8; 256];
+        loop {
+            match self.try_read(&mut buf) {
+                Ok(0) => return Err(anyhow!("Remote serial port closed the connection")),
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Read without waiting. Telnet commands are handled and only data
+    /// comes back, so a read of nothing but commands would block.
+    pub fn try_read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.send_pending()?;
+        let n = self.stream.try_read(buf)?;
+        if n == 0 {
+            return Ok(0);
+        }
+        let Some(telnet) = self.telnet.as_mut() else {
+            return Ok(n);
+        };
+        let (data, replies) = telnet.decode(&buf[..n]);
+        self.pending.extend_from_slice(&replies);
+        buf[..data.len()].copy_from_slice(&data);
+        if data.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(data.len())
+    }
+
+    /// Write without waiting. Once accepted, data is sent by later reads
+    /// and writes if the socket can't take it all at once.
+    pub fn try_write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send_pending()?;
+        if !self.pending.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        if self.telnet.is_none() {
+            return self.stream.try_write(buf);
+        }
+        self.pending = escape(buf);
+        self.send_pending()?;
+        Ok(buf.len())
+    }
+
+    fn send_pending(&mut self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            match self.stream.try_write(&self.pending) {
+                Ok(n) => {
+                    self.pending.drain(..n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn poll_send_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            ready!(self.stream.poll_write_ready(cx))?;
+            self.send_pending()?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for RemoteSerial {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        // Ports spend their time waiting to read, so that's where replies
+        // and the rest of a large write get sent
+        if let Poll::Ready(Err(e)) = self.poll_send_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        loop {
+            ready!(self.stream.poll_read_ready(cx))?;
+            match self.try_read(buf.initialize_unfilled()) {
+                // A serial port doesn't end, so the connection closing is
+                // an error rather than end of file
+                Ok(0) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "remote serial port closed the connection",
+                    )))
+                }
+                Ok(n) => {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for RemoteSerial {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            ready!(self.stream.poll_write_ready(cx))?;
+            match self.try_write(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return Poll::Ready(result),
+            }
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_send_pending(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_send_pending(cx))?;
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+enum State {
+    #[default]
+    Data,
+    Iac,
+    Negotiation(u8),
+    Sub,
+    SubIac,
+}
+
+/// Telnet as an RFC 2217 client
+#[derive(Debug, Default)]
+struct Telnet {
+    state: State,
+    sub: Vec<u8>,
+    carrier: Option<bool>,
+}
+
+impl Telnet {
+    /// What a client sends on connecting: 8-bit clean options, the com
+    /// port option, and the line settings
+    fn open(baud_rate: u32) -> Vec<u8> {
+        let mut open = Vec::new();
+        for option in [BINARY, SGA, COM_PORT] {
+            open.extend_from_slice(&[IAC, WILL, option]);
+        }
+        for option in [BINARY, SGA] {
+            open.extend_from_slice(&[IAC, DO, option]);
+        }
+        open.extend(subnegotiation(SET_BAUDRATE, &baud_rate.to_be_bytes()));
+        open.extend(subnegotiation(SET_DATASIZE, &[8]));
+        open.extend(subnegotiation(SET_PARITY, &[PARITY_NONE]));
+        open.extend(subnegotiation(SET_STOPSIZE, &[STOPSIZE_1]));
+        open.extend(subnegotiation(SET_MODEMSTATE_MASK, &[CARRIER_DETECT]));
+        open
+    }
+
+    /// Split received bytes into data and the replies owed to the server
+    fn decode(&mut self, received: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut data = Vec::with_capacity(received.len());
+        let mut replies = Vec::new();
+        for &byte in received {
+            self.state = match (&self.state, byte) {
+                (State::Data, IAC) => State::Iac,
+                (State::Data, _) => {
+                    data.push(byte);
+                    State::Data
+                }
+                (State::Iac, IAC) => {
+                    data.push(IAC);
+                    State::Data
+                }
+                (State::Iac, DO | DONT | WILL | WONT) => State::Negotiation(byte),
+                (State::Iac, SB) => {
+                    self.sub.clear();
+                    State::Sub
+                }
+                // NOP, go ahead and the like mean nothing here
+                (State::Iac, _) => State::Data,
+                (State::Negotiation(verb), option) => {
+                    match (*verb, option) {
+                        // Agreeing to what was asked for when connecting
+                        (DO | WILL, BINARY | SGA | COM_PORT) => {}
+                        (DO, _) => replies.extend_from_slice(&[IAC, WONT, option]),
+                        (WILL, _) => replies.extend_from_slice(&[IAC, DONT, option]),
+                        (DONT | WONT, COM_PORT) => {
+                            log::warn!("Remote serial port refused RFC 2217 control")
+                        }
+                        _ => {}
+                    }
+                    State::Data
+                }
+                (State::Sub, IAC) => State::SubIac,
+                (State::Sub, _) => {
+                    self.sub.push(byte);
+                    State::Sub
+                }
+                (State::SubIac, SE) => {
+                    self.notified();
+                    State::Data
+                }
+                (State::SubIac, _) => {
+                    self.sub.push(byte);
+                    State::Sub
+                }
+            };
+        }
+        (data, replies)
+    }
+
+    fn notified(&mut self) {
+        if let [COM_PORT, NOTIFY_MODEMSTATE, state, ..] = self.sub[..] {
+            self.carrier = Some(state & CARRIER_DETECT != 0);
+        }
+    }
+}
+
+/// A com port option command, escaped
+fn subnegotiation(command: u8, value: &[u8]) -> Vec<u8> {
+    let mut sub = vec![IAC, SB, COM_PORT, command];
+    sub.extend(escape(value));
+    sub.extend_from_slice(&[IAC, SE]);
+    sub
+}
+
+/// Data with IAC doubled, as telnet sends it
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for &byte in data {
+        escaped.push(byte);
+        if byte == IAC {
+            escaped.push(IAC);
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_telnet_decode() {
+        let mut telnet = Telnet::default();
+        // Data with an escaped 0xff, an option we don't do, and the carrier
+        // coming up, split across reads
+        let (data, replies) = telnet.decode(&[0xc0, IAC, IAC, 0x01, IAC, DO, 24, IAC, SB]);
+        assert_eq!(data, vec![0xc0, 0xff, 0x01]);
+        assert_eq!(replies, vec![IAC, WONT, 24]);
+        assert_eq!(telnet.carrier, None);
+
+        let (data, replies) = telnet.decode(&[COM_PORT, NOTIFY_MODEMSTATE, 0x80, IAC, SE, 0xc0]);
+        assert_eq!(data, vec![0xc0]);
+        assert!(replies.is_empty());
+        assert_eq!(telnet.carrier, Some(true));
+
+        // Agreeing to what we asked for needs no reply
+        let (_, replies) = telnet.decode(&[IAC, WILL, BINARY, IAC, DO, COM_PORT]);
+        assert!(replies.is_empty());
+    }
+
+    #[test]
+    fn test_subnegotiation() {
+        // 0xff in a value is escaped too
+        assert_eq!(
+            subnegotiation(SET_BAUDRATE, &0x0000_25ffu32.to_be_bytes()),
+            vec![
+                IAC,
+                SB,
+                COM_PORT,
+                SET_BAUDRATE,
+                0,
+                0,
+                0x25,
+                IAC,
+                IAC,
+                IAC,
+                SE
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rfc2217() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let open = Telnet::open(9600);
+            let mut received = vec![0u8; open.len()];
+            stream.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, open);
+            stream
+                .write_all(&[
+                    IAC,
+                    DO,
+                    COM_PORT,
+                    IAC,
+                    SB,
+                    COM_PORT,
+                    NOTIFY_MODEMSTATE,
+                    0x80,
+                ])
+                .await
+                .unwrap();
+            stream
+                .write_all(&[IAC, SE, 0xc0, IAC, IAC, 0xc0])
+                .await
+                .unwrap();
+
+            let mut frame = [0u8; 4];
+            stream.read_exact(&mut frame).await.unwrap();
+            frame
+        });
+
+        let mut port = RemoteSerial::connect(&format!("rfc2217://{}", address), 9600)
+            .await
+            .unwrap();
+        let mut data = [0u8; 3];
+        port.read_exact(&mut data).await.unwrap();
+        assert_eq!(data, [0xc0, 0xff, 0xc0]);
+        assert!(port.carrier_detect().unwrap());
+
+        port.write_all(&[0xc0, 0xff, 0xc0]).await.unwrap();
+        port.flush().await.unwrap();
+        assert_eq!(server.await.unwrap(), [0xc0, IAC, IAC, 0xc0]);
+    }
+
+    #[tokio::test]
+    async fn test_raw_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(&[IAC, 0xc0]).await.unwrap();
+        });
+
+        let mut port = RemoteSerial::connect(&format!("tcp://{}", address), 9600)
+            .await
+            .unwrap();
+        let mut data = [0u8; 2];
+        port.read_exact(&mut data).await.unwrap();
+        // Bytes pass straight through
+        assert_eq!(data, [IAC, 0xc0]);
+        assert!(port.carrier_detect().is_err());
+
+        server.await.unwrap();
+        let error = port.read(&mut data).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,62 @@
+//! Daemon-wide shutdown signal.
+//!
+//! Tasks that need to tidy up before exiting hold a clone of [`Shutdown`]
+//! and wait on it alongside their normal work; main triggers it when a
+//! signal arrives and gives them a moment to finish.
+
+use std::sync::Arc;
+use tokio::sync::watch;
+
+#[derive(Clone)]
+pub struct Shutdown {
+    tx: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown {
+            tx: Arc::new(watch::Sender::new(false)),
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tell every holder to stop
+    pub fn trigger(&self) {
+        self.tx.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.tx.borrow()
+    }
+
+    /// Resolves once shutdown has been triggered
+    pub async fn wait(&self) {
+        let mut rx = self.tx.subscribe();
+        // The sender lives as long as self, so this can't fail
+        let _ = rx.wait_for(|stopping| *stopping).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let shutdown = Shutdown::new();
+        let waiter = shutdown.clone();
+        let task = tokio::spawn(async move { waiter.wait().await });
+
+        assert!(!shutdown.is_triggered());
+        shutdown.trigger();
+        task.await.unwrap();
+        assert!(shutdown.is_triggered());
+        // Waiting after the fact returns straight away
+        shutdown.wait().await;
+    }
+}
@@ -0,0 +1,127 @@
+//! Periodic station identification.
+//!
+//! Some administrations want an automatic station to identify itself at
+//! least every so often while it transmits. Every interval, each port that
+//! sent anything since the last check gets a short status packet like
+//! `>DE N0CALL-10`. A port that stayed quiet isn't keyed up just to
+//! identify.
+
+use crate::aprs::{AprsPacket, CallSign};
+use crate::channel::{self, Overflow};
+use crate::config::StationIdConfig;
+use crate::router::{PacketSource, RoutedPacket, TAG_PORT_PREFIX};
+use crate::serial::queue::PortStats;
+use anyhow::Result;
+use log::{debug, info};
+use std::sync::atomic::Ordering;
+use tokio::sync::mpsc;
+
+/// What a port has sent, as of the last check
+struct Window {
+    port: String,
+    sent: u64,
+    identified: bool, // An ID went out at the last check, and counts as sent
+}
+
+impl Window {
+    fn new(port: String) -> Self {
+        let sent = PortStats::for_port(&port).sent.load(Ordering::Relaxed);
+        Window {
+            port,
+            sent,
+            identified: false,
+        }
+    }
+
+    /// Whether the port transmitted since the last check, other than our ID
+    fn transmitted(&mut self) -> bool {
+        let sent = PortStats::for_port(&self.port).sent.load(Ordering::Relaxed);
+        let ours = u64::from(self.identified);
+        let transmitted = sent.saturating_sub(self.sent) > ours;
+        self.sent = sent;
+        self.identified = transmitted;
+        transmitted
+    }
+}
+
+fn id_text(config: &StationIdConfig, mycall: &str) -> String {
+    match &config.text {
+        Some(text) => format!(">{}", text),
+        None => format!(">DE {}", mycall),
+    }
+}
+
+pub async fn run_station_id(
+    config: StationIdConfig,
+    mycall: String,
+    ports: Vec<String>,
+    tx: mpsc::Sender<RoutedPacket>,
+) -> Result<()> {
+    info!(
+        "Identifying on {} every {}s they transmit",
+        ports.join(", "),
+        config.interval
+    );
+
+    let source = CallSign::parse(&mycall).unwrap_or(CallSign::new("N0CALL", 0));
+    let text = id_text(&config, &mycall);
+    let mut windows: Vec<Window> = ports.into_iter().map(Window::new).collect();
+    let mut interval =
+        tokio::time::interval(tokio::time::Duration::from_secs(config.interval as u64));
+    // The first tick is immediate, and nothing has been sent yet
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        for window in &mut windows {
+            if !window.transmitted() {
+                debug!("Nothing sent on {}, no ID needed", window.port);
+                continue;
+            }
+            let mut packet =
+                AprsPacket::new(source.clone(), CallSign::new("APRS", 0), text.clone());
+            packet
+                .tags
+                .push(format!("{}{}", TAG_PORT_PREFIX, window.port));
+
+            info!("Identifying on {}: {}", window.port, packet.information);
+            let routed = RoutedPacket {
+                packet,
+                source: PacketSource::Internal,
+            };
+            channel::send(&tx, routed, channel::ROUTER, Overflow::Block).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_id_only_after_transmitting() {
+        let stats = PortStats::for_port("station-id-test");
+        let mut window = Window::new("station-id-test".to_string());
+        assert!(!window.transmitted());
+
+        stats.sent.fetch_add(3, Ordering::Relaxed);
+        assert!(window.transmitted());
+        // The ID itself doesn't call for another
+        stats.sent.fetch_add(1, Ordering::Relaxed);
+        assert!(!window.transmitted());
+        assert!(!window.transmitted());
+
+        stats.sent.fetch_add(1, Ordering::Relaxed);
+        assert!(window.transmitted());
+    }
+
+    #[test]
+    fn test_id_text() {
+        let mut config: StationIdConfig = toml::from_str("").unwrap();
+        assert_eq!(config.interval, 600);
+        assert_eq!(id_text(&config, "N0CALL-10"), ">DE N0CALL-10");
+        config.text = Some("N0CALL-10 digi, Hilltop".to_string());
+        assert_eq!(id_text(&config, "N0CALL-10"), ">N0CALL-10 digi, Hilltop");
+    }
+}
@@ -0,0 +1,122 @@
+//! Software and capabilities status beacon.
+//!
+//! Sends a status report like `>aprstx v0.1.0 digi igate msg` so network
+//! surveys can tell which stations run aprstx and what each one does. With
+//! `grid` on it leads with the station's locator and symbol, as in
+//! `>FN31pr/& aprstx v0.1.0 igate msg`.
+
+use crate::aprs::grid::grid_square;
+use crate::aprs::{AprsPacket, CallSign};
+use crate::channel::{self, Overflow};
+use crate::config::{Config, StatusBeaconConfig};
+use crate::gps::GpsTracker;
+use crate::router::{PacketSource, RoutedPacket, TAG_ISONLY};
+use crate::template;
+use anyhow::Result;
+use log::{debug, info};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+/// What this station has switched on
+fn capabilities(config: &Config) -> Vec<&'static str> {
+    let mut capabilities = Vec::new();
+    if config.digipeater.enabled {
+        capabilities.push("digi");
+    }
+    if config.aprs_is.is_some() {
+        capabilities.push("igate");
+    }
+    // Messages to us are always answered
+    capabilities.push("msg");
+    capabilities
+}
+
+fn status_text(config: &Config, comment: &str, position: Option<[f64; 2]>) -> String {
+    let mut text = ">".to_string();
+    if let Some([lat, lon]) = position {
+        let (table, symbol) = match &config.beacon {
+            Some(beacon) => (beacon.symbol_table, beacon.symbol),
+            None => ('/', '&'),
+        };
+        text.push_str(&format!("{}{}{} ", grid_square(lat, lon, 6), table, symbol));
+    }
+    text.push_str(&format!(
+        "aprstx v{} {}",
+        env!("CARGO_PKG_VERSION"),
+        capabilities(config).join(" ")
+    ));
+    if !comment.is_empty() {
+        text.push(' ');
+        text.push_str(comment);
+    }
+    text
+}
+
+pub async fn run_status_beacon(
+    config: StatusBeaconConfig,
+    station: Arc<Config>,
+    gps: Option<Arc<GpsTracker>>,
+    tx: mpsc::Sender<RoutedPacket>,
+) -> Result<()> {
+    info!("Starting status beacon with interval {}s", config.interval);
+
+    let source = CallSign::parse(&station.mycall).unwrap_or(CallSign::new("N0CALL", 0));
+    let mut interval =
+        tokio::time::interval(tokio::time::Duration::from_secs(config.interval as u64));
+
+    loop {
+        interval.tick().await;
+
+        let mut position = None;
+        if let (true, Some(gps)) = (config.grid, &gps) {
+            position = gps
+                .get_current_position()
+                .await
+                .map(|pos| [pos.latitude, pos.longitude]);
+            if position.is_none() {
+                debug!("No position yet, sending status without a locator");
+            }
+        }
+        let comment = template::expand(&config.comment, gps.as_deref()).await;
+        let text = status_text(&station, &comment, position);
+        let mut packet = AprsPacket::new(source.clone(), CallSign::new("APRS", 0), text);
+        if !config.rf {
+            packet.tags.push(TAG_ISONLY.to_string());
+        }
+
+        info!("Sending status: {}", packet.information);
+        let routed = RoutedPacket {
+            packet,
+            source: PacketSource::Internal,
+        };
+        channel::send(&tx, routed, channel::ROUTER, Overflow::Drop).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_text() {
+        let mut config: Config = toml::from_str("mycall = \"N0CALL\"").unwrap();
+        let version = env!("CARGO_PKG_VERSION");
+        assert_eq!(
+            status_text(&config, "", None),
+            format!(">aprstx v{} msg", version)
+        );
+
+        config.digipeater.enabled = true;
+        assert_eq!(
+            status_text(&config, "Hilltop", None),
+            format!(">aprstx v{} digi msg Hilltop", version)
+        );
+
+        let text = status_text(&config, "", Some([41.7146, -72.7272]));
+        assert_eq!(text, format!(">FN31pr/& aprstx v{} digi msg", version));
+        assert_eq!(
+            crate::aprs::grid::status_grid(&text).as_deref(),
+            Some("FN31PR")
+        );
+    }
+}
@@ -0,0 +1,177 @@
+//! Packet storage.
+//!
+//! Every packet heard on RF or APRS-IS is written to a database as a row of
+//! `packets`, and a decoded position as a row of `positions` pointing back
+//! at it. Each backend creates both tables with its own column types, then
+//! the [`INDEXES`] they share; packets heard while a database is
+//! unreachable aren't recorded.
+
+#[cfg(feature = "postgres")]
+pub mod postgres;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+use crate::channel;
+use crate::filter::APRS_IS_PORT;
+use crate::router::{PacketSource, RoutedPacket};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// Drops counted against storage when it falls behind
+const STORAGE_CHANNEL: &str = "storage";
+
+/// Lookups are by time, and by station and time
+pub const INDEXES: &str = "\
+CREATE INDEX IF NOT EXISTS packets_received ON packets (received);
+CREATE INDEX IF NOT EXISTS packets_source_received ON packets (source, received);
+CREATE INDEX IF NOT EXISTS positions_source_received ON positions (source, received);
+";
+
+/// A database packets are recorded in, with its tables already created
+#[async_trait]
+pub trait Storage: Send {
+    /// Record one packet, and its position if it has one
+    async fn store(&mut self, record: &PacketRecord) -> Result<()>;
+}
+
+/// One row of `packets`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PacketRecord {
+    pub received: DateTime<Utc>,
+    pub port: String,
+    pub source: String,
+    pub destination: String,
+    pub path: String, // Comma separated, as in TNC2
+    pub data_type: String,
+    pub information: String,
+    pub position: Option<PositionRecord>,
+}
+
+/// One row of `positions`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PositionRecord {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: Option<f32>, // metres
+    pub course: Option<u16>,   // degrees
+    pub speed: Option<f32>,    // knots
+    pub symbol: String,        // Table and symbol
+    pub comment: String,
+}
+
+impl From<&RoutedPacket> for PacketRecord {
+    fn from(routed: &RoutedPacket) -> Self {
+        let packet = &routed.packet;
+        let port = match &routed.source {
+            PacketSource::SerialPort(name) => name.as_str(),
+            PacketSource::AprsIs => APRS_IS_PORT,
+            PacketSource::Internal | PacketSource::Digipeater(_) => "local",
+        };
+        let data_type = serde_json::to_value(&packet.data_type)
+            .ok()
+            .and_then(|v| v.as_str().map(str::to_string))
+            .unwrap_or_default();
+        PacketRecord {
+            received: packet.timestamp,
+            port: port.to_string(),
+            source: packet.source.to_string(),
+            destination: packet.destination.to_string(),
+            path: packet
+                .path
+                .iter()
+                .map(|hop| hop.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+            data_type,
+            information: packet.information.clone(),
+            position: packet.position().map(|pos| PositionRecord {
+                latitude: pos.latitude,
+                longitude: pos.longitude,
+                altitude: pos.altitude,
+                course: pos.course,
+                speed: pos.speed,
+                symbol: format!("{}{}", pos.symbol_table, pos.symbol),
+                comment: pos.comment.clone(),
+            }),
+        }
+    }
+}
+
+/// Record heard packets in a database, connecting again whenever it fails
+pub async fn run_storage<S, F, Fut>(
+    name: &str,
+    connect: F,
+    heard_rx: broadcast::Receiver<RoutedPacket>,
+) -> Result<()>
+where
+    S: Storage,
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<S>>,
+{
+    loop {
+        match store_heard(&connect, heard_rx.resubscribe()).await {
+            Ok(_) => return Ok(()),
+            Err(e) => {
+                error!("{} storage error: {}, reconnecting in 30s...", name, e);
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(30)).await;
+    }
+}
+
+async fn store_heard<S, F, Fut>(
+    connect: &F,
+    mut heard_rx: broadcast::Receiver<RoutedPacket>,
+) -> Result<()>
+where
+    S: Storage,
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<S>>,
+{
+    let mut storage = connect().await?;
+    info!("Recording heard packets");
+
+    loop {
+        let routed = match heard_rx.recv().await {
+            Ok(routed) => routed,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!("Packet storage fell behind, dropped {} packets", missed);
+                channel::record_drops(STORAGE_CHANNEL, missed);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        };
+        storage.store(&PacketRecord::from(&routed)).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aprs::parse_packet;
+
+    #[test]
+    fn test_record() {
+        let packet = parse_packet(
+            "N0CALL-9>APRS,WIDE1-1,WIDE2-1:!3853.86N/07702.19W>088/036/A=000100 Mobile",
+        )
+        .unwrap();
+        let record = PacketRecord::from(&RoutedPacket {
+            packet,
+            source: PacketSource::SerialPort("vhf".to_string()),
+        });
+        assert_eq!(record.port, "vhf");
+        assert_eq!(record.source, "N0CALL-9");
+        assert_eq!(record.path, "WIDE1-1,WIDE2-1");
+        assert_eq!(record.data_type, "position");
+        let position = record.position.unwrap();
+        assert_eq!(position.symbol, "/>");
+        assert_eq!(position.course, Some(88));
+        assert!((position.latitude - 38.8977).abs() < 0.001);
+    }
+}
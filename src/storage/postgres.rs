@@ -0,0 +1,160 @@
+//! PostgreSQL backend, for installations that outgrow a file.
+
+use super::{PacketRecord, Storage, INDEXES};
+use crate::config::PostgresConfig;
+use crate::router::RoutedPacket;
+use anyhow::Result;
+use async_trait::async_trait;
+use log::{info, warn};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio_postgres::{Client, NoTls, Statement};
+
+const POSTGRES_TIMEOUT: Duration = Duration::from_secs(30);
+
+const TABLES: &str = "\
+CREATE TABLE IF NOT EXISTS packets (
+    id BIGSERIAL PRIMARY KEY,
+    received TIMESTAMPTZ NOT NULL,
+    port TEXT NOT NULL,
+    source TEXT NOT NULL,
+    destination TEXT NOT NULL,
+    path TEXT NOT NULL,
+    data_type TEXT NOT NULL,
+    information TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS positions (
+    packet_id BIGINT PRIMARY KEY REFERENCES packets (id) ON DELETE CASCADE,
+    received TIMESTAMPTZ NOT NULL,
+    source TEXT NOT NULL,
+    latitude DOUBLE PRECISION NOT NULL,
+    longitude DOUBLE PRECISION NOT NULL,
+    altitude REAL,
+    course SMALLINT,
+    speed REAL,
+    symbol TEXT NOT NULL,
+    comment TEXT NOT NULL
+);
+";
+
+pub async fn run_postgres(
+    config: PostgresConfig,
+    heard_rx: broadcast::Receiver<RoutedPacket>,
+) -> Result<()> {
+    super::run_storage(
+        "PostgreSQL",
+        move || PostgresStorage::connect(config.clone()),
+        heard_rx,
+    )
+    .await
+}
+
+pub struct PostgresStorage {
+    client: Client,
+    insert_packet: Statement,
+    insert_position: Statement,
+}
+
+impl PostgresStorage {
+    /// Log in and create the tables that aren't there yet
+    pub async fn connect(config: PostgresConfig) -> Result<Self> {
+        info!(
+            "Connecting to PostgreSQL {}:{}/{}",
+            config.host, config.port, config.database
+        );
+        let mut options = tokio_postgres::Config::new();
+        options
+            .host(&config.host)
+            .port(config.port)
+            .user(&config.user)
+            .dbname(&config.database)
+            .application_name("aprstx")
+            .connect_timeout(POSTGRES_TIMEOUT);
+        if let Some(password) = &config.password {
+            options.password(password);
+        }
+        let (client, connection) = options.connect(NoTls).await?;
+        // The connection does the talking; the client's calls fail once it stops
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                warn!("PostgreSQL connection lost: {}", e);
+            }
+        });
+
+        client
+            .batch_execute(&format!("{}{}", TABLES, INDEXES))
+            .await?;
+        let insert_packet = client
+            .prepare(
+                "INSERT INTO packets (received, port, source, destination, path, data_type, information) \
+                 VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id",
+            )
+            .await?;
+        let insert_position = client
+            .prepare(
+                "INSERT INTO positions (packet_id, received, source, latitude, longitude, altitude, course, speed, symbol, comment) \
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+            )
+            .await?;
+        info!("Connected to PostgreSQL");
+        Ok(PostgresStorage {
+            client,
+            insert_packet,
+            insert_position,
+        })
+    }
+
+    async fn insert(&mut self, record: &PacketRecord) -> Result<(), tokio_postgres::Error> {
+        let transaction = self.client.transaction().await?;
+        let row = transaction
+            .query_one(
+                &self.insert_packet,
+                &[
+                    &record.received,
+                    &record.port,
+                    &record.source,
+                    &record.destination,
+                    &record.path,
+                    &record.data_type,
+                    &record.information,
+                ],
+            )
+            .await?;
+        if let Some(pos) = &record.position {
+            let id: i64 = row.get(0);
+            transaction
+                .execute(
+                    &self.insert_position,
+                    &[
+                        &id,
+                        &record.received,
+                        &record.source,
+                        &pos.latitude,
+                        &pos.longitude,
+                        &pos.altitude,
+                        &pos.course.map(|c| c as i16),
+                        &pos.speed,
+                        &pos.symbol,
+                        &pos.comment,
+                    ],
+                )
+                .await?;
+        }
+        transaction.commit().await
+    }
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    async fn store(&mut self, record: &PacketRecord) -> Result<()> {
+        match self.insert(record).await {
+            Ok(()) => Ok(()),
+            // A row the server won't take leaves the connection usable
+            Err(e) if e.as_db_error().is_some() => {
+                warn!("PostgreSQL didn't store {}: {}", record.source, e);
+                Ok(())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! SQLite backend: packets in a local file, nothing else to run.
+
+use super::{PacketRecord, Storage, INDEXES};
+use crate::config::SqliteConfig;
+use crate::router::RoutedPacket;
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::SecondsFormat;
+use log::info;
+use rusqlite::{params, Connection};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+// Times are RFC 3339 in UTC with milliseconds, which sort as they compare
+const TABLES: &str = "\
+CREATE TABLE IF NOT EXISTS packets (
+    id INTEGER PRIMARY KEY,
+    received TEXT NOT NULL,
+    port TEXT NOT NULL,
+    source TEXT NOT NULL,
+    destination TEXT NOT NULL,
+    path TEXT NOT NULL,
+    data_type TEXT NOT NULL,
+    information TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS positions (
+    packet_id INTEGER PRIMARY KEY REFERENCES packets (id) ON DELETE CASCADE,
+    received TEXT NOT NULL,
+    source TEXT NOT NULL,
+    latitude REAL NOT NULL,
+    longitude REAL NOT NULL,
+    altitude REAL,
+    course INTEGER,
+    speed REAL,
+    symbol TEXT NOT NULL,
+    comment TEXT NOT NULL
+);
+";
+
+pub async fn run_sqlite(
+    config: SqliteConfig,
+    heard_rx: broadcast::Receiver<RoutedPacket>,
+) -> Result<()> {
+    super::run_storage(
+        "SQLite",
+        move || SqliteStorage::open(config.clone()),
+        heard_rx,
+    )
+    .await
+}
+
+pub struct SqliteStorage {
+    // Calls block, so they run on the blocking pool with the connection
+    db: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    /// Open the file and create the tables that aren't there yet
+    pub async fn open(config: SqliteConfig) -> Result<Self> {
+        let db = tokio::task::spawn_blocking(move || -> Result<Connection> {
+            let db = Connection::open(&config.file)?;
+            db.pragma_update(None, "journal_mode", "WAL")?;
+            db.execute_batch(&format!("{}{}", TABLES, INDEXES))?;
+            info!("Recording packets in {}", config.file);
+            Ok(db)
+        })
+        .await??;
+        Ok(SqliteStorage {
+            db: Arc::new(Mutex::new(db)),
+        })
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn store(&mut self, record: &PacketRecord) -> Result<()> {
+        let db = self.db.clone();
+        let record = record.clone();
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let mut db = db.lock().unwrap();
+            let transaction = db.transaction()?;
+            let received = record.received.to_rfc3339_opts(SecondsFormat::Millis, true);
+            transaction
+                .prepare_cached(
+                    "INSERT INTO packets (received, port, source, destination, path, data_type, information) \
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                )?
+                .execute(params![
+                    received,
+                    record.port,
+                    record.source,
+                    record.destination,
+                    record.path,
+                    record.data_type,
+                    record.information,
+                ])?;
+            if let Some(pos) = &record.position {
+                let id = transaction.last_insert_rowid();
+                transaction
+                    .prepare_cached(
+                        "INSERT INTO positions (packet_id, received, source, latitude, longitude, altitude, course, speed, symbol, comment) \
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                    )?
+                    .execute(params![
+                        id,
+                        received,
+                        record.source,
+                        pos.latitude,
+                        pos.longitude,
+                        pos.altitude,
+                        pos.course,
+                        pos.speed,
+                        pos.symbol,
+                        pos.comment,
+                    ])?;
+            }
+            transaction.commit()?;
+            Ok(())
+        })
+        .await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aprs::parse_packet;
+    use crate::filter::APRS_IS_PORT;
+    use crate::router::PacketSource;
+
+    fn record(packet: &str) -> PacketRecord {
+        PacketRecord::from(&RoutedPacket {
+            packet: parse_packet(packet).unwrap(),
+            source: PacketSource::AprsIs,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("packets.db").display().to_string();
+        let config = SqliteConfig { file: file.clone() };
+        let mut storage = SqliteStorage::open(config.clone()).await.unwrap();
+        storage
+            .store(&record("N0CALL-9>APRS:!3853.86N/07702.19W>088/036Mobile"))
+            .await
+            .unwrap();
+        storage.store(&record("N1CALL>APRS:>Status")).await.unwrap();
+        drop(storage);
+
+        // Opening again keeps what's there
+        SqliteStorage::open(config).await.unwrap();
+        let db = Connection::open(&file).unwrap();
+        let packets: i64 = db
+            .query_row("SELECT COUNT(*) FROM packets", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(packets, 2);
+        let (source, course, port): (String, u16, String) = db
+            .query_row(
+                "SELECT positions.source, course, port FROM positions JOIN packets ON packets.id = packet_id",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            (source.as_str(), course, port.as_str()),
+            ("N0CALL-9", 88, APRS_IS_PORT)
+        );
+    }
+}
@@ -0,0 +1,141 @@
+//! Restarting services that fail.
+//!
+//! Every long-running service is spawned through [`supervise`], which runs
+//! each attempt in a task of its own so a panic is caught like an error. A
+//! service that fails is logged and started again after a backoff that grows
+//! while it keeps failing quickly; a critical one that keeps failing shuts
+//! the daemon down instead. A service that returns cleanly is left stopped.
+
+use crate::shutdown::Shutdown;
+use anyhow::{anyhow, Result};
+use log::{error, info};
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// A run this long counts as healthy, so the next failure starts afresh
+const HEALTHY_RUN: Duration = Duration::from_secs(60);
+/// Quick failures in a row after which a critical service gives up
+const CRITICAL_FAILURES: u32 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Restart for as long as it takes, such as a TNC that's unplugged
+    Restart,
+    /// The daemon is no use without it; shut down if it keeps failing
+    Critical,
+}
+
+#[derive(Debug, Default)]
+struct Backoff {
+    failures: u32, // Quick failures in a row
+}
+
+impl Backoff {
+    /// Note a failure after running for `ran`, giving the wait before the
+    /// next attempt
+    fn failed(&mut self, ran: Duration) -> Duration {
+        if ran >= HEALTHY_RUN {
+            self.failures = 0;
+        }
+        self.failures += 1;
+        FIRST_BACKOFF
+            .saturating_mul(1 << (self.failures - 1).min(16))
+            .min(MAX_BACKOFF)
+    }
+}
+
+/// Spawn a service, calling `start` for each attempt
+pub fn supervise<F, Fut>(
+    name: impl Into<String>,
+    policy: Policy,
+    shutdown: Shutdown,
+    mut start: F,
+) -> JoinHandle<Result<()>>
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let name = name.into();
+    tokio::spawn(async move {
+        let mut backoff = Backoff::default();
+        loop {
+            let started = Instant::now();
+            let outcome = tokio::spawn(start()).await;
+            let failure = match outcome {
+                Ok(Ok(())) => {
+                    if !shutdown.is_triggered() {
+                        info!("{} finished", name);
+                    }
+                    return Ok(());
+                }
+                Ok(Err(e)) => e.to_string(),
+                Err(e) if e.is_panic() => "panicked".to_string(),
+                Err(e) => e.to_string(),
+            };
+            if shutdown.is_triggered() {
+                error!("{} failed while shutting down: {}", name, failure);
+                return Ok(());
+            }
+
+            let delay = backoff.failed(started.elapsed());
+            if policy == Policy::Critical && backoff.failures >= CRITICAL_FAILURES {
+                error!("{} failed: {}; giving up and shutting down", name, failure);
+                shutdown.trigger();
+                return Err(anyhow!("{} kept failing", name));
+            }
+            error!(
+                "{} failed: {}; restarting in {}s",
+                name,
+                failure,
+                delay.as_secs()
+            );
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = shutdown.wait() => return Ok(()),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::default();
+        let quick = Duration::from_secs(1);
+        assert_eq!(backoff.failed(quick), Duration::from_secs(1));
+        assert_eq!(backoff.failed(quick), Duration::from_secs(2));
+        assert_eq!(backoff.failed(quick), Duration::from_secs(4));
+        for _ in 0..20 {
+            backoff.failed(quick);
+        }
+        assert_eq!(backoff.failed(quick), MAX_BACKOFF);
+        // A long healthy run starts the backoff over
+        assert_eq!(backoff.failed(HEALTHY_RUN), FIRST_BACKOFF);
+        assert_eq!(backoff.failures, 1);
+    }
+
+    #[tokio::test]
+    async fn test_restarts_after_panic() {
+        let attempts = Arc::new(AtomicU32::new(0));
+        let counted = attempts.clone();
+        let handle = supervise("test", Policy::Critical, Shutdown::new(), move || {
+            let attempt = counted.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if attempt == 0 {
+                    panic!("first attempt");
+                }
+                Ok(())
+            }
+        });
+        handle.await.unwrap().unwrap();
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,17 +1,40 @@
 use crate::aprs::{AprsPacket, CallSign};
-use crate::config::TelemetryConfig;
+use crate::channel::{self, Overflow};
+use crate::config::{TelemetryChannel, TelemetryConfig};
+use crate::gps::{FixQuality, GpsTracker};
 use crate::router::{PacketSource, RoutedPacket};
+use crate::serial::queue::PortStats;
+use crate::template;
 use anyhow::Result;
-use log::info;
+use log::{info, warn};
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use tokio::sync::mpsc;
 
+const MAX_ANALOG_CHANNELS: usize = 5;
+
 pub struct TelemetryStats {
     pub packets_rx: AtomicU64,
     pub packets_tx: AtomicU64,
     pub packets_digipeated: AtomicU64,
     pub packets_igate_rf_to_is: AtomicU64,
     pub packets_igate_is_to_rf: AtomicU64,
+    /// Messages among the packets gated from APRS-IS to RF
+    pub messages_igate_is_to_rf: AtomicU64,
+    /// AX.25 frames that are not APRS UI frames (connected mode, other PIDs)
+    pub frames_non_aprs: AtomicU64,
+    /// KISS frames that could not be decoded as AX.25
+    pub frames_invalid: AtomicU64,
+    /// Packets dropped by the per-source rate limiter
+    pub packets_rate_limited: AtomicU64,
+    /// Packets with timestamps showing they were delayed
+    pub packets_stale: AtomicU64,
+    /// Times the APRS-IS connection was lost or failed and tried again
+    pub is_reconnects: AtomicU64,
+    /// Milliseconds for the last APRS-IS ping to come back, 0 if unknown
+    pub is_rtt_ms: AtomicU64,
+    pub is_bytes_rx: AtomicU64,
+    pub is_bytes_tx: AtomicU64,
 }
 
 pub static TELEMETRY_STATS: TelemetryStats = TelemetryStats {
@@ -20,11 +43,228 @@ pub static TELEMETRY_STATS: TelemetryStats = TelemetryStats {
     packets_digipeated: AtomicU64::new(0),
     packets_igate_rf_to_is: AtomicU64::new(0),
     packets_igate_is_to_rf: AtomicU64::new(0),
+    messages_igate_is_to_rf: AtomicU64::new(0),
+    frames_non_aprs: AtomicU64::new(0),
+    frames_invalid: AtomicU64::new(0),
+    packets_rate_limited: AtomicU64::new(0),
+    packets_stale: AtomicU64::new(0),
+    is_reconnects: AtomicU64::new(0),
+    is_rtt_ms: AtomicU64::new(0),
+    is_bytes_rx: AtomicU64::new(0),
+    is_bytes_tx: AtomicU64::new(0),
 };
 
+impl TelemetryChannel {
+    fn label(&self) -> &'static str {
+        match self {
+            TelemetryChannel::RxPackets => "RxPkts",
+            TelemetryChannel::TxPackets => "TxPkts",
+            TelemetryChannel::Digipeated => "Digi",
+            TelemetryChannel::RfToIs => "RF>IS",
+            TelemetryChannel::IsToRf => "IS>RF",
+            TelemetryChannel::ClockDrift => "Drift",
+            TelemetryChannel::TripDistance => "Trip",
+            TelemetryChannel::MaxSpeed => "MaxSpd",
+            TelemetryChannel::MovingTime => "Moving",
+            TelemetryChannel::Satellites => "Sats",
+            TelemetryChannel::Hdop => "HDOP",
+            TelemetryChannel::FixType => "Fix",
+            TelemetryChannel::NonAprs => "NonAPRS",
+            TelemetryChannel::Dropped => "Dropped",
+            TelemetryChannel::IsRtt => "ISRTT",
+            TelemetryChannel::IsReconnects => "Reconn",
+            TelemetryChannel::SerialErrors => "SerErr",
+        }
+    }
+
+    fn unit(&self) -> &'static str {
+        match self {
+            TelemetryChannel::ClockDrift => "Sec",
+            TelemetryChannel::TripDistance => "km",
+            TelemetryChannel::MaxSpeed => "kt",
+            TelemetryChannel::MovingTime => "Min",
+            TelemetryChannel::Satellites => "Sats",
+            TelemetryChannel::Hdop => "x0.1",
+            TelemetryChannel::FixType => "D",
+            TelemetryChannel::NonAprs => "Frms",
+            TelemetryChannel::IsRtt => "x10ms",
+            TelemetryChannel::IsReconnects => "Conn",
+            TelemetryChannel::SerialErrors => "Errs",
+            _ => "Pkts",
+        }
+    }
+
+    /// Counts that only go up, so can be reported as deltas
+    fn is_counter(&self) -> bool {
+        matches!(
+            self,
+            TelemetryChannel::RxPackets
+                | TelemetryChannel::TxPackets
+                | TelemetryChannel::Digipeated
+                | TelemetryChannel::RfToIs
+                | TelemetryChannel::IsToRf
+                | TelemetryChannel::NonAprs
+                | TelemetryChannel::Dropped
+                | TelemetryChannel::IsReconnects
+                | TelemetryChannel::SerialErrors
+        )
+    }
+
+    /// UNIT of a delta, which EQNS turns into a rate
+    fn rate_unit(&self) -> &'static str {
+        match self {
+            TelemetryChannel::NonAprs => "Frm/h",
+            TelemetryChannel::IsReconnects => "Conn/h",
+            TelemetryChannel::SerialErrors => "Err/h",
+            _ => "Pkt/h",
+        }
+    }
+
+    async fn value(&self, gps: Option<&GpsTracker>) -> u64 {
+        match self {
+            TelemetryChannel::RxPackets => TELEMETRY_STATS.packets_rx.load(Ordering::Relaxed),
+            TelemetryChannel::TxPackets => TELEMETRY_STATS.packets_tx.load(Ordering::Relaxed),
+            TelemetryChannel::Digipeated => {
+                TELEMETRY_STATS.packets_digipeated.load(Ordering::Relaxed)
+            }
+            TelemetryChannel::RfToIs => TELEMETRY_STATS
+                .packets_igate_rf_to_is
+                .load(Ordering::Relaxed),
+            TelemetryChannel::IsToRf => TELEMETRY_STATS
+                .packets_igate_is_to_rf
+                .load(Ordering::Relaxed),
+            TelemetryChannel::NonAprs => TELEMETRY_STATS.frames_non_aprs.load(Ordering::Relaxed),
+            TelemetryChannel::Dropped => channel::total_drops(),
+            TelemetryChannel::IsRtt => {
+                (TELEMETRY_STATS.is_rtt_ms.load(Ordering::Relaxed) / 10).min(255)
+            }
+            TelemetryChannel::IsReconnects => TELEMETRY_STATS.is_reconnects.load(Ordering::Relaxed),
+            TelemetryChannel::SerialErrors => PortStats::all()
+                .iter()
+                .map(|(_, stats)| stats.errors.load(Ordering::Relaxed))
+                .sum(),
+            TelemetryChannel::ClockDrift => match gps {
+                Some(gps) => drift_value(gps.clock_drift().await),
+                None => drift_value(None),
+            },
+            TelemetryChannel::TripDistance => match gps {
+                Some(gps) => (gps.trip_stats().await.distance_km as u64).min(255),
+                None => 0,
+            },
+            TelemetryChannel::MaxSpeed => match gps {
+                Some(gps) => (gps.trip_stats().await.max_speed as u64).min(255),
+                None => 0,
+            },
+            TelemetryChannel::MovingTime => match gps {
+                Some(gps) => (gps.trip_stats().await.moving_secs / 60).min(255),
+                None => 0,
+            },
+            TelemetryChannel::Satellites | TelemetryChannel::Hdop | TelemetryChannel::FixType => {
+                let pos = match gps {
+                    Some(gps) => gps.get_current_position().await,
+                    None => None,
+                };
+                let Some(pos) = pos else {
+                    return 0;
+                };
+                match self {
+                    TelemetryChannel::Satellites => pos.satellites.unwrap_or(0) as u64,
+                    TelemetryChannel::Hdop => pos
+                        .hdop
+                        .map(|h| ((h * 10.0).round() as u64).min(255))
+                        .unwrap_or(0),
+                    _ => match pos.fix {
+                        FixQuality::NoFix => 0,
+                        FixQuality::Fix2D => 2,
+                        FixQuality::Fix3D => 3,
+                    },
+                }
+            }
+        }
+    }
+}
+
+fn format_telemetry(sequence: u32, values: &[u64], wide: bool) -> String {
+    let mut data = format!("T#{:03}", sequence % 1000);
+    for i in 0..MAX_ANALOG_CHANNELS {
+        let value = values.get(i).copied().unwrap_or(0);
+        if wide {
+            data.push_str(&format!(",{:03}", value));
+        } else {
+            data.push_str(&format!(",{:03}", (value % 256) as u8));
+        }
+    }
+    data.push_str(",00000000");
+    data
+}
+
+/// How a channel is reported
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Report {
+    channel: TelemetryChannel,
+    delta: bool,
+}
+
+impl Report {
+    fn unit(&self) -> &'static str {
+        if self.delta {
+            self.channel.rate_unit()
+        } else {
+            self.channel.unit()
+        }
+    }
+
+    /// EQNS coefficients: values as sent, except deltas, which are scaled
+    /// to an hourly rate so graphs don't depend on the interval, and clock
+    /// drift, which is sent offset by DRIFT_BIAS to keep its sign
+    fn equation(&self, interval: u32) -> String {
+        if self.channel == TelemetryChannel::ClockDrift {
+            return format!("0,1,-{}", DRIFT_BIAS);
+        }
+        if !self.delta {
+            return "0,1,0".to_string();
+        }
+        let scale = format!("{:.3}", 3600.0 / interval.max(1) as f64);
+        let scale = scale.trim_end_matches('0').trim_end_matches('.');
+        format!("0,{},0", scale)
+    }
+}
+
+/// Clock drift is sent as DRIFT_BIAS plus the seconds GPS is ahead, so a
+/// slow system clock reads above it and a fast one below
+const DRIFT_BIAS: i64 = 128;
+
+/// The value for a clock drift, clamped to 8 bits; no drift if unknown
+fn drift_value(drift: Option<chrono::Duration>) -> u64 {
+    let seconds = drift.map_or(0, |d| d.num_seconds());
+    (DRIFT_BIAS + seconds).clamp(0, 255) as u64
+}
+
+/// Turn readings into the values to send: deltas count from the last
+/// reading, and in 8 bits they stop at 255 rather than wrap
+fn report_values(reports: &[Report], readings: &[u64], last: &[u64], wide: bool) -> Vec<u64> {
+    reports
+        .iter()
+        .zip(readings)
+        .zip(last)
+        .map(|((report, &reading), &last)| {
+            if !report.delta {
+                return reading;
+            }
+            let delta = reading.saturating_sub(last);
+            if wide {
+                delta
+            } else {
+                delta.min(255)
+            }
+        })
+        .collect()
+}
+
 pub async fn run_telemetry(
     config: TelemetryConfig,
     mycall: String,
+    gps: Option<Arc<GpsTracker>>,
     tx: mpsc::Sender<RoutedPacket>,
 ) -> Result<()> {
     info!(
@@ -32,53 +272,79 @@ pub async fn run_telemetry(
         config.interval
     );
 
+    let mut channels = config.channels.clone();
+    if channels.len() > MAX_ANALOG_CHANNELS {
+        warn!(
+            "Telemetry supports {} analog channels, ignoring the rest",
+            MAX_ANALOG_CHANNELS
+        );
+        channels.truncate(MAX_ANALOG_CHANNELS);
+    }
+    let reports: Vec<Report> = channels
+        .iter()
+        .map(|&channel| {
+            let delta = config.deltas.contains(&channel);
+            if delta && !channel.is_counter() {
+                warn!(
+                    "Telemetry channel {} isn't a counter, reporting it as is",
+                    channel.label()
+                );
+            }
+            Report {
+                channel,
+                delta: delta && channel.is_counter(),
+            }
+        })
+        .collect();
+
     let mut interval =
         tokio::time::interval(tokio::time::Duration::from_secs(config.interval as u64));
     let mut sequence = 0u32;
+    // Counters start from zero, so the first delta covers startup onwards
+    let mut last = vec![0; channels.len()];
 
     loop {
         interval.tick().await;
 
         // Read statistics
-        let rx_count = TELEMETRY_STATS.packets_rx.load(Ordering::Relaxed);
-        let tx_count = TELEMETRY_STATS.packets_tx.load(Ordering::Relaxed);
-        let digi_count = TELEMETRY_STATS.packets_digipeated.load(Ordering::Relaxed);
-        let rf_to_is = TELEMETRY_STATS
-            .packets_igate_rf_to_is
-            .load(Ordering::Relaxed);
-        let is_to_rf = TELEMETRY_STATS
-            .packets_igate_is_to_rf
-            .load(Ordering::Relaxed);
+        let mut readings = Vec::with_capacity(channels.len());
+        for channel in &channels {
+            readings.push(channel.value(gps.as_deref()).await);
+        }
+        let values = report_values(&reports, &readings, &last, config.wide);
+        last = readings;
 
         // Create telemetry packet
-        let telem_data = format!(
-            "T#{:03},{:03},{:03},{:03},{:03},{:03},00000000",
-            sequence % 1000,
-            (rx_count % 256) as u8,
-            (tx_count % 256) as u8,
-            (digi_count % 256) as u8,
-            (rf_to_is % 256) as u8,
-            (is_to_rf % 256) as u8
-        );
+        let telem_data = format_telemetry(sequence, &values, config.wide);
 
         let source = CallSign::parse(&mycall).unwrap_or(CallSign::new("N0CALL", 0));
         let packet = AprsPacket::new(source, CallSign::new("APRS", 0), telem_data);
 
-        info!(
-            "Sending telemetry: RX={}, TX={}, Digi={}, RF>IS={}, IS>RF={}",
-            rx_count, tx_count, digi_count, rf_to_is, is_to_rf
-        );
+        let summary: Vec<String> = channels
+            .iter()
+            .zip(&values)
+            .map(|(c, v)| format!("{}={}", c.label(), v))
+            .collect();
+        info!("Sending telemetry: {}", summary.join(", "));
 
         let routed = RoutedPacket {
             packet,
             source: PacketSource::Internal,
         };
 
-        let _ = tx.send(routed).await;
+        channel::send(&tx, routed, channel::ROUTER, Overflow::Drop).await;
 
         // Send telemetry labels every 10 sequences
-        if sequence % 10 == 0 {
-            let labels = format!(":{:<9}:PARM.RxPkts,TxPkts,Digi,RF>IS,IS>RF", mycall);
+        if sequence.is_multiple_of(10) {
+            let labels = format!(
+                ":{:<9}:PARM.{}",
+                mycall,
+                channels
+                    .iter()
+                    .map(|c| c.label())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            );
 
             let label_packet = AprsPacket::new(
                 CallSign::parse(&mycall).unwrap_or(CallSign::new("N0CALL", 0)),
@@ -91,10 +357,18 @@ pub async fn run_telemetry(
                 source: PacketSource::Internal,
             };
 
-            let _ = tx.send(routed_labels).await;
+            channel::send(&tx, routed_labels, channel::ROUTER, Overflow::Drop).await;
 
             // Send units
-            let units = format!(":{:<9}:UNIT.Pkts,Pkts,Pkts,Pkts,Pkts", mycall);
+            let units = format!(
+                ":{:<9}:UNIT.{}",
+                mycall,
+                reports
+                    .iter()
+                    .map(|r| r.unit())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            );
 
             let unit_packet = AprsPacket::new(
                 CallSign::parse(&mycall).unwrap_or(CallSign::new("N0CALL", 0)),
@@ -107,12 +381,37 @@ pub async fn run_telemetry(
                 source: PacketSource::Internal,
             };
 
-            let _ = tx.send(routed_units).await;
+            channel::send(&tx, routed_units, channel::ROUTER, Overflow::Drop).await;
+
+            // Send equations
+            let equations = format!(
+                ":{:<9}:EQNS.{}",
+                mycall,
+                reports
+                    .iter()
+                    .map(|r| r.equation(config.interval))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            );
+
+            let equation_packet = AprsPacket::new(
+                CallSign::parse(&mycall).unwrap_or(CallSign::new("N0CALL", 0)),
+                CallSign::new("APRS", 0),
+                equations,
+            );
+
+            let routed_equations = RoutedPacket {
+                packet: equation_packet,
+                source: PacketSource::Internal,
+            };
+
+            channel::send(&tx, routed_equations, channel::ROUTER, Overflow::Drop).await;
         }
 
         // Also send a status message
         if !config.comment.is_empty() {
-            let status = format!(">aprstx {}", config.comment);
+            let comment = template::expand(&config.comment, gps.as_deref()).await;
+            let status = format!(">aprstx {}", comment);
             let status_packet = AprsPacket::new(
                 CallSign::parse(&mycall).unwrap_or(CallSign::new("N0CALL", 0)),
                 CallSign::new("APRS", 0),
@@ -124,9 +423,95 @@ pub async fn run_telemetry(
                 source: PacketSource::Internal,
             };
 
-            let _ = tx.send(routed_status).await;
+            channel::send(&tx, routed_status, channel::ROUTER, Overflow::Drop).await;
         }
 
         sequence = sequence.wrapping_add(1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_telemetry() {
+        assert_eq!(
+            format_telemetry(7, &[1, 2, 3, 4, 5], false),
+            "T#007,001,002,003,004,005,00000000"
+        );
+
+        // Missing channels are zero-filled and values wrap at 256
+        assert_eq!(
+            format_telemetry(1001, &[300, 12], false),
+            "T#001,044,012,000,000,000,00000000"
+        );
+        // unless written in full
+        assert_eq!(
+            format_telemetry(1001, &[300, 12], true),
+            "T#001,300,012,000,000,000,00000000"
+        );
+    }
+
+    #[test]
+    fn test_delta_reports() {
+        let reports = [
+            Report {
+                channel: TelemetryChannel::RxPackets,
+                delta: true,
+            },
+            Report {
+                channel: TelemetryChannel::TxPackets,
+                delta: false,
+            },
+        ];
+        assert_eq!(
+            report_values(&reports, &[1300, 1300], &[1000, 1000], true),
+            [300, 1300]
+        );
+        // Too many for 8 bits stops at 255 instead of wrapping
+        assert_eq!(
+            report_values(&reports, &[1300, 1300], &[1000, 1000], false),
+            [255, 1300]
+        );
+
+        assert_eq!(reports[0].equation(1200), "0,3,0");
+        assert_eq!(reports[0].equation(7200), "0,0.5,0");
+        assert_eq!(reports[0].equation(700), "0,5.143,0");
+        assert_eq!(reports[1].equation(1200), "0,1,0");
+        assert_eq!(reports[0].unit(), "Pkt/h");
+        assert_eq!(reports[1].unit(), "Pkts");
+    }
+
+    #[tokio::test]
+    async fn test_clock_drift_channel_without_gps() {
+        assert_eq!(TelemetryChannel::ClockDrift.value(None).await, 128);
+        assert_eq!(TelemetryChannel::ClockDrift.unit(), "Sec");
+    }
+
+    #[test]
+    fn test_clock_drift_keeps_its_sign() {
+        let seconds = |s| Some(chrono::Duration::seconds(s));
+        assert_eq!(drift_value(seconds(5)), 133);
+        // System clock ahead of GPS
+        assert_eq!(drift_value(seconds(-5)), 123);
+        assert_eq!(drift_value(seconds(-600)), 0);
+        assert_eq!(drift_value(seconds(600)), 255);
+        let report = Report {
+            channel: TelemetryChannel::ClockDrift,
+            delta: false,
+        };
+        assert_eq!(report.equation(1200), "0,1,-128");
+    }
+
+    #[tokio::test]
+    async fn test_gps_health_channels() {
+        let pos = crate::gps::parse_fixed_position("40.7128,-74.0060,10").unwrap();
+        let gps = GpsTracker::new(crate::gps::GpsSource::Fixed(pos));
+
+        assert_eq!(TelemetryChannel::FixType.value(Some(&gps)).await, 3);
+        // A surveyed position has no satellite data
+        assert_eq!(TelemetryChannel::Satellites.value(Some(&gps)).await, 0);
+        assert_eq!(TelemetryChannel::Hdop.value(None).await, 0);
+    }
+}
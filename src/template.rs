@@ -0,0 +1,153 @@
+//! Variables in comments.
+//!
+//! Beacon, status and telemetry comments can carry `{name}` variables,
+//! filled in each time the packet is sent:
+//!
+//! | Variable    | Value                                         |
+//! |-------------|-----------------------------------------------|
+//! | `{voltage}` | Supply voltage from the kernel, as `13.8V`    |
+//! | `{temp}`    | CPU temperature from the kernel, as `47.2C`   |
+//! | `{sats}`    | Satellites the GPS is using                   |
+//! | `{uptime}`  | How long aprstx has run, as `3d4h` or `25m`   |
+//! | `{pkt_rx}`  | Packets received since starting               |
+//! | `{grid}`    | Our Maidenhead locator, from the GPS          |
+//!
+//! A variable with nothing to report, such as `{voltage}` on a machine
+//! without a supply monitor, comes out empty. Anything else in braces is
+//! left as it is.
+
+use crate::aprs::grid::grid_square;
+use crate::gps::GpsTracker;
+use crate::telemetry::TELEMETRY_STATS;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+/// Note when aprstx started, for `{uptime}`
+pub fn start() {
+    STARTED.get_or_init(Instant::now);
+}
+
+fn uptime() -> Duration {
+    STARTED.get_or_init(Instant::now).elapsed()
+}
+
+/// A comment with its variables filled in
+pub async fn expand(template: &str, gps: Option<&GpsTracker>) -> String {
+    if !template.contains('{') {
+        return template.to_string();
+    }
+    let position = match gps {
+        Some(gps) if template.contains("{sats}") || template.contains("{grid}") => {
+            gps.get_current_position().await
+        }
+        _ => None,
+    };
+    render(template, |name| {
+        Some(match name {
+            "voltage" => supply_voltage().map(|v| format!("{:.1}V", v)),
+            "temp" => cpu_temperature().map(|t| format!("{:.1}C", t)),
+            "sats" => position.and_then(|p| p.satellites).map(|s| s.to_string()),
+            "uptime" => Some(format_uptime(uptime())),
+            "pkt_rx" => Some(
+                TELEMETRY_STATS
+                    .packets_rx
+                    .load(Ordering::Relaxed)
+                    .to_string(),
+            ),
+            "grid" => position.map(|p| grid_square(p.latitude, p.longitude, 6)),
+            _ => return None,
+        })
+    })
+}
+
+/// Replace each `{name}` that `lookup` knows, with nothing where it knows
+/// the name but has no value
+fn render(template: &str, lookup: impl Fn(&str) -> Option<Option<String>>) -> String {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        out.push_str(&rest[..open]);
+        let after = &rest[open + 1..];
+        let known = after
+            .find('}')
+            .and_then(|close| Some((close, lookup(&after[..close])?)));
+        match known {
+            Some((close, value)) => {
+                out.push_str(&value.unwrap_or_default());
+                rest = &after[close + 1..];
+            }
+            None => {
+                out.push('{');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn format_uptime(uptime: Duration) -> String {
+    let minutes = uptime.as_secs() / 60;
+    let (days, hours, minutes) = (minutes / 1440, minutes / 60 % 24, minutes % 60);
+    if days > 0 {
+        format!("{}d{}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h{}m", hours, minutes)
+    } else {
+        format!("{}m", minutes)
+    }
+}
+
+/// Volts from the first power supply the kernel reports one for
+fn supply_voltage() -> Option<f64> {
+    let supplies = std::fs::read_dir("/sys/class/power_supply").ok()?;
+    supplies
+        .flatten()
+        .find_map(|supply| read_number(&supply.path().join("voltage_now")))
+        .map(|microvolts| microvolts / 1_000_000.0)
+}
+
+/// Degrees Celsius from the first thermal zone
+fn cpu_temperature() -> Option<f64> {
+    read_number(Path::new("/sys/class/thermal/thermal_zone0/temp")).map(|millis| millis / 1000.0)
+}
+
+fn read_number(path: &Path) -> Option<f64> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let lookup = |name: &str| match name {
+            "sats" => Some(Some("7".to_string())),
+            "voltage" => Some(None),
+            _ => None,
+        };
+        assert_eq!(render("{sats} sats", lookup), "7 sats");
+        assert_eq!(render("Batt {voltage}", lookup), "Batt ");
+        assert_eq!(render("{nope} {sats} {", lookup), "{nope} 7 {");
+        assert_eq!(render("{{sats}}", lookup), "{7}");
+    }
+
+    #[test]
+    fn test_format_uptime() {
+        assert_eq!(format_uptime(Duration::from_secs(25 * 60 + 59)), "25m");
+        assert_eq!(format_uptime(Duration::from_secs(5 * 3600 + 120)), "5h2m");
+        assert_eq!(format_uptime(Duration::from_secs(76 * 3600)), "3d4h");
+    }
+
+    #[tokio::test]
+    async fn test_expand_without_gps() {
+        assert_eq!(expand("Hilltop digi", None).await, "Hilltop digi");
+        assert_eq!(expand("{grid} {sats}", None).await, " ");
+        assert!(expand("Up {uptime}", None).await.ends_with('m'));
+    }
+}
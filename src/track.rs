@@ -0,0 +1,233 @@
+//! Quick-start tracker mode.
+//!
+//! `aprstx track` runs a mobile tracker from command line flags alone: a
+//! KISS TNC, a GPS and a callsign are enough. The flags are turned into an
+//! ordinary configuration with SmartBeaconing switched on.
+
+use crate::aprs::CallSign;
+use crate::config::Config;
+use crate::gps::parse_fixed_position;
+use anyhow::{anyhow, Result};
+use clap::Args;
+
+#[derive(Args, Debug, Clone)]
+pub struct TrackOptions {
+    /// Callsign to beacon as, e.g. N0CALL-9
+    #[arg(long)]
+    pub call: String,
+
+    /// Serial device of the TNC
+    #[arg(long)]
+    pub tnc: String,
+
+    /// TNC baud rate
+    #[arg(long, default_value_t = 9600)]
+    pub baud: u32,
+
+    /// TNC protocol: kiss, tnc2, lora or lora_kiss
+    #[arg(long, default_value = "kiss")]
+    pub protocol: String,
+
+    /// "gpsd", "gpsd:HOST[:PORT]", an NMEA serial device, or a fixed "lat,lon"
+    #[arg(long, default_value = "gpsd")]
+    pub gps: String,
+
+    /// Baud rate of a serial GPS
+    #[arg(long, default_value_t = 4800)]
+    pub gps_baud: u32,
+
+    /// Symbol table and symbol
+    #[arg(long, default_value = "/>")]
+    pub symbol: String,
+
+    /// Digipeater path
+    #[arg(long, default_value = "WIDE1-1,WIDE2-1")]
+    pub path: String,
+
+    /// Beacon comment
+    #[arg(long, default_value = "")]
+    pub comment: String,
+}
+
+/// A TOML string, quoted and escaped
+fn quote(s: &str) -> String {
+    toml::Value::String(s.to_string()).to_string()
+}
+
+impl TrackOptions {
+    pub fn config(&self) -> Result<Config> {
+        let call = CallSign::parse(&self.call)
+            .ok_or_else(|| anyhow!("Invalid callsign: {}", self.call))?
+            .to_string();
+        let mut symbol = self.symbol.chars();
+        let (Some(table), Some(symbol), None) = (symbol.next(), symbol.next(), symbol.next())
+        else {
+            return Err(anyhow!(
+                "Symbol must be a table and a symbol, like \"/>\", not {:?}",
+                self.symbol
+            ));
+        };
+
+        let toml = format!(
+            r#"
+mycall = {call}
+filters = []
+
+[[serial_ports]]
+name = "radio"
+device = {tnc}
+baud_rate = {baud}
+protocol = {protocol}
+tx_enable = true
+rx_enable = true
+
+[digipeater]
+enabled = false
+mycall = {call}
+aliases = []
+viscous_delay = 0
+max_hops = 2
+
+[telemetry]
+enabled = false
+interval = 1200
+comment = ""
+
+{gps}
+
+[beacon]
+enabled = true
+callsign = {call}
+interval = 1800
+path = {path}
+symbol_table = {table}
+symbol = {symbol}
+comment = {comment}
+timestamp = false
+
+[beacon.smart_beacon]
+enabled = true
+check_interval = 5
+min_interval = 30
+stationary_interval = 1800
+low_speed = 5
+low_speed_interval = 900
+high_speed = 60
+high_speed_interval = 180
+turn_angle = 28
+turn_speed = 5
+"#,
+            call = quote(&call),
+            tnc = quote(&self.tnc),
+            baud = self.baud,
+            protocol = quote(&self.protocol),
+            gps = self.gps_section()?,
+            path = quote(&self.path),
+            table = quote(&table.to_string()),
+            symbol = quote(&symbol.to_string()),
+            comment = quote(&self.comment),
+        );
+        let mut config: Config =
+            toml::from_str(&toml).map_err(|e| anyhow!("Invalid tracker settings: {}", e))?;
+        config.fill_callsigns();
+        Ok(config)
+    }
+
+    fn gps_section(&self) -> Result<String> {
+        let gps = self.gps.as_str();
+        if let Some(rest) = gps
+            .strip_prefix("gpsd")
+            .filter(|r| r.is_empty() || r.starts_with(':'))
+        {
+            let mut parts = rest.trim_start_matches(':').splitn(2, ':');
+            let host = parts
+                .next()
+                .filter(|h| !h.is_empty())
+                .unwrap_or("localhost");
+            let port: u16 = match parts.next() {
+                Some(port) => port
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid gpsd port in {}", gps))?,
+                None => 2947,
+            };
+            return Ok(format!(
+                "[gps]\ntype = \"gpsd\"\nhost = {}\nport = {}",
+                quote(host),
+                port
+            ));
+        }
+        if gps.starts_with('/') {
+            return Ok(format!(
+                "[gps]\ntype = \"serial\"\ndevice = {}\nbaud_rate = {}",
+                quote(gps),
+                self.gps_baud
+            ));
+        }
+        parse_fixed_position(gps)
+            .map_err(|_| anyhow!("GPS must be gpsd, a serial device or lat,lon: {}", gps))?;
+        Ok(format!(
+            "[gps]\ntype = \"fixed\"\nposition = {}",
+            quote(gps)
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(gps: &str) -> TrackOptions {
+        TrackOptions {
+            call: "n0call-9".to_string(),
+            tnc: "/dev/ttyUSB0".to_string(),
+            baud: 9600,
+            protocol: "kiss".to_string(),
+            gps: gps.to_string(),
+            gps_baud: 4800,
+            symbol: "/>".to_string(),
+            path: "WIDE1-1,WIDE2-1".to_string(),
+            comment: "On the \"road\"".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_tracker_config() {
+        let config = options("gpsd").config().unwrap();
+        assert_eq!(config.mycall, "N0CALL-9");
+        assert_eq!(config.serial_ports[0].device, "/dev/ttyUSB0");
+        let beacon = config.beacon.unwrap();
+        assert!(beacon.enabled && beacon.smart_beacon.enabled);
+        assert_eq!(beacon.symbol, '>');
+        assert_eq!(beacon.comment, "On the \"road\"");
+        let gps = config.gps.unwrap();
+        assert_eq!(gps.gps_type, "gpsd");
+        assert_eq!(gps.port, Some(2947));
+    }
+
+    #[test]
+    fn test_gps_sources() {
+        let gps = options("gpsd:pi.local:3000").config().unwrap().gps.unwrap();
+        assert_eq!(gps.host.as_deref(), Some("pi.local"));
+        assert_eq!(gps.port, Some(3000));
+
+        let gps = options("/dev/ttyACM0").config().unwrap().gps.unwrap();
+        assert_eq!(gps.gps_type, "serial");
+        assert_eq!(gps.baud_rate, Some(4800));
+
+        let gps = options("40.7128,-74.0060").config().unwrap().gps.unwrap();
+        assert_eq!(gps.gps_type, "fixed");
+
+        assert!(options("somewhere").config().is_err());
+    }
+
+    #[test]
+    fn test_bad_options() {
+        let mut bad = options("gpsd");
+        bad.symbol = ">".to_string();
+        assert!(bad.config().is_err());
+
+        let mut bad = options("gpsd");
+        bad.protocol = "morse".to_string();
+        assert!(bad.config().is_err());
+    }
+}
@@ -0,0 +1,310 @@
+//! Track log: where we, and stations we watch, have been.
+//!
+//! Our own GPS position is recorded every `interval` while it keeps
+//! moving, and the position reports of listed stations as they are heard.
+//! The control socket exports a track as GPX or KML for mapping software:
+//! `track gpx` for ours, `track kml N0CALL-9` for a station's.
+
+use crate::channel;
+use crate::config::TrackLogConfig;
+use crate::gps::{distance_km, GpsTracker};
+use crate::router::RoutedPacket;
+use anyhow::Result;
+use chrono::{DateTime, SecondsFormat, Utc};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// Drops counted against the track log when it falls behind
+const TRACK_LOG_CHANNEL: &str = "track_log";
+
+lazy_static! {
+    pub static ref TRACKS: TrackLog = TrackLog::default();
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackPoint {
+    pub time: DateTime<Utc>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: Option<f32>, // metres
+}
+
+#[derive(Default)]
+pub struct TrackLog {
+    own: Mutex<Option<String>>, // Our callsign, once recording has started
+    max_points: AtomicUsize,
+    tracks: Mutex<HashMap<String, VecDeque<TrackPoint>>>,
+}
+
+impl TrackLog {
+    /// Add a point to a station's track, dropping the oldest when full.
+    /// A point where the station already was is only kept once `spacing`
+    /// has passed, so a parked station doesn't fill its track.
+    pub fn record(&self, call: &str, point: TrackPoint, spacing: chrono::Duration) {
+        let max = match self.max_points.load(Ordering::Relaxed) {
+            0 => usize::MAX,
+            max => max,
+        };
+        let mut tracks = self.tracks.lock().unwrap();
+        let track = tracks.entry(call.to_string()).or_default();
+        if let Some(last) = track.back() {
+            let same_place = last.latitude == point.latitude && last.longitude == point.longitude;
+            if same_place && point.time - last.time < spacing {
+                return;
+            }
+        }
+        while track.len() >= max {
+            track.pop_front();
+        }
+        track.push_back(point);
+    }
+
+    /// A copy of one station's track, ours if `call` is None
+    pub fn track(&self, call: Option<&str>) -> Option<(String, Vec<TrackPoint>)> {
+        let call = match call {
+            Some(call) => call.to_ascii_uppercase(),
+            None => self.own.lock().unwrap().clone()?,
+        };
+        let tracks = self.tracks.lock().unwrap();
+        let points = tracks.get(&call)?.iter().cloned().collect();
+        Some((call, points))
+    }
+
+    /// Each recorded track and how many points it has
+    pub fn counts(&self) -> Vec<(String, usize)> {
+        let tracks = self.tracks.lock().unwrap();
+        let mut counts: Vec<_> = tracks
+            .iter()
+            .map(|(call, track)| (call.clone(), track.len()))
+            .collect();
+        counts.sort();
+        counts
+    }
+
+    /// Forget one station's track, or all of them
+    pub fn clear(&self, call: Option<&str>) {
+        let mut tracks = self.tracks.lock().unwrap();
+        match call {
+            Some(call) => {
+                tracks.remove(&call.to_ascii_uppercase());
+            }
+            None => tracks.clear(),
+        }
+    }
+}
+
+pub async fn run_track_log(
+    config: TrackLogConfig,
+    mycall: String,
+    gps: Option<Arc<GpsTracker>>,
+    mut heard_rx: broadcast::Receiver<RoutedPacket>,
+) -> Result<()> {
+    let mycall = mycall.to_ascii_uppercase();
+    *TRACKS.own.lock().unwrap() = Some(mycall.clone());
+    TRACKS
+        .max_points
+        .store(config.max_points, Ordering::Relaxed);
+    let watched: Vec<String> = config
+        .stations
+        .iter()
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    let spacing = chrono::Duration::seconds(config.interval as i64);
+    info!(
+        "Recording track{}{}",
+        if gps.is_some() { " of our GPS" } else { "" },
+        if watched.is_empty() {
+            String::new()
+        } else {
+            format!(" and {}", watched.join(", "))
+        }
+    );
+
+    let mut timer = tokio::time::interval(Duration::from_secs(config.interval.max(1) as u64));
+    let mut last: Option<[f64; 2]> = None;
+    loop {
+        tokio::select! {
+            _ = timer.tick(), if gps.is_some() => {
+                let Some(gps) = &gps else { continue };
+                let Some(pos) = gps.get_current_position().await else { continue };
+                if !pos.has_fix() {
+                    continue;
+                }
+                let here = [pos.latitude, pos.longitude];
+                if last.is_some_and(|l| distance_km(l[0], l[1], here[0], here[1]) < config.min_move) {
+                    continue;
+                }
+                last = Some(here);
+                TRACKS.record(
+                    &mycall,
+                    TrackPoint {
+                        time: Utc::now(),
+                        latitude: pos.latitude,
+                        longitude: pos.longitude,
+                        altitude: pos.altitude,
+                    },
+                    spacing,
+                );
+            }
+
+            result = heard_rx.recv(), if !watched.is_empty() => {
+                let routed = match result {
+                    Ok(routed) => routed,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("Track log fell behind, dropped {} packets", missed);
+                        channel::record_drops(TRACK_LOG_CHANNEL, missed);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                };
+                let call = routed.packet.source.to_string().to_ascii_uppercase();
+                let base = call.split('-').next().unwrap_or_default();
+                if !watched.iter().any(|w| *w == call || w == base) {
+                    continue;
+                }
+                let Some(pos) = routed.packet.position() else { continue };
+                TRACKS.record(
+                    &call,
+                    TrackPoint {
+                        time: Utc::now(),
+                        latitude: pos.latitude,
+                        longitude: pos.longitude,
+                        altitude: pos.altitude,
+                    },
+                    spacing,
+                );
+            }
+        }
+    }
+}
+
+/// A track as a GPX 1.1 document
+pub fn gpx(name: &str, points: &[TrackPoint]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    let _ = writeln!(
+        out,
+        r#"<gpx version="1.1" creator="aprstx {}" xmlns="http://www.topografix.com/GPX/1/1">"#,
+        env!("CARGO_PKG_VERSION")
+    );
+    let _ = writeln!(out, "<trk><name>{}</name><trkseg>", xml_escape(name));
+    for point in points {
+        let _ = write!(
+            out,
+            r#"<trkpt lat="{:.6}" lon="{:.6}">"#,
+            point.latitude, point.longitude
+        );
+        if let Some(altitude) = point.altitude {
+            let _ = write!(out, "<ele>{:.1}</ele>", altitude);
+        }
+        let _ = write!(
+            out,
+            "<time>{}</time>",
+            point.time.to_rfc3339_opts(SecondsFormat::Secs, true)
+        );
+        let _ = writeln!(out, "</trkpt>");
+    }
+    let _ = writeln!(out, "</trkseg></trk>");
+    let _ = writeln!(out, "</gpx>");
+    out
+}
+
+/// A track as a KML document with one line string
+pub fn kml(name: &str, points: &[TrackPoint]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    let _ = writeln!(out, r#"<kml xmlns="http://www.opengis.net/kml/2.2">"#);
+    let _ = writeln!(
+        out,
+        "<Document><name>{0}</name><Placemark><name>{0}</name>",
+        xml_escape(name)
+    );
+    if let (Some(first), Some(last)) = (points.first(), points.last()) {
+        let _ = writeln!(
+            out,
+            "<TimeSpan><begin>{}</begin><end>{}</end></TimeSpan>",
+            first.time.to_rfc3339_opts(SecondsFormat::Secs, true),
+            last.time.to_rfc3339_opts(SecondsFormat::Secs, true)
+        );
+    }
+    let _ = writeln!(out, "<LineString><tessellate>1</tessellate><coordinates>");
+    for point in points {
+        // KML coordinates are lon,lat[,alt]
+        let _ = write!(out, "{:.6},{:.6}", point.longitude, point.latitude);
+        if let Some(altitude) = point.altitude {
+            let _ = write!(out, ",{:.1}", altitude);
+        }
+        let _ = writeln!(out);
+    }
+    let _ = writeln!(out, "</coordinates></LineString></Placemark></Document>");
+    let _ = writeln!(out, "</kml>");
+    out
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn point(minute: u32, lat: f64) -> TrackPoint {
+        TrackPoint {
+            time: Utc.with_ymd_and_hms(2026, 10, 14, 12, minute, 0).unwrap(),
+            latitude: lat,
+            longitude: -77.0365,
+            altitude: Some(12.0),
+        }
+    }
+
+    #[test]
+    fn test_record() {
+        let log = TrackLog::default();
+        log.max_points.store(3, Ordering::Relaxed);
+        let spacing = chrono::Duration::minutes(5);
+        log.record("N0CALL-9", point(0, 38.0), spacing);
+        // Parked: the same place again is skipped until the spacing is up
+        log.record("N0CALL-9", point(1, 38.0), spacing);
+        log.record("N0CALL-9", point(6, 38.0), spacing);
+        log.record("N0CALL-9", point(7, 38.1), spacing);
+        log.record("N0CALL-9", point(8, 38.2), spacing);
+        let (call, track) = log.track(Some("n0call-9")).unwrap();
+        assert_eq!(call, "N0CALL-9");
+        let minutes: Vec<_> = track
+            .iter()
+            .map(|p| p.time.format("%M").to_string())
+            .collect();
+        assert_eq!(minutes, ["06", "07", "08"]);
+        assert_eq!(log.track(None), None);
+        assert_eq!(log.counts(), [("N0CALL-9".to_string(), 3)]);
+        log.clear(Some("N0CALL-9"));
+        assert!(log.counts().is_empty());
+    }
+
+    #[test]
+    fn test_gpx() {
+        let text = gpx("N0CALL-9 & co", &[point(0, 38.8977)]);
+        assert!(text.contains("<name>N0CALL-9 &amp; co</name>"));
+        assert!(text.contains(
+            r#"<trkpt lat="38.897700" lon="-77.036500"><ele>12.0</ele><time>2026-10-14T12:00:00Z</time></trkpt>"#
+        ));
+    }
+
+    #[test]
+    fn test_kml() {
+        let text = kml("N0CALL-9", &[point(0, 38.8977), point(5, 38.9)]);
+        assert!(text.contains("-77.036500,38.897700,12.0\n-77.036500,38.900000,12.0\n"));
+        assert!(text.contains("<begin>2026-10-14T12:00:00Z</begin><end>2026-10-14T12:05:00Z</end>"));
+    }
+}
@@ -0,0 +1,122 @@
+//! UDP broadcast output.
+//!
+//! Sends every packet heard on our radios as a TNC2 line to a broadcast or
+//! multicast address, one datagram per packet, so desktop clients such as
+//! Xastir and YAAC on the LAN can show local traffic without their own TNC.
+
+use crate::channel;
+use crate::config::UdpOutputConfig;
+use crate::router::{PacketSource, RoutedPacket};
+use anyhow::Result;
+use log::{debug, info, warn};
+use std::net::SocketAddr;
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::sync::broadcast;
+
+/// Drops counted against the UDP output when it falls behind the router
+const UDP_CHANNEL: &str = "udp";
+
+pub async fn run_udp_output(
+    config: UdpOutputConfig,
+    mut heard_rx: broadcast::Receiver<RoutedPacket>,
+) -> Result<()> {
+    let target = lookup_host(&config.address)
+        .await?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Cannot resolve {}", config.address))?;
+    let socket = bind(target, config.ttl).await?;
+    info!("Sending heard packets to UDP {}", target);
+
+    loop {
+        let routed = match heard_rx.recv().await {
+            Ok(routed) => routed,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!("UDP output fell behind, dropped {} packets", missed);
+                channel::record_drops(UDP_CHANNEL, missed);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        };
+        if !forwards(&config, &routed) {
+            continue;
+        }
+
+        // A full socket buffer or an unreachable network shouldn't stop
+        // the output; the next packet gets another try
+        let line = format!("{}\r\n", routed.packet);
+        if let Err(e) = socket.send_to(line.as_bytes(), target).await {
+            debug!("UDP send to {} failed: {}", target, e);
+            channel::record_drops(UDP_CHANNEL, 1);
+        }
+    }
+}
+
+async fn bind(target: SocketAddr, ttl: u32) -> Result<UdpSocket> {
+    let local = if target.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(local).await?;
+    match target {
+        SocketAddr::V4(addr) if addr.ip().is_multicast() => socket.set_multicast_ttl_v4(ttl)?,
+        SocketAddr::V4(_) => socket.set_broadcast(true)?,
+        SocketAddr::V6(_) => {}
+    }
+    Ok(socket)
+}
+
+fn forwards(config: &UdpOutputConfig, routed: &RoutedPacket) -> bool {
+    match routed.source {
+        PacketSource::SerialPort(_) => true,
+        PacketSource::AprsIs => config.aprs_is,
+        PacketSource::Internal | PacketSource::Digipeater(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aprs::parse_packet;
+
+    fn heard(source: PacketSource) -> RoutedPacket {
+        RoutedPacket {
+            packet: parse_packet("N0CALL-9>APRS,WIDE1-1:>Status").unwrap(),
+            source,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sends_tnc2_lines() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config: UdpOutputConfig =
+            toml::from_str(&format!("address = \"{}\"", receiver.local_addr().unwrap())).unwrap();
+        assert!(!config.aprs_is);
+
+        let (heard_tx, heard_rx) = broadcast::channel(10);
+        tokio::spawn(run_udp_output(config, heard_rx));
+
+        heard_tx.send(heard(PacketSource::AprsIs)).unwrap();
+        heard_tx
+            .send(heard(PacketSource::SerialPort("vhf".to_string())))
+            .unwrap();
+
+        let mut buf = [0u8; 512];
+        let n = receiver.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"N0CALL-9>APRS,WIDE1-1:>Status\r\n");
+    }
+
+    #[test]
+    fn test_aprs_is_is_opt_in() {
+        let mut config: UdpOutputConfig = toml::from_str("address = \"239.0.0.1:8001\"").unwrap();
+        assert!(forwards(
+            &config,
+            &heard(PacketSource::SerialPort("vhf".into()))
+        ));
+        assert!(!forwards(&config, &heard(PacketSource::AprsIs)));
+        assert!(!forwards(&config, &heard(PacketSource::Internal)));
+
+        config.aprs_is = true;
+        assert!(forwards(&config, &heard(PacketSource::AprsIs)));
+    }
+}
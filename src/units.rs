@@ -0,0 +1,272 @@
+//! Config values with units.
+//!
+//! Durations, distances and speeds in the config can be written the way
+//! people say them — `"10m"`, `"1h30m"`, `"5 km"`, `"60 mph"` — as well as
+//! bare numbers, which keep the unit the field has always used. Each
+//! function here is a `deserialize_with` for one internal unit, converting
+//! at load time so the rest of the daemon only ever sees plain numbers.
+
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+
+/// A number a unit value can be stored as
+pub trait FromUnit: Sized {
+    fn from_unit(value: f64) -> Option<Self>;
+}
+
+macro_rules! from_unit_int {
+    ($($t:ty),*) => {$(
+        impl FromUnit for $t {
+            fn from_unit(value: f64) -> Option<Self> {
+                let value = value.round();
+                (value >= 0.0 && value <= <$t>::MAX as f64).then_some(value as $t)
+            }
+        }
+    )*};
+}
+
+from_unit_int!(u8, u16, u32, u64);
+
+impl FromUnit for f32 {
+    fn from_unit(value: f64) -> Option<Self> {
+        value.is_finite().then_some(value as f32)
+    }
+}
+
+impl FromUnit for f64 {
+    fn from_unit(value: f64) -> Option<Self> {
+        value.is_finite().then_some(value)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Raw {
+    Number(f64),
+    Text(String),
+}
+
+/// Seconds in a duration unit
+fn duration_unit(unit: &str) -> Option<f64> {
+    match unit {
+        "ms" => Some(0.001),
+        "s" | "sec" | "secs" | "second" | "seconds" => Some(1.0),
+        "m" | "min" | "mins" | "minute" | "minutes" => Some(60.0),
+        "h" | "hr" | "hrs" | "hour" | "hours" => Some(3600.0),
+        "d" | "day" | "days" => Some(86400.0),
+        _ => None,
+    }
+}
+
+/// Kilometres in a distance unit
+fn distance_unit(unit: &str) -> Option<f64> {
+    match unit {
+        "m" | "meters" | "metres" => Some(0.001),
+        "km" | "kilometers" | "kilometres" => Some(1.0),
+        "mi" | "mile" | "miles" => Some(1.609344),
+        "nm" | "nmi" => Some(1.852),
+        _ => None,
+    }
+}
+
+/// Knots in a speed unit
+fn speed_unit(unit: &str) -> Option<f64> {
+    match unit {
+        "kn" | "kt" | "kts" | "knot" | "knots" => Some(1.0),
+        "mph" => Some(0.868976),
+        "km/h" | "kmh" | "kph" => Some(0.539957),
+        "m/s" => Some(1.943844),
+        _ => None,
+    }
+}
+
+/// Parse `text` as one or more number-unit pairs, like "1h30m" or "5 km",
+/// into the base unit of `units`. A bare number is worth `bare` base units.
+fn parse_units(text: &str, units: fn(&str) -> Option<f64>, bare: f64) -> Result<f64, String> {
+    let text = text.trim();
+    if let Ok(number) = text.parse::<f64>() {
+        return Ok(number * bare);
+    }
+
+    let mut total = 0.0;
+    let mut rest = text;
+    while !rest.is_empty() {
+        let number_len = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(rest.len());
+        let number: f64 = rest[..number_len]
+            .parse()
+            .map_err(|_| format!("expected a number in {:?}", text))?;
+        rest = rest[number_len..].trim_start();
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit() || c.is_whitespace())
+            .unwrap_or(rest.len());
+        let unit = &rest[..unit_len];
+        let scale = units(&unit.to_ascii_lowercase())
+            .ok_or_else(|| format!("unknown unit {:?} in {:?}", unit, text))?;
+        total += number * scale;
+        rest = rest[unit_len..].trim_start();
+    }
+    Ok(total)
+}
+
+fn deserialize_units<'de, D, T>(
+    deserializer: D,
+    units: fn(&str) -> Option<f64>,
+    bare: f64,
+    scale: f64,
+) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromUnit,
+{
+    let value = match Raw::deserialize(deserializer)? {
+        Raw::Number(number) => number * bare,
+        Raw::Text(text) => parse_units(&text, units, bare).map_err(D::Error::custom)?,
+    };
+    T::from_unit(value / scale)
+        .ok_or_else(|| D::Error::custom(format!("{} is out of range", value)))
+}
+
+/// A duration stored in seconds
+pub fn seconds<'de, D: Deserializer<'de>, T: FromUnit>(deserializer: D) -> Result<T, D::Error> {
+    deserialize_units(deserializer, duration_unit, 1.0, 1.0)
+}
+
+/// A duration stored in milliseconds
+pub fn millis<'de, D: Deserializer<'de>, T: FromUnit>(deserializer: D) -> Result<T, D::Error> {
+    deserialize_units(deserializer, duration_unit, 0.001, 0.001)
+}
+
+/// A duration stored in minutes
+pub fn minutes<'de, D: Deserializer<'de>, T: FromUnit>(deserializer: D) -> Result<T, D::Error> {
+    deserialize_units(deserializer, duration_unit, 60.0, 60.0)
+}
+
+/// A duration stored in hours
+pub fn hours<'de, D: Deserializer<'de>, T: FromUnit>(deserializer: D) -> Result<T, D::Error> {
+    deserialize_units(deserializer, duration_unit, 3600.0, 3600.0)
+}
+
+/// A distance stored in kilometres
+pub fn km<'de, D: Deserializer<'de>, T: FromUnit>(deserializer: D) -> Result<T, D::Error> {
+    deserialize_units(deserializer, distance_unit, 1.0, 1.0)
+}
+
+/// A speed stored in knots
+pub fn knots<'de, D: Deserializer<'de>, T: FromUnit>(deserializer: D) -> Result<T, D::Error> {
+    deserialize_units(deserializer, speed_unit, 1.0, 1.0)
+}
+
+/// Wraps a unit function for an optional field
+#[derive(Deserialize)]
+#[serde(transparent)]
+struct Seconds<T: FromUnit>(#[serde(deserialize_with = "seconds")] T);
+
+#[derive(Deserialize)]
+#[serde(transparent)]
+struct Minutes<T: FromUnit>(#[serde(deserialize_with = "minutes")] T);
+
+#[derive(Deserialize)]
+#[serde(transparent)]
+struct Km<T: FromUnit>(#[serde(deserialize_with = "km")] T);
+
+pub fn opt_seconds<'de, D: Deserializer<'de>, T: FromUnit>(
+    deserializer: D,
+) -> Result<Option<T>, D::Error> {
+    Ok(Option::<Seconds<T>>::deserialize(deserializer)?.map(|v| v.0))
+}
+
+pub fn opt_minutes<'de, D: Deserializer<'de>, T: FromUnit>(
+    deserializer: D,
+) -> Result<Option<T>, D::Error> {
+    Ok(Option::<Minutes<T>>::deserialize(deserializer)?.map(|v| v.0))
+}
+
+pub fn opt_km<'de, D: Deserializer<'de>, T: FromUnit>(
+    deserializer: D,
+) -> Result<Option<T>, D::Error> {
+    Ok(Option::<Km<T>>::deserialize(deserializer)?.map(|v| v.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct Units {
+        #[serde(deserialize_with = "seconds")]
+        interval: u32,
+        #[serde(default, deserialize_with = "opt_minutes")]
+        window: Option<u32>,
+        #[serde(deserialize_with = "hours")]
+        expire: u32,
+        #[serde(deserialize_with = "km")]
+        range: f64,
+        #[serde(deserialize_with = "knots")]
+        speed: u32,
+    }
+
+    fn parse(toml: &str) -> Result<Units, toml::de::Error> {
+        toml::from_str(toml)
+    }
+
+    #[test]
+    fn test_bare_numbers_keep_their_unit() {
+        let units =
+            parse("interval = 600\nwindow = 30\nexpire = 24\nrange = 50\nspeed = 5").unwrap();
+        assert_eq!(units.interval, 600);
+        assert_eq!(units.window, Some(30));
+        assert_eq!(units.expire, 24);
+        assert_eq!(units.range, 50.0);
+        assert_eq!(units.speed, 5);
+    }
+
+    #[test]
+    fn test_units() {
+        let units = parse(
+            r#"
+            interval = "1h30m"
+            window = "2h"
+            expire = "2d"
+            range = "5 mi"
+            speed = "60 mph"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(units.interval, 5400);
+        assert_eq!(units.window, Some(120));
+        assert_eq!(units.expire, 48);
+        assert!((units.range - 8.047).abs() < 0.001);
+        assert_eq!(units.speed, 52);
+
+        let units =
+            parse("interval = \"90 s\"\nexpire = \"36h\"\nrange = \"500 m\"\nspeed = \"100 km/h\"")
+                .unwrap();
+        assert_eq!(units.interval, 90);
+        assert_eq!(units.window, None);
+        assert_eq!(units.expire, 36);
+        assert_eq!(units.range, 0.5);
+        assert_eq!(units.speed, 54);
+
+        #[derive(Deserialize)]
+        struct Delay {
+            #[serde(deserialize_with = "millis")]
+            delay: u32,
+        }
+        let delay: Delay = toml::from_str("delay = \"1.5s\"").unwrap();
+        assert_eq!(delay.delay, 1500);
+        let delay: Delay = toml::from_str("delay = \"250ms\"").unwrap();
+        assert_eq!(delay.delay, 250);
+    }
+
+    #[test]
+    fn test_bad_units() {
+        let error = parse("interval = \"10 parsecs\"\nexpire = 1\nrange = 1\nspeed = 1")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("unknown unit"), "{}", error);
+        assert!(parse("interval = -5\nexpire = 1\nrange = 1\nspeed = 1").is_err());
+        assert!(parse("interval = \"m\"\nexpire = 1\nrange = 1\nspeed = 1").is_err());
+    }
+}
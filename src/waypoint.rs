@@ -0,0 +1,314 @@
+//! NMEA waypoint output.
+//!
+//! Turns the positions of stations, objects and items we hear into NMEA
+//! waypoint sentences so a radio or handheld GPS can plot them:
+//!
+//! ```text
+//! $GPWPL,4903.50,N,07201.75,W,N0CALL-9*3C
+//! $PKWDWPL,140309,V,4903.50,N,07201.75,W,36,88,010624,376,N0CALL-9,/>*71
+//! ```
+//!
+//! `$GPWPL` is understood by most GPS units; Kenwood radios want
+//! `$PKWDWPL`, which also carries course, speed, altitude and the APRS
+//! symbol. Sentences go to a serial port, TCP clients, or both.
+
+use crate::aprs::{AprsPacket, Position};
+use crate::channel;
+use crate::config::{WaypointConfig, WaypointFormat};
+use crate::router::{PacketSource, RoutedPacket};
+use crate::serial::pure_serial::SerialPort;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+
+/// Drops counted against the waypoint output when it falls behind
+const WAYPOINT_CHANNEL: &str = "waypoints";
+
+/// Sentences held for each TCP client before it misses some
+const CLIENT_BUFFER: usize = 100;
+
+pub async fn run_waypoint_output(
+    config: WaypointConfig,
+    mut heard_rx: broadcast::Receiver<RoutedPacket>,
+) -> Result<()> {
+    let mut port = match &config.device {
+        Some(device) => {
+            let port = SerialPort::open(device, config.baud_rate).await?;
+            info!("Sending waypoints to {}", device);
+            Some(port)
+        }
+        None => None,
+    };
+
+    let (sentence_tx, _) = broadcast::channel::<Arc<str>>(CLIENT_BUFFER);
+    if let Some(listen) = &config.listen {
+        let listener = TcpListener::bind(listen).await?;
+        info!("Waypoint output listening on {}", listen);
+        tokio::spawn(accept_clients(listener, sentence_tx.clone()));
+    }
+
+    let mut last_sent: HashMap<String, Instant> = HashMap::new();
+    let interval = Duration::from_secs(config.interval as u64);
+    loop {
+        let routed = match heard_rx.recv().await {
+            Ok(routed) => routed,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!("Waypoint output fell behind, dropped {} packets", missed);
+                channel::record_drops(WAYPOINT_CHANNEL, missed);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        };
+        if !forwards(&config, &routed) {
+            continue;
+        }
+        let Some(waypoint) = Waypoint::from_packet(&routed.packet) else {
+            continue;
+        };
+
+        // Radios have little room for waypoints; a station beaconing
+        // every few seconds only needs moving now and then
+        let now = Instant::now();
+        if last_sent
+            .get(&waypoint.name)
+            .is_some_and(|t| now.duration_since(*t) < interval)
+        {
+            continue;
+        }
+        last_sent.insert(waypoint.name.clone(), now);
+        last_sent.retain(|_, t| now.duration_since(*t) < interval);
+
+        let sentence = waypoint.sentence(config.format, Utc::now());
+        debug!("Waypoint: {}", sentence.trim_end());
+        if let Some(port) = &mut port {
+            port.write_all(sentence.as_bytes()).await?;
+        }
+        // Sending only fails with no clients connected
+        sentence_tx.send(sentence.into()).ok();
+    }
+}
+
+async fn accept_clients(listener: TcpListener, sentences: broadcast::Sender<Arc<str>>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                info!("Waypoint client {} connected", addr);
+                let rx = sentences.subscribe();
+                tokio::spawn(async move {
+                    if let Err(e) = send_to_client(stream, rx).await {
+                        debug!("Waypoint client {}: {}", addr, e);
+                    }
+                    info!("Waypoint client {} disconnected", addr);
+                });
+            }
+            Err(e) => warn!("Waypoint output accept failed: {}", e),
+        }
+    }
+}
+
+async fn send_to_client(
+    mut stream: TcpStream,
+    mut sentences: broadcast::Receiver<Arc<str>>,
+) -> Result<()> {
+    loop {
+        match sentences.recv().await {
+            Ok(sentence) => stream.write_all(sentence.as_bytes()).await?,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                channel::record_drops(WAYPOINT_CHANNEL, missed);
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+fn forwards(config: &WaypointConfig, routed: &RoutedPacket) -> bool {
+    match routed.source {
+        PacketSource::SerialPort(_) => true,
+        PacketSource::AprsIs => config.aprs_is,
+        PacketSource::Internal | PacketSource::Digipeater(_) => false,
+    }
+}
+
+/// A position to plot, named after the station or object it belongs to
+#[derive(Debug, Clone, PartialEq)]
+struct Waypoint {
+    name: String,
+    position: Position,
+}
+
+impl Waypoint {
+    fn from_packet(packet: &AprsPacket) -> Option<Self> {
+        let position = packet.position()?.clone();
+        let name = match packet.object() {
+            // A killed object should come off the map, which WPL can't say
+            Some(object) if !object.alive => return None,
+            Some(object) => object.name.trim_end().to_string(),
+            None => packet.source.to_string(),
+        };
+        Some(Waypoint {
+            name: sanitize(&name),
+            position,
+        })
+    }
+
+    fn sentence(&self, format: WaypointFormat, now: DateTime<Utc>) -> String {
+        let (lat, ns) = coordinate(self.position.latitude, 2, 'N', 'S');
+        let (lon, ew) = coordinate(self.position.longitude, 3, 'E', 'W');
+        let body = match format {
+            WaypointFormat::Nmea => format!("GPWPL,{},{},{},{},{}", lat, ns, lon, ew, self.name),
+            WaypointFormat::Kenwood => {
+                let optional =
+                    |value: Option<f32>| value.map(|v| format!("{:.0}", v)).unwrap_or_default();
+                format!(
+                    "PKWDWPL,{},V,{},{},{},{},{},{},{},{},{},{}{}",
+                    now.format("%H%M%S"),
+                    lat,
+                    ns,
+                    lon,
+                    ew,
+                    optional(self.position.speed),
+                    optional(self.position.course.map(f32::from)),
+                    now.format("%d%m%y"),
+                    optional(self.position.altitude),
+                    self.name,
+                    self.position.symbol_table,
+                    self.position.symbol
+                )
+            }
+        };
+        format!("${}*{:02X}\r\n", body, checksum(&body))
+    }
+}
+
+/// Degrees and minutes as NMEA writes them, e.g. `4903.50` and `N`
+fn coordinate(value: f64, degree_digits: usize, positive: char, negative: char) -> (String, char) {
+    // Rounded as a whole so 59.999 minutes carries into the degrees
+    let hundredths = (value.abs() * 6000.0).round() as u64;
+    let text = format!(
+        "{:0width$}{:02}.{:02}",
+        hundredths / 6000,
+        hundredths % 6000 / 100,
+        hundredths % 100,
+        width = degree_digits
+    );
+    (text, if value >= 0.0 { positive } else { negative })
+}
+
+/// Keep a name from breaking the sentence around it
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            ',' | '*' | '$' | '!' | '\\' | '^' | '~' => '_',
+            c if c.is_ascii_graphic() || c == ' ' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+fn checksum(body: &str) -> u8 {
+    body.bytes().fold(0, |sum, b| sum ^ b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aprs::parse_packet;
+    use chrono::TimeZone;
+    use tokio::io::AsyncBufReadExt;
+
+    fn waypoint(packet: &str) -> Option<Waypoint> {
+        Waypoint::from_packet(&parse_packet(packet).unwrap())
+    }
+
+    #[test]
+    fn test_sentences() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 14, 3, 9).unwrap();
+        let station =
+            waypoint("N0CALL-9>APRS:!4903.50N/07201.75W>088/036/A=001234 Mobile").unwrap();
+        assert_eq!(
+            station.sentence(WaypointFormat::Nmea, now),
+            "$GPWPL,4903.50,N,07201.75,W,N0CALL-9*3C\r\n"
+        );
+        assert_eq!(
+            station.sentence(WaypointFormat::Kenwood, now),
+            "$PKWDWPL,140309,V,4903.50,N,07201.75,W,36,88,010624,376,N0CALL-9,/>*71\r\n"
+        );
+
+        // Fields a position doesn't have are left empty
+        let fixed = waypoint("N1CALL>APRS:=3351.00S\\15112.00E#").unwrap();
+        assert_eq!(
+            fixed.sentence(WaypointFormat::Kenwood, now),
+            "$PKWDWPL,140309,V,3351.00,S,15112.00,E,,,010624,,N1CALL,\\#*3D\r\n"
+        );
+    }
+
+    #[test]
+    fn test_names() {
+        let object = waypoint("N0CALL>APRS:;LEADER   *092345z4903.50N/07201.75W>").unwrap();
+        assert_eq!(object.name, "LEADER");
+        let item = waypoint("N0CALL>APRS:)AID,1!4903.50N/07201.75W-").unwrap();
+        assert_eq!(item.name, "AID_1");
+
+        assert!(waypoint("N0CALL>APRS:;LEADER   _092345z4903.50N/07201.75W>").is_none());
+        assert!(waypoint("N0CALL>APRS:>Status").is_none());
+    }
+
+    #[test]
+    fn test_coordinate_rounding() {
+        assert_eq!(
+            coordinate(49.999999, 2, 'N', 'S'),
+            ("5000.00".to_string(), 'N')
+        );
+        assert_eq!(coordinate(-0.5, 3, 'E', 'W'), ("00030.00".to_string(), 'W'));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_clients_get_sentences() {
+        let probe = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listen = probe.local_addr().unwrap();
+        drop(probe);
+        let config: WaypointConfig =
+            toml::from_str(&format!("listen = \"{}\"\nformat = \"nmea\"", listen)).unwrap();
+
+        let (heard_tx, heard_rx) = broadcast::channel(10);
+        tokio::spawn(run_waypoint_output(config, heard_rx));
+        let mut client = loop {
+            match TcpStream::connect(listen).await {
+                Ok(client) => break client,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        // Give the output a moment to subscribe the client
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let heard = |packet: &str| RoutedPacket {
+            packet: parse_packet(packet).unwrap(),
+            source: PacketSource::SerialPort("vhf".to_string()),
+        };
+        heard_tx.send(heard("N0CALL-9>APRS:>Status")).unwrap();
+        heard_tx
+            .send(heard("N0CALL-9>APRS:!4903.50N/07201.75W>"))
+            .unwrap();
+        // Too soon after the last one to be sent again
+        heard_tx
+            .send(heard("N0CALL-9>APRS:!4903.60N/07201.75W>"))
+            .unwrap();
+        heard_tx
+            .send(heard("N1CALL>APRS:!4903.50N/07201.75W>"))
+            .unwrap();
+
+        let mut lines = tokio::io::BufReader::new(&mut client);
+        let mut line = String::new();
+        lines.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "$GPWPL,4903.50,N,07201.75,W,N0CALL-9*3C\r\n");
+        line.clear();
+        lines.read_line(&mut line).await.unwrap();
+        assert!(line.starts_with("$GPWPL,4903.50,N,07201.75,W,N1CALL*"));
+    }
+}
@@ -0,0 +1,365 @@
+//! Live packet stream over WebSocket.
+//!
+//! Clients connect to `ws://<listen>/` and get a JSON text message for every
+//! packet heard on RF or APRS-IS. `?filter=name,name` picks configured
+//! filters to run on each client's stream, evaluated like a port's chain.
+
+use crate::channel;
+use crate::config::WebSocketConfig;
+use crate::filter::{FilterChain, PacketFilter};
+use crate::router::RoutedPacket;
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::{debug, info, warn};
+use sha1::{Digest, Sha1};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc};
+
+/// Drops counted against the WebSocket stream when a client falls behind
+const WEBSOCKET_CHANNEL: &str = "websocket";
+
+/// Largest HTTP request we read before upgrading
+const MAX_REQUEST: usize = 8192;
+
+/// Largest frame we accept from a client; they only ever send control frames
+const MAX_FRAME: u64 = 4096;
+
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OP_TEXT: u8 = 0x1;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+pub async fn run_websocket_server(
+    config: WebSocketConfig,
+    filter: Arc<PacketFilter>,
+    heard: broadcast::Sender<RoutedPacket>,
+) -> Result<()> {
+    let listener = TcpListener::bind(&config.listen).await?;
+    info!("WebSocket stream listening on {}", config.listen);
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let filter = filter.clone();
+        let heard_rx = heard.subscribe();
+        tokio::spawn(async move {
+            match handle_client(stream, filter, heard_rx).await {
+                Ok(()) => debug!("WebSocket client {} disconnected", addr),
+                Err(e) => debug!("WebSocket client {}: {}", addr, e),
+            }
+        });
+    }
+}
+
+async fn handle_client(
+    mut stream: TcpStream,
+    filter: Arc<PacketFilter>,
+    mut heard_rx: broadcast::Receiver<RoutedPacket>,
+) -> Result<()> {
+    let request = read_request(&mut stream).await?;
+    let (path, key) = match parse_upgrade(&request) {
+        Ok(upgrade) => upgrade,
+        Err(e) => {
+            stream
+                .write_all(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n")
+                .await?;
+            return Err(e);
+        }
+    };
+
+    let names = filter_names(&path);
+    let chain = match filter.named_chain(&names) {
+        Ok(chain) => chain,
+        Err(e) => {
+            stream
+                .write_all(b"HTTP/1.1 404 Not Found\r\nConnection: close\r\n\r\n")
+                .await?;
+            return Err(e);
+        }
+    };
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(&key)
+    );
+    stream.write_all(response.as_bytes()).await?;
+    info!("WebSocket client subscribed with filters {:?}", names);
+
+    // Frames are read in their own task so a half-read frame isn't lost
+    // when a packet comes in
+    let (mut reader, mut writer) = stream.into_split();
+    let (frame_tx, mut frames) = mpsc::channel(8);
+    let reader_task = tokio::spawn(async move {
+        loop {
+            let result = read_frame(&mut reader).await;
+            let failed = result.is_err();
+            if frame_tx.send(result).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    let result = async {
+        loop {
+            tokio::select! {
+                Some(received) = frames.recv() => {
+                    let (opcode, payload) = received?;
+                    match opcode {
+                        OP_CLOSE => {
+                            writer.write_all(&frame(OP_CLOSE, &[])).await?;
+                            return Ok(());
+                        }
+                        OP_PING => writer.write_all(&frame(OP_PONG, &payload)).await?,
+                        _ => {}
+                    }
+                }
+
+                result = heard_rx.recv() => {
+                    let routed = match result {
+                        Ok(routed) => routed,
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            warn!("WebSocket client fell behind, dropped {} packets", missed);
+                            channel::record_drops(WEBSOCKET_CHANNEL, missed);
+                            continue;
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                    };
+                    if passes(&filter, &chain, &routed) {
+                        let json = routed.packet.to_json().to_string();
+                        writer.write_all(&frame(OP_TEXT, json.as_bytes())).await?;
+                    }
+                }
+            }
+        }
+    }
+    .await;
+    reader_task.abort();
+    result
+}
+
+fn passes(filter: &PacketFilter, chain: &FilterChain, routed: &RoutedPacket) -> bool {
+    filter.should_pass_chain(chain, &routed.packet)
+}
+
+async fn read_request(stream: &mut TcpStream) -> Result<String> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.ends_with(b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err(anyhow!("connection closed during handshake"));
+        }
+        request.extend_from_slice(&buf[..n]);
+        if request.len() > MAX_REQUEST {
+            return Err(anyhow!("handshake too large"));
+        }
+    }
+    Ok(String::from_utf8_lossy(&request).into_owned())
+}
+
+/// The request path and Sec-WebSocket-Key of an upgrade request
+fn parse_upgrade(request: &str) -> Result<(String, String)> {
+    let mut lines = request.lines();
+    let mut request_line = lines.next().unwrap_or("").split_whitespace();
+    if request_line.next() != Some("GET") {
+        return Err(anyhow!("not a GET request"));
+    }
+    let path = request_line.next().unwrap_or("/").to_string();
+
+    let mut key = None;
+    let mut upgrade = false;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "upgrade" => upgrade = value.eq_ignore_ascii_case("websocket"),
+            "sec-websocket-key" => key = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    if !upgrade {
+        return Err(anyhow!("not a WebSocket upgrade"));
+    }
+    let key = key.ok_or_else(|| anyhow!("missing Sec-WebSocket-Key"))?;
+    Ok((path, key))
+}
+
+/// Filter names from a `?filter=a,b` query
+fn filter_names(path: &str) -> Vec<String> {
+    let Some((_, query)) = path.split_once('?') else {
+        return Vec::new();
+    };
+    query
+        .split('&')
+        .filter_map(|pair| pair.strip_prefix("filter="))
+        .flat_map(|names| names.split(','))
+        .filter(|name| !name.is_empty())
+        .map(|name| name.replace("%20", " "))
+        .collect()
+}
+
+fn accept_key(key: &str) -> String {
+    STANDARD.encode(Sha1::digest(format!("{}{}", key, WEBSOCKET_GUID)))
+}
+
+/// An unmasked, unfragmented frame, as servers send them
+fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Read one client frame, returning its opcode and unmasked payload
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<(u8, Vec<u8>)> {
+    let first = reader.read_u8().await?;
+    let second = reader.read_u8().await?;
+    let len = match second & 0x7F {
+        126 => reader.read_u16().await? as u64,
+        127 => reader.read_u64().await?,
+        len => len as u64,
+    };
+    if len > MAX_FRAME {
+        return Err(anyhow!("frame too large ({} bytes)", len));
+    }
+
+    let mut mask = [0u8; 4];
+    let masked = second & 0x80 != 0;
+    if masked {
+        reader.read_exact(&mut mask).await?;
+    }
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload).await?;
+    if masked {
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+    }
+    Ok((first & 0x0F, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aprs::parse_packet;
+    use crate::config::{FilterAction, FilterConfig, FilterDirection};
+    use crate::router::PacketSource;
+
+    #[test]
+    fn test_accept_key() {
+        // The example handshake from RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_parse_upgrade() {
+        let request = "GET /?filter=positions,no-wx HTTP/1.1\r\nHost: igate\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: abc==\r\n\r\n";
+        let (path, key) = parse_upgrade(request).unwrap();
+        assert_eq!(key, "abc==");
+        assert_eq!(filter_names(&path), ["positions", "no-wx"]);
+        assert!(filter_names("/").is_empty());
+
+        assert!(parse_upgrade("GET / HTTP/1.1\r\nHost: igate\r\n\r\n").is_err());
+        assert!(parse_upgrade("POST / HTTP/1.1\r\nUpgrade: websocket\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_frame_lengths() {
+        assert_eq!(frame(OP_TEXT, b"hi"), [0x81, 2, b'h', b'i']);
+        assert_eq!(frame(OP_TEXT, &[0; 200])[..4], [0x81, 126, 0, 200]);
+        assert_eq!(frame(OP_TEXT, &[0; 70000])[..2], [0x81, 127]);
+    }
+
+    #[tokio::test]
+    async fn test_read_masked_frame() {
+        let mask = [1, 2, 3, 4];
+        let mut data = vec![0x89, 0x80 | 3];
+        data.extend_from_slice(&mask);
+        data.extend(b"abc".iter().zip(mask).map(|(b, m)| b ^ m));
+
+        let (opcode, payload) = read_frame(&mut data.as_slice()).await.unwrap();
+        assert_eq!(opcode, OP_PING);
+        assert_eq!(payload, b"abc");
+    }
+
+    #[tokio::test]
+    async fn test_streams_heard_packets() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let filter = Arc::new(PacketFilter::new(Vec::new()).unwrap());
+        let (heard_tx, _) = broadcast::channel(10);
+        let heard_rx = heard_tx.subscribe();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            handle_client(stream, filter, heard_rx).await
+        });
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_request(&mut client).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 101"));
+        assert!(response.contains("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+        heard_tx
+            .send(RoutedPacket {
+                packet: parse_packet("N0CALL-9>APRS:>Status").unwrap(),
+                source: PacketSource::AprsIs,
+            })
+            .unwrap();
+        let (opcode, payload) = read_frame(&mut client).await.unwrap();
+        assert_eq!(opcode, OP_TEXT);
+        let json: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(json["source"], "N0CALL-9");
+    }
+
+    #[test]
+    fn test_client_filters() {
+        let filter = PacketFilter::new(vec![FilterConfig {
+            name: "no-status".to_string(),
+            action: FilterAction::Drop,
+            pattern: ":>".to_string(),
+            direction: Some(FilterDirection::Port),
+            ..Default::default()
+        }])
+        .unwrap();
+        let chain = filter.named_chain(&["no-status".to_string()]).unwrap();
+        let heard = |packet: &str| RoutedPacket {
+            packet: parse_packet(packet).unwrap(),
+            source: PacketSource::AprsIs,
+        };
+
+        assert!(!passes(&filter, &chain, &heard("N0CALL>APRS:>Status")));
+        assert!(passes(
+            &filter,
+            &chain,
+            &heard("N0CALL>APRS:!4903.50N/07201.75W-")
+        ));
+        assert!(passes(
+            &filter,
+            &FilterChain::default(),
+            &heard("N0CALL>APRS:>Status")
+        ));
+    }
+}
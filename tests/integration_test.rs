@@ -1,5 +1,5 @@
 use aprstx::aprs::{parse_packet, AprsPacket, CallSign};
-use aprstx::config::{FilterAction, FilterConfig};
+use aprstx::config::{Config, FilterAction, FilterConfig};
 use aprstx::filter::PacketFilter;
 use std::sync::Arc;
 use tokio::sync::mpsc;
@@ -25,11 +25,13 @@ async fn test_filter_integration() {
             name: "rfonly".to_string(),
             action: FilterAction::Drop,
             pattern: "RFONLY".to_string(),
+            ..Default::default()
         },
         FilterConfig {
             name: "emergency".to_string(),
             action: FilterAction::Pass,
             pattern: "EMERGENCY".to_string(),
+            ..Default::default()
         },
     ];
 
@@ -138,7 +140,7 @@ async fn test_message_ack_flow() {
 
 #[test]
 fn test_position_packet_creation() {
-    use aprstx::gps::GpsPosition;
+    use aprstx::gps::{FixQuality, GpsPosition};
     use chrono::Utc;
 
     let pos = GpsPosition {
@@ -148,6 +150,10 @@ fn test_position_packet_creation() {
         speed: Some(50.0),
         course: Some(90.0),
         timestamp: Utc::now(),
+        fix: FixQuality::Fix3D,
+        received: std::time::Instant::now(),
+        satellites: None,
+        hdop: None,
     };
 
     // Test position formatting
@@ -160,7 +166,7 @@ fn test_position_packet_creation() {
 async fn test_smart_beacon_logic() {
     use aprstx::beacon::BeaconService;
     use aprstx::config::{BeaconConfig, SmartBeaconConfig};
-    use aprstx::gps::{GpsPosition, GpsSource, GpsTracker};
+    use aprstx::gps::{FixQuality, GpsPosition, GpsSource, GpsTracker};
     use chrono::Utc;
 
     let config = BeaconConfig {
@@ -184,6 +190,11 @@ async fn test_smart_beacon_logic() {
             turn_angle: 20,
             turn_speed: 5,
         },
+        geofences: vec![],
+        trip_comment: false,
+        speed_paths: vec![],
+        frequency: false,
+        ports: vec![],
     };
 
     let pos = GpsPosition {
@@ -193,6 +204,10 @@ async fn test_smart_beacon_logic() {
         speed: Some(0.0),
         course: Some(0.0),
         timestamp: Utc::now(),
+        fix: FixQuality::Fix3D,
+        received: std::time::Instant::now(),
+        satellites: None,
+        hdop: None,
     };
 
     let gps = Arc::new(GpsTracker::new(GpsSource::Fixed(pos)));
@@ -202,3 +217,321 @@ async fn test_smart_beacon_logic() {
 }
 
 // More comprehensive integration tests would go here...
+
+#[test]
+fn test_config_includes() {
+    let dir = tempfile::tempdir().unwrap();
+    let main = dir.path().join("aprstx.conf");
+    std::fs::create_dir(dir.path().join("conf.d")).unwrap();
+    std::fs::write(
+        &main,
+        r#"
+        mycall = "N0CALL-10"
+        include = ["conf.d/*.toml"]
+        serial_ports = []
+        filters = []
+
+        [digipeater]
+        enabled = false
+        mycall = "N0CALL-10"
+        aliases = []
+        viscous_delay = 5
+        max_hops = 3
+        "#,
+    )
+    .unwrap();
+    std::fs::write(
+        dir.path().join("conf.d/filters.toml"),
+        r#"
+        [[filters]]
+        name = "nogate"
+        action = "drop"
+        pattern = "NOGATE"
+        "#,
+    )
+    .unwrap();
+    std::fs::write(
+        dir.path().join("conf.d/telemetry.toml"),
+        r#"
+        [telemetry]
+        enabled = false
+        interval = 1200
+        comment = "Test"
+        "#,
+    )
+    .unwrap();
+
+    let config = Config::load(&main).unwrap();
+    assert_eq!(config.filters.len(), 1);
+    assert_eq!(config.telemetry.interval, 1200);
+
+    // Problems name the file they came from
+    std::fs::write(
+        dir.path().join("conf.d/telemetry.toml"),
+        "[telemetry]\nenabled = false\ninterval = \"often\"\ncomment = \"\"\n",
+    )
+    .unwrap();
+    let error = Config::load(&main).unwrap_err().to_string();
+    assert!(error.contains("telemetry.toml"), "{}", error);
+
+    std::fs::write(
+        dir.path().join("conf.d/telemetry.toml"),
+        "[digipeater]\nenabled = true\n",
+    )
+    .unwrap();
+    let error = Config::load(&main).unwrap_err().to_string();
+    assert!(
+        error.contains("digipeater.enabled is already set"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn test_config_defaults_and_unknown_keys() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("aprstx.conf");
+
+    // Everything but the callsign has a default
+    std::fs::write(
+        &path,
+        "mycall = \"N0CALL-10\"\n[aprs_is]\n[digipeater]\nenabled = true\n",
+    )
+    .unwrap();
+    let config = Config::load(&path).unwrap();
+    assert_eq!(config.digipeater.mycall, "N0CALL-10");
+    assert_eq!(config.digipeater.viscous_delay, 5);
+    assert_eq!(config.aprs_is.unwrap().port, 14580);
+    assert!(!config.telemetry.enabled);
+
+    std::fs::write(
+        &path,
+        "mycall = \"N0CALL-10\"\n[digipeater]\nviscus_delay = 5\n",
+    )
+    .unwrap();
+    let error = Config::load(&path).unwrap_err().to_string();
+    assert!(error.contains("unknown field `viscus_delay`"), "{}", error);
+    assert!(error.contains("digipeater"), "{}", error);
+
+    // A proxy that can't be used is caught at load
+    std::fs::write(
+        &path,
+        "mycall = \"N0CALL-10\"\n[aprs_is]\nproxy = \"ftp://proxy:21\"\n",
+    )
+    .unwrap();
+    let error = Config::load(&path).unwrap_err().to_string();
+    assert!(error.contains("unsupported proxy scheme"), "{}", error);
+}
+
+#[test]
+fn test_config_units() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("aprstx.conf");
+    std::fs::write(
+        &path,
+        r#"
+mycall = "N0CALL-10"
+dedup_window = "30s"
+
+[aprs_is]
+local_window = "1h"
+
+[[filters]]
+name = "nearby"
+action = "log"
+range = "10 mi"
+
+[beacon]
+interval = "1h30m"
+
+[beacon.smart_beacon]
+low_speed = "5 mph"
+high_speed = 60
+stationary_interval = "30 min"
+"#,
+    )
+    .unwrap();
+    let config = Config::load(&path).unwrap();
+    assert_eq!(config.dedup_window, 30);
+    assert_eq!(config.aprs_is.unwrap().local_window, Some(60));
+    assert!((config.filters[0].range.unwrap() - 16.09).abs() < 0.01);
+    let beacon = config.beacon.unwrap();
+    assert_eq!(beacon.interval, 5400);
+    assert_eq!(beacon.smart_beacon.low_speed, 4);
+    assert_eq!(beacon.smart_beacon.high_speed, 60);
+    assert_eq!(beacon.smart_beacon.stationary_interval, 1800);
+
+    std::fs::write(
+        &path,
+        "mycall = \"N0CALL-10\"\n[beacon]\ninterval = \"10 fortnights\"\n",
+    )
+    .unwrap();
+    let error = Config::load(&path).unwrap_err().to_string();
+    assert!(error.contains("unknown unit"), "{}", error);
+}
+
+#[test]
+fn test_config_groups() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("aprstx.conf");
+    let ports = r#"
+mycall = "N0CALL-10"
+
+[aprs_is]
+
+[[serial_ports]]
+name = "vhf"
+device = "/dev/null"
+inbound_filters = ["own"]
+
+[[serial_ports]]
+name = "uhf"
+device = "/dev/null"
+
+[[serial_ports]]
+name = "hf"
+device = "/dev/null"
+tx_from = ["local", "2m70cm"]
+digipeat_to = ["hf"]
+
+[[filters]]
+name = "own"
+action = "log"
+
+[[filters]]
+name = "no-wx"
+action = "drop"
+"#;
+    std::fs::write(
+        &path,
+        format!(
+            r#"{}
+[[groups]]
+name = "2m70cm"
+ports = ["vhf", "uhf"]
+inbound_filters = ["no-wx"]
+
+[[groups]]
+name = "internet"
+ports = ["aprs-is"]
+outbound_filters = ["no-wx"]
+
+[beacon]
+ports = ["2m70cm", "vhf"]
+"#,
+            ports
+        ),
+    )
+    .unwrap();
+    let config = Config::load(&path).unwrap();
+    let hf = &config.serial_ports[2];
+    assert_eq!(hf.tx_from.as_deref().unwrap(), ["local", "vhf", "uhf"]);
+    assert_eq!(hf.digipeat_to.as_deref().unwrap(), ["hf"]);
+    assert_eq!(config.serial_ports[0].inbound_filters, ["no-wx", "own"]);
+    assert_eq!(config.serial_ports[1].inbound_filters, ["no-wx"]);
+    assert!(hf.inbound_filters.is_empty());
+    assert_eq!(config.aprs_is.unwrap().outbound_filters, ["no-wx"]);
+    assert_eq!(config.beacon.unwrap().ports, ["vhf", "uhf"]);
+
+    // Groups only name interfaces that exist, and don't hide them
+    for group in [
+        "name = \"radios\"\nports = [\"vhf\", \"vhf2\"]",
+        "name = \"hf\"\nports = [\"vhf\"]",
+    ] {
+        std::fs::write(&path, format!("{}\n[[groups]]\n{}\n", ports, group)).unwrap();
+        assert!(Config::load(&path).is_err(), "{}", group);
+    }
+}
+
+#[test]
+fn test_example_config_loads() {
+    let example = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("aprstx.conf.example");
+    Config::load(example).unwrap();
+}
+
+#[test]
+fn test_dumped_config_loads_back() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("aprstx.conf");
+    std::fs::write(&path, aprstx::config::EXAMPLE_CONFIG).unwrap();
+    let config = Config::load(&path).unwrap();
+
+    let dumped = config.to_toml().unwrap();
+    assert!(dumped.contains("viscous_delay = 5"));
+    std::fs::write(&path, &dumped).unwrap();
+    let reloaded = Config::load(&path).unwrap();
+    assert_eq!(reloaded.to_toml().unwrap(), dumped);
+}
+
+#[tokio::test]
+async fn test_pty_port() {
+    use aprstx::config::SerialPortConfig;
+    use aprstx::router::{PacketSource, RoutedPacket};
+    use aprstx::shutdown::Shutdown;
+    use std::io::{BufRead, BufReader, Write};
+    use std::time::Duration;
+
+    let dir = tempfile::tempdir().unwrap();
+    let link = dir.path().join("tnc");
+    let config: SerialPortConfig = toml::from_str(&format!(
+        "name = \"pty\"\ndevice = \"pty:{}\"\nprotocol = \"tnc2\"",
+        link.display()
+    ))
+    .unwrap();
+    let filter = Arc::new(PacketFilter::new(vec![]).unwrap());
+    let (packet_tx, mut packet_rx) = mpsc::channel(10);
+    let (rf_tx, mut rf_rx) = mpsc::channel(10);
+    let shutdown = Shutdown::new();
+    let port = tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            aprstx::serial::run_serial_port(config, filter, packet_tx, &mut rf_rx, shutdown).await
+        }
+    });
+
+    for _ in 0..100 {
+        if link.exists() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // The test plays the TNC on the far end
+    let mut tnc = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&link)
+        .unwrap();
+    tnc.write_all(b"N1CALL>APRS,WIDE1-1:>Hello from a PTY\r\n")
+        .unwrap();
+    let heard = tokio::time::timeout(Duration::from_secs(5), packet_rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(heard.packet.information, ">Hello from a PTY");
+    assert_eq!(heard.source, PacketSource::SerialPort("pty".to_string()));
+
+    rf_tx
+        .send(RoutedPacket {
+            packet: parse_packet("N0CALL>APRS:>Hello back").unwrap(),
+            source: PacketSource::Internal,
+        })
+        .await
+        .unwrap();
+    let sent = tokio::task::spawn_blocking(move || {
+        let mut line = String::new();
+        BufReader::new(tnc).read_line(&mut line).unwrap();
+        line
+    });
+    let sent = tokio::time::timeout(Duration::from_secs(5), sent)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(sent, "N0CALL>APRS:>Hello back\r\n");
+
+    shutdown.trigger();
+    drop(rf_tx);
+    port.await.unwrap().unwrap();
+    // The link goes when the port closes
+    assert!(!link.exists());
+}